@@ -0,0 +1,32 @@
+#![feature(register_tool)]
+#![register_tool(liquid)]
+#![allow(dead_code)]
+
+#[liquid::ty("fn<p: int -> bool>(a: {v: int | p(v)}, b: {v: int | p(v)}) -> {v: int | p(v)}")]
+fn max(a: i32, b: i32) -> i32 {
+    if a >= b {
+        a
+    } else {
+        b
+    }
+}
+
+#[liquid::ty("fn(a: {int | a > 0}, b: {int | b > 0}) -> {v: int | v > 0}")]
+fn max_pos(a: i32, b: i32) -> i32 {
+    max(a, b)
+}
+
+#[liquid::ty("fn(a: {int | a < 0}, b: {int | b < 0}) -> {v: int | v < 0}")]
+fn max_neg(a: i32, b: i32) -> i32 {
+    max(a, b)
+}
+
+#[liquid::ty("fn<p: (int, int) -> bool>(n: int, a: {v: int | p(v, n)}) -> {v: int | p(v, n)}")]
+fn keep(_n: i32, a: i32) -> i32 {
+    a
+}
+
+#[liquid::ty("fn(n: int, a: {int | a > n}) -> {v: int | v > n}")]
+fn keep_above(n: i32, a: i32) -> i32 {
+    keep(n, a)
+}
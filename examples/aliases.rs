@@ -0,0 +1,31 @@
+#![feature(register_tool, custom_inner_attributes)]
+#![register_tool(liquid)]
+#![allow(dead_code)]
+#![liquid::alias(Nat = "{int | v >= 0}")]
+#![liquid::alias(Range(lo, hi) = "{int | lo <= v && v < hi}")]
+
+// Aliases can refer to other aliases.
+#[liquid::alias(Pos = "{int | v > 0}")]
+#[liquid::alias(Index(len) = "Range(0, len)")]
+#[liquid::ty("fn(n: Nat) -> Pos")]
+fn succ(n: u32) -> u32 {
+    n + 1
+}
+
+#[liquid::ty("fn(lo: int, hi: {int | hi > lo}) -> Range(lo, hi)")]
+fn first(lo: i32, _hi: i32) -> i32 {
+    lo
+}
+
+#[liquid::ty("fn(len: Pos) -> Index(len)")]
+fn last(len: i32) -> i32 {
+    len - 1
+}
+
+// The value in the alias is bound to the argument of the annotation.
+#[liquid::ty("fn(n: Nat) -> {v: int | v >= n}")]
+fn id_nat(n: u32) -> u32 {
+    n
+}
+
+fn main() {}
@@ -0,0 +1,16 @@
+#![feature(register_tool)]
+#![register_tool(liquid)]
+#![allow(dead_code)]
+
+#[liquid::ty("fn(n: {int | n >= 0}) -> int")]
+fn double(n: i32) -> i32 {
+    let m = n + n;
+    liquid::assert!(m >= n);
+    m
+}
+
+#[liquid::ty("fn(n: int) -> {v: int | v >= 0}")]
+fn trusted(n: i32) -> i32 {
+    liquid::assume!(n >= 0);
+    n
+}
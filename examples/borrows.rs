@@ -0,0 +1,25 @@
+#![feature(register_tool)]
+#![register_tool(liquid)]
+#![allow(dead_code)]
+
+#[liquid::ty("fn() -> {v: int | v == 2}")]
+fn push_len() -> usize {
+    let mut v = Vec::new();
+    v.push(v.len());
+    v.push(v.len());
+    v.len()
+}
+
+#[liquid::ty("fn() -> {v: int | v == 3}")]
+fn reborrow() -> i32 {
+    let mut n = 0;
+    let p = &mut n;
+    let q = &mut *p;
+    *q = 1;
+    let r = &mut *p;
+    *r = 2;
+    *p += 1;
+    n
+}
+
+fn main() {}
@@ -0,0 +1,36 @@
+#![feature(register_tool)]
+#![register_tool(liquid)]
+#![allow(dead_code)]
+
+#[liquid::ty("fn() -> {v: int | v == 2}")]
+fn unbox() -> i32 {
+    let b = Box::new(1);
+    let x = *b;
+    x + 1
+}
+
+#[liquid::ty("fn() -> {v: int | v == 3}")]
+fn update() -> i32 {
+    let mut b = Box::new(1);
+    *b = 2;
+    let r = &mut *b;
+    *r += 1;
+    *b
+}
+
+#[liquid::ty("fn() -> {v: int | v == 3}")]
+fn nested() -> i32 {
+    let b = Box::new(Box::new((1, 2)));
+    let p = **b;
+    p.0 + p.1
+}
+
+fn move_out() -> usize {
+    let mut v = Vec::new();
+    v.push(1);
+    let b = Box::new(v);
+    let w = *b;
+    w.len()
+}
+
+fn main() {}
@@ -0,0 +1,39 @@
+#![feature(register_tool, stmt_expr_attributes)]
+#![register_tool(liquid)]
+#![allow(dead_code)]
+
+#[liquid::ty("fn(f: Fn(x: {int | x > 0}) -> {v: int | v > x}, n: {int | n > 0}) -> {v: int | v > n}")]
+fn apply<F: Fn(i32) -> i32>(f: F, n: i32) -> i32 {
+    f(n)
+}
+
+#[liquid::ty("fn(f: Fn(x: {int | x > 0}) -> {v: int | v > x}, n: {int | n > 0}) -> {v: int | v > n}")]
+fn apply_ptr(f: fn(i32) -> i32, n: i32) -> i32 {
+    f(n)
+}
+
+#[liquid::ty("fn(x: {int | x > 0}) -> {v: int | v > x}")]
+fn succ(x: i32) -> i32 {
+    x + 1
+}
+
+#[liquid::ty("fn(k: {int | k > 0}) -> {v: int | v > 1}")]
+fn add_captured(k: i32) -> i32 {
+    apply(
+        #[liquid::ty("fn(x: {int | x > 0}) -> {v: int | v > x}")]
+        move |x| x + k,
+        1,
+    )
+}
+
+#[liquid::ty("fn() -> {v: int | v > 1}")]
+fn succ_ptr() -> i32 {
+    apply_ptr(succ, 1)
+}
+
+#[liquid::ty("fn() -> {v: int | v > 1}")]
+fn call_local() -> i32 {
+    let double = #[liquid::ty("fn(x: {int | x > 0}) -> {v: int | v > x}")]
+    |x| x + x;
+    double(1)
+}
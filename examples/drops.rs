@@ -0,0 +1,42 @@
+#![feature(register_tool)]
+#![register_tool(liquid)]
+#![allow(dead_code)]
+
+struct Counter {
+    n: i32,
+}
+
+impl Drop for Counter {
+    #[liquid::ty("fn(&mut self: Counter)")]
+    fn drop(&mut self) {
+        self.n = 0;
+    }
+}
+
+#[liquid::ty("fn() -> {v: int | v == 2}")]
+fn drop_vec() -> usize {
+    let mut v = Vec::new();
+    v.push(1);
+    v.push(2);
+    v.len()
+}
+
+#[liquid::ty("fn() -> {v: int | v == 1}")]
+fn drop_counter() -> i32 {
+    let c = Counter { n: 1 };
+    c.n
+}
+
+#[liquid::ty("fn() -> {v: int | v == 2}")]
+fn drop_scope() -> usize {
+    let mut v = Vec::new();
+    v.push(1);
+    {
+        let mut w = Vec::new();
+        w.push(v.len());
+    }
+    v.push(2);
+    v.len()
+}
+
+fn main() {}
@@ -0,0 +1,30 @@
+#![feature(register_tool)]
+#![register_tool(liquid)]
+#![allow(dead_code)]
+
+fn id<T>(x: T) -> T {
+    x
+}
+
+fn max<T: PartialOrd>(a: T, b: T) -> T {
+    if a >= b {
+        a
+    } else {
+        b
+    }
+}
+
+#[liquid::ty("fn(x: {int | x > 0}) -> {v: int | v > 0}")]
+fn id_pos(x: i32) -> i32 {
+    id(x)
+}
+
+#[liquid::ty("fn(a: {int | a >= 0}, b: {int | b >= 0}) -> {v: int | v >= 0}")]
+fn max_nat(a: i32, b: i32) -> i32 {
+    max(a, b)
+}
+
+#[liquid::ty("fn(v: Vec<T>) -> Vec<T>")]
+fn id_vec<T>(v: Vec<T>) -> Vec<T> {
+    id(v)
+}
@@ -0,0 +1,47 @@
+#![feature(register_tool)]
+#![register_tool(liquid)]
+#![allow(dead_code)]
+
+#[liquid::refined_by("a: int, b: int")]
+struct Pair {
+    #[liquid::field("int[a]")]
+    fst: i32,
+    #[liquid::field("int[b]")]
+    snd: i32,
+}
+
+#[liquid::ty("fn(x: int, y: int) -> Pair[x, y]")]
+fn pair(x: i32, y: i32) -> Pair {
+    Pair { fst: x, snd: y }
+}
+
+#[liquid::ty("fn(p: Pair[@a, @b]) -> Pair[b, a]")]
+fn swap(p: Pair) -> Pair {
+    Pair {
+        fst: p.snd,
+        snd: p.fst,
+    }
+}
+
+#[liquid::ty("fn(p: Pair[@a, @b]) -> int[a - b]")]
+fn diff(p: Pair) -> i32 {
+    p.fst - p.snd
+}
+
+#[liquid::ty("fn() -> int[1]")]
+fn test() -> i32 {
+    let p = swap(pair(1, 2));
+    diff(p)
+}
+
+impl Pair {
+    #[liquid::ty("fn(&self: Pair[@a, @b]) -> {v: int | v == a}")]
+    fn fst(&self) -> i32 {
+        self.fst
+    }
+
+    #[liquid::ty("fn(&mut self: Pair[@a, @b] -> Pair[a, b + 1])")]
+    fn incr(&mut self) {
+        self.snd += 1;
+    }
+}
@@ -0,0 +1,25 @@
+#![feature(register_tool)]
+#![register_tool(liquid)]
+#![allow(dead_code)]
+
+#[liquid::ty("fn(n: {int | n >= 0}) -> {v: int | v == n}")]
+fn count(n: i32) -> i32 {
+    let mut i = 0;
+    while i < n {
+        liquid::invariant!(i <= n);
+        i += 1;
+    }
+    i
+}
+
+#[liquid::ty("fn(n: {int | n >= 0}) -> {v: int | v >= 0}")]
+fn sum(n: i32) -> i32 {
+    let mut i = 0;
+    let mut r = 0;
+    while i < n {
+        liquid::invariant!(r >= 0 && i >= 0);
+        r += i;
+        i += 1;
+    }
+    r
+}
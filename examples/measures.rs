@@ -0,0 +1,22 @@
+#![feature(register_tool)]
+#![register_tool(liquid)]
+#![allow(dead_code)]
+
+// Only the signature of a measure is known in the logic.
+#[liquid::measure]
+fn size<T>(_x: T) -> i32 {
+    unimplemented!()
+}
+
+#[liquid::ty("fn(x: T) -> {v: int | v == size(x) && v >= 0}")]
+#[liquid::trusted]
+fn len<T>(_x: T) -> i32 {
+    unimplemented!()
+}
+
+#[liquid::ty("fn(x: T) -> {v: int | v >= 0}")]
+fn len_nat<T>(x: T) -> i32 {
+    len(x)
+}
+
+fn main() {}
@@ -0,0 +1,28 @@
+#![feature(register_tool)]
+#![register_tool(liquid)]
+#![allow(dead_code)]
+
+trait Counter {
+    #[liquid::ty("fn(&mut self: {int | self >= 0} -> {v: int | v > self})")]
+    fn incr(&mut self);
+
+    #[liquid::ty("fn(&self: {int | self >= 0}) -> {v: int | v == self}")]
+    fn get(&self) -> i32;
+}
+
+impl Counter for i32 {
+    fn incr(&mut self) {
+        *self += 1;
+    }
+
+    fn get(&self) -> i32 {
+        *self
+    }
+}
+
+#[liquid::ty("fn() -> {v: int | v > 0}")]
+fn count() -> i32 {
+    let mut x = 0;
+    x.incr();
+    x.get()
+}
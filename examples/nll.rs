@@ -0,0 +1,17 @@
+#![feature(register_tool)]
+#![register_tool(liquid)]
+#![allow(dead_code)]
+
+// The loan of `y` through `p` is still recorded in the region of `r` when `p` is dead and moved
+// out at the join.
+#[liquid::ty("fn(c: bool) -> {v: int | v == 0 || v == 1}")]
+fn join_reborrow(c: bool) -> i32 {
+    let mut y = 2;
+    let mut x = 1;
+    let p = &mut y;
+    let r = if c { &mut *p } else { &mut x };
+    *r = 0;
+    x
+}
+
+fn main() {}
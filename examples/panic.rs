@@ -0,0 +1,29 @@
+#![feature(register_tool)]
+#![register_tool(liquid)]
+#![allow(dead_code)]
+
+#[liquid::panic_free]
+#[liquid::ty("fn(x: {int | x > 0}) -> {v: int | v >= 0}")]
+fn dec(x: u32) -> u32 {
+    assert!(x > 0);
+    x - 1
+}
+
+#[liquid::panic_free]
+#[liquid::ty("fn(x: {int | x >= 0}) -> {v: int | v == 1}")]
+fn sign(x: i32) -> i32 {
+    if x >= 0 {
+        1
+    } else {
+        unreachable!()
+    }
+}
+
+#[liquid::ty("fn(x: int) -> {v: int | v > 0}")]
+fn positive(x: i32) -> i32 {
+    if x > 0 {
+        x
+    } else {
+        panic!("not positive")
+    }
+}
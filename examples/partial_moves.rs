@@ -0,0 +1,28 @@
+#![feature(register_tool)]
+#![register_tool(liquid)]
+#![allow(dead_code)]
+
+#[liquid::ty("fn() -> {v: int | v == 3}")]
+fn use_rest() -> i32 {
+    let t = (Box::new(1), Box::new(2));
+    let a = t.0;
+    let b = t.1;
+    *a + *b
+}
+
+#[liquid::ty("fn() -> {v: int | v == 5}")]
+fn reinit() -> i32 {
+    let mut t = (Box::new(1), Box::new(2));
+    let a = t.0;
+    t.0 = Box::new(3);
+    let u = t;
+    *a + *u.0 + 1
+}
+
+fn vecs() -> usize {
+    let t = (Vec::<i32>::new(), Vec::<i32>::new());
+    let v = t.0;
+    v.len() + t.1.len()
+}
+
+fn main() {}
@@ -0,0 +1,30 @@
+#![feature(register_tool)]
+#![register_tool(liquid)]
+#![allow(dead_code)]
+
+// Pure functions are checked as usual and can also be used in annotations.
+#[liquid::pure]
+fn max(a: i32, b: i32) -> i32 {
+    if a > b {
+        a
+    } else {
+        b
+    }
+}
+
+#[liquid::pure]
+fn is_between(x: i32, lo: i32, hi: i32) -> bool {
+    lo <= x && x <= hi
+}
+
+#[liquid::ty("fn(a: int, b: int) -> {v: int | v == max(a, b) && v >= a && v >= b}")]
+fn max_ge(a: i32, b: i32) -> i32 {
+    max(a, b)
+}
+
+#[liquid::ty("fn(x: int) -> {v: bool | v == is_between(x, 0, 10)}")]
+fn is_digit(x: i32) -> bool {
+    is_between(x, 0, 10)
+}
+
+fn main() {}
@@ -0,0 +1,50 @@
+#![feature(register_tool)]
+#![register_tool(liquid)]
+#![allow(dead_code)]
+
+#[liquid::invariant("self.lo <= self.hi")]
+struct Range {
+    lo: i32,
+    hi: i32,
+}
+
+#[liquid::ty("fn(lo: int, hi: {int | hi >= lo}) -> Range")]
+fn range(lo: i32, hi: i32) -> Range {
+    Range { lo, hi }
+}
+
+#[liquid::ty("fn(r: Range) -> {v: int | v >= 0}")]
+fn len(r: Range) -> i32 {
+    r.hi - r.lo
+}
+
+#[liquid::ty("fn(r: Range, n: {int | n >= 0}) -> Range")]
+fn shift(mut r: Range, n: i32) -> Range {
+    r.hi += n;
+    r.lo += n;
+    r
+}
+
+trait Incr {
+    #[liquid::ty("fn(&mut self: int -> {v: int | v > self})")]
+    fn incr(&mut self);
+}
+
+impl Incr for i32 {
+    fn incr(&mut self) {
+        *self += 1;
+    }
+}
+
+#[liquid::ty("fn(r: Range) -> Range")]
+fn extend(mut r: Range) -> Range {
+    r.hi.incr();
+    r
+}
+
+impl Range {
+    #[liquid::ty("fn(&mut self: Range, n: {int | n >= 0})")]
+    fn grow(&mut self, n: i32) {
+        self.hi += n;
+    }
+}
@@ -0,0 +1,26 @@
+#![feature(register_tool)]
+#![register_tool(liquid)]
+#![allow(dead_code)]
+
+#[liquid::ty("fn(n: {int | n >= 0}) -> {v: int | v >= n}")]
+#[liquid::decreases("n")]
+fn sum_rec(n: u32) -> u32 {
+    if n == 0 {
+        0
+    } else {
+        n + sum_rec(n - 1)
+    }
+}
+
+#[liquid::ty("fn(n: {int | n >= 0}) -> {v: int | v == n}")]
+fn count(n: i32) -> i32 {
+    let mut i = 0;
+    while i < n {
+        liquid::invariant!(i <= n);
+        liquid::decreases!(n - i);
+        i += 1;
+    }
+    i
+}
+
+fn main() {}
@@ -0,0 +1,35 @@
+#![feature(register_tool)]
+#![register_tool(liquid)]
+#![allow(dead_code)]
+
+trait Step {
+    #[liquid::ty("fn(x: {int | x >= 0}) -> {v: int | v > x}")]
+    fn step(x: i32) -> i32;
+}
+
+struct Inc;
+
+impl Step for Inc {
+    fn step(x: i32) -> i32 {
+        x + 1
+    }
+}
+
+struct Double;
+
+impl Step for Double {
+    #[liquid::ty("fn(x: int) -> {v: int | v == x + x + 1}")]
+    fn step(x: i32) -> i32 {
+        x + x + 1
+    }
+}
+
+#[liquid::ty("fn(x: {int | x >= 0}) -> {v: int | v > 1}")]
+fn twice<S: Step>(x: i32) -> i32 {
+    S::step(S::step(x))
+}
+
+#[liquid::ty("fn() -> {v: int | v > 0}")]
+fn inc() -> i32 {
+    twice::<Inc>(0)
+}
@@ -0,0 +1,23 @@
+#![feature(register_tool)]
+#![register_tool(liquid)]
+#![allow(dead_code)]
+
+// The body is not checked, calls rely on the annotation.
+#[liquid::ty("fn(n: int) -> {v: int | v > n}")]
+#[liquid::trusted]
+fn next(n: u32) -> u32 {
+    (n as f32 + 1.5) as u32
+}
+
+#[liquid::ty("fn(n: int) -> {v: int | v > n + 1}")]
+fn twice(n: u32) -> u32 {
+    next(next(n))
+}
+
+// Neither checked nor callable from checked functions.
+#[liquid::ignore]
+fn scale(x: f32) -> f32 {
+    x * 2.0
+}
+
+fn main() {}
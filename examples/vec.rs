@@ -0,0 +1,36 @@
+#![feature(register_tool)]
+#![register_tool(liquid)]
+#![allow(dead_code)]
+
+fn push_len() -> usize {
+    let mut v = Vec::new();
+    v.push(1);
+    v.push(2);
+    v.len()
+}
+
+fn last() -> i32 {
+    let mut v = Vec::new();
+    v.push(1);
+    let n = v.len();
+    v[n - 1]
+}
+
+fn set() {
+    let mut v = Vec::new();
+    v.push(true);
+    v[0] = false;
+}
+
+fn pop() -> i32 {
+    let mut v = Vec::new();
+    v.push(1);
+    v.pop().unwrap()
+}
+
+#[liquid::ty("fn(v: {Vec<int> | v > 0}) -> int")]
+fn first(v: Vec<i32>) -> i32 {
+    v[0]
+}
+
+fn main() {}
@@ -1,9 +1,12 @@
 use std::{
+    cell::RefCell,
+    collections::HashMap,
     env,
     fs::File,
     io::{prelude::*, BufReader},
 };
 
+use codespan::Span;
 use codespan_reporting::{
     diagnostic::{Diagnostic, Label},
     files::SimpleFile,
@@ -13,33 +16,197 @@ use codespan_reporting::{
     },
 };
 use lalrpop_util::lalrpop_mod;
-use liquid_rust_typeck::check_program;
+use liquid_rust_core::{
+    ast::{
+        pred::{self, Pred},
+        BaseTy, FnDecl, FnDef, MeasureDecl, PredParam, Refine, Ty, TyParam, UniversalRegion,
+    },
+    names::{Field, FnId, Location, MeasureId},
+};
+use liquid_rust_typeck::{check_program, dump::DumpConfig};
 lalrpop_mod!(
     #[allow(clippy::all, clippy::pedantic)]
     pub grammar
 );
+/// A top level item in a program.
+pub enum Item<'input> {
+    Fn(FnId<&'input str>, FnDef<Span, &'input str>),
+    Extern(FnId<&'input str>, FnDecl<&'input str>),
+    Measure(MeasureId<&'input str>, MeasureDecl<&'input str>),
+    /// An implementation of a trait method and the specification it must satisfy.
+    Impl(FnId<&'input str>, FnId<&'input str>),
+    /// A type alias. Aliases are expanded while parsing, so they don't appear in the program.
+    Alias,
+}
+
+/// The type aliases defined so far, with the locations standing for their parameters.
+pub type Aliases<'input> = HashMap<&'input str, (Vec<Location<&'input str>>, Ty<&'input str>)>;
+
+/// Expands the alias `name` applied to `args`. A name without arguments that is not an alias is a
+/// type parameter.
+pub fn expand_alias<'input>(
+    aliases: &Aliases<'input>,
+    name: &'input str,
+    args: Vec<Pred<&'input str>>,
+) -> Result<Ty<&'input str>, &'static str> {
+    match aliases.get(name) {
+        Some((params, ty)) if params.len() == args.len() => {
+            let subst: HashMap<_, _> = params.iter().copied().zip(args).collect();
+            subst_ty(ty, &subst)
+        }
+        Some(_) => Err("wrong number of arguments for type alias"),
+        None if args.is_empty() => Ok(Ty::Param(TyParam::new(name))),
+        None => Err("undefined type alias"),
+    }
+}
+
+type Subst<'input> = HashMap<Location<&'input str>, Pred<&'input str>>;
+
+/// Substitutes the parameters of an alias in its definition. The arguments of an abstract
+/// refinement are variables, so the parameters they mention can only be replaced by variables.
+fn subst_ty<'input>(
+    ty: &Ty<&'input str>,
+    subst: &Subst<'input>,
+) -> Result<Ty<&'input str>, &'static str> {
+    let subst_refine = |refine: &Refine<&'input str>| -> Result<_, &'static str> {
+        match refine {
+            Refine::Pred(pred) => Ok(Refine::Pred(subst_pred(pred, subst))),
+            Refine::Infer => Ok(Refine::Infer),
+            Refine::Abstract(param, args) => Ok(Refine::Abstract(
+                *param,
+                args.iter()
+                    .map(|arg| match arg {
+                        pred::Var::Location(l) => match subst.get(l) {
+                            Some(Pred::Place(place)) if place.projs.is_empty() => Ok(place.base),
+                            Some(_) => Err("abstract refinements can only be applied to variables"),
+                            None => Ok(*arg),
+                        },
+                        _ => Ok(*arg),
+                    })
+                    .collect::<Result<_, _>>()?,
+            )),
+        }
+    };
+    let subst_fields = |fields: &[(Field<&'input str>, Ty<&'input str>)]| {
+        fields
+            .iter()
+            .map(|(f, ty)| Ok((*f, subst_ty(ty, subst)?)))
+            .collect::<Result<_, &'static str>>()
+    };
+    let ty = match ty {
+        Ty::Refine(bty, refine) => Ty::Refine(*bty, subst_refine(refine)?),
+        Ty::Vec(ty, refine) => Ty::Vec(Box::new(subst_ty(ty, subst)?), subst_refine(refine)?),
+        Ty::Tuple(fields) => Ty::Tuple(subst_fields(fields)?),
+        Ty::Struct(fields, invariant) => Ty::Struct(subst_fields(fields)?, invariant.clone()),
+        Ty::Indexed(ty, indices) => Ty::Indexed(
+            Box::new(subst_ty(ty, subst)?),
+            indices
+                .iter()
+                .map(|index| subst_pred(index, subst))
+                .collect(),
+        ),
+        _ => ty.clone(),
+    };
+    Ok(ty)
+}
+
+fn subst_pred<'input>(pred: &Pred<&'input str>, subst: &Subst<'input>) -> Pred<&'input str> {
+    let boxed = |pred: &Pred<&'input str>| Box::new(subst_pred(pred, subst));
+    match pred {
+        Pred::Place(place) => match place.base {
+            pred::Var::Location(l) if subst.contains_key(&l) => match subst[&l].clone() {
+                Pred::Place(mut arg) => {
+                    arg.projs.extend(&place.projs);
+                    Pred::Place(arg)
+                }
+                arg => arg,
+            },
+            _ => pred.clone(),
+        },
+        Pred::Constant(_) => pred.clone(),
+        Pred::BinaryOp(op, p1, p2) => Pred::BinaryOp(*op, boxed(p1), boxed(p2)),
+        Pred::UnaryOp(op, p) => Pred::UnaryOp(*op, boxed(p)),
+        Pred::App(measure, args) => Pred::App(
+            *measure,
+            args.iter().map(|arg| subst_pred(arg, subst)).collect(),
+        ),
+        Pred::Ite(p1, p2, p3) => Pred::Ite(boxed(p1), boxed(p2), boxed(p3)),
+    }
+}
+
+/// A generic parameter of a function, either a region together with the regions it outlives, a
+/// type or a predicate together with the sorts of its arguments.
+pub enum GenericParam<'input> {
+    Region(
+        UniversalRegion<&'input str>,
+        Vec<UniversalRegion<&'input str>>,
+    ),
+    Ty(TyParam<&'input str>),
+    Pred(PredParam<&'input str>, Vec<BaseTy>),
+}
+
 type ParseError<'input> = lalrpop_util::ParseError<usize, grammar::Token<'input>, &'input str>;
 
 fn main() -> Result<(), codespan_reporting::files::Error> {
-    let args: Vec<_> = env::args().collect();
+    let mut args: Vec<_> = env::args().collect();
+    let dump = match DumpConfig::from_args(&mut args) {
+        Ok(dump) => dump,
+        Err(err) => {
+            eprintln!("error: {}", err);
+            std::process::exit(1);
+        }
+    };
     let mut buf_reader = BufReader::new(File::open(&args[1])?);
     let mut contents = String::new();
     buf_reader.read_to_string(&mut contents)?;
 
     let file = SimpleFile::new(&args[1], &contents);
 
-    let program = match grammar::ProgramParser::new().parse(&contents) {
-        Ok(program) => program,
-        Err(err) => {
-            diagnostics(&file, err)?;
-            return Ok(());
-        }
-    };
+    let program =
+        match grammar::ProgramParser::new().parse(&RefCell::new(HashMap::new()), &contents) {
+            Ok(program) => program,
+            Err(err) => {
+                diagnostics(&file, err)?;
+                return Ok(());
+            }
+        };
 
-    check_program(program);
+    let mut errors = vec![];
+    check_program(
+        program,
+        &dump,
+        |x| x.inner().to_string(),
+        |func, span, msg| errors.push((func, span, msg)),
+    );
+    for (func, span, msg) in errors {
+        report_error(&file, func, span, msg)?;
+    }
     Ok(())
 }
 
+/// Reports an error found while checking `func`, pointing to the statement where it was found if
+/// it is known.
+fn report_error(
+    file: &SimpleFile<&String, &String>,
+    func: FnId<&str>,
+    span: Option<Span>,
+    msg: String,
+) -> Result<(), codespan_reporting::files::Error> {
+    let labels = span
+        .map(|span| Label::primary((), span))
+        .into_iter()
+        .collect();
+    let diagnostic = Diagnostic::error()
+        .with_message(msg)
+        .with_labels(labels)
+        .with_notes(vec![format!("`{}` is not safe", func.inner())]);
+    let writer = StandardStream::stderr(ColorChoice::Always);
+    let config = codespan_reporting::term::Config::default();
+    let mut lock = writer.lock();
+
+    term::emit(&mut lock, &config, file, &diagnostic)
+}
+
 fn diagnostics(
     file: &SimpleFile<&String, &String>,
     err: ParseError,
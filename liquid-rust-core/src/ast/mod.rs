@@ -4,17 +4,23 @@ pub mod visitor;
 use std::{collections::HashMap, fmt};
 
 pub use self::pred::Pred;
-use crate::names::{ContId, Field, FnId, Local, Location};
+use crate::names::{ContId, Field, FnId, Local, Location, MeasureId};
 
 #[derive(Default)]
 pub struct Program<I, S = usize> {
     functions: HashMap<FnId<S>, FnDef<I, S>>,
+    externs: HashMap<FnId<S>, FnDecl<S>>,
+    impls: Vec<(FnId<S>, FnId<S>)>,
+    measures: HashMap<MeasureId<S>, MeasureDecl<S>>,
 }
 
 impl<I, S: Eq + std::hash::Hash> Program<I, S> {
     pub fn new() -> Self {
         Self {
             functions: HashMap::new(),
+            externs: HashMap::new(),
+            impls: Vec::new(),
+            measures: HashMap::new(),
         }
     }
 
@@ -26,9 +32,68 @@ impl<I, S: Eq + std::hash::Hash> Program<I, S> {
         self.functions.insert(fn_id, def);
     }
 
+    /// Removes a function with a body from the program, e.g., because it can't be checked.
+    pub fn remove_fn(&mut self, fn_id: &FnId<S>) -> Option<FnDef<I, S>> {
+        self.functions.remove(fn_id)
+    }
+
+    /// Returns the definition of a function with a body.
+    pub fn get(&self, fn_id: &FnId<S>) -> Option<&FnDef<I, S>> {
+        self.functions.get(fn_id)
+    }
+
+    /// Whether `fn_id` is defined in the program, either with a body or as an extern function.
+    pub fn contains(&self, fn_id: &FnId<S>) -> bool {
+        self.functions.contains_key(fn_id) || self.externs.contains_key(fn_id)
+    }
+
+    /// Adds a trusted function signature without a body. Calls to the function are checked
+    /// against the signature, but there is nothing to verify for the function itself.
+    pub fn add_extern_fn(&mut self, fn_id: FnId<S>, decl: FnDecl<S>) {
+        self.externs.insert(fn_id, decl);
+    }
+
+    /// Records that `impl_fn` implements a trait method whose (instantiated) specification is
+    /// `trait_fn`. The signature of `impl_fn` must be a subtype of the one of `trait_fn`.
+    pub fn add_impl(&mut self, impl_fn: FnId<S>, trait_fn: FnId<S>) {
+        self.impls.push((impl_fn, trait_fn));
+    }
+
+    /// Declares a measure that can be applied in the predicates of the program.
+    pub fn add_measure(&mut self, measure: MeasureId<S>, decl: MeasureDecl<S>) {
+        self.measures.insert(measure, decl);
+    }
+
     pub fn iter(&self) -> impl Iterator<Item = (&FnId<S>, &FnDef<I, S>)> {
         self.functions.iter()
     }
+
+    pub fn externs(&self) -> impl Iterator<Item = (&FnId<S>, &FnDecl<S>)> {
+        self.externs.iter()
+    }
+
+    pub fn impls(&self) -> impl Iterator<Item = &(FnId<S>, FnId<S>)> {
+        self.impls.iter()
+    }
+
+    pub fn measures(&self) -> impl Iterator<Item = (&MeasureId<S>, &MeasureDecl<S>)> {
+        self.measures.iter()
+    }
+
+    /// Removes the measures from the program.
+    pub fn take_measures(&mut self) -> HashMap<MeasureId<S>, MeasureDecl<S>> {
+        std::mem::take(&mut self.measures)
+    }
+
+    /// Removes the pairs of implementations and trait methods from the program.
+    pub fn take_impls(&mut self) -> Vec<(FnId<S>, FnId<S>)> {
+        std::mem::take(&mut self.impls)
+    }
+
+    /// Removes the extern functions from the program, leaving only the functions with a body.
+    pub fn take_externs(&mut self) -> HashMap<FnId<S>, FnDecl<S>> {
+        std::mem::take(&mut self.externs)
+    }
 }
 
 impl<I, S> IntoIterator for Program<I, S> {
@@ -41,11 +106,31 @@ impl<I, S> IntoIterator for Program<I, S> {
     }
 }
 
+/// The signature of a measure. Measures without a definition are uninterpreted in the logic, so
+/// all that is known about them is that they are functions.
+#[derive(Clone)]
+pub struct MeasureDecl<S = usize> {
+    pub inputs: Vec<BaseTy>,
+    pub output: BaseTy,
+    /// The definition of a measure reflecting a pure function.
+    pub def: Option<MeasureDef<S>>,
+}
+
+/// The body of a reflected function, a predicate over locations standing for its parameters.
+#[derive(Clone)]
+pub struct MeasureDef<S = usize> {
+    pub params: Vec<Location<S>>,
+    pub body: Pred<S>,
+}
+
 pub struct FnDef<I, S = usize> {
     pub ty: FnDecl<S>,
     pub params: Vec<Local<S>>,
     pub body: FnBody<I, S>,
     pub ret: ContId<S>,
+    /// A termination metric over the input locations. It must be non-negative and strictly
+    /// decrease at each recursive call.
+    pub decreases: Option<Pred<S>>,
 }
 
 pub enum FnBody<I, S = usize> {
@@ -56,7 +141,7 @@ pub enum FnBody<I, S = usize> {
         else_: Box<FnBody<I, S>>,
     },
     Call {
-        func: FnId<S>,
+        func: Callee<S>,
         args: Vec<Local<S>>,
         destination: Option<(Place<S>, ContId<S>)>,
     },
@@ -68,6 +153,14 @@ pub enum FnBody<I, S = usize> {
     Abort,
 }
 
+/// The function called by a `FnBody::Call`.
+pub enum Callee<S = usize> {
+    /// A function known statically.
+    Fn(FnId<S>),
+    /// A function value stored in a place, e.g., a closure or a function pointer.
+    Place(Place<S>),
+}
+
 pub struct ContDef<I, S = usize> {
     pub name: ContId<S>,
     pub params: Vec<Local<S>>,
@@ -79,6 +172,37 @@ pub struct ContTy<S = usize> {
     pub heap: Heap<S>,
     pub locals: Vec<(Local<S>, Location<S>)>,
     pub inputs: Vec<Location<S>>,
+    /// A loop invariant over the locations in the heap. It is conjoined with the refinement of
+    /// the last location it mentions, which is no longer inferred.
+    pub invariant: Option<Pred<S>>,
+    /// A termination metric over the locations in the heap. It must be non-negative and strictly
+    /// decrease at each jump to the continuation from its own body, i.e., at each back edge.
+    pub decreases: Option<Pred<S>>,
+}
+
+impl<S: Copy + Eq> ContTy<S> {
+    /// Returns the location whose refinement is fixed by the loop invariant, i.e., the last
+    /// location of the heap it mentions, or `None` if there is no invariant.
+    pub fn invariant_location(&self) -> Result<Option<Location<S>>, &'static str> {
+        let invariant = match &self.invariant {
+            Some(invariant) => invariant,
+            None => return Ok(None),
+        };
+        let mentioned = invariant.locations();
+        let (l, ty) = self
+            .heap
+            .iter()
+            .rev()
+            .find(|(l, _)| mentioned.contains(l))
+            .ok_or("invariant doesn't mention any location in scope")?;
+        match ty {
+            Ty::Refine(_, Refine::Abstract(..)) | Ty::Vec(_, Refine::Abstract(..)) => {
+                Err("invariant can't refine a location with an abstract refinement")
+            }
+            Ty::Refine(..) | Ty::Vec(..) => Ok(Some(*l)),
+            _ => Err("invariant must refine a location of base type"),
+        }
+    }
 }
 
 pub struct Statement<I, S = usize> {
@@ -87,15 +211,20 @@ pub struct Statement<I, S = usize> {
 }
 
 pub enum StatementKind<S = usize> {
-    Let(Local<S>, TypeLayout),
+    Let(Local<S>, TypeLayout<S>),
     Assign(Place<S>, Rvalue<S>),
     Drop(Place<S>),
+    /// A predicate over the locations in scope that must hold at this point.
+    Assert(Pred<S>),
+    /// A predicate over the locations in scope that is trusted to hold at this point.
+    Assume(Pred<S>),
     Nop,
 }
 
-#[derive(Debug)]
-pub enum TypeLayout {
-    Tuple(Vec<TypeLayout>),
+pub enum TypeLayout<S = usize> {
+    Tuple(Vec<TypeLayout<S>>),
+    /// The layout of a struct whose fields must satisfy the invariant once initialized.
+    Struct(Vec<TypeLayout<S>>, Pred<S>),
     Block(usize),
 }
 #[derive(Debug)]
@@ -126,9 +255,17 @@ impl Constant {
 pub enum Rvalue<S = usize> {
     Use(Operand<S>),
     Ref(BorrowKind, Place<S>),
+    /// A mutable borrow that is only reserved until the reference is first used, as in Rust's
+    /// two-phase borrows. While reserved, it behaves like a shared borrow of the place.
+    TwoPhaseRef(Place<S>),
     BinaryOp(BinOp, Operand<S>, Operand<S>),
     CheckedBinaryOp(BinOp, Operand<S>, Operand<S>),
     UnaryOp(UnOp, Operand<S>),
+    /// A closure value. The operands are the captured values, which the closure receives through
+    /// its first argument.
+    Closure(FnId<S>, Vec<Operand<S>>),
+    /// A function used as a value.
+    FnPtr(FnId<S>),
 }
 
 #[derive(Copy, Clone, Eq, PartialEq, Debug)]
@@ -181,8 +318,24 @@ pub enum Ty<S = usize> {
     OwnRef(Location<S>),
     Ref(BorrowKind, Region<S>, Location<S>),
     Tuple(Vec<(Field<S>, Ty<S>)>),
+    /// A tuple representing a struct whose fields must satisfy the invariant. The invariant refers
+    /// to the fields by their position in `V`, e.g., `V.0 <= V.1`.
+    Struct(Vec<(Field<S>, Ty<S>)>, Pred<S>),
+    /// A struct refined by abstract indices, e.g., `Pair[a, b]`. The first component is the
+    /// tuple the struct unfolds to, with the indices already substituted in the types of its
+    /// fields. The indices are kept to instantiate the index binders of a function at calls.
+    Indexed(Box<Ty<S>>, Vec<Pred<S>>),
     Uninit(usize),
     Refine(BaseTy, Refine<S>),
+    /// A vector with elements of the given type. The refinement constrains the length of the
+    /// vector, i.e., `V` stands for the length in the predicate.
+    Vec(Box<Ty<S>>, Refine<S>),
+    /// A type parameter of the enclosing function. Values of this type are opaque, and the
+    /// parameter is instantiated at each call site.
+    Param(TyParam<S>),
+    /// A function value with the given signature, e.g., a closure or a function pointer. The
+    /// signature doesn't mention the state captured by the function.
+    Fn(Box<FnDecl<S>>),
 }
 
 impl<S> Ty<S> {
@@ -192,10 +345,31 @@ impl<S> Ty<S> {
             Refine::Pred(pred::Pred::Constant(pred::Constant::Bool(true))),
         )
     }
+
+    /// Whether some refinement in the type is left to be inferred.
+    pub fn has_holes(&self) -> bool {
+        match self {
+            Ty::OwnRef(_) | Ty::Ref(..) | Ty::Uninit(_) | Ty::Param(_) => false,
+            Ty::Tuple(fields) | Ty::Struct(fields, _) => {
+                fields.iter().any(|(_, ty)| ty.has_holes())
+            }
+            Ty::Indexed(ty, _) => ty.has_holes(),
+            Ty::Refine(_, refine) => matches!(refine, Refine::Infer),
+            Ty::Vec(ty, refine) => matches!(refine, Refine::Infer) || ty.has_holes(),
+            Ty::Fn(decl) => decl.has_holes(),
+        }
+    }
 }
 
+#[derive(Clone)]
 pub struct FnDecl<S = usize> {
+    pub ty_params: Vec<TyParam<S>>,
+    /// The predicates the function is parametric over, together with the sorts of their
+    /// arguments.
+    pub pred_params: Vec<(PredParam<S>, Vec<BaseTy>)>,
     pub regions: Vec<UniversalRegion<S>>,
+    /// The bounds `'a: 'b` between the universal regions of the function.
+    pub outlives: Vec<(UniversalRegion<S>, UniversalRegion<S>)>,
     pub in_heap: Heap<S>,
     pub inputs: Vec<(Local<S>, Location<S>)>,
     pub out_heap: Heap<S>,
@@ -203,12 +377,27 @@ pub struct FnDecl<S = usize> {
     pub output: Location<S>,
 }
 
+impl<S> FnDecl<S> {
+    /// Whether some refinement in the signature is left to be inferred. The kvars inferred for it
+    /// are shared by the function and the ones referring to it.
+    pub fn has_holes(&self) -> bool {
+        self.in_heap
+            .iter()
+            .chain(self.out_heap.iter())
+            .any(|(_, ty)| ty.has_holes())
+    }
+}
+
 #[derive(Clone)]
 pub enum Refine<S = usize> {
     Infer,
     Pred(Pred<S>),
+    /// An abstract refinement, i.e., a predicate parameter of the enclosing function applied to
+    /// some variables, e.g., `p(V)`.
+    Abstract(PredParam<S>, Vec<pred::Var<S>>),
 }
 
+#[derive(Clone)]
 pub struct Heap<S = usize>(Vec<(Location<S>, Ty<S>)>);
 
 wrap_iterable! {
@@ -226,6 +415,14 @@ newtype_name! {
     struct UniversalRegion
 }
 
+newtype_name! {
+    struct TyParam
+}
+
+newtype_name! {
+    struct PredParam
+}
+
 impl<S> From<Vec<Place<S>>> for Region<S> {
     fn from(v: Vec<Place<S>>) -> Self {
         Region::Concrete(v)
@@ -248,25 +445,10 @@ impl<S> Place<S> {
     pub fn new(base: Local<S>, projs: Vec<Proj>) -> Self {
         Self { base, projs }
     }
-}
 
-impl<S: Eq> Place<S> {
-    pub fn overlaps(&self, place: &Place<S>) -> bool {
-        if self.base != place.base {
-            return false;
-        }
-        for (proj1, proj2) in self.projs.iter().zip(&place.projs) {
-            if proj1 != proj2 {
-                return false;
-            }
-        }
-        true
-    }
-}
-
-impl fmt::Display for Place {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let mut s = format!("_{}", self.base.inner());
+    /// Formats the place naming its base local `base`.
+    pub fn format_with_base(&self, base: String) -> String {
+        let mut s = base;
         let mut need_parens = false;
         for proj in &self.projs {
             match proj {
@@ -284,7 +466,21 @@ impl fmt::Display for Place {
                 }
             }
         }
-        write!(f, "{}", s)
+        s
+    }
+}
+
+impl<S: Eq> Place<S> {
+    pub fn overlaps(&self, place: &Place<S>) -> bool {
+        if self.base != place.base {
+            return false;
+        }
+        for (proj1, proj2) in self.projs.iter().zip(&place.projs) {
+            if proj1 != proj2 {
+                return false;
+            }
+        }
+        true
     }
 }
 
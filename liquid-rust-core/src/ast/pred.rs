@@ -1,7 +1,7 @@
 use std::fmt;
 
 use super::BaseTy;
-use crate::names::{Field, Location};
+use crate::names::{Field, Location, MeasureId};
 
 #[derive(Clone)]
 pub enum Pred<S = usize> {
@@ -9,6 +9,10 @@ pub enum Pred<S = usize> {
     Place(Place<S>),
     BinaryOp(BinOp, Box<Pred<S>>, Box<Pred<S>>),
     UnaryOp(UnOp, Box<Pred<S>>),
+    /// An application of a measure, i.e., an uninterpreted function.
+    App(MeasureId<S>, Vec<Pred<S>>),
+    /// A conditional, `if p1 then p2 else p3`.
+    Ite(Box<Pred<S>>, Box<Pred<S>>, Box<Pred<S>>),
 }
 
 impl<S> Pred<S> {
@@ -17,6 +21,41 @@ impl<S> Pred<S> {
     }
 }
 
+impl<S: Copy> Pred<S> {
+    /// The locations mentioned by the predicate.
+    pub fn locations(&self) -> Vec<Location<S>> {
+        let mut locations = vec![];
+        self.collect_locations(&mut locations);
+        locations
+    }
+
+    fn collect_locations(&self, locations: &mut Vec<Location<S>>) {
+        match self {
+            Pred::Constant(_) => {}
+            Pred::Place(place) => {
+                if let Var::Location(l) = place.base {
+                    locations.push(l);
+                }
+            }
+            Pred::BinaryOp(_, op1, op2) => {
+                op1.collect_locations(locations);
+                op2.collect_locations(locations);
+            }
+            Pred::UnaryOp(_, op) => op.collect_locations(locations),
+            Pred::App(_, args) => {
+                for arg in args {
+                    arg.collect_locations(locations);
+                }
+            }
+            Pred::Ite(p1, p2, p3) => {
+                p1.collect_locations(locations);
+                p2.collect_locations(locations);
+                p3.collect_locations(locations);
+            }
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
 pub enum Constant {
     Bool(bool),
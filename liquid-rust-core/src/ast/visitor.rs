@@ -62,7 +62,10 @@ pub fn walk_fn_body<I, S, V: Visitor<I, S>>(visitor: &mut V, fn_body: &FnBody<I,
             args,
             destination,
         } => {
-            visitor.visit_fn_id(func);
+            match func {
+                Callee::Fn(func) => visitor.visit_fn_id(func),
+                Callee::Place(place) => visitor.visit_place(place),
+            }
             walk_list!(visitor, visit_local, args);
             if let Some((place, ret)) = destination {
                 visitor.visit_cont_id(ret);
@@ -99,7 +102,7 @@ pub fn walk_stmnt<I, S, V: Visitor<I, S>>(visitor: &mut V, stmnt: &Statement<I,
         StatementKind::Drop(place) => {
             visitor.visit_place(place);
         }
-        StatementKind::Nop => {}
+        StatementKind::Assert(_) | StatementKind::Assume(_) | StatementKind::Nop => {}
     }
 }
 
@@ -108,13 +111,20 @@ pub fn walk_rvalue<I, S, V: Visitor<I, S>>(visitor: &mut V, rvalue: &Rvalue<S>)
         Rvalue::Use(operand) | Rvalue::UnaryOp(_, operand) => {
             visitor.visit_operand(operand);
         }
-        Rvalue::Ref(_, place) => {
+        Rvalue::Ref(_, place) | Rvalue::TwoPhaseRef(place) => {
             visitor.visit_place(place);
         }
         Rvalue::CheckedBinaryOp(_, lhs, rhs) | Rvalue::BinaryOp(_, lhs, rhs) => {
             visitor.visit_operand(lhs);
             visitor.visit_operand(rhs);
         }
+        Rvalue::Closure(func, captures) => {
+            visitor.visit_fn_id(func);
+            walk_list!(visitor, visit_operand, captures);
+        }
+        Rvalue::FnPtr(func) => {
+            visitor.visit_fn_id(func);
+        }
     }
 }
 
@@ -124,3 +134,18 @@ pub fn walk_operand<I, S, V: Visitor<I, S>>(visitor: &mut V, operand: &Operand<S
         Operand::Constant(c) => visitor.visit_constant(c),
     }
 }
+
+/// Returns the functions referred to in a body, by calls or as values.
+pub fn fn_refs<I, S: Copy>(body: &FnBody<I, S>) -> Vec<FnId<S>> {
+    struct FnRefs<S>(Vec<FnId<S>>);
+
+    impl<I, S: Copy> Visitor<I, S> for FnRefs<S> {
+        fn visit_fn_id(&mut self, fn_id: &FnId<S>) {
+            self.0.push(*fn_id);
+        }
+    }
+
+    let mut refs = FnRefs(vec![]);
+    refs.visit_fn_body(body);
+    refs.0
+}
@@ -5,7 +5,7 @@ use crate::{
         pred::{Pred, Var},
         *,
     },
-    names::{ContId, Field, FnId, Local, Location},
+    names::{ContId, Field, FnId, Local, Location, MeasureId},
     ty::context::TyCtxt,
 };
 use quickscope::ScopeMap;
@@ -15,11 +15,24 @@ pub struct NameFreshener<'a, S> {
     locals: ScopeMap<Local<S>, Local>,
     locations: ScopeMap<Location<S>, Location>,
     fields: ScopeMap<Field<S>, Field>,
-    regions: HashMap<UniversalRegion<S>, UniversalRegion>,
+    regions: ScopeMap<UniversalRegion<S>, UniversalRegion>,
+    ty_params: ScopeMap<TyParam<S>, TyParam>,
+    pred_params: ScopeMap<PredParam<S>, PredParam>,
     fns: HashMap<FnId<S>, FnId>,
+    measures: HashMap<MeasureId<S>, MeasureId>,
+    /// The original name of each fresh local and universal region.
+    local_names: HashMap<Local, Local<S>>,
+    region_names: HashMap<UniversalRegion, UniversalRegion<S>>,
     tcx: &'a TyCtxt,
 }
 
+/// The original names of a freshened program, used to report errors in terms of the source.
+pub struct SourceNames<S> {
+    pub fns: HashMap<FnId, FnId<S>>,
+    pub locals: HashMap<Local, Local<S>>,
+    pub regions: HashMap<UniversalRegion, UniversalRegion<S>>,
+}
+
 impl<'a, S> NameFreshener<'a, S>
 where
     S: Eq + Copy + std::hash::Hash + std::fmt::Debug,
@@ -30,37 +43,136 @@ where
             locals: ScopeMap::new(),
             locations: ScopeMap::new(),
             fields: ScopeMap::new(),
-            regions: HashMap::new(),
+            regions: ScopeMap::new(),
+            ty_params: ScopeMap::new(),
+            pred_params: ScopeMap::new(),
             fns: HashMap::new(),
+            measures: HashMap::new(),
+            local_names: HashMap::new(),
+            region_names: HashMap::new(),
             tcx,
         }
     }
 
-    pub fn freshen<I>(mut self, program: Program<I, S>) -> Program<I> {
+    /// Freshens the names in `program`, returning the original names as well.
+    pub fn freshen<I>(mut self, mut program: Program<I, S>) -> (Program<I>, SourceNames<S>) {
+        let externs = program.take_externs();
+        let impls = program.take_impls();
+        let mut measures = vec![];
+        let decls = program.take_measures();
+        for measure in decls.keys() {
+            self.measures
+                .insert(*measure, self.tcx.fresh::<MeasureId>());
+        }
+        for (measure, decl) in decls {
+            measures.push((self.measures[&measure], self.freshen_measure(decl)));
+        }
         let mut defs = vec![];
         for (fn_id, def) in program {
             let fresh = self.tcx.fresh::<FnId>();
             self.fns.insert(fn_id, fresh);
             defs.push((fresh, def))
         }
+        let mut decls = vec![];
+        for (fn_id, decl) in externs {
+            let fresh = self.tcx.fresh::<FnId>();
+            self.fns.insert(fn_id, fresh);
+            decls.push((fresh, decl))
+        }
         let mut program = Program::new();
+        for (measure, decl) in measures {
+            program.add_measure(measure, decl);
+        }
         for (fn_id, def) in defs {
             program.add_fn(fn_id, self.freshen_fn_def(def));
         }
-        program
+        for (fn_id, decl) in decls {
+            program.add_extern_fn(fn_id, self.freshen_extern_fn(decl));
+        }
+        for (impl_fn, trait_fn) in impls {
+            program.add_impl(self.fns[&impl_fn], self.fns[&trait_fn]);
+        }
+        let names = SourceNames {
+            fns: self
+                .fns
+                .iter()
+                .map(|(name, fresh)| (*fresh, *name))
+                .collect(),
+            locals: self.local_names,
+            regions: self.region_names,
+        };
+        (program, names)
+    }
+
+    fn freshen_measure(&mut self, decl: MeasureDecl<S>) -> MeasureDecl {
+        let def = decl.def.map(|def| {
+            self.locations.push_layer();
+            let params = def
+                .params
+                .iter()
+                .map(|param| {
+                    let fresh = self.tcx.fresh::<Location>();
+                    self.locations.define(*param, fresh);
+                    fresh
+                })
+                .collect();
+            let body = self.freshen_pred(def.body);
+            self.locations.pop_layer();
+            MeasureDef { params, body }
+        });
+        MeasureDecl {
+            inputs: decl.inputs,
+            output: decl.output,
+            def,
+        }
+    }
+
+    /// Freshens a signature that binds its own locations, i.e., the one of an extern function or
+    /// of a function type. Its generics are scoped too, so a function type doesn't shadow the ones
+    /// of the signature it appears in.
+    fn freshen_extern_fn(&mut self, decl: FnDecl<S>) -> FnDecl {
+        let tcx = self.tcx;
+        self.locations.push_layer();
+        self.regions.push_layer();
+        self.ty_params.push_layer();
+        self.pred_params.push_layer();
+        for (location, _) in &decl.in_heap {
+            self.locations.define(*location, tcx.fresh::<Location>());
+        }
+        for region in &decl.regions {
+            self.define_region(*region);
+        }
+        for param in &decl.ty_params {
+            self.ty_params.define(*param, tcx.fresh::<TyParam>());
+        }
+        for (param, _) in &decl.pred_params {
+            self.pred_params.define(*param, tcx.fresh::<PredParam>());
+        }
+        let decl = self.freshen_fn_ty(decl);
+        self.pred_params.pop_layer();
+        self.ty_params.pop_layer();
+        self.regions.pop_layer();
+        self.locations.pop_layer();
+        decl
     }
 
     fn freshen_fn_def<I>(&mut self, def: FnDef<I, S>) -> FnDef<I> {
         let tcx = self.tcx;
         self.conts.define(def.ret, tcx.fresh::<ContId>());
         for local in &def.params {
-            self.locals.define(*local, tcx.fresh::<Local>())
+            self.define_local(*local)
         }
         for (location, _) in &def.ty.in_heap {
             self.locations.define(*location, tcx.fresh::<Location>());
         }
         for region in &def.ty.regions {
-            self.regions.insert(*region, tcx.fresh::<UniversalRegion>());
+            self.define_region(*region);
+        }
+        for param in &def.ty.ty_params {
+            self.ty_params.define(*param, tcx.fresh::<TyParam>());
+        }
+        for (param, _) in &def.ty.pred_params {
+            self.pred_params.define(*param, tcx.fresh::<PredParam>());
         }
 
         FnDef {
@@ -68,6 +180,7 @@ where
             body: self.freshen_body(def.body),
             ty: self.freshen_fn_ty(def.ty),
             ret: self.freshen_cont_id(def.ret),
+            decreases: def.decreases.map(|pred| self.freshen_pred(pred)),
         }
     }
 
@@ -104,7 +217,7 @@ where
                 args,
                 destination,
             } => Call {
-                func: self.fns[&func],
+                func: self.freshen_callee(func),
                 args: self.freshen_args(args),
                 destination: destination
                     .map(|(place, ret)| (self.freshen_place(place), self.freshen_cont_id(ret))),
@@ -126,11 +239,18 @@ where
         }
     }
 
+    fn freshen_callee(&mut self, callee: Callee<S>) -> Callee {
+        match callee {
+            Callee::Fn(func) => Callee::Fn(self.fns[&func]),
+            Callee::Place(place) => Callee::Place(self.freshen_place(place)),
+        }
+    }
+
     fn freshen_cont_def<I>(&mut self, cont: ContDef<I, S>) -> ContDef<I> {
         let tcx = self.tcx;
         self.locals.push_layer();
         for local in &cont.params {
-            self.locals.define(*local, tcx.fresh::<Local>());
+            self.define_local(*local);
         }
         self.locations.push_layer();
         for (location, _) in &cont.ty.heap {
@@ -159,6 +279,8 @@ where
             heap: self.freshen_heap(cont_ty.heap),
             locals: self.freshen_locals(cont_ty.locals),
             inputs,
+            invariant: cont_ty.invariant.map(|pred| self.freshen_pred(pred)),
+            decreases: cont_ty.decreases.map(|pred| self.freshen_pred(pred)),
         }
     }
 
@@ -170,13 +292,15 @@ where
         use StatementKind::*;
         let kind = match statement.kind {
             StatementKind::Let(local, layout) => {
-                self.locals.define(local, self.tcx.fresh::<Local>());
-                Let(self.freshen_local(local), layout)
+                self.define_local(local);
+                Let(self.freshen_local(local), self.freshen_layout(layout))
             }
             StatementKind::Assign(place, value) => {
                 Assign(self.freshen_place(place), self.freshen_rvalue(value))
             }
             Drop(place) => Drop(self.freshen_place(place)),
+            Assert(pred) => Assert(self.freshen_pred(pred)),
+            Assume(pred) => Assume(self.freshen_pred(pred)),
             Nop => Nop,
         };
         Statement {
@@ -190,6 +314,7 @@ where
         match rvalue {
             Use(op) => Use(self.freshen_operand(op)),
             Ref(kind, place) => Ref(kind, self.freshen_place(place)),
+            TwoPhaseRef(place) => TwoPhaseRef(self.freshen_place(place)),
             BinaryOp(op, lhs, rhs) => {
                 BinaryOp(op, self.freshen_operand(lhs), self.freshen_operand(rhs))
             }
@@ -197,6 +322,14 @@ where
                 CheckedBinaryOp(op, self.freshen_operand(lhs), self.freshen_operand(rhs))
             }
             UnaryOp(op, operand) => UnaryOp(op, self.freshen_operand(operand)),
+            Closure(func, captures) => Closure(
+                self.fns[&func],
+                captures
+                    .into_iter()
+                    .map(|op| self.freshen_operand(op))
+                    .collect(),
+            ),
+            FnPtr(func) => FnPtr(self.fns[&func]),
         }
     }
 
@@ -218,19 +351,34 @@ where
                     .collect(),
             ),
             Region::Infer => Region::Infer,
-            Region::Universal(region) => Region::Universal(self.regions[&region]),
+            Region::Universal(region) => Region::Universal(self.freshen_universal(region)),
         }
     }
 
     fn freshen_fn_ty(&mut self, ty: FnDecl<S>) -> FnDecl {
+        let ty_params = ty
+            .ty_params
+            .iter()
+            .map(|p| self.freshen_ty_param(*p))
+            .collect();
+        let pred_params = ty
+            .pred_params
+            .into_iter()
+            .map(|(p, sorts)| (self.freshen_pred_param(p), sorts))
+            .collect();
         let mut regions = vec![];
         for region in ty.regions {
-            regions.push(self.regions[&region])
+            regions.push(self.freshen_universal(region))
         }
+        let outlives = ty
+            .outlives
+            .into_iter()
+            .map(|(r1, r2)| (self.freshen_universal(r1), self.freshen_universal(r2)))
+            .collect();
 
         self.locals.push_layer();
         for (local, _) in &ty.inputs {
-            self.locals.define(*local, self.tcx.fresh::<Local>())
+            self.define_local(*local)
         }
 
         let in_heap = self.freshen_heap(ty.in_heap);
@@ -246,7 +394,10 @@ where
         self.locations.pop_layer();
         self.locals.pop_layer();
         FnDecl {
+            ty_params,
+            pred_params,
             regions,
+            outlives,
             in_heap,
             inputs,
             out_heap,
@@ -276,8 +427,35 @@ where
                 self.fields.pop_layer();
                 Tuple(tup)
             }
+            Struct(tup, invariant) => match self.freshen_ty(Tuple(tup)) {
+                Tuple(tup) => Struct(tup, self.freshen_pred(invariant)),
+                _ => unreachable!(),
+            },
+            Indexed(box ty, indices) => Indexed(
+                box self.freshen_ty(ty),
+                indices
+                    .into_iter()
+                    .map(|index| self.freshen_pred(index))
+                    .collect(),
+            ),
             Uninit(s) => Uninit(s),
             Refine(bty, refine) => Refine(bty, self.freshen_refine(refine)),
+            Vec(box ty, refine) => Vec(box self.freshen_ty(ty), self.freshen_refine(refine)),
+            Param(param) => Param(self.freshen_ty_param(param)),
+            Fn(box decl) => Fn(box self.freshen_extern_fn(decl)),
+        }
+    }
+
+    fn freshen_layout(&mut self, layout: TypeLayout<S>) -> TypeLayout {
+        match layout {
+            TypeLayout::Tuple(tup) => {
+                TypeLayout::Tuple(tup.into_iter().map(|l| self.freshen_layout(l)).collect())
+            }
+            TypeLayout::Struct(tup, invariant) => TypeLayout::Struct(
+                tup.into_iter().map(|l| self.freshen_layout(l)).collect(),
+                self.freshen_pred(invariant),
+            ),
+            TypeLayout::Block(size) => TypeLayout::Block(size),
         }
     }
 
@@ -285,6 +463,10 @@ where
         match refine {
             Refine::Infer => Refine::Infer,
             Refine::Pred(pred) => Refine::Pred(self.freshen_pred(pred)),
+            Refine::Abstract(param, args) => Refine::Abstract(
+                self.freshen_pred_param(param),
+                args.into_iter().map(|arg| self.freshen_var(arg)).collect(),
+            ),
         }
     }
 
@@ -300,6 +482,15 @@ where
                 BinaryOp(op, box self.freshen_pred(lhs), box self.freshen_pred(rhs))
             }
             UnaryOp(op, box operand) => UnaryOp(op, box self.freshen_pred(operand)),
+            App(measure, args) => App(
+                self.measures[&measure],
+                args.into_iter().map(|arg| self.freshen_pred(arg)).collect(),
+            ),
+            Ite(box p1, box p2, box p3) => Ite(
+                box self.freshen_pred(p1),
+                box self.freshen_pred(p2),
+                box self.freshen_pred(p3),
+            ),
         }
     }
 
@@ -337,6 +528,18 @@ where
             .collect()
     }
 
+    fn define_local(&mut self, x: Local<S>) {
+        let fresh = self.tcx.fresh::<Local>();
+        self.locals.define(x, fresh);
+        self.local_names.insert(fresh, x);
+    }
+
+    fn define_region(&mut self, region: UniversalRegion<S>) {
+        let fresh = self.tcx.fresh::<UniversalRegion>();
+        self.regions.define(region, fresh);
+        self.region_names.insert(fresh, region);
+    }
+
     fn freshen_cont_id(&mut self, cont_id: ContId<S>) -> ContId {
         self.conts
             .get(&cont_id)
@@ -364,6 +567,27 @@ where
             .copied()
             .expect("NameFreshener: Field not found")
     }
+
+    fn freshen_universal(&mut self, region: UniversalRegion<S>) -> UniversalRegion {
+        self.regions
+            .get(&region)
+            .copied()
+            .expect("NameFreshener: UniversalRegion not found")
+    }
+
+    fn freshen_ty_param(&mut self, param: TyParam<S>) -> TyParam {
+        self.ty_params
+            .get(&param)
+            .copied()
+            .expect("NameFreshener: TyParam not found")
+    }
+
+    fn freshen_pred_param(&mut self, param: PredParam<S>) -> PredParam {
+        self.pred_params
+            .get(&param)
+            .copied()
+            .expect("NameFreshener: PredParam not found")
+    }
 }
 
 #[derive(Debug, PartialEq, Eq, Hash)]
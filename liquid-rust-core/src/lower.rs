@@ -4,7 +4,7 @@ use ast::ContDef;
 
 use crate::{
     ast::{self, visitor::Visitor, FnDef},
-    names::ContId,
+    names::{ContId, Location},
     ty::{self, KVid, RegionVid, TyCtxt, Var},
 };
 
@@ -37,6 +37,15 @@ impl<'a> TypeLowerer<'a> {
         }
     }
 
+    pub fn lower_extern_fn(tcx: &TyCtxt, decl: &ast::FnDecl) -> ty::FnDecl {
+        TypeLowerer::new(tcx).lower_fn_ty(decl)
+    }
+
+    /// Lowers a predicate appearing in a statement, e.g., an assertion.
+    pub fn lower_stmnt_pred(tcx: &TyCtxt, pred: &ast::Pred) -> ty::Pred {
+        TypeLowerer::new(tcx).lower_pred(pred)
+    }
+
     pub fn lower_fn_def<I>(
         tcx: &TyCtxt,
         func: &FnDef<I>,
@@ -65,24 +74,49 @@ impl<'a> TypeLowerer<'a> {
             ast::Ty::Ref(bk, region, location) => {
                 self.tcx.mk_ref(*bk, self.lower_region(region), *location)
             }
-            ast::Ty::Tuple(tup) => {
-                let mut vec = Vec::new();
-                let len = self.vars_in_scope.len();
-                for (f, ty) in tup {
-                    vec.push((*f, self.lower_ty(ty)));
-                    self.vars_in_scope.push(Var::Field(*f));
-                }
-                self.vars_in_scope.truncate(len);
-                self.tcx.mk_tuple(ty::Tuple::from_iter(vec))
+            ast::Ty::Tuple(tup) => self.tcx.mk_tuple(self.lower_tuple(tup)),
+            ast::Ty::Struct(tup, invariant) => {
+                let tup = self.lower_tuple(tup);
+                self.tcx
+                    .mk_tuple(tup.with_invariant(self.lower_pred(invariant)))
+            }
+            ast::Ty::Indexed(ty, indices) => {
+                let ty = self.lower_ty(ty);
+                let indices = indices.iter().map(|index| self.lower_pred(index)).collect();
+                self.tcx.mk_indexed(ty, indices)
             }
             ast::Ty::Uninit(n) => self.tcx.mk_uninit(*n),
             ast::Ty::Refine(bty, refine) => self.tcx.mk_refine(*bty, self.lower_refine(refine)),
+            ast::Ty::Vec(ty, refine) => {
+                let ty = self.lower_ty(ty);
+                self.tcx.mk_vec(ty, self.lower_refine(refine))
+            }
+            ast::Ty::Param(param) => self.tcx.mk_param(*param),
+            ast::Ty::Fn(decl) => {
+                let decl = self.lower_fn_ty(decl);
+                self.tcx.mk_fn(decl)
+            }
         }
     }
 
+    fn lower_tuple(&mut self, tup: &[(ty::Field, ast::Ty)]) -> ty::Tuple {
+        let mut vec = Vec::new();
+        let len = self.vars_in_scope.len();
+        for (f, ty) in tup {
+            vec.push((*f, self.lower_ty(ty)));
+            self.vars_in_scope.push(Var::Field(*f));
+        }
+        self.vars_in_scope.truncate(len);
+        ty::Tuple::from_iter(vec)
+    }
+
     fn lower_cont_ty(&mut self, cont_ty: &ast::ContTy) -> ty::ContTy {
+        let heap = match &cont_ty.invariant {
+            Some(_) => self.lower_heap(&fix_invariant(cont_ty)),
+            None => self.lower_heap(&cont_ty.heap),
+        };
         ty::ContTy::new(
-            self.lower_heap(&cont_ty.heap),
+            heap,
             cont_ty.locals.iter().copied().collect(),
             cont_ty.inputs.clone(),
         )
@@ -90,7 +124,10 @@ impl<'a> TypeLowerer<'a> {
 
     fn lower_fn_ty(&mut self, fn_ty: &ast::FnDecl) -> ty::FnDecl {
         ty::FnDecl {
+            ty_params: fn_ty.ty_params.clone(),
+            pred_params: fn_ty.pred_params.clone(),
             regions: fn_ty.regions.clone(),
+            outlives: fn_ty.outlives.clone(),
             in_heap: self.lower_heap(&fn_ty.in_heap),
             inputs: fn_ty.inputs.iter().copied().collect(),
             out_heap: self.lower_heap(&fn_ty.out_heap),
@@ -129,6 +166,7 @@ impl<'a> TypeLowerer<'a> {
                 vars_in_scope.extend(&self.vars_in_scope);
                 ty::Refine::Infer(ty::Kvar(self.tcx.fresh::<KVid>(), vars_in_scope))
             }
+            ast::Refine::Abstract(param, args) => ty::Refine::Abstract(*param, args.clone()),
         }
     }
 
@@ -141,6 +179,77 @@ impl<'a> TypeLowerer<'a> {
                     .mk_bin_op(*bin_op, self.lower_pred(op1), self.lower_pred(op2))
             }
             ast::Pred::UnaryOp(un_op, op) => self.tcx.mk_un_op(*un_op, self.lower_pred(op)),
+            ast::Pred::App(measure, args) => self.tcx.mk_app(
+                *measure,
+                args.iter().map(|arg| self.lower_pred(arg)).collect(),
+            ),
+            ast::Pred::Ite(p1, p2, p3) => self.tcx.mk_ite(
+                self.lower_pred(p1),
+                self.lower_pred(p2),
+                self.lower_pred(p3),
+            ),
+        }
+    }
+}
+
+/// Conjoins a loop invariant with the refinement of the last location it mentions, such that every
+/// other location it mentions is in scope. The invariant was checked when building the program.
+fn fix_invariant(cont_ty: &ast::ContTy) -> ast::Heap {
+    let l = cont_ty
+        .invariant_location()
+        .unwrap_or_else(|err| bug!("{}", err))
+        .unwrap_or_else(|| bug!("continuation without invariant"));
+    let invariant = location_to_nu(cont_ty.invariant.as_ref().unwrap(), l);
+    cont_ty
+        .heap
+        .iter()
+        .map(|(l2, ty)| {
+            let mut ty = ty.clone();
+            if *l2 == l {
+                if let ast::Ty::Refine(_, r) | ast::Ty::Vec(_, r) = &mut ty {
+                    *r = match r {
+                        ast::Refine::Pred(pred) => ast::Refine::Pred(ast::Pred::BinaryOp(
+                            ast::pred::BinOp::And,
+                            Box::new(pred.clone()),
+                            Box::new(invariant.clone()),
+                        )),
+                        _ => ast::Refine::Pred(invariant.clone()),
+                    };
+                }
+            }
+            (*l2, ty)
+        })
+        .collect()
+}
+
+fn location_to_nu(pred: &ast::Pred, location: Location) -> ast::Pred {
+    match pred {
+        ast::Pred::Constant(c) => ast::Pred::Constant(*c),
+        ast::Pred::Place(place) => {
+            let mut place = place.clone();
+            if place.base == ast::pred::Var::Location(location) {
+                place.base = ast::pred::Var::Nu;
+            }
+            ast::Pred::Place(place)
+        }
+        ast::Pred::BinaryOp(op, op1, op2) => ast::Pred::BinaryOp(
+            *op,
+            Box::new(location_to_nu(op1, location)),
+            Box::new(location_to_nu(op2, location)),
+        ),
+        ast::Pred::UnaryOp(op, op1) => {
+            ast::Pred::UnaryOp(*op, Box::new(location_to_nu(op1, location)))
         }
+        ast::Pred::App(measure, args) => ast::Pred::App(
+            *measure,
+            args.iter()
+                .map(|arg| location_to_nu(arg, location))
+                .collect(),
+        ),
+        ast::Pred::Ite(p1, p2, p3) => ast::Pred::Ite(
+            Box::new(location_to_nu(p1, location)),
+            Box::new(location_to_nu(p2, location)),
+            Box::new(location_to_nu(p3, location)),
+        ),
     }
 }
@@ -11,17 +11,22 @@ use crate::{
         pred::{Pred, Var},
         *,
     },
-    names::{ContId, Field, FnId, Local, Location},
+    names::{ContId, Field, FnId, Local, Location, MeasureId},
 };
-use quickscope::ScopeSet;
+use quickscope::{ScopeMap, ScopeSet};
 use std::collections::HashSet;
 
+#[derive(Default)]
 pub struct NameChecker<S> {
     fns: HashSet<FnId<S>>,
+    measures: HashSet<MeasureId<S>>,
     locals: ScopeSet<Local<S>>,
     locations: ScopeSet<Location<S>>,
     fields: ScopeSet<Field<S>>,
     conts: ScopeSet<ContId<S>>,
+    ty_params: ScopeSet<TyParam<S>>,
+    /// The predicate parameters in scope together with their arity.
+    pred_params: ScopeMap<PredParam<S>, usize>,
 }
 
 impl<S> NameChecker<S>
@@ -31,25 +36,59 @@ where
     pub fn new() -> Self {
         Self {
             fns: HashSet::new(),
+            measures: HashSet::new(),
             locals: ScopeSet::new(),
             locations: ScopeSet::new(),
             fields: ScopeSet::new(),
             conts: ScopeSet::new(),
+            ty_params: ScopeSet::new(),
+            pred_params: ScopeMap::new(),
         }
     }
 
     pub fn check<I>(mut self, program: &Program<I, S>) {
-        for (fn_id, def) in program.iter() {
-            // We first check if the function has already been defined;
-            // we error out if this is the case
+        // We first record every function, including the ones without a body, so they can be
+        // called before their definition. We error out if a function is defined twice.
+        let fn_ids = program
+            .iter()
+            .map(|(fn_id, _)| fn_id)
+            .chain(program.externs().map(|(fn_id, _)| fn_id));
+        for fn_id in fn_ids {
             if !self.fns.insert(*fn_id) {
                 panic!("NameChecker: duplicate function {:?} defined", fn_id);
-            } else {
-                self.check_fn_def(def);
             }
         }
 
-        todo!()
+        for (measure, _) in program.measures() {
+            self.measures.insert(*measure);
+        }
+
+        // The body of a reflected function can only mention its parameters
+        for (_, decl) in program.measures() {
+            if let Some(def) = &decl.def {
+                self.locations.push_layer();
+                for param in &def.params {
+                    self.locations.define(*param);
+                }
+                self.check_pred(&def.body);
+                self.locations.pop_layer();
+            }
+        }
+
+        for (_, def) in program.iter() {
+            self.check_fn_def(def);
+        }
+
+        for (_, decl) in program.externs() {
+            self.push_fn_scope();
+            self.check_fn_decl(decl);
+            self.pop_fn_scope();
+        }
+
+        for (impl_fn, trait_fn) in program.impls() {
+            self.check_fn_id(*impl_fn);
+            self.check_fn_id(*trait_fn);
+        }
     }
 
     pub fn push_fn_scope(&mut self) {
@@ -57,6 +96,8 @@ where
         self.locations.push_layer();
         self.fields.push_layer();
         self.conts.push_layer();
+        self.ty_params.push_layer();
+        self.pred_params.push_layer();
     }
 
     pub fn pop_fn_scope(&mut self) {
@@ -64,6 +105,8 @@ where
         self.locations.pop_layer();
         self.fields.pop_layer();
         self.conts.pop_layer();
+        self.ty_params.pop_layer();
+        self.pred_params.pop_layer();
     }
 
     pub fn check_fn_def<I>(&mut self, def: &FnDef<I, S>) {
@@ -78,6 +121,11 @@ where
         // We then check the params of the function
         self.check_params(&def.params);
 
+        // The metric can only mention the input locations
+        if let Some(metric) = &def.decreases {
+            self.check_pred(metric);
+        }
+
         // Finally, we check the body
         self.check_body(&def.body);
 
@@ -91,8 +139,15 @@ where
         // For now, we do this super inefficiently and for each item in the
         // input and output, we just search through the heaps for the
         // corresponding item.
+        for param in &ty.ty_params {
+            self.ty_params.define(*param);
+        }
+        for (param, sorts) in &ty.pred_params {
+            self.pred_params.define(*param, sorts.len());
+        }
+
         for (inl, inloc) in &ty.inputs {
-            if !ty.in_heap.iter().find(|(x, _ty)| x == inloc).is_some() {
+            if !ty.in_heap.iter().any(|(x, _ty)| x == inloc) {
                 // If we can't find the corresponding location of the input
                 // local in the input heap, panic and complain
                 panic!(
@@ -106,7 +161,7 @@ where
         }
 
         for (outl, outloc) in &ty.outputs {
-            if !ty.out_heap.iter().find(|(x, _ty)| x == outloc).is_some() {
+            if !ty.out_heap.iter().any(|(x, _ty)| x == outloc) {
                 // Same with the outputs
                 panic!(
                     "NameChecker: local {:?} references undefined location {:?}",
@@ -150,8 +205,11 @@ where
         match body {
             LetCont(defs, box rest) => {
                 self.conts.push_layer();
+                // Continuations in the same block are mutually recursive
                 for def in defs {
                     self.conts.define(def.name);
+                }
+                for def in defs {
                     self.check_cont_def(def);
                 }
 
@@ -173,8 +231,9 @@ where
                 destination,
             } => {
                 // We first check that the function is in our list of functions
-                if !self.fns.contains(&func) {
-                    panic!("NameChecker: function {:?} not defined", func);
+                match func {
+                    Callee::Fn(func) => self.check_fn_id(*func),
+                    Callee::Place(place) => self.check_place(place),
                 }
 
                 // Then check the arguments
@@ -234,7 +293,7 @@ where
 
         // and check our locals
         for (inl, inloc) in &cont_ty.locals {
-            if !cont_ty.heap.iter().find(|(x, _ty)| x == inloc).is_some() {
+            if !cont_ty.heap.iter().any(|(x, _ty)| x == inloc) {
                 // If we can't find the corresponding location of the input
                 // local in the input heap, panic and complain
                 panic!(
@@ -246,13 +305,22 @@ where
             // Check the local
             self.check_local(*inl);
         }
+
+        if let Some(invariant) = &cont_ty.invariant {
+            self.check_pred(invariant);
+        }
+
+        if let Some(metric) = &cont_ty.decreases {
+            self.check_pred(metric);
+        }
     }
 
     fn check_statement<I>(&mut self, statement: &Statement<I, S>) {
         use StatementKind::*;
 
         match &statement.kind {
-            StatementKind::Let(local, _layout) => {
+            StatementKind::Let(local, layout) => {
+                self.check_layout(layout);
                 self.locals.define(*local);
             }
             StatementKind::Assign(place, value) => {
@@ -260,6 +328,7 @@ where
                 self.check_rvalue(&value);
             }
             Drop(place) => self.check_place(&place),
+            Assert(pred) | Assume(pred) => self.check_pred(pred),
             Nop => {}
         }
     }
@@ -269,7 +338,7 @@ where
 
         match rvalue {
             Use(op) => self.check_operand(op),
-            Ref(_kind, place) => self.check_place(place),
+            Ref(_, place) | TwoPhaseRef(place) => self.check_place(place),
             BinaryOp(_op, lhs, rhs) => {
                 self.check_operand(lhs);
                 self.check_operand(rhs);
@@ -279,6 +348,13 @@ where
                 self.check_operand(rhs);
             }
             UnaryOp(_op, operand) => self.check_operand(&operand),
+            Closure(func, captures) => {
+                self.check_fn_id(*func);
+                for op in captures {
+                    self.check_operand(op);
+                }
+            }
+            FnPtr(func) => self.check_fn_id(*func),
         }
     }
 
@@ -306,8 +382,45 @@ where
                 }
                 self.fields.pop_layer();
             }
+            Struct(tup, invariant) => {
+                self.check_ty(&Tuple(tup.clone()));
+                self.check_pred(invariant);
+            }
+            Indexed(box ty, indices) => {
+                self.check_ty(ty);
+                indices.iter().for_each(|index| self.check_pred(index));
+            }
             Uninit(_s) => {}
             Refine(_bty, refine) => self.check_refine(refine),
+            Vec(box ty, refine) => {
+                self.check_ty(ty);
+                self.check_refine(refine);
+            }
+            Param(param) => {
+                if !self.ty_params.contains(param) {
+                    panic!("NameChecker: type parameter {:?} undefined", param);
+                }
+            }
+            Fn(box decl) => {
+                // `check_fn_decl` leaves the output locations in a new layer
+                self.locals.push_layer();
+                self.locations.push_layer();
+                self.check_fn_decl(decl);
+                self.locations.pop_layer();
+                self.locations.pop_layer();
+                self.locals.pop_layer();
+            }
+        }
+    }
+
+    fn check_layout(&mut self, layout: &TypeLayout<S>) {
+        match layout {
+            TypeLayout::Tuple(tup) => tup.iter().for_each(|l| self.check_layout(l)),
+            TypeLayout::Struct(tup, invariant) => {
+                tup.iter().for_each(|l| self.check_layout(l));
+                self.check_pred(invariant);
+            }
+            TypeLayout::Block(_) => {}
         }
     }
 
@@ -315,6 +428,21 @@ where
         match refine {
             Refine::Infer => {}
             Refine::Pred(pred) => self.check_pred(pred),
+            Refine::Abstract(param, args) => {
+                match self.pred_params.get(param) {
+                    Some(arity) if *arity == args.len() => {}
+                    Some(arity) => panic!(
+                        "NameChecker: predicate parameter {:?} takes {} arguments but {} were supplied",
+                        param,
+                        arity,
+                        args.len()
+                    ),
+                    None => panic!("NameChecker: predicate parameter {:?} undefined", param),
+                }
+                for arg in args {
+                    self.check_var(*arg);
+                }
+            }
         }
     }
 
@@ -332,6 +460,19 @@ where
                 self.check_pred(rhs);
             }
             UnaryOp(_op, box operand) => self.check_pred(operand),
+            App(measure, args) => {
+                if !self.measures.contains(measure) {
+                    panic!("NameChecker: measure {:?} undefined", measure);
+                }
+                for arg in args {
+                    self.check_pred(arg);
+                }
+            }
+            Ite(box p1, box p2, box p3) => {
+                self.check_pred(p1);
+                self.check_pred(p2);
+                self.check_pred(p3);
+            }
         }
     }
 
@@ -347,6 +488,12 @@ where
         self.check_local(place.base);
     }
 
+    fn check_fn_id(&mut self, func: FnId<S>) {
+        if !self.fns.contains(&func) {
+            panic!("NameChecker: function {:?} not defined", func);
+        }
+    }
+
     fn check_cont_id(&mut self, c: ContId<S>) {
         if !self.conts.contains(&c) {
             panic!("NameChecker: cont {:?} undefined", c);
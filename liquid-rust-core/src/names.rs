@@ -17,3 +17,7 @@ newtype_name! {
 newtype_name! {
     struct FnId
 }
+
+newtype_name! {
+    struct MeasureId
+}
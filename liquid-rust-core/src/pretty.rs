@@ -19,6 +19,8 @@ macro_rules! join {
     }};
 }
 
+/// Programs are printed in the syntax accepted by `liquid-rust-core-cli`, so they can be checked
+/// again on their own.
 impl<I, S: fmt::Display + Eq + std::hash::Hash> fmt::Display for ast::Program<I, S> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         PrettyPrinter.print_program(self, f, 0)
@@ -27,6 +29,7 @@ impl<I, S: fmt::Display + Eq + std::hash::Hash> fmt::Display for ast::Program<I,
 
 impl<I, S: fmt::Display> fmt::Display for ast::FnDef<I, S> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "fn")?;
         PrettyPrinter.print_fn_def(self, f, 0)
     }
 }
@@ -85,6 +88,12 @@ impl<S: fmt::Display> fmt::Debug for ast::pred::Place<S> {
     }
 }
 
+impl<S: fmt::Display> fmt::Display for ast::Place<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        PrettyPrinter.print_place(self, f)
+    }
+}
+
 impl<S: fmt::Display> fmt::Display for ast::Rvalue<S> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         PrettyPrinter.print_rvalue(self, f)
@@ -114,43 +123,165 @@ impl PrettyPrinter {
         f: &mut fmt::Formatter<'_>,
         indent: usize,
     ) -> fmt::Result {
-        for (_, def) in program.iter() {
+        // Items are sorted by name so that printing the same program always gives the same output.
+        let mut measures: Vec<_> = program.measures().collect();
+        measures.sort_by_key(|(measure, _)| measure.inner().to_string());
+        for (measure, decl) in measures {
+            write!(f, "measure m{}(", measure.inner())?;
+            match &decl.def {
+                Some(def) => {
+                    join!(f, ", ", (l, bty) in def.params.iter().zip(&decl.inputs) => {
+                        self.print_location(l, f)?;
+                        write!(f, ": {}", bty)?;
+                    });
+                    write!(f, ") -> {} = ", decl.output)?;
+                    self.print_pred(&def.body, f)?;
+                    writeln!(f, ";")?;
+                }
+                None => {
+                    join!(f, ", ", bty in &decl.inputs => write!(f, "{}", bty)?);
+                    writeln!(f, ") -> {};", decl.output)?;
+                }
+            }
+        }
+        let mut externs: Vec<_> = program.externs().collect();
+        externs.sort_by_key(|(fn_id, _)| fn_id.inner().to_string());
+        for (fn_id, decl) in externs {
+            write!(f, "extern fn f{}", fn_id.inner())?;
+            self.print_extern_fn(decl, f, indent)?;
+            writeln!(f)?;
+        }
+        for (impl_fn, trait_fn) in program.impls() {
+            writeln!(f, "impl f{} for f{};", impl_fn.inner(), trait_fn.inner())?;
+        }
+        let mut functions: Vec<_> = program.iter().collect();
+        functions.sort_by_key(|(fn_id, _)| fn_id.inner().to_string());
+        for (fn_id, def) in functions {
+            write!(f, "fn f{}", fn_id.inner())?;
             self.print_fn_def(def, f, indent)?;
             writeln!(f)?;
         }
         Ok(())
     }
-    fn print_fn_def<I, S: fmt::Display>(
+
+    /// Prints the name of the function, which is only known at the level of the program.
+    fn print_callee<S: fmt::Display>(
         &mut self,
-        func: &ast::FnDef<I, S>,
+        func: &ast::Callee<S>,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
+        match func {
+            ast::Callee::Fn(fn_id) => write!(f, "f{}", fn_id.inner()),
+            ast::Callee::Place(place) => {
+                write!(f, "[")?;
+                self.print_place(place, f)?;
+                write!(f, "]")
+            }
+        }
+    }
+
+    fn print_generics<S: fmt::Display>(
+        &mut self,
+        decl: &ast::FnDecl<S>,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
+        if decl.regions.is_empty() && decl.ty_params.is_empty() && decl.pred_params.is_empty() {
+            return Ok(());
+        }
+        // The kinds of parameters are printed one after the other, so the separator is tracked
+        // across them.
+        let mut sep = "<";
+        for region in &decl.regions {
+            let name = region.inner().to_string();
+            write!(f, "{}'{}", sep, name)?;
+            sep = ", ";
+            // Names are only printable here, so the bounds of a region are found by its name.
+            let bounds = decl
+                .outlives
+                .iter()
+                .filter(|(r, _)| r.inner().to_string() == name);
+            join!(f, " + ", (i, (_, bound)) in bounds.enumerate() => {
+                if i == 0 {
+                    write!(f, ": ")?;
+                }
+                write!(f, "'{}", bound.inner())?;
+            });
+        }
+        for param in &decl.ty_params {
+            write!(f, "{}T{}", sep, param.inner())?;
+            sep = ", ";
+        }
+        for (param, sorts) in &decl.pred_params {
+            write!(f, "{}?p{}: ", sep, param.inner())?;
+            sep = ", ";
+            if let [sort] = &sorts[..] {
+                self.print_base_ty(*sort, f)?;
+            } else {
+                write!(f, "(")?;
+                join!(f, ", ", sort in sorts => self.print_base_ty(*sort, f)?);
+                write!(f, ")")?;
+            }
+            write!(f, " -> bool")?;
+        }
+        write!(f, ">")
+    }
+
+    /// Prints the part of a signature after the generics, shared by functions, extern functions
+    /// and function types.
+    fn print_fn_sig<S: fmt::Display>(
+        &mut self,
+        decl: &ast::FnDecl<S>,
+        ret: Option<&ContId<S>>,
         f: &mut fmt::Formatter<'_>,
         indent: usize,
     ) -> fmt::Result {
-        write!(f, "fn ...")?;
         indent!(f, indent + 2)?;
         write!(f, "( ")?;
-        self.print_heap(&func.ty.in_heap, f)?;
+        self.print_heap(&decl.in_heap, f)?;
         indent!(f, indent + 2)?;
         write!(f, "; ")?;
-        self.print_locals(
-            func.params
-                .iter()
-                .zip(&func.ty.inputs)
-                .map(|(x, (_, ty))| (x, ty)),
-            f,
-        )?;
+        self.print_locals(decl.inputs.iter().map(|(x, l)| (x, l)), f)?;
         indent!(f, indent + 2)?;
         write!(f, ") ret ")?;
-        self.print_cont_id(&func.ret, f)?;
+        if let Some(ret) = ret {
+            self.print_cont_id(ret, f)?;
+        }
         write!(f, "(")?;
-        self.print_heap(&func.ty.out_heap, f)?;
+        self.print_heap(&decl.out_heap, f)?;
+        write!(f, "; ")?;
+        self.print_locals(decl.outputs.iter().map(|(x, l)| (x, l)), f)?;
         write!(f, "; own(")?;
-        self.print_location(&func.ty.output, f)?;
-        write!(f, ")) = ")?;
+        self.print_location(&decl.output, f)?;
+        write!(f, "))")
+    }
+    fn print_fn_def<I, S: fmt::Display>(
+        &mut self,
+        func: &ast::FnDef<I, S>,
+        f: &mut fmt::Formatter<'_>,
+        indent: usize,
+    ) -> fmt::Result {
+        self.print_generics(&func.ty, f)?;
+        self.print_fn_sig(&func.ty, Some(&func.ret), f, indent)?;
+        if let Some(metric) = &func.decreases {
+            write!(f, " decreases ")?;
+            self.print_pred(metric, f)?;
+        }
+        write!(f, " = ")?;
         self.print_fn_body(&func.body, f, indent + 2)?;
         Ok(())
     }
 
+    fn print_extern_fn<S: fmt::Display>(
+        &mut self,
+        decl: &ast::FnDecl<S>,
+        f: &mut fmt::Formatter<'_>,
+        indent: usize,
+    ) -> fmt::Result {
+        self.print_generics(decl, f)?;
+        self.print_fn_sig(decl, None, f, indent)?;
+        write!(f, ";")
+    }
+
     fn print_fn_body<I, S: fmt::Display>(
         &mut self,
         fn_body: &ast::FnBody<I, S>,
@@ -183,22 +314,23 @@ impl PrettyPrinter {
                 self.print_fn_body(else_, f, indent + 2)?;
             }
             ast::FnBody::Call {
-                func: _func,
+                func,
                 args,
                 destination,
             } => {
                 indent!(f, indent)?;
-                if let Some((place, ret)) = destination {
-                    write!(f, "call")?;
+                write!(f, "call ")?;
+                if let Some((place, _)) = destination {
                     self.print_place(place, f)?;
-                    write!(f, " := ...(")?;
-                    join!(f, ", ", x in args => self.print_local(x, f)? );
-                    write!(f, ") ret ")?;
+                    write!(f, " := ")?;
+                }
+                self.print_callee(func, f)?;
+                write!(f, "(")?;
+                join!(f, ", ", x in args => self.print_local(x, f)? );
+                write!(f, ")")?;
+                if let Some((_, ret)) = destination {
+                    write!(f, " ret ")?;
                     self.print_cont_id(ret, f)?;
-                } else {
-                    write!(f, "call ...(")?;
-                    join!(f, ", ", x in args => self.print_local(x, f)? );
-                    write!(f, ")")?;
                 }
             }
             ast::FnBody::Jump { target, args } => {
@@ -248,6 +380,14 @@ impl PrettyPrinter {
                 self.print_place(place, f)?;
                 write!(f, ")")?;
             }
+            ast::StatementKind::Assert(pred) => {
+                write!(f, "assert ")?;
+                self.print_pred(pred, f)?;
+            }
+            ast::StatementKind::Assume(pred) => {
+                write!(f, "assume ")?;
+                self.print_pred(pred, f)?;
+            }
             ast::StatementKind::Nop => {
                 write!(f, "Nop")?;
             }
@@ -272,6 +412,10 @@ impl PrettyPrinter {
                 write!(f, "&{} ", bk)?;
                 self.print_place(place, f)?;
             }
+            ast::Rvalue::TwoPhaseRef(place) => {
+                write!(f, "&two_phase mut ")?;
+                self.print_place(place, f)?;
+            }
             ast::Rvalue::BinaryOp(bin_op, op1, op2) => {
                 self.print_operand(op1, f)?;
                 write!(f, " ")?;
@@ -296,6 +440,14 @@ impl PrettyPrinter {
                 write!(f, "{}", un_op)?;
                 self.print_operand(op, f)?;
             }
+            ast::Rvalue::Closure(func, captures) => {
+                write!(f, "closure f{}(", func.inner())?;
+                join!(f, ", ", op in captures => self.print_operand(op, f)?);
+                write!(f, ")")?;
+            }
+            ast::Rvalue::FnPtr(func) => {
+                write!(f, "fn f{}", func.inner())?;
+            }
         };
         Ok(())
     }
@@ -306,7 +458,7 @@ impl PrettyPrinter {
             ast::BinOp::Sub => write!(f, "-"),
             ast::BinOp::Lt => write!(f, "<"),
             ast::BinOp::Le => write!(f, "<="),
-            ast::BinOp::Eq => write!(f, "="),
+            ast::BinOp::Eq => write!(f, "=="),
             ast::BinOp::Ge => write!(f, ">="),
             ast::BinOp::Gt => write!(f, ">"),
         }
@@ -348,11 +500,22 @@ impl PrettyPrinter {
         indent!(f, indent + 2)?;
         write!(f, "; ")?;
         self.print_locals(def.ty.locals.iter().map(|(x, l)| (x, l)), f)?;
+        if !def.params.is_empty() {
+            indent!(f, indent + 2)?;
+            write!(f, "; ")?;
+            self.print_locals(def.params.iter().zip(&def.ty.inputs), f)?;
+        }
         indent!(f, indent + 2)?;
-        write!(f, "; ")?;
-        self.print_locals(def.params.iter().zip(&def.ty.inputs), f)?;
-        indent!(f, indent + 2)?;
-        write!(f, ") =")?;
+        write!(f, ")")?;
+        if let Some(invariant) = &def.ty.invariant {
+            write!(f, " invariant ")?;
+            self.print_pred(invariant, f)?;
+        }
+        if let Some(metric) = &def.ty.decreases {
+            write!(f, " decreases ")?;
+            self.print_pred(metric, f)?;
+        }
+        write!(f, " =")?;
         self.print_fn_body(&def.body, f, indent)?;
         Ok(())
     }
@@ -403,7 +566,11 @@ impl PrettyPrinter {
                 match r {
                     ast::Region::Infer => write!(f, "{{ _ }}")?,
                     ast::Region::Universal(urid) => write!(f, "'{}", urid.inner())?,
-                    ast::Region::Concrete(_) => write!(f, "...")?,
+                    ast::Region::Concrete(places) => {
+                        write!(f, "{{ ")?;
+                        join!(f, ", ", place in places => self.print_place(place, f)?);
+                        write!(f, " }}")?;
+                    }
                 }
                 match bk {
                     ast::BorrowKind::Shared => write!(f, " ")?,
@@ -413,13 +580,22 @@ impl PrettyPrinter {
             }
             ast::Ty::Tuple(tup) => {
                 write!(f, "(")?;
-                join!(f, ", ", (fld, ty) in tup => {
-                    self.print_field(fld, f)?;
-                    write!(f, ": ")?;
-                    self.print_ty(ty, f)?;
-                });
+                self.print_fields(tup, f)?;
                 write!(f, ")")?;
             }
+            ast::Ty::Struct(tup, invariant) => {
+                write!(f, "(")?;
+                self.print_fields(tup, f)?;
+                write!(f, " | ")?;
+                self.print_pred(invariant, f)?;
+                write!(f, ")")?;
+            }
+            ast::Ty::Indexed(ty, indices) => {
+                self.print_ty(ty, f)?;
+                write!(f, "[")?;
+                join!(f, ", ", index in indices => self.print_pred(index, f)?);
+                write!(f, "]")?;
+            }
             ast::Ty::Uninit(size) => {
                 write!(f, "uninit({})", size)?;
             }
@@ -427,12 +603,66 @@ impl PrettyPrinter {
                 write!(f, "{{ ")?;
                 self.print_base_ty(*bty, f)?;
                 write!(f, " | ")?;
-                match refine {
-                    ast::Refine::Infer => write!(f, "_")?,
-                    ast::Refine::Pred(pred) => self.print_pred(pred, f)?,
-                }
+                self.print_refine(refine, f)?;
                 write!(f, " }}")?;
             }
+            ast::Ty::Vec(ty, refine) => {
+                write!(f, "{{ vec<")?;
+                self.print_ty(ty, f)?;
+                write!(f, "> | ")?;
+                self.print_refine(refine, f)?;
+                write!(f, " }}")?;
+            }
+            ast::Ty::Param(param) => write!(f, "T{}", param.inner())?,
+            ast::Ty::Fn(decl) => {
+                write!(f, "fn")?;
+                self.print_generics(decl, f)?;
+                write!(f, "(")?;
+                self.print_heap(&decl.in_heap, f)?;
+                write!(f, "; ")?;
+                self.print_locals(decl.inputs.iter().map(|(x, l)| (x, l)), f)?;
+                write!(f, ") ret (")?;
+                self.print_heap(&decl.out_heap, f)?;
+                write!(f, "; ")?;
+                self.print_locals(decl.outputs.iter().map(|(x, l)| (x, l)), f)?;
+                write!(f, "; own(")?;
+                self.print_location(&decl.output, f)?;
+                write!(f, "))")?;
+            }
+        }
+        Ok(())
+    }
+
+    fn print_fields<S: fmt::Display>(
+        &mut self,
+        fields: &[(Field<S>, ast::Ty<S>)],
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
+        join!(f, ", ", (fld, ty) in fields => {
+            self.print_field(fld, f)?;
+            write!(f, ": ")?;
+            self.print_ty(ty, f)?;
+        });
+        // Like in Rust, a tuple with a single field is distinguished by a trailing comma.
+        if fields.len() == 1 {
+            write!(f, ",")?;
+        }
+        Ok(())
+    }
+
+    fn print_refine<S: fmt::Display>(
+        &mut self,
+        refine: &ast::Refine<S>,
+        f: &mut fmt::Formatter<'_>,
+    ) -> fmt::Result {
+        match refine {
+            ast::Refine::Infer => write!(f, "_")?,
+            ast::Refine::Pred(pred) => self.print_pred(pred, f)?,
+            ast::Refine::Abstract(param, args) => {
+                write!(f, "?p{}(", param.inner())?;
+                join!(f, ", ", arg in args => self.print_var(arg, f)?);
+                write!(f, ")")?;
+            }
         }
         Ok(())
     }
@@ -464,8 +694,28 @@ impl PrettyPrinter {
                 self.print_pred(op2, f)?;
                 write!(f, ")")?;
             }
-            ast::Pred::UnaryOp(_un_op, _op) => {
-                write!(f, "...")?;
+            ast::Pred::UnaryOp(un_op, op) => {
+                let un_op = match un_op {
+                    ast::pred::UnOp::Not => "!",
+                    ast::pred::UnOp::Neg => "-",
+                };
+                write!(f, "({}(", un_op)?;
+                self.print_pred(op, f)?;
+                write!(f, "))")?;
+            }
+            ast::Pred::App(measure, args) => {
+                write!(f, "m{}(", measure.inner())?;
+                join!(f, ", ", arg in args => self.print_pred(arg, f)?);
+                write!(f, ")")?;
+            }
+            ast::Pred::Ite(p1, p2, p3) => {
+                write!(f, "(if ")?;
+                self.print_pred(p1, f)?;
+                write!(f, " then ")?;
+                self.print_pred(p2, f)?;
+                write!(f, " else ")?;
+                self.print_pred(p3, f)?;
+                write!(f, ")")?;
             }
         }
         Ok(())
@@ -522,9 +772,9 @@ impl PrettyPrinter {
         Ok(())
     }
 
-    fn print_type_layout(
+    fn print_type_layout<S: fmt::Display>(
         &mut self,
-        layout: &ast::TypeLayout,
+        layout: &ast::TypeLayout<S>,
         f: &mut fmt::Formatter<'_>,
     ) -> fmt::Result {
         match layout {
@@ -533,6 +783,13 @@ impl PrettyPrinter {
                 join!(f, ", ", layout in tup => self.print_type_layout(layout, f)?);
                 write!(f, ")")?;
             }
+            ast::TypeLayout::Struct(tup, invariant) => {
+                write!(f, "(")?;
+                join!(f, ", ", layout in tup => self.print_type_layout(layout, f)?);
+                write!(f, " | ")?;
+                self.print_pred(invariant, f)?;
+                write!(f, ")")?;
+            }
             ast::TypeLayout::Block(size) => {
                 write!(f, "{}", size)?;
             }
@@ -553,25 +810,8 @@ impl PrettyPrinter {
         place: &ast::Place<S>,
         f: &mut fmt::Formatter<'_>,
     ) -> fmt::Result {
-        let mut s = format!("_{}", place.base.inner());
-        let mut need_parens = false;
-        for proj in &place.projs {
-            match proj {
-                ast::Proj::Field(n) => {
-                    if need_parens {
-                        s = format!("({}).{}", s, n);
-                        need_parens = false;
-                    } else {
-                        s = format!("{}.{}", s, n);
-                    }
-                }
-                ast::Proj::Deref => {
-                    s = format!("*{}", s);
-                    need_parens = true;
-                }
-            }
-        }
-        write!(f, "{}", s)
+        let base = format!("_{}", place.base.inner());
+        write!(f, "{}", place.format_with_base(base))
     }
 
     fn print_cont_id<S: fmt::Display>(
@@ -5,6 +5,7 @@ use liquid_rust_common::index::Idx;
 
 use crate::{
     ast::TypeLayout,
+    lower::TypeLowerer,
     names::Location,
     ty::{self, pred, *},
 };
@@ -60,6 +61,10 @@ impl TyCtxt {
         self.mk_ty(TyKind::Tuple(tup))
     }
 
+    pub fn mk_indexed(&self, ty: Ty, indices: Vec<Pred>) -> Ty {
+        self.mk_ty(TyKind::Indexed(ty, indices))
+    }
+
     pub fn mk_uninit(&self, n: usize) -> Ty {
         self.mk_ty(TyKind::Uninit(n))
     }
@@ -68,6 +73,18 @@ impl TyCtxt {
         self.mk_ty(TyKind::Refine(bty, refine.into()))
     }
 
+    pub fn mk_vec<R: Into<Refine>>(&self, ty: Ty, refine: R) -> Ty {
+        self.mk_ty(TyKind::Vec(ty, refine.into()))
+    }
+
+    pub fn mk_param(&self, param: TyParam) -> Ty {
+        self.mk_ty(TyKind::Param(param))
+    }
+
+    pub fn mk_fn(&self, decl: FnDecl) -> Ty {
+        self.mk_ty(TyKind::Fn(box decl))
+    }
+
     pub fn mk_ref<R: Into<Region>>(&self, bk: BorrowKind, region: R, location: Location) -> Ty {
         self.mk_ty(TyKind::Ref(bk, region.into(), location))
     }
@@ -81,6 +98,14 @@ impl TyCtxt {
                     .collect();
                 self.mk_ty(TyKind::Tuple(tup))
             }
+            TypeLayout::Struct(tup, invariant) => {
+                let tup: Tuple = tup
+                    .iter()
+                    .map(|layout| (self.fresh::<Field>(), self.mk_ty_for_layout(layout)))
+                    .collect();
+                let invariant = TypeLowerer::lower_stmnt_pred(self, invariant);
+                self.mk_tuple(tup.with_invariant(invariant))
+            }
             TypeLayout::Block(size) => self.mk_ty(TyKind::Uninit(*size)),
         }
     }
@@ -91,9 +116,14 @@ impl TyCtxt {
                 let tup = tup.map(|_, fld, ty| (*fld, self.uninitialize(ty)));
                 self.mk_tuple(tup)
             }
-            TyKind::OwnRef(..) | TyKind::Ref(..) | TyKind::Uninit(_) | TyKind::Refine(..) => {
-                self.mk_uninit(ty.size())
-            }
+            TyKind::Indexed(ty, _) => self.uninitialize(ty),
+            TyKind::OwnRef(..)
+            | TyKind::Ref(..)
+            | TyKind::Uninit(_)
+            | TyKind::Refine(..)
+            | TyKind::Vec(..)
+            | TyKind::Param(_)
+            | TyKind::Fn(_) => self.mk_uninit(ty.size()),
         }
     }
 
@@ -107,6 +137,13 @@ impl TyCtxt {
                 let tup = tup.map(|i, fld, ty| (*fld, self.selfify(ty, place.extend_path(i))));
                 self.mk_tuple(tup)
             }
+            TyKind::Indexed(ty, indices) => {
+                self.mk_indexed(self.selfify(ty, place), indices.clone())
+            }
+            TyKind::Vec(ty, _) => {
+                let pred = self.mk_bin_op(BinOp::Eq, self.preds.nu(), self.mk_pred_place(place));
+                self.mk_vec(ty.clone(), pred)
+            }
             _ => ty.clone(),
         }
     }
@@ -118,14 +155,56 @@ impl TyCtxt {
                     tup.map(|_, fld, ty| (*fld, self.replace_with_fresh_vars(ty, vars_in_scope)));
                 self.mk_tuple(tup)
             }
+            // The indices of the joined values may differ, so the join is unfolded.
+            TyKind::Indexed(ty, _) => self.replace_with_fresh_vars(ty, vars_in_scope),
             TyKind::Refine(bty, _) => {
                 let mut vec = vec![Var::Nu];
                 vec.extend(vars_in_scope);
                 let kvar = ty::Kvar(self.fresh::<KVid>(), vec);
                 self.mk_refine(*bty, kvar)
             }
+            TyKind::Vec(ty, _) => {
+                let mut vec = vec![Var::Nu];
+                vec.extend(vars_in_scope);
+                let kvar = ty::Kvar(self.fresh::<KVid>(), vec);
+                self.mk_vec(self.replace_with_fresh_vars(ty, vars_in_scope), kvar)
+            }
             TyKind::Ref(bk, _, l) => self.mk_ref(*bk, self.fresh::<RegionVid>(), *l),
-            TyKind::Uninit(..) | TyKind::OwnRef(..) => ty.clone(),
+            TyKind::Uninit(..) | TyKind::OwnRef(..) | TyKind::Param(_) | TyKind::Fn(_) => {
+                ty.clone()
+            }
+        }
+    }
+
+    /// Replaces every refinement in `ty` with a fresh kvar, keeping regions and locations
+    /// untouched. This is used as the template for instantiating a type parameter.
+    pub fn replace_refines_with_fresh_vars(&self, ty: &Ty, vars_in_scope: &[Var]) -> Ty {
+        let fresh_kvar = || {
+            let mut vec = vec![Var::Nu];
+            vec.extend(vars_in_scope);
+            ty::Kvar(self.fresh::<KVid>(), vec)
+        };
+        match ty.kind() {
+            TyKind::Tuple(tup) => {
+                let tup = tup.map(|_, fld, ty| {
+                    (
+                        *fld,
+                        self.replace_refines_with_fresh_vars(ty, vars_in_scope),
+                    )
+                });
+                self.mk_tuple(tup)
+            }
+            TyKind::Indexed(ty, _) => self.replace_refines_with_fresh_vars(ty, vars_in_scope),
+            TyKind::Refine(bty, _) => self.mk_refine(*bty, fresh_kvar()),
+            TyKind::Vec(ty, _) => self.mk_vec(
+                self.replace_refines_with_fresh_vars(ty, vars_in_scope),
+                fresh_kvar(),
+            ),
+            TyKind::Ref(..)
+            | TyKind::Uninit(..)
+            | TyKind::OwnRef(..)
+            | TyKind::Param(_)
+            | TyKind::Fn(_) => ty.clone(),
         }
     }
 
@@ -146,6 +225,14 @@ impl TyCtxt {
     pub fn mk_un_op(&self, op: ty::UnOp, operand: Pred) -> Pred {
         self.mk_pred(PredKind::UnaryOp(op, operand))
     }
+
+    pub fn mk_app(&self, measure: MeasureId, args: Vec<Pred>) -> Pred {
+        self.mk_pred(PredKind::App(measure, args))
+    }
+
+    pub fn mk_ite(&self, cond: Pred, then: Pred, else_: Pred) -> Pred {
+        self.mk_pred(PredKind::Ite(cond, then, else_))
+    }
 }
 
 pub struct CommonTypes {
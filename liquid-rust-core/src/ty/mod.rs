@@ -9,9 +9,9 @@ use crate::{
 pub use crate::{
     ast::{
         pred::{BinOp, UnOp, Var},
-        BaseTy, BorrowKind, UniversalRegion,
+        BaseTy, BorrowKind, PredParam, TyParam, UniversalRegion,
     },
-    names::{ContId, Field, Location},
+    names::{ContId, Field, Location, MeasureId},
 };
 pub use context::TyCtxt;
 use hashconsing::HConsed;
@@ -29,10 +29,15 @@ impl TyS {
         &self.kind
     }
 
-    pub fn is_copy(&self) -> bool {
+    /// Whether a value of this type may appear in a `Copy` operand. Bounds are not tracked, so
+    /// type parameters and function values are accepted and we rely on rustc having checked
+    /// that they are `Copy`.
+    pub fn may_be_copied(&self) -> bool {
         match self.kind() {
-            TyKind::Tuple(tup) => tup.types().all(|ty| ty.is_copy()),
+            TyKind::Tuple(tup) => tup.types().all(|ty| ty.may_be_copied()),
+            TyKind::Indexed(ty, _) => ty.may_be_copied(),
             TyKind::Refine { .. } | TyKind::Ref(BorrowKind::Shared, ..) => true,
+            TyKind::Param(_) | TyKind::Fn(_) => true,
             _ => false,
         }
     }
@@ -45,11 +50,30 @@ impl TyS {
         matches!(self.kind(), TyKind::Refine(BaseTy::Bool, ..))
     }
 
+    pub fn is_vec(&self) -> bool {
+        matches!(self.kind(), TyKind::Vec(..))
+    }
+
+    /// Whether no part of a value of this type has been moved out or is yet to be initialized.
+    pub fn is_initialized(&self) -> bool {
+        let uninit = self.walk(|ty, _| match ty.kind() {
+            TyKind::Uninit(_) => Walk::Stop(()),
+            _ => Walk::Continue,
+        });
+        matches!(uninit, Walk::Continue)
+    }
+
     pub fn size(&self) -> usize {
         match self.kind() {
             TyKind::Tuple(tup) => tup.types().map(|ty| ty.size()).sum(),
+            TyKind::Indexed(ty, _) => ty.size(),
             TyKind::Uninit(n) => *n,
-            TyKind::OwnRef(..) | TyKind::Ref(..) | TyKind::Refine(..) => 1,
+            TyKind::OwnRef(..)
+            | TyKind::Ref(..)
+            | TyKind::Refine(..)
+            | TyKind::Vec(..)
+            | TyKind::Param(_)
+            | TyKind::Fn(_) => 1,
         }
     }
 
@@ -63,17 +87,30 @@ impl TyS {
         projs: &mut Vec<ast::Proj>,
     ) -> Walk<T> {
         f(self, projs)?;
-        if let TyKind::Tuple(tup) = self.kind() {
-            for (i, ty) in tup.types().enumerate() {
-                projs.push(ast::Proj::Field(i));
-                ty.walk_internal(f, projs)?;
-                projs.pop();
+        match self.kind() {
+            TyKind::Tuple(tup) => {
+                for (i, ty) in tup.types().enumerate() {
+                    projs.push(ast::Proj::Field(i));
+                    ty.walk_internal(f, projs)?;
+                    projs.pop();
+                }
             }
+            // The fields of an indexed type are at the same place as the indexed value.
+            TyKind::Indexed(ty, _) => ty.walk_internal(f, projs)?,
+            _ => {}
         }
         Walk::Continue
     }
 }
 
+/// Looks through indexed types, returning the tuple they unfold to.
+pub fn unfold(mut ty: &Ty) -> &Ty {
+    while let TyKind::Indexed(inner, _) = ty.kind() {
+        ty = inner;
+    }
+    ty
+}
+
 pub enum Walk<T = ()> {
     Stop(T),
     Continue,
@@ -106,16 +143,37 @@ impl fmt::Display for TyS {
             TyKind::Ref(BorrowKind::Shared, r, l) => write!(f, "&{} l{}", r, l.inner()),
             TyKind::Ref(BorrowKind::Mut, r, l) => write!(f, "&{} mut l{}", r, l.inner()),
             TyKind::Tuple(tup) => {
-                let tup = tup
+                let fields = tup
                     .iter()
                     .map(|(f, ty)| format!("f{}: {}", f.inner(), ty))
                     .collect::<Vec<_>>()
                     .join(", ");
-                write!(f, "({})", tup)
+                match tup.invariant() {
+                    Some(invariant) => write!(f, "({} | {})", fields, invariant),
+                    None => write!(f, "({})", fields),
+                }
+            }
+            TyKind::Indexed(ty, indices) => {
+                let indices = indices
+                    .iter()
+                    .map(|index| format!("{}", index))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                write!(f, "{}[{}]", ty, indices)
             }
             TyKind::Uninit(size) => write!(f, "uninit({})", size),
-            TyKind::Refine(bty, Refine::Infer(k)) => write!(f, "{{ {} | {} }}", bty, k),
-            TyKind::Refine(bty, Refine::Pred(pred)) => write!(f, "{{ {} | {} }}", bty, pred),
+            TyKind::Refine(bty, refine) => write!(f, "{{ {} | {} }}", bty, refine),
+            TyKind::Vec(ty, refine) => write!(f, "{{ vec<{}> | {} }}", ty, refine),
+            TyKind::Param(param) => write!(f, "T{}", param.as_usize()),
+            TyKind::Fn(decl) => {
+                let inputs = decl
+                    .inputs
+                    .iter()
+                    .map(|(_, l)| format!("{}", decl.in_heap[l]))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                write!(f, "fn({}) -> {}", inputs, decl.out_heap[&decl.output])
+            }
         }
     }
 }
@@ -125,13 +183,24 @@ pub enum TyKind {
     OwnRef(Location),
     Ref(BorrowKind, Region, Location),
     Tuple(Tuple),
+    /// A struct refined by abstract indices. The type is the tuple the struct unfolds to, whose
+    /// fields already mention the indices. Accessing a field unfolds the value, while the indices
+    /// are used to instantiate the index binders of a function at calls.
+    Indexed(Ty, Vec<Pred>),
     Uninit(usize),
     Refine(BaseTy, Refine),
+    Vec(Ty, Refine),
+    Param(TyParam),
+    /// A function value, e.g., a closure or a function pointer.
+    Fn(Box<FnDecl>),
 }
 
 #[derive(Debug, Clone, Hash, PartialEq, Eq)]
 pub struct FnDecl {
+    pub ty_params: Vec<TyParam>,
+    pub pred_params: Vec<(PredParam, Vec<BaseTy>)>,
     pub regions: Vec<UniversalRegion>,
+    pub outlives: Vec<(UniversalRegion, UniversalRegion)>,
     pub in_heap: Heap,
     pub inputs: LocalsMap,
     pub out_heap: Heap,
@@ -159,44 +228,144 @@ impl FnDecl {
 
         self.outputs.iter().map(|(x, l)| (map[x], *l)).collect()
     }
+
+    /// Splits off the first input returning its location and the signature without it. This is
+    /// used to hide the captured state of a closure, which is received through its first input.
+    pub fn split_first_input(&self) -> (Location, FnDecl) {
+        let (x, l) = self.inputs.iter().next().expect("function without inputs");
+        let decl = FnDecl {
+            inputs: self.inputs.iter().skip(1).map(|(x, l)| (*x, *l)).collect(),
+            outputs: self
+                .outputs
+                .iter()
+                .filter(|(y, _)| *y != x)
+                .map(|(y, l)| (*y, *l))
+                .collect(),
+            ..self.clone()
+        };
+        (*l, decl)
+    }
+}
+
+impl fmt::Display for FnDecl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let regions = self
+            .regions
+            .iter()
+            .map(|r| format!("'{}", r.as_usize()))
+            .chain(
+                self.outlives
+                    .iter()
+                    .map(|(r1, r2)| format!("'{}: '{}", r1.as_usize(), r2.as_usize())),
+            )
+            .collect::<Vec<_>>()
+            .join(", ");
+        write!(
+            f,
+            "fn<{}>({}; {}) -> ({}; {}; l{})",
+            regions,
+            self.in_heap,
+            self.inputs,
+            self.out_heap,
+            self.outputs,
+            self.output.as_usize()
+        )
+    }
 }
 
 #[derive(Debug, Clone, Hash, PartialEq, Eq)]
-pub struct Tuple(Vec<(Field, Ty)>);
+pub struct Tuple {
+    fields: Vec<(Field, Ty)>,
+    /// The invariant of the struct represented by the tuple, if any. It refers to the fields by
+    /// their position in `V`.
+    invariant: Option<Pred>,
+}
 
 impl Tuple {
     pub fn len(&self) -> usize {
-        self.0.len()
+        self.fields.len()
     }
 
     pub fn is_empty(&self) -> bool {
-        self.0.is_empty()
+        self.fields.is_empty()
     }
 
+    pub fn with_invariant(self, invariant: Pred) -> Tuple {
+        Tuple {
+            invariant: Some(invariant),
+            ..self
+        }
+    }
+
+    pub fn invariant(&self) -> Option<&Pred> {
+        self.invariant.as_ref()
+    }
+
+    /// Returns the tuple without its invariant, e.g., to check that its fields satisfy it.
+    pub fn without_invariant(&self) -> Tuple {
+        Tuple {
+            fields: self.fields.clone(),
+            invariant: None,
+        }
+    }
+
+    /// Replaces the type at position `n`. The invariant, if any, is preserved.
     pub fn map_ty_at(&self, n: usize, f: impl FnOnce(&Ty) -> Ty) -> Tuple {
-        let mut v = self.0.clone();
-        v[n].1 = f(&v[n].1);
-        Tuple(v)
+        let mut fields = self.fields.clone();
+        fields[n].1 = f(&fields[n].1);
+        Tuple {
+            fields,
+            invariant: self.invariant.clone(),
+        }
     }
 
+    /// Maps every field and its type. The invariant, if any, is preserved.
     pub fn map(&self, mut f: impl FnMut(usize, &Field, &Ty) -> (Field, Ty)) -> Tuple {
-        self.0
+        let fields = self
+            .fields
             .iter()
             .enumerate()
             .map(|(i, (fld, ty))| f(i, fld, ty))
-            .collect()
+            .collect();
+        Tuple {
+            fields,
+            invariant: self.invariant.clone(),
+        }
     }
 
     pub fn ty_at(&self, n: usize) -> &Ty {
-        &self.0[n].1
+        &self.fields[n].1
     }
 
     pub fn types(&self) -> impl DoubleEndedIterator<Item = &Ty> + ExactSizeIterator {
-        self.0.iter().map(|x| &x.1)
+        self.fields.iter().map(|x| &x.1)
     }
 
     pub fn fields(&self) -> impl DoubleEndedIterator<Item = &Field> + ExactSizeIterator {
-        self.0.iter().map(|x| &x.0)
+        self.fields.iter().map(|x| &x.0)
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, (Field, Ty)> {
+        self.fields.iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a Tuple {
+    type Item = &'a (Field, Ty);
+
+    type IntoIter = std::slice::Iter<'a, (Field, Ty)>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.fields.iter()
+    }
+}
+
+impl std::iter::FromIterator<(Field, Ty)> for Tuple {
+    fn from_iter<T: IntoIterator<Item = (Field, Ty)>>(iter: T) -> Self {
+        Tuple {
+            fields: iter.into_iter().collect(),
+            invariant: None,
+        }
     }
 }
 
@@ -211,10 +380,6 @@ macro_rules! tup {
     }};
 }
 
-wrap_iterable! {
-    Tuple: Vec<(Field, Ty)>
-}
-
 #[derive(Debug)]
 pub struct ContTy {
     pub heap: Heap,
@@ -241,11 +406,26 @@ impl ContTy {
     }
 }
 
+impl fmt::Display for ContTy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let inputs = self
+            .inputs
+            .iter()
+            .map(|l| format!("l{}", l.as_usize()))
+            .collect::<Vec<_>>()
+            .join(", ");
+        write!(f, "({}; {}; {})", self.heap, self.locals, inputs)
+    }
+}
+
 #[derive(Eq, PartialEq, Clone, Debug, Hash)]
 pub enum Region {
     Concrete(Vec<Place>),
     Infer(RegionVid),
     Universal(UniversalRegion),
+    /// A universal region together with the loans of some places of the function, e.g., for a
+    /// reference that is either a parameter or a local borrow.
+    Mixed(UniversalRegion, Vec<Place>),
 }
 
 impl From<Vec<Place>> for Region {
@@ -263,7 +443,7 @@ impl From<RegionVid> for Region {
 impl Region {
     pub fn places(&self) -> &[Place] {
         match self {
-            Region::Concrete(places) => places,
+            Region::Concrete(places) | Region::Mixed(_, places) => places,
             Region::Infer(_) | Region::Universal(_) => &[],
         }
     }
@@ -282,6 +462,13 @@ impl fmt::Display for Region {
             }
             Region::Infer(rvid) => write!(f, "r{}", rvid.as_usize()),
             Region::Universal(param) => write!(f, "'{}", param.as_usize()),
+            Region::Mixed(param, places) => {
+                write!(f, "{{ '{}", param.as_usize())?;
+                for place in places {
+                    write!(f, ", {}", place)?;
+                }
+                write!(f, " }}")
+            }
         }
     }
 }
@@ -296,6 +483,26 @@ impl From<Place> for Region {
 pub enum Refine {
     Pred(Pred),
     Infer(Kvar),
+    /// A predicate parameter of the enclosing function applied to some variables. Predicate
+    /// parameters are instantiated with kvars at call sites.
+    Abstract(PredParam, Vec<Var>),
+}
+
+impl fmt::Display for Refine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Refine::Pred(pred) => write!(f, "{}", pred),
+            Refine::Infer(kvar) => write!(f, "{}", kvar),
+            Refine::Abstract(param, args) => {
+                let args = args
+                    .iter()
+                    .map(|arg| format!("{}", arg))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                write!(f, "p{}({})", param.as_usize(), args)
+            }
+        }
+    }
 }
 
 impl From<Kvar> for Refine {
@@ -513,6 +720,8 @@ pub enum PredKind {
     Place(pred::Place),
     BinaryOp(BinOp, Pred, Pred),
     UnaryOp(UnOp, Pred),
+    App(MeasureId, Vec<Pred>),
+    Ite(Pred, Pred, Pred),
 }
 
 impl std::fmt::Display for PredS {
@@ -526,6 +735,17 @@ impl std::fmt::Display for PredS {
             PredKind::UnaryOp(op, operand) => {
                 write!(f, "{}({})", op, operand)?;
             }
+            PredKind::App(measure, args) => {
+                let args = args
+                    .iter()
+                    .map(|arg| format!("{}", arg))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                write!(f, "m{}({})", measure.as_usize(), args)?;
+            }
+            PredKind::Ite(p1, p2, p3) => {
+                write!(f, "(if {} then {} else {})", p1, p2, p3)?;
+            }
         }
         Ok(())
     }
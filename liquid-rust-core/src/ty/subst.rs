@@ -7,6 +7,13 @@ pub struct Subst {
     locations: HashMap<Location, Location>,
     fields: HashMap<Field, Field>,
     regions: HashMap<Region, Region>,
+    types: HashMap<TyParam, Ty>,
+    /// The instantiation of index variables, i.e., the locations bound by the index binders of a
+    /// function, which don't hold a value of their own.
+    indices: HashMap<Location, Pred>,
+    /// The instantiation of predicate parameters. The vars of each kvar are the ones in scope
+    /// where the parameter is instantiated, and are appended to the arguments of the parameter.
+    preds: HashMap<PredParam, Kvar>,
 }
 
 impl Subst {
@@ -15,18 +22,34 @@ impl Subst {
             locations: HashMap::new(),
             fields: HashMap::new(),
             regions: HashMap::new(),
+            types: HashMap::new(),
+            indices: HashMap::new(),
+            preds: HashMap::new(),
         }
     }
 
-    pub fn infer(heap1: &Heap, locals1: &LocalsMap, heap2: &Heap, locals2: &LocalsMap) -> Self {
+    pub fn infer(
+        tcx: &TyCtxt,
+        heap1: &Heap,
+        locals1: &LocalsMap,
+        heap2: &Heap,
+        locals2: &LocalsMap,
+    ) -> Self {
         let mut subst = Subst::new();
         for (x, l2) in locals2 {
             let l1 = &locals1[x];
             let ty1 = &heap1[l1];
             let ty2 = &heap2[l2];
             subst.add_location_subst(*l2, *l1);
-            infer_subst_ty(&mut subst, heap1, ty1, heap2, ty2);
+            let place1 = pred::Place::from(Var::Location(*l1));
+            infer_subst_ty(tcx, &mut subst, heap1, ty1, Some(&place1), heap2, ty2);
         }
+        // Index variables are the locations bound by `heap2` that don't hold a value, others are
+        // either instantiated by a location or already in scope.
+        let locations = &subst.locations;
+        subst
+            .indices
+            .retain(|l, _| heap2.get(l).is_some() && !locations.contains_key(l));
         subst
     }
 
@@ -46,6 +69,34 @@ impl Subst {
         self.regions.insert(r1, r2);
     }
 
+    pub fn add_ty_subst(&mut self, param: TyParam, ty: Ty) {
+        self.types.insert(param, ty);
+    }
+
+    /// Instantiates the index variable `l` with `index`, unless it was already instantiated.
+    pub fn add_index_subst(&mut self, l: Location, index: Pred) {
+        self.indices.entry(l).or_insert(index);
+    }
+
+    pub fn add_pred_subst(&mut self, param: PredParam, kvar: Kvar) {
+        self.preds.insert(param, kvar);
+    }
+
+    /// Removes the instantiation of universal regions. Universal regions are fixed in the body of
+    /// the function they belong to, so they must be related by its bounds instead, e.g., when
+    /// jumping to a continuation.
+    pub fn remove_universal_region_substs(&mut self) {
+        self.regions
+            .retain(|r, _| !matches!(r, Region::Universal(_) | Region::Mixed(..)));
+    }
+
+    /// Applies `f` to the types each type parameter is instantiated with.
+    pub fn map_ty_substs(&mut self, mut f: impl FnMut(&Ty) -> Ty) {
+        for ty in self.types.values_mut() {
+            *ty = f(ty);
+        }
+    }
+
     fn get_field(&self, fld: Field) -> Option<Field> {
         self.fields.get(&fld).copied()
     }
@@ -54,9 +105,24 @@ impl Subst {
         self.locations.get(&l).copied()
     }
 
+    fn get_index(&self, l: Location) -> Option<&Pred> {
+        match self.get_location(l) {
+            Some(_) => None,
+            None => self.indices.get(&l),
+        }
+    }
+
     fn get_region(&self, r: &Region) -> Option<&Region> {
         self.regions.get(r)
     }
+
+    fn get_ty(&self, param: TyParam) -> Option<&Ty> {
+        self.types.get(&param)
+    }
+
+    fn get_pred(&self, param: PredParam) -> Option<&Kvar> {
+        self.preds.get(&param)
+    }
 }
 
 pub trait ApplySubst {
@@ -75,8 +141,20 @@ impl ApplySubst for Ty {
                     tup.map(|_, fld, ty| (fld.apply_subst(tcx, subst), ty.apply_subst(tcx, subst)));
                 tcx.mk_tuple(tup)
             }
+            TyKind::Indexed(ty, indices) => tcx.mk_indexed(
+                ty.apply_subst(tcx, subst),
+                indices
+                    .iter()
+                    .map(|index| index.apply_subst(tcx, subst))
+                    .collect(),
+            ),
             TyKind::Uninit(_) => self.clone(),
             TyKind::Refine(bty, refine) => tcx.mk_refine(*bty, refine.apply_subst(tcx, subst)),
+            TyKind::Vec(ty, refine) => {
+                tcx.mk_vec(ty.apply_subst(tcx, subst), refine.apply_subst(tcx, subst))
+            }
+            TyKind::Param(param) => subst.get_ty(*param).unwrap_or(self).clone(),
+            TyKind::Fn(decl) => tcx.mk_fn(decl.apply_subst(tcx, subst)),
         }
     }
 }
@@ -84,7 +162,10 @@ impl ApplySubst for Ty {
 impl ApplySubst for FnDecl {
     fn apply_subst(&self, tcx: &TyCtxt, subst: &Subst) -> Self {
         FnDecl {
+            ty_params: self.ty_params.clone(),
+            pred_params: self.pred_params.clone(),
             regions: self.regions.clone(),
+            outlives: self.outlives.clone(),
             in_heap: self.in_heap.apply_subst(tcx, subst),
             inputs: self.inputs.apply_subst(tcx, subst),
             out_heap: self.out_heap.apply_subst(tcx, subst),
@@ -115,6 +196,15 @@ impl ApplySubst for Refine {
         match self {
             Refine::Pred(pred) => Refine::Pred(pred.apply_subst(tcx, subst)),
             Refine::Infer(kvar) => Refine::Infer(kvar.apply_subst(tcx, subst)),
+            Refine::Abstract(param, args) => {
+                let args = args.iter().map(|arg| arg.apply_subst(tcx, subst));
+                match subst.get_pred(*param) {
+                    Some(Kvar(kvid, vars)) => {
+                        Refine::Infer(Kvar(*kvid, args.chain(vars.iter().copied()).collect()))
+                    }
+                    None => Refine::Abstract(*param, args.collect()),
+                }
+            }
         }
     }
 }
@@ -143,7 +233,12 @@ impl ApplySubst for Var {
     fn apply_subst(&self, _tcx: &TyCtxt, subst: &Subst) -> Self {
         let var = match self {
             Var::Nu => Some(Var::Nu),
-            Var::Location(l) => subst.get_location(*l).map(Var::Location),
+            // Index variables can only be replaced by another variable here, e.g., in the
+            // arguments of a kvar.
+            Var::Location(l) => match subst.get_index(*l).map(|index| index.kind()) {
+                Some(PredKind::Place(place)) if place.projs.is_empty() => Some(place.base),
+                _ => subst.get_location(*l).map(Var::Location),
+            },
             Var::Field(fld) => subst.get_field(*fld).map(Var::Field),
         };
         var.unwrap_or(*self)
@@ -154,13 +249,35 @@ impl ApplySubst for Pred {
     fn apply_subst(&self, tcx: &TyCtxt, subst: &Subst) -> Self {
         match self.kind() {
             PredKind::Constant(_) => self.clone(),
-            PredKind::Place(place) => tcx.mk_pred_place(place.apply_subst(tcx, subst)),
+            PredKind::Place(place) => match place.base {
+                Var::Location(l) if subst.get_index(l).is_some() => {
+                    let index = subst.get_index(l).unwrap();
+                    match index.kind() {
+                        _ if place.projs.is_empty() => index.clone(),
+                        PredKind::Place(base) => tcx.mk_pred_place(pred::Place {
+                            base: base.base,
+                            projs: base.projs.iter().chain(&place.projs).copied().collect(),
+                        }),
+                        _ => panic!("Subst: projection of an index that is not a place"),
+                    }
+                }
+                _ => tcx.mk_pred_place(place.apply_subst(tcx, subst)),
+            },
             PredKind::BinaryOp(bin_op, op1, op2) => tcx.mk_bin_op(
                 *bin_op,
                 op1.apply_subst(tcx, subst),
                 op2.apply_subst(tcx, subst),
             ),
             PredKind::UnaryOp(un_op, op) => tcx.mk_un_op(*un_op, op.apply_subst(tcx, subst)),
+            PredKind::App(measure, args) => tcx.mk_app(
+                *measure,
+                args.iter().map(|arg| arg.apply_subst(tcx, subst)).collect(),
+            ),
+            PredKind::Ite(p1, p2, p3) => tcx.mk_ite(
+                p1.apply_subst(tcx, subst),
+                p2.apply_subst(tcx, subst),
+                p3.apply_subst(tcx, subst),
+            ),
         }
     }
 }
@@ -183,23 +300,105 @@ impl ApplySubst for Region {
     }
 }
 
-fn infer_subst_ty(subst: &mut Subst, heap1: &Heap, ty1: &Ty, heap2: &Heap, ty2: &Ty) {
+/// Infers the substitution making `ty2` match `ty1`. `place1` is the place holding the value of
+/// type `ty1`, if it can be named, and is used to instantiate index variables.
+fn infer_subst_ty(
+    tcx: &TyCtxt,
+    subst: &mut Subst,
+    heap1: &Heap,
+    ty1: &Ty,
+    place1: Option<&pred::Place>,
+    heap2: &Heap,
+    ty2: &Ty,
+) {
     match (ty1.kind(), ty2.kind()) {
         (TyKind::Ref(_, r1, l1), TyKind::Ref(_, r2, l2)) => {
             subst.add_region_subst(r2.clone(), r1.clone());
             subst.add_location_subst(*l2, *l1);
-            infer_subst_ty(subst, heap1, &heap1[l1], heap2, &heap2[l2]);
+            let place1 = pred::Place::from(Var::Location(*l1));
+            infer_subst_ty(
+                tcx,
+                subst,
+                heap1,
+                &heap1[l1],
+                Some(&place1),
+                heap2,
+                &heap2[l2],
+            );
         }
         (TyKind::OwnRef(l1), TyKind::OwnRef(l2)) => {
             subst.add_location_subst(*l2, *l1);
-            infer_subst_ty(subst, heap1, &heap1[l1], heap2, &heap2[l2]);
+            let place1 = pred::Place::from(Var::Location(*l1));
+            infer_subst_ty(
+                tcx,
+                subst,
+                heap1,
+                &heap1[l1],
+                Some(&place1),
+                heap2,
+                &heap2[l2],
+            );
         }
         (TyKind::Tuple(tup1), TyKind::Tuple(tup2)) if tup1.len() == tup2.len() => {
-            for ((fld1, ty1), (fld2, ty2)) in tup1.iter().zip(tup2) {
+            for (i, ((fld1, ty1), (fld2, ty2))) in tup1.iter().zip(tup2).enumerate() {
                 subst.add_field_subst(*fld2, *fld1);
-                infer_subst_ty(subst, heap1, ty1, heap2, ty2);
+                let place1 = place1.map(|place| place.extend_path(i));
+                infer_subst_ty(tcx, subst, heap1, ty1, place1.as_ref(), heap2, ty2);
+            }
+        }
+        // The indices of a value of an indexed type instantiate the index variables directly,
+        // otherwise they are looked for in the types of the fields.
+        (TyKind::Indexed(ty1, indices1), TyKind::Indexed(ty2, indices2)) => {
+            for (index1, index2) in indices1.iter().zip(indices2) {
+                if let Some(l) = index_var(index2) {
+                    subst.add_index_subst(l, index1.clone());
+                }
+            }
+            infer_subst_ty(tcx, subst, heap1, ty1, place1, heap2, ty2);
+        }
+        (TyKind::Indexed(ty1, _), _) => infer_subst_ty(tcx, subst, heap1, ty1, place1, heap2, ty2),
+        (_, TyKind::Indexed(ty2, _)) => infer_subst_ty(tcx, subst, heap1, ty1, place1, heap2, ty2),
+        // A value whose refinement is `V == a` instantiates the index variable `a` with the place
+        // holding the value. For vectors this is their length.
+        (TyKind::Refine(..), TyKind::Refine(_, Refine::Pred(pred)))
+        | (TyKind::Vec(..), TyKind::Vec(_, Refine::Pred(pred)))
+            if place1.is_some() =>
+        {
+            if let Some(l) = selfified_index_var(pred) {
+                subst.add_index_subst(l, tcx.mk_pred_place(place1.unwrap().clone()));
             }
+            if let (TyKind::Vec(ty1, _), TyKind::Vec(ty2, _)) = (ty1.kind(), ty2.kind()) {
+                infer_subst_ty(tcx, subst, heap1, ty1, None, heap2, ty2);
+            }
+        }
+        (TyKind::Vec(ty1, _), TyKind::Vec(ty2, _)) => {
+            infer_subst_ty(tcx, subst, heap1, ty1, None, heap2, ty2);
+        }
+        // The first argument determines the shape of the instantiation.
+        (_, TyKind::Param(param)) if subst.get_ty(*param).is_none() => {
+            subst.add_ty_subst(*param, ty1.clone());
         }
         _ => {}
     }
 }
+
+/// The index variable `index` consists of, if it is a bare location.
+fn index_var(index: &Pred) -> Option<Location> {
+    match index.kind() {
+        PredKind::Place(pred::Place {
+            base: Var::Location(l),
+            projs,
+        }) if projs.is_empty() => Some(*l),
+        _ => None,
+    }
+}
+
+/// The index variable `a` if `pred` is `V == a` or `a == V`.
+fn selfified_index_var(pred: &Pred) -> Option<Location> {
+    let is_nu = |pred: &Pred| matches!(pred.kind(), PredKind::Place(place) if place.base == Var::Nu && place.projs.is_empty());
+    match pred.kind() {
+        PredKind::BinaryOp(BinOp::Eq, lhs, rhs) if is_nu(lhs) => index_var(rhs),
+        PredKind::BinaryOp(BinOp::Eq, lhs, rhs) if is_nu(rhs) => index_var(lhs),
+        _ => None,
+    }
+}
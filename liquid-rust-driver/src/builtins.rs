@@ -0,0 +1,423 @@
+//! Trusted specifications for functions in the standard library.
+//!
+//! We don't have the MIR for functions outside the local crate, so calls to them can only be
+//! checked against a signature that we trust. The signatures are generated on demand for each
+//! instantiation of the element type and added to the program as extern functions.
+
+use std::{collections::HashMap, iter::FromIterator};
+
+use crate::translate::Unsupported;
+use liquid_rust_core::{
+    ast::{
+        pred::{self, BinOp},
+        *,
+    },
+    names::*,
+};
+use rustc_hir::def_id::DefId;
+use rustc_middle::ty::{
+    self,
+    subst::{GenericArgKind, SubstsRef},
+    TyCtxt,
+};
+use rustc_span::sym;
+
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub enum Builtin {
+    VecNew,
+    VecPush,
+    VecPop,
+    VecLen,
+    VecIndex,
+    VecIndexMut,
+    OptionUnwrap,
+    BoxNew,
+    /// A comparison through the `PartialEq` or `PartialOrd` traits. Comparisons on integers are
+    /// primitive operations, so these only show up on type parameters.
+    Compare,
+}
+
+/// Locations used by the element type of a builtin are numbered starting from here so they don't
+/// collide with the ones used in the signatures below.
+const FIRST_ELEM_LOCATION: usize = 16;
+
+impl Builtin {
+    /// Returns the builtin corresponding to a call to `def_id` with the given substitutions
+    /// together with the element type it is instantiated with.
+    pub fn resolve<'tcx>(
+        tcx: TyCtxt<'tcx>,
+        def_id: DefId,
+        substs: SubstsRef<'tcx>,
+    ) -> Option<(Builtin, ty::Ty<'tcx>)> {
+        if let Some(impl_id) = tcx.impl_of_method(def_id) {
+            let builtin = match (
+                tcx.type_of(impl_id).kind(),
+                &*tcx.item_name(def_id).as_str(),
+            ) {
+                (ty::Adt(adt, _), name) if tcx.is_diagnostic_item(sym::vec_type, adt.did) => {
+                    match name {
+                        "new" => Builtin::VecNew,
+                        "push" => Builtin::VecPush,
+                        "pop" => Builtin::VecPop,
+                        "len" => Builtin::VecLen,
+                        _ => return None,
+                    }
+                }
+                (ty::Adt(adt, _), "unwrap")
+                    if tcx.is_diagnostic_item(sym::option_type, adt.did) =>
+                {
+                    Builtin::OptionUnwrap
+                }
+                (ty::Adt(adt, _), "new") if adt.is_box() => Builtin::BoxNew,
+                _ => return None,
+            };
+            return Some((builtin, substs.type_at(0)));
+        }
+
+        let trait_id = tcx.trait_of_item(def_id)?;
+        let lang_items = tcx.lang_items();
+        if Some(trait_id) == lang_items.eq_trait()
+            || Some(trait_id) == lang_items.partial_ord_trait()
+        {
+            return match &*tcx.item_name(def_id).as_str() {
+                "eq" | "ne" | "lt" | "le" | "gt" | "ge" => {
+                    Some((Builtin::Compare, substs.type_at(0)))
+                }
+                _ => None,
+            };
+        }
+        let builtin = if Some(trait_id) == lang_items.index_trait() {
+            Builtin::VecIndex
+        } else if Some(trait_id) == lang_items.index_mut_trait() {
+            Builtin::VecIndexMut
+        } else {
+            return None;
+        };
+        // Only indexing with a `usize` is supported, i.e., no ranges.
+        match (substs.type_at(0).kind(), substs.type_at(1).kind()) {
+            (ty::Adt(adt, substs), ty::Uint(ty::UintTy::Usize))
+                if tcx.is_diagnostic_item(sym::vec_type, adt.did) =>
+            {
+                Some((builtin, substs.type_at(0)))
+            }
+            _ => None,
+        }
+    }
+
+    /// Builds the signature of the builtin for the given element type. The length of a vector
+    /// is tracked in its refinement. We don't track anything about the elements, so they are
+    /// given the trivial refinement. `elem_heap` contains the locations owned by the element.
+    /// `ty_params` are the type parameters occurring in the element type.
+    fn fn_decl(self, elem: Ty, elem_heap: Vec<(Location, Ty)>, ty_params: Vec<TyParam>) -> FnDecl {
+        let vec = |refine| Ty::Vec(box elem.clone(), refine);
+        let a = UniversalRegion::new(0);
+        let x = Local::new(0);
+        let y = Local::new(1);
+
+        let mk = |regions, in_heap: Vec<_>, inputs, out_heap: Vec<_>, outputs, output| FnDecl {
+            ty_params: ty_params.clone(),
+            pred_params: vec![],
+            regions,
+            outlives: vec![],
+            in_heap: Heap::from_iter(in_heap),
+            inputs,
+            out_heap: Heap::from_iter(out_heap),
+            outputs,
+            output: Location::new(output),
+        };
+        let l = Location::new;
+        let sref = |n| Ty::Ref(BorrowKind::Shared, Region::Universal(a), l(n));
+        let mref = |n| Ty::Ref(BorrowKind::Mut, Region::Universal(a), l(n));
+
+        match self {
+            // fn new() -> {vec<T> | V == 0}
+            Builtin::VecNew => mk(
+                vec![],
+                vec![],
+                vec![],
+                vec![(l(0), vec(refine(eq(nu(), int(0)))))],
+                vec![],
+                0,
+            ),
+            // fn push<'a>(l0: vec<T>, l1: &'a mut l0, l2: T)
+            //   ret (l3: {vec<T> | V == l0 + 1}, l4: &'a mut l3, l5: ())
+            Builtin::VecPush => mk(
+                vec![a],
+                vec![
+                    (l(0), vec(refine(Pred::tt()))),
+                    (l(1), mref(0)),
+                    (l(2), elem.clone()),
+                ],
+                vec![(x, l(1)), (y, l(2))],
+                vec![
+                    (l(3), vec(refine(eq(nu(), add(loc(0), int(1)))))),
+                    (l(4), mref(3)),
+                    (l(5), Ty::unit()),
+                ],
+                vec![(x, l(4))],
+                5,
+            ),
+            // fn pop<'a>(l0: {vec<T> | V > 0}, l1: &'a mut l0)
+            //   ret (l2: {vec<T> | V == l0 - 1}, l3: &'a mut l2, l4: T)
+            Builtin::VecPop => mk(
+                vec![a],
+                vec![(l(0), vec(refine(gt(nu(), int(0))))), (l(1), mref(0))],
+                vec![(x, l(1))],
+                vec![
+                    (l(2), vec(refine(eq(nu(), sub(loc(0), int(1)))))),
+                    (l(3), mref(2)),
+                    (l(4), elem.clone()),
+                ],
+                vec![(x, l(3))],
+                4,
+            ),
+            // fn len<'a>(l0: vec<T>, l1: &'a l0) ret (l2: {int | V == l0})
+            Builtin::VecLen => mk(
+                vec![a],
+                vec![(l(0), vec(refine(Pred::tt()))), (l(1), sref(0))],
+                vec![(x, l(1))],
+                vec![(l(2), Ty::Refine(BaseTy::Int, refine(eq(nu(), loc(0)))))],
+                vec![],
+                2,
+            ),
+            // fn index<'a>(l0: vec<T>, l1: &'a l0, l2: {int | V >= 0 && V < l0})
+            //   ret (l3: T, l4: &'a l3)
+            Builtin::VecIndex => mk(
+                vec![a],
+                vec![
+                    (l(0), vec(refine(Pred::tt()))),
+                    (l(1), sref(0)),
+                    (l(2), in_bounds(0)),
+                ],
+                vec![(x, l(1)), (y, l(2))],
+                vec![(l(3), elem.clone()), (l(4), sref(3))],
+                vec![],
+                4,
+            ),
+            // fn index_mut<'a>(l0: vec<T>, l1: &'a mut l0, l2: {int | V >= 0 && V < l0})
+            //   ret (l3: {vec<T> | V == l0}, l4: &'a mut l3, l5: T, l6: &'a mut l5)
+            Builtin::VecIndexMut => mk(
+                vec![a],
+                vec![
+                    (l(0), vec(refine(Pred::tt()))),
+                    (l(1), mref(0)),
+                    (l(2), in_bounds(0)),
+                ],
+                vec![(x, l(1)), (y, l(2))],
+                vec![
+                    (l(3), vec(refine(eq(nu(), loc(0))))),
+                    (l(4), mref(3)),
+                    (l(5), elem.clone()),
+                    (l(6), mref(5)),
+                ],
+                vec![(x, l(4))],
+                6,
+            ),
+            // The only options we know about are the ones returned by `pop`, which are required
+            // to be unwrapped right away (see `Transformer::check_pops`). The precondition of
+            // `pop` guarantees there is an element, thus options are represented by their payload.
+            // fn unwrap(l0: T) ret (l1: T)
+            Builtin::OptionUnwrap => mk(
+                vec![],
+                vec![(l(0), elem.clone())],
+                vec![(x, l(0))],
+                vec![(l(1), elem)],
+                vec![],
+                1,
+            ),
+            // We don't know anything about the comparison, e.g., `PartialEq` may be implemented
+            // arbitrarily.
+            // fn cmp<'a, 'b>(l0: T, l1: T, l2: &'a l0, l3: &'b l1) ret (l4: bool)
+            Builtin::Compare => {
+                let rhs = UniversalRegion::new(1);
+                mk(
+                    vec![a, rhs],
+                    vec![
+                        (l(0), elem.clone()),
+                        (l(1), elem),
+                        (l(2), sref(0)),
+                        (
+                            l(3),
+                            Ty::Ref(BorrowKind::Shared, Region::Universal(rhs), l(1)),
+                        ),
+                    ],
+                    vec![(x, l(2)), (y, l(3))],
+                    vec![(l(4), Ty::Refine(BaseTy::Bool, refine(Pred::tt())))],
+                    vec![],
+                    4,
+                )
+            }
+            // The box takes ownership of the location of the argument, so we don't lose
+            // anything about its type.
+            // fn new(l0: T) ret (l1: own(l0))
+            Builtin::BoxNew => {
+                let mut in_heap = elem_heap;
+                in_heap.push((l(0), elem));
+                mk(
+                    vec![],
+                    in_heap,
+                    vec![(x, l(0))],
+                    vec![(l(1), Ty::OwnRef(l(0)))],
+                    vec![],
+                    1,
+                )
+            }
+        }
+    }
+}
+
+/// Keeps track of the builtins used in the crate, allocating a fresh `FnId` for each instantiation
+/// of a builtin.
+pub struct Builtins<'tcx> {
+    tcx: TyCtxt<'tcx>,
+    next_id: usize,
+    fn_ids: HashMap<(Builtin, ty::Ty<'tcx>), FnId>,
+    decls: Vec<(FnId, FnDecl)>,
+}
+
+impl<'tcx> Builtins<'tcx> {
+    pub fn new(tcx: TyCtxt<'tcx>) -> Self {
+        Self {
+            tcx,
+            // Local functions use their `DefIndex` as id, so we start counting after them.
+            next_id: tcx.hir().definitions().def_index_count(),
+            fn_ids: HashMap::new(),
+            decls: Vec::new(),
+        }
+    }
+
+    /// Returns the id of the builtin corresponding to a call to `def_id`, or `None` if the
+    /// function is not a builtin. Builtins are only supported for some element types.
+    pub fn lookup(
+        &mut self,
+        def_id: DefId,
+        substs: SubstsRef<'tcx>,
+    ) -> Result<Option<FnId>, Unsupported> {
+        let (builtin, elem) = match Builtin::resolve(self.tcx, def_id, substs) {
+            Some(resolved) => resolved,
+            None => return Ok(None),
+        };
+        if let Some(fn_id) = self.fn_ids.get(&(builtin, elem)) {
+            return Ok(Some(*fn_id));
+        }
+        let mut elem_heap = vec![];
+        let elem_ty = trivially_refined(self.tcx, elem, &mut elem_heap)?;
+        // Boxed elements are only supported in `Box::new`.
+        if builtin != Builtin::BoxNew && !elem_heap.is_empty() {
+            return Err(Unsupported::new(format!(
+                "Boxed elements are not supported yet: `{}`.",
+                elem
+            )));
+        }
+        let fn_id = self.fresh_fn_id();
+        let mut ty_params: Vec<_> = elem
+            .walk()
+            .filter_map(|arg| match arg.unpack() {
+                GenericArgKind::Type(ty) => match ty.kind() {
+                    ty::Param(param) => Some(TyParam::new(param.index as usize)),
+                    _ => None,
+                },
+                _ => None,
+            })
+            .collect();
+        ty_params.sort();
+        ty_params.dedup();
+        self.decls
+            .push((fn_id, builtin.fn_decl(elem_ty, elem_heap, ty_params)));
+        self.fn_ids.insert((builtin, elem), fn_id);
+        Ok(Some(fn_id))
+    }
+
+    /// Returns an id that is not used by any local function nor builtin.
+    pub fn fresh_fn_id(&mut self) -> FnId {
+        self.next_id += 1;
+        FnId::new(self.next_id - 1)
+    }
+
+    pub fn into_decls(self) -> Vec<(FnId, FnDecl)> {
+        self.decls
+    }
+}
+
+/// Lowers an element type giving every refinement the trivial predicate. The contents of boxes
+/// are allocated in `heap`.
+pub fn trivially_refined<'tcx>(
+    tcx: TyCtxt<'tcx>,
+    ty: ty::Ty<'tcx>,
+    heap: &mut Vec<(Location, Ty)>,
+) -> Result<Ty, Unsupported> {
+    let ty = match ty.kind() {
+        ty::Tuple(substs) if !substs.is_empty() => Ty::Tuple(
+            ty.tuple_fields()
+                .enumerate()
+                .map(|(i, ty)| Ok((Field::new(i), trivially_refined(tcx, ty, heap)?)))
+                .collect::<Result<_, _>>()?,
+        ),
+        ty::Adt(adt, substs) if adt.is_box() => {
+            let ty = trivially_refined(tcx, substs.type_at(0), heap)?;
+            let l = Location::new(FIRST_ELEM_LOCATION + heap.len());
+            heap.push((l, ty));
+            Ty::OwnRef(l)
+        }
+        ty::Adt(adt, substs) if tcx.is_diagnostic_item(sym::vec_type, adt.did) => {
+            let ty = trivially_refined(tcx, substs.type_at(0), heap)?;
+            Ty::Vec(box ty, refine(Pred::tt()))
+        }
+        ty::Tuple(_) => Ty::unit(),
+        ty::Bool => Ty::Refine(BaseTy::Bool, refine(Pred::tt())),
+        ty::Int(_) | ty::Uint(_) => Ty::Refine(BaseTy::Int, refine(Pred::tt())),
+        ty::Param(param) => Ty::Param(TyParam::new(param.index as usize)),
+        _ => {
+            return Err(Unsupported::new(format!(
+                "Unsupported element type `{}`.",
+                ty
+            )))
+        }
+    };
+    Ok(ty)
+}
+
+fn in_bounds(len: usize) -> Ty {
+    let pred = binop(
+        BinOp::And,
+        binop(BinOp::Ge, nu(), int(0)),
+        binop(BinOp::Lt, nu(), loc(len)),
+    );
+    Ty::Refine(BaseTy::Int, refine(pred))
+}
+
+fn refine(pred: Pred) -> Refine {
+    Refine::Pred(pred)
+}
+
+fn nu() -> Pred {
+    Pred::Place(pred::Place::from(pred::Var::Nu))
+}
+
+fn loc(n: usize) -> Pred {
+    Pred::Place(pred::Place::from(Location::new(n)))
+}
+
+fn int(n: u128) -> Pred {
+    Pred::Constant(pred::Constant::Int(n))
+}
+
+fn eq(lhs: Pred, rhs: Pred) -> Pred {
+    binop(BinOp::Eq, lhs, rhs)
+}
+
+fn gt(lhs: Pred, rhs: Pred) -> Pred {
+    binop(BinOp::Gt, lhs, rhs)
+}
+
+fn add(lhs: Pred, rhs: Pred) -> Pred {
+    binop(BinOp::Add, lhs, rhs)
+}
+
+fn sub(lhs: Pred, rhs: Pred) -> Pred {
+    binop(BinOp::Sub, lhs, rhs)
+}
+
+fn binop(op: BinOp, lhs: Pred, rhs: Pred) -> Pred {
+    Pred::BinaryOp(op, box lhs, box rhs)
+}
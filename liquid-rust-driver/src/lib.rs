@@ -2,7 +2,10 @@
 #![feature(box_syntax)]
 #![feature(or_patterns)]
 
+mod builtins;
 mod lower;
+mod macros;
+mod reflect;
 mod translate;
 mod visitor;
 
@@ -16,25 +19,68 @@ extern crate rustc_interface;
 #[macro_use]
 extern crate rustc_middle;
 extern crate rustc_mir;
+extern crate rustc_parse;
+extern crate rustc_session;
 extern crate rustc_span;
 extern crate rustc_target;
+extern crate smallvec;
 
-use liquid_rust_core::{ast::Program, names::FnId};
+use builtins::Builtins;
+use liquid_rust_core::{
+    ast::{visitor::fn_refs, Program},
+    names::{FnId, Local},
+};
+use rustc_hir::def_id::{DefIndex, LocalDefId, LOCAL_CRATE};
 use rustc_index::vec::Idx;
-use visitor::DefCollector;
+use rustc_middle::ty::TyCtxt;
+use rustc_span::Span;
+use visitor::{
+    alias_definitions, collect_measures, collect_structs, is_ignored, is_measure, is_pure,
+    is_trusted, parse_aliases, DefCollector,
+};
 
-use liquid_rust_typeck::check_program;
+use liquid_rust_typeck::{
+    check_program,
+    dump::{DumpConfig, Stage},
+};
 use rustc_driver::{catch_with_exit_code, Callbacks, Compilation, RunCompiler};
 use rustc_interface::{interface::Compiler, Queries};
-use translate::Transformer;
+use std::collections::HashMap;
+use translate::{Transformer, Unsupported};
 
-pub fn run_compiler(args: Vec<String>) -> i32 {
-    catch_with_exit_code(move || RunCompiler::new(&args, &mut LiquidRustDriver).run())
+pub fn run_compiler(mut args: Vec<String>) -> i32 {
+    // The `--dump` flags are ours, so they are removed before passing the rest to rustc.
+    let dump = match DumpConfig::from_args(&mut args) {
+        Ok(dump) => dump,
+        Err(err) => {
+            eprintln!("error: {}", err);
+            return rustc_driver::EXIT_FAILURE;
+        }
+    };
+    let mut driver = LiquidRustDriver { dump };
+    catch_with_exit_code(move || RunCompiler::new(&args, &mut driver).run())
 }
 
-struct LiquidRustDriver;
+struct LiquidRustDriver {
+    dump: DumpConfig,
+}
 
 impl Callbacks for LiquidRustDriver {
+    fn after_parsing<'tcx>(
+        &mut self,
+        compiler: &Compiler,
+        queries: &'tcx Queries<'tcx>,
+    ) -> Compilation {
+        let sess = compiler.session();
+        let mut krate = queries.parse().unwrap().peek_mut();
+        macros::expand_macros(sess, &mut krate);
+        if sess.has_errors() {
+            Compilation::Stop
+        } else {
+            Compilation::Continue
+        }
+    }
+
     fn after_analysis<'tcx>(
         &mut self,
         compiler: &Compiler,
@@ -42,11 +88,23 @@ impl Callbacks for LiquidRustDriver {
     ) -> Compilation {
         let handler = compiler.session().diagnostic();
         let mut buffer = Vec::new();
+        let dump = &mut self.dump;
 
         queries.global_ctxt().unwrap().peek_mut().enter(|tcx| {
-            let mut visitor = DefCollector::new(tcx, handler, &mut buffer);
+            let measures = collect_measures(tcx);
+            let alias_definitions = alias_definitions(tcx);
+            let aliases = parse_aliases(tcx, &alias_definitions);
+            let structs = collect_structs(tcx, &measures);
+            let mut visitor =
+                DefCollector::new(tcx, &measures, &aliases, &structs, handler, &mut buffer);
             tcx.hir().krate().visit_all_item_likes(&mut visitor);
-            let mut annotations = visitor.annotations();
+            for &body_id in &tcx.hir().krate().body_ids {
+                let def_id = tcx.hir().body_owner_def_id(body_id).to_def_id();
+                if tcx.is_closure(def_id) {
+                    visitor.visit_closure(def_id);
+                }
+            }
+            let (mut annotations, impl_specs, mut unsupported) = visitor.annotations();
 
             if !buffer.is_empty() {
                 // compilation = Compilation::Stop;
@@ -56,15 +114,181 @@ impl Callbacks for LiquidRustDriver {
                 }
             }
 
+            let mut decls = HashMap::new();
+            for (measure, decl) in measures.values() {
+                let mut decl = decl.clone();
+                let def_id = local_def_id(FnId::new(measure.as_usize()));
+                if is_pure(tcx, def_id.to_def_id()) {
+                    match reflect::reflect(tcx, def_id) {
+                        Ok(def) => decl.def = Some(def),
+                        Err(err) => report_unsupported(tcx, def_id, err, "reflected"),
+                    }
+                }
+                decls.insert(*measure, decl);
+            }
+            for measure in reflect::recursive_measures(&decls) {
+                decls.get_mut(&measure).unwrap().def = None;
+                tcx.sess.span_err(
+                    tcx.def_span(local_def_id(FnId::new(measure.as_usize()))),
+                    "Recursive pure functions are not supported.",
+                );
+            }
+            // The output of a reflected function is known to be the function applied to its
+            // inputs.
+            for (measure, decl) in &decls {
+                if decl.def.is_some() {
+                    let def_id = local_def_id(FnId::new(measure.as_usize())).to_def_id();
+                    let annot = annotations.remove(&def_id);
+                    annotations.insert(def_id, reflect::reflected_decl(*measure, decl, annot));
+                }
+            }
+
             let mut program = Program::new();
+            for (measure, decl) in decls {
+                program.add_measure(measure, decl);
+            }
+            let mut builtins = Builtins::new(tcx);
             for &body_id in &tcx.hir().krate().body_ids {
                 let def_id = tcx.hir().body_owner_def_id(body_id);
+                if macros::is_marker(tcx, def_id.to_def_id()) || is_measure(tcx, def_id.to_def_id())
+                {
+                    continue;
+                }
+                // Ignored functions don't have a signature, so they can't be called by checked
+                // functions, while trusted ones are only known by their annotation.
+                if is_ignored(tcx, def_id.to_def_id()) {
+                    annotations.remove(&def_id.to_def_id());
+                    continue;
+                }
+                if is_trusted(tcx, def_id.to_def_id()) {
+                    if !tcx.is_closure(def_id.to_def_id())
+                        && !annotations.contains_key(&def_id.to_def_id())
+                    {
+                        tcx.sess.span_err(
+                            tcx.def_span(def_id),
+                            "Trusted function without a type annotation.",
+                        );
+                    }
+                    continue;
+                }
+                // Callers can still rely on an annotation we can't check the body against.
+                if let Some(err) = unsupported.remove(&def_id.to_def_id()) {
+                    report_unsupported(tcx, def_id, err, "checked");
+                    continue;
+                }
+                dump.name_fn(def_id.index(), dump_name(tcx, def_id));
                 let body = tcx.optimized_mir(def_id);
-                let func = Transformer::translate(tcx, &mut annotations, body);
-                program.add_fn(FnId::new(def_id.index()), func);
+                match Transformer::translate(
+                    tcx,
+                    &mut annotations,
+                    &mut builtins,
+                    &measures,
+                    &structs,
+                    body,
+                ) {
+                    Ok(func) => program.add_fn(FnId::new(def_id.index()), func),
+                    Err(err) => report_unsupported(tcx, def_id, err, "checked"),
+                }
+            }
+            // The remaining annotations belong to trait methods without a default body, trusted
+            // functions and functions that couldn't be translated, so calls to them can only be
+            // checked against the annotation.
+            for (def_id, decl) in annotations {
+                program.add_extern_fn(FnId::new(def_id.index.index()), decl);
             }
-            check_program(program);
+            for (def_id, decl) in impl_specs {
+                let trait_fn = builtins.fresh_fn_id();
+                program.add_extern_fn(trait_fn, decl);
+                program.add_impl(FnId::new(def_id.index.index()), trait_fn);
+            }
+            for (fn_id, decl) in builtins.into_decls() {
+                program.add_extern_fn(fn_id, decl);
+            }
+            remove_unresolved(tcx, &mut program);
+            // The translated program can be checked on its own with `liquid-rust-core-cli`,
+            // which makes it possible to reproduce a bug without going through rustc.
+            dump.dump(Stage::Lr, tcx.crate_name(LOCAL_CRATE), &program);
+            // Locals are named after the ones of the MIR.
+            let local_name = |x: &Local| format!("_{}", x.inner());
+            check_program(program, dump, local_name, |fn_id, span, msg| {
+                let def_id = local_def_id(fn_id);
+                tcx.sess
+                    .struct_span_err(span.unwrap_or_else(|| tcx.def_span(def_id)), &msg)
+                    .note(&format!(
+                        "`{}` is not safe",
+                        tcx.def_path_str(def_id.to_def_id())
+                    ))
+                    .emit();
+            });
         });
         Compilation::Stop
     }
 }
+
+/// The name of the dump files of a function, which is its path with the characters that may not
+/// be allowed in file names replaced.
+fn dump_name(tcx: TyCtxt, def_id: LocalDefId) -> String {
+    tcx.def_path_str(def_id.to_def_id())
+        .replace("::", ".")
+        .replace(|c: char| !c.is_alphanumeric() && c != '_' && c != '.', "_")
+}
+
+fn report_unsupported(tcx: TyCtxt, def_id: LocalDefId, err: Unsupported, what: &str) {
+    tcx.sess
+        .struct_span_err(err.span.unwrap_or_else(|| tcx.def_span(def_id)), &err.msg)
+        .note(&format!(
+            "`{}` is not {}",
+            tcx.def_path_str(def_id.to_def_id()),
+            what
+        ))
+        .emit();
+}
+
+/// Removes the functions referring to a function without a signature, i.e., an ignored function
+/// or one that couldn't be translated and has no annotation. This is repeated until every
+/// function left refers only to functions in the program.
+fn remove_unresolved(tcx: TyCtxt, program: &mut Program<Span>) {
+    loop {
+        let unresolved: Vec<_> = program
+            .iter()
+            .filter_map(|(fn_id, def)| {
+                let callee = fn_refs(&def.body)
+                    .into_iter()
+                    .find(|callee| !program.contains(callee))?;
+                Some((*fn_id, callee))
+            })
+            .collect();
+        if unresolved.is_empty() {
+            break;
+        }
+        for (fn_id, callee) in unresolved {
+            program.remove_fn(&fn_id);
+            let def_id = local_def_id(fn_id);
+            tcx.sess
+                .struct_span_err(
+                    tcx.def_span(def_id),
+                    &format!(
+                        "Use of `{}`, which is neither checked nor annotated.",
+                        tcx.def_path_str(local_def_id(callee).to_def_id())
+                    ),
+                )
+                .note(&format!(
+                    "`{}` is not checked",
+                    tcx.def_path_str(def_id.to_def_id())
+                ))
+                .emit();
+        }
+    }
+    let impls = program.take_impls();
+    for (impl_fn, trait_fn) in impls {
+        if program.contains(&impl_fn) {
+            program.add_impl(impl_fn, trait_fn);
+        }
+    }
+}
+
+fn local_def_id(fn_id: FnId) -> LocalDefId {
+    LocalDefId {
+        local_def_index: DefIndex::new(fn_id.as_usize()),
+    }
+}
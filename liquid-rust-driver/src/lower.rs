@@ -1,35 +1,448 @@
 //! Lowering refinement annotations into the core IR.
 
-use std::iter::FromIterator;
+use std::{collections::HashMap, iter::FromIterator, mem};
 
 use liquid_rust_core::{
     ast::{
         pred::{Place, Var},
-        FnDecl, Heap, Pred, Refine, Ty,
+        BaseTy, BorrowKind, FnDecl, Heap, MeasureDecl, Pred, PredParam, Refine, Region, Ty,
+        TyParam, UniversalRegion,
     },
-    names::{Field, Local},
+    names::{Field, Local, MeasureId},
     ty::{BinOp, Location, UnOp},
 };
-use liquid_rust_parser::ast;
+use liquid_rust_parser::{ast, parse_refined_ty};
 use quickscope::ScopeMap;
 
+/// The measures available to annotations indexed by name.
+pub type Measures = HashMap<String, (MeasureId, MeasureDecl)>;
+
+/// The refinement type aliases available to annotations indexed by name.
+pub type Aliases<'src> = HashMap<&'src str, ast::Alias<'src>>;
+
+/// The structs defined in the crate indexed by name.
+pub type Structs = HashMap<String, StructDef>;
+
+/// A struct as seen by annotations.
+pub struct StructDef {
+    /// The names of the fields in order, together with the name of their struct if they are one.
+    pub fields: Vec<(String, Option<String>)>,
+    /// The type standing for the struct in annotations, i.e., a tuple with trivially refined
+    /// fields. `None` if the struct is generic or some field can't be refined.
+    pub ty: Option<Ty>,
+    /// The invariant of the struct, referring to its fields by position in `V`.
+    pub invariant: Option<Pred>,
+    /// The indices the struct is refined by, if any.
+    pub indices: Option<StructIndices>,
+}
+
+/// The indices of a struct, given by a `liquid::refined_by` attribute, e.g.,
+/// `#[liquid::refined_by("lo: int, hi: int")]`.
+pub struct StructIndices {
+    /// The names of the indices together with their sort.
+    pub params: Vec<(String, BaseTy)>,
+    /// The types of the fields given by `liquid::field` attributes, which can refer to the
+    /// indices. Fields without annotation have the type they have in [`StructDef::ty`].
+    pub fields: Vec<Option<String>>,
+}
+
 pub struct LowerCtx<'src> {
     vars: ScopeMap<&'src str, Var>,
+    measures: &'src Measures,
+    aliases: Option<&'src Aliases<'src>>,
+    structs: Option<&'src Structs>,
+    /// The name of the struct of the variables whose fields can be accessed by name.
+    var_structs: HashMap<&'src str, String>,
+    /// The arguments of the alias being expanded indexed by the name of its parameters.
+    alias_args: HashMap<&'src str, Pred>,
+    /// The aliases or indexed structs being expanded together with the span of their use,
+    /// outermost first.
+    expanding: Vec<(&'src str, ast::Span)>,
+    /// The index binders found while lowering the type of an argument, together with the span of
+    /// the binder, the location standing for the index and its sort.
+    binders: Vec<(&'src str, ast::Span, Location, BaseTy)>,
+    errors: Vec<(ast::Span, String)>,
+    ty_params: Vec<TyParam>,
+    /// The types the type parameters in scope stand for. `None` means the parameter is
+    /// instantiated with a type that can't be refined.
+    ty_names: Vec<(String, Option<Ty>)>,
+    /// The predicate parameters in scope together with their arity.
+    pred_params: Vec<(&'src str, PredParam, usize)>,
     locations: usize,
     fields: usize,
     locals: usize,
+    regions: usize,
+    preds: usize,
 }
 
 impl<'src> LowerCtx<'src> {
-    pub fn new() -> Self {
+    pub fn new(measures: &'src Measures) -> Self {
         LowerCtx {
             vars: ScopeMap::new(),
+            measures,
+            aliases: None,
+            structs: None,
+            var_structs: HashMap::new(),
+            alias_args: HashMap::new(),
+            expanding: Vec::new(),
+            binders: Vec::new(),
+            errors: Vec::new(),
+            ty_params: Vec::new(),
+            ty_names: Vec::new(),
+            pred_params: Vec::new(),
             locations: 0,
             fields: 0,
             locals: 0,
+            regions: 0,
+            preds: 0,
         }
     }
 
+    /// Makes `aliases` available to the annotation.
+    pub fn with_aliases(mut self, aliases: &'src Aliases<'src>) -> Self {
+        self.aliases = Some(aliases);
+        self
+    }
+
+    /// Makes `structs` available to the annotation, both as types and to access their fields by
+    /// name.
+    pub fn with_structs(mut self, structs: &'src Structs) -> Self {
+        self.structs = Some(structs);
+        self
+    }
+
+    /// Returns the errors found while lowering, i.e., misuses of aliases or unknown fields, with
+    /// the span of the offending use in the annotation.
+    pub fn take_errors(&mut self) -> Vec<(ast::Span, String)> {
+        mem::take(&mut self.errors)
+    }
+
+    /// Makes a type parameter of the function available to the annotation.
+    pub fn define_ty_param(&mut self, name: String, param: TyParam) {
+        self.ty_params.push(param);
+        self.ty_names.push((name, Some(Ty::Param(param))));
+    }
+
+    /// Makes `name` stand for `ty` in the annotation, shadowing previous definitions. This is
+    /// used to instantiate the type parameters of a trait in the specification of its methods.
+    pub fn instantiate_ty_param(&mut self, name: String, ty: Option<Ty>) {
+        self.ty_names.push((name, ty));
+    }
+
+    /// Makes `name` stand for `var` in the annotation.
+    pub fn define_var(&mut self, name: &'src str, var: Var) {
+        self.vars.define(name, var);
+    }
+
+    /// Makes the fields of `name` accessible by name if `strukt` is the name of a struct.
+    pub fn define_var_struct(&mut self, name: &'src str, strukt: Option<&str>) {
+        match strukt.filter(|strukt| self.struct_def(strukt).is_some()) {
+            Some(strukt) => self.var_structs.insert(name, strukt.to_owned()),
+            None => self.var_structs.remove(name),
+        };
+    }
+
+    fn struct_def(&self, name: &str) -> Option<&'src StructDef> {
+        self.structs.and_then(|structs| structs.get(name))
+    }
+
+    /// Resolves the projections of a place with base `base` to field positions.
+    fn lower_projs(
+        &mut self,
+        base: &str,
+        projs: Vec<ast::Proj<'src>>,
+        span: ast::Span,
+    ) -> Vec<usize> {
+        let mut strukt = self.var_structs.get(base).cloned();
+        let mut lowered = vec![];
+        for proj in projs {
+            match proj {
+                ast::Proj::Index(n) => {
+                    strukt = strukt
+                        .and_then(|s| self.struct_def(&s))
+                        .and_then(|def| def.fields.get(n))
+                        .and_then(|(_, s)| s.clone());
+                    lowered.push(n);
+                }
+                ast::Proj::Field(field) => {
+                    let def = strukt.as_deref().and_then(|s| self.struct_def(s));
+                    let found = def.and_then(|def| {
+                        def.fields
+                            .iter()
+                            .position(|(name, _)| name == field.symbol)
+                            .map(|n| (n, def.fields[n].1.clone()))
+                    });
+                    match found {
+                        Some((n, s)) => {
+                            strukt = s;
+                            lowered.push(n);
+                        }
+                        None => {
+                            self.errors
+                                .push((span, format!("Cannot find field `{}`.", field.symbol)));
+                            return lowered;
+                        }
+                    }
+                }
+            }
+        }
+        lowered
+    }
+
+    /// Expands the alias `name` applied to `args`, used at `span`. The arguments are lowered in
+    /// the scope of the use, while the aliased type only sees the parameters of the alias and `v`.
+    fn expand_alias(
+        &mut self,
+        name: ast::Ident<'src>,
+        args: Vec<ast::Predicate<'src>>,
+        span: ast::Span,
+    ) -> Ty {
+        let alias = match self.aliases.and_then(|aliases| aliases.get(name.symbol)) {
+            Some(alias) => alias,
+            None => {
+                return self.alias_error(
+                    span,
+                    format!("Cannot find type parameter or alias `{}`.", name.symbol),
+                )
+            }
+        };
+        if alias.params.len() != args.len() {
+            return self.alias_error(
+                span,
+                format!(
+                    "Alias `{}` takes {} argument(s) but {} were supplied.",
+                    name.symbol,
+                    alias.params.len(),
+                    args.len()
+                ),
+            );
+        }
+        if self.expanding.iter().any(|(n, _)| *n == name.symbol) {
+            return self.alias_error(span, format!("Alias `{}` is recursive.", name.symbol));
+        }
+
+        let args = args
+            .into_iter()
+            .map(|arg| arg.lower(self))
+            .collect::<Vec<_>>();
+        let vars = mem::replace(&mut self.vars, ScopeMap::new());
+        let alias_args = mem::replace(
+            &mut self.alias_args,
+            alias
+                .params
+                .iter()
+                .map(|param| param.symbol)
+                .zip(args)
+                .collect(),
+        );
+        if !self.alias_args.contains_key("v") {
+            self.vars.define("v", Var::Nu);
+        }
+        self.expanding.push((name.symbol, span));
+        let ty = alias.ty.clone().lower(self);
+        self.expanding.pop();
+        self.vars = vars;
+        self.alias_args = alias_args;
+        ty
+    }
+
+    /// Expands the struct `name` refined by `indices`, used at `span`, into the tuple of its fields
+    /// with the indices substituted in their types. Like aliases, the types of the fields only see
+    /// the indices and `v`.
+    fn expand_indexed(
+        &mut self,
+        name: ast::Ident<'src>,
+        indices: Vec<ast::Index<'src>>,
+        span: ast::Span,
+    ) -> Ty {
+        let (fields, invariant, def) = match self.struct_def(name.symbol) {
+            Some(StructDef {
+                ty: Some(ty),
+                indices: Some(def),
+                ..
+            }) => match ty {
+                Ty::Tuple(fields) => (fields.clone(), None, def),
+                Ty::Struct(fields, invariant) => (fields.clone(), Some(invariant.clone()), def),
+                _ => (vec![], None, def),
+            },
+            Some(StructDef { ty: None, .. }) => {
+                return self.alias_error(
+                    span,
+                    format!("Struct `{}` can't be used in annotations.", name.symbol),
+                )
+            }
+            Some(StructDef { indices: None, .. }) => {
+                return self.alias_error(
+                    span,
+                    format!("Struct `{}` is not refined by indices.", name.symbol),
+                )
+            }
+            None => {
+                return self.alias_error(span, format!("Cannot find struct `{}`.", name.symbol))
+            }
+        };
+        if def.params.len() != indices.len() {
+            return self.alias_error(
+                span,
+                format!(
+                    "Struct `{}` takes {} index(es) but {} were supplied.",
+                    name.symbol,
+                    def.params.len(),
+                    indices.len()
+                ),
+            );
+        }
+
+        let indices = indices
+            .into_iter()
+            .zip(&def.params)
+            .map(|(index, (_, sort))| self.lower_index(index, *sort))
+            .collect::<Vec<_>>();
+        let vars = mem::replace(&mut self.vars, ScopeMap::new());
+        let alias_args = mem::replace(
+            &mut self.alias_args,
+            def.params
+                .iter()
+                .map(|(param, _)| param.as_str())
+                .zip(indices.iter().cloned())
+                .collect(),
+        );
+        if !self.alias_args.contains_key("v") {
+            self.vars.define("v", Var::Nu);
+        }
+        self.expanding.push((name.symbol, span));
+        let fields = fields
+            .into_iter()
+            .zip(&def.fields)
+            .map(|((field, ty), annot)| match annot {
+                Some(annot) => {
+                    // Field annotations are checked to parse when collecting the structs.
+                    let annot = match parse_refined_ty(annot) {
+                        Ok(annot) => annot,
+                        Err(_) => panic!("Lower: Invalid field annotation"),
+                    };
+                    (field, annot.lower(self))
+                }
+                None => (field, ty),
+            })
+            .collect();
+        self.expanding.pop();
+        self.vars = vars;
+        self.alias_args = alias_args;
+
+        let ty = match invariant {
+            Some(invariant) => Ty::Struct(fields, invariant),
+            None => Ty::Tuple(fields),
+        };
+        Ty::Indexed(Box::new(ty), indices)
+    }
+
+    /// Lowers an index of sort `sort`. A binder stands for a fresh location which is bound in the
+    /// signature by [`LowerCtx::take_binders`].
+    fn lower_index(&mut self, index: ast::Index<'src>, sort: BaseTy) -> Pred {
+        match index {
+            ast::Index::Expr(pred) => pred.lower(self),
+            ast::Index::Binder(name) => {
+                let l = self.fresh_location();
+                self.binders.push((name.symbol, name.span, l, sort));
+                Pred::Place(Place::from(Var::Location(l)))
+            }
+        }
+    }
+
+    /// Brings the index binders found so far into scope, returning the locations they stand for
+    /// with their types.
+    fn take_binders(&mut self) -> Vec<(Location, Ty)> {
+        mem::take(&mut self.binders)
+            .into_iter()
+            .map(|(name, _, l, sort)| {
+                self.vars.define(name, Var::Location(l));
+                (l, Ty::Refine(sort, Refine::Pred(Pred::tt())))
+            })
+            .collect()
+    }
+
+    /// Reports the index binders found so far, e.g., in the output of a function.
+    fn reject_binders(&mut self) {
+        for (_, span, ..) in mem::take(&mut self.binders) {
+            self.errors.push((
+                span,
+                "Index binders can only be used in the arguments of a function.".to_owned(),
+            ));
+        }
+    }
+
+    /// Records an error in the use of an alias. Errors in the expansion of an alias are reported
+    /// at its outermost use, mentioning the aliases being expanded.
+    fn alias_error(&mut self, span: ast::Span, msg: String) -> Ty {
+        match self.expanding.first() {
+            Some((_, outer)) => {
+                let names = self
+                    .expanding
+                    .iter()
+                    .map(|(name, _)| format!("`{}`", name))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                let msg = format!("{} Found in the expansion of {}.", msg, names);
+                self.errors.push((outer.clone(), msg));
+            }
+            None => self.errors.push((span, msg)),
+        }
+        Ty::unit()
+    }
+
+    fn pred_param(&self, name: &str) -> Option<(PredParam, usize)> {
+        self.pred_params
+            .iter()
+            .rev()
+            .find(|(param, ..)| *param == name)
+            .map(|(_, param, arity)| (*param, *arity))
+    }
+
+    /// Lowers the refinement of a type, which is abstract if the predicate is an application of a
+    /// predicate parameter, e.g., `p(v)`.
+    fn lower_refine(&mut self, pred: ast::Predicate<'src>) -> Refine {
+        let (name, args) = match pred.kind {
+            ast::PredicateKind::App(name, args) if self.pred_param(name.symbol).is_some() => {
+                (name, args)
+            }
+            kind => {
+                return Refine::Pred(
+                    ast::Predicate {
+                        kind,
+                        span: pred.span,
+                    }
+                    .lower(self),
+                )
+            }
+        };
+        let (param, arity) = self.pred_param(name.symbol).unwrap();
+        if arity != args.len() {
+            self.errors.push((
+                pred.span,
+                format!(
+                    "Predicate `{}` takes {} argument(s) but {} were supplied.",
+                    name.symbol,
+                    arity,
+                    args.len()
+                ),
+            ));
+            return Refine::Pred(Pred::tt());
+        }
+        let mut vars = vec![];
+        for arg in args {
+            let span = arg.span.clone();
+            match arg.lower(self) {
+                Pred::Place(Place { base, projs }) if projs.is_empty() => vars.push(base),
+                _ => self.errors.push((
+                    span,
+                    "The arguments of an abstract refinement must be variables.".to_owned(),
+                )),
+            }
+        }
+        Refine::Abstract(param, vars)
+    }
+
     fn fresh_location(&mut self) -> Location {
         self.locations += 1;
         Location::new(self.locations - 1)
@@ -44,6 +457,16 @@ impl<'src> LowerCtx<'src> {
         self.locals += 1;
         Local::new(self.locals - 1)
     }
+
+    fn fresh_region(&mut self) -> UniversalRegion {
+        self.regions += 1;
+        UniversalRegion::new(self.regions - 1)
+    }
+
+    fn fresh_pred_param(&mut self) -> PredParam {
+        self.preds += 1;
+        PredParam::new(self.preds - 1)
+    }
 }
 
 pub trait Lower<'src> {
@@ -91,8 +514,27 @@ impl<'src> Lower<'src> for ast::Predicate<'src> {
         match self.kind {
             ast::PredicateKind::Lit(c) => Pred::Constant(c),
             ast::PredicateKind::Place(base, projs) => {
-                let base = *lcx.vars.get(&base.symbol).expect("Lower: Var not found");
-                Pred::Place(Place { base, projs })
+                let projs = lcx.lower_projs(base.symbol, projs, self.span);
+                match lcx.vars.get(&base.symbol) {
+                    Some(base) => Pred::Place(Place { base: *base, projs }),
+                    None => {
+                        let arg = lcx
+                            .alias_args
+                            .get(base.symbol)
+                            .expect("Lower: Var not found")
+                            .clone();
+                        match arg {
+                            Pred::Place(mut place) => {
+                                place.projs.extend(projs);
+                                Pred::Place(place)
+                            }
+                            _ if projs.is_empty() => arg,
+                            _ => {
+                                panic!("Lower: Projection of an alias argument that is not a place")
+                            }
+                        }
+                    }
+                }
             }
             ast::PredicateKind::UnaryOp(uo, bp) => {
                 Pred::UnaryOp(uo.lower(lcx), Box::new((*bp).lower(lcx)))
@@ -102,6 +544,28 @@ impl<'src> Lower<'src> for ast::Predicate<'src> {
                 Box::new(ba.lower(lcx)),
                 Box::new(bb.lower(lcx)),
             ),
+            ast::PredicateKind::App(measure, _) if lcx.pred_param(measure.symbol).is_some() => {
+                lcx.errors.push((
+                    self.span,
+                    "Abstract refinements must be the whole refinement of a type.".to_owned(),
+                ));
+                Pred::tt()
+            }
+            ast::PredicateKind::App(measure, args) => {
+                let (measure, decl) = lcx
+                    .measures
+                    .get(measure.symbol)
+                    .expect("Lower: Measure not found");
+                assert_eq!(
+                    decl.inputs.len(),
+                    args.len(),
+                    "Lower: Wrong number of arguments for measure"
+                );
+                Pred::App(
+                    *measure,
+                    args.into_iter().map(|arg| arg.lower(lcx)).collect(),
+                )
+            }
         }
     }
 }
@@ -115,11 +579,11 @@ impl<'src> Lower<'src> for ast::Ty<'src> {
             ast::TyKind::Refined(Some(i), b, p) => {
                 lcx.vars.push_layer();
                 lcx.vars.define(i.symbol, Var::Nu);
-                let p = p.lower(lcx);
+                let refine = lcx.lower_refine(p);
                 lcx.vars.pop_layer();
-                Ty::Refine(b, Refine::Pred(p))
+                Ty::Refine(b, refine)
             }
-            ast::TyKind::Refined(None, b, p) => Ty::Refine(b, Refine::Pred(p.lower(lcx))),
+            ast::TyKind::Refined(None, b, p) => Ty::Refine(b, lcx.lower_refine(p)),
             ast::TyKind::Tuple(fs) => {
                 lcx.vars.push_layer();
                 let mut tup = Vec::new();
@@ -134,6 +598,63 @@ impl<'src> Lower<'src> for ast::Ty<'src> {
                 lcx.vars.pop_layer();
                 Ty::Tuple(tup)
             }
+            ast::TyKind::Vec(ty) => Ty::Vec(Box::new(ty.lower(lcx)), Refine::Pred(Pred::tt())),
+            ast::TyKind::RefinedVec(Some(i), ty, p) => {
+                let ty = ty.lower(lcx);
+                lcx.vars.push_layer();
+                lcx.vars.define(i.symbol, Var::Nu);
+                let refine = lcx.lower_refine(p);
+                lcx.vars.pop_layer();
+                Ty::Vec(Box::new(ty), refine)
+            }
+            ast::TyKind::RefinedVec(None, ty, p) => {
+                let ty = ty.lower(lcx);
+                Ty::Vec(Box::new(ty), lcx.lower_refine(p))
+            }
+            ast::TyKind::Param(ident) => {
+                let ty = lcx
+                    .ty_names
+                    .iter()
+                    .rev()
+                    .find(|(name, _)| name == ident.symbol)
+                    .map(|(_, ty)| ty.clone());
+                match ty {
+                    Some(ty) => {
+                        ty.expect("Lower: Type parameter instantiated with an unsupported type")
+                    }
+                    None => match lcx.struct_def(ident.symbol) {
+                        Some(StructDef { ty: Some(ty), .. }) => ty.clone(),
+                        Some(StructDef { ty: None, .. }) => {
+                            lcx.errors.push((
+                                self.span,
+                                format!("Struct `{}` can't be used in annotations.", ident.symbol),
+                            ));
+                            Ty::unit()
+                        }
+                        None => lcx.expand_alias(ident, vec![], self.span),
+                    },
+                }
+            }
+            ast::TyKind::Alias(ident, args) => lcx.expand_alias(ident, args, self.span),
+            ast::TyKind::IndexedBase(b, index) => {
+                let index = lcx.lower_index(index, b);
+                let nu = Pred::Place(Place::from(Var::Nu));
+                Ty::Refine(
+                    b,
+                    Refine::Pred(Pred::BinaryOp(BinOp::Eq, Box::new(nu), Box::new(index))),
+                )
+            }
+            ast::TyKind::Indexed(ident, indices) => lcx.expand_indexed(ident, indices, self.span),
+            ast::TyKind::Fn(decl) => {
+                // The arguments of a function type are only in scope in its signature, which does
+                // not quantify over the type parameters of the enclosing function.
+                let ty_params = std::mem::take(&mut lcx.ty_params);
+                lcx.vars.push_layer();
+                let decl = decl.lower(lcx);
+                lcx.vars.pop_layer();
+                lcx.ty_params = ty_params;
+                Ty::Fn(Box::new(decl))
+            }
         }
     }
 }
@@ -148,19 +669,91 @@ impl<'src> Lower<'src> for ast::FnDecl<'src> {
         let mut inputs = Vec::new();
         let mut in_heap = Vec::new();
         let mut out_heap = Vec::new();
+        let mut outputs = Vec::new();
+        let mut regions = Vec::new();
+
+        // Predicate parameters are in scope in the whole signature.
+        let pred_params_len = lcx.pred_params.len();
+        let mut pred_params = Vec::new();
+        for (ident, sorts) in self.pred_params {
+            let param = lcx.fresh_pred_param();
+            lcx.pred_params.push((ident.symbol, param, sorts.len()));
+            pred_params.push((param, sorts));
+        }
+
+        // The receiver is the first argument. References to it are lowered to a reference to a
+        // location holding `self`, with an updated location on return if it is mutable.
+        if let Some(receiver) = self.receiver {
+            let (ty, kind) = match receiver.kind {
+                ast::ReceiverKind::Value(ty) => (ty, None),
+                ast::ReceiverKind::Ref(ty) => (ty, Some((BorrowKind::Shared, None))),
+                ast::ReceiverKind::RefMut(ty, post) => (ty, Some((BorrowKind::Mut, Some(post)))),
+            };
+
+            lcx.vars.push_layer();
+            lcx.vars.define("self", Var::Nu);
+            let lowered = ty.clone().lower(lcx);
+            lcx.vars.pop_layer();
+            in_heap.extend(lcx.take_binders());
+
+            let loc = lcx.fresh_location();
+            in_heap.push((loc, lowered.clone()));
+            lcx.vars.define("self", Var::Location(loc));
+            lcx.define_var_struct("self", struct_name(&ty));
+
+            let local = lcx.fresh_local();
+            match kind {
+                None => inputs.push((local, loc)),
+                Some((bk, post)) => {
+                    let region = lcx.fresh_region();
+                    regions.push(region);
+                    let ref_loc = lcx.fresh_location();
+                    in_heap.push((ref_loc, Ty::Ref(bk, Region::Universal(region), loc)));
+                    inputs.push((local, ref_loc));
+
+                    if let Some(post) = post {
+                        // In an explicit postcondition `self` refers to the initial value,
+                        // otherwise the type of the receiver, including its indices, is
+                        // preserved.
+                        let post = match post {
+                            Some(post) => {
+                                let post = post.lower(lcx);
+                                lcx.reject_binders();
+                                post
+                            }
+                            None => lowered,
+                        };
+                        let post_loc = lcx.fresh_location();
+                        let post_ref_loc = lcx.fresh_location();
+                        out_heap.push((post_loc, post));
+                        out_heap.push((
+                            post_ref_loc,
+                            Ty::Ref(bk, Region::Universal(region), post_loc),
+                        ));
+                        outputs.push((local, post_ref_loc));
+                    }
+                }
+            }
+        }
 
         // We then iterate through each of the args and lower each of them.
         for (ident, ty) in args {
+            let strukt = struct_name(&ty).map(str::to_owned);
+
             // We lower the target type
             lcx.vars.push_layer();
             lcx.vars.define(ident.symbol, Var::Nu);
             let ty = ty.lower(lcx);
             lcx.vars.pop_layer();
 
+            // Index binders are bound before the argument, whose type refers to them.
+            in_heap.extend(lcx.take_binders());
+
             // Generate a fresh location which will be used in the input
             // heap
             let loc = lcx.fresh_location();
             lcx.vars.define(ident.symbol, Var::Location(loc));
+            lcx.define_var_struct(ident.symbol, strukt.as_deref());
 
             // We then insert the arg into the inputs and the heap.
             inputs.push((lcx.fresh_local(), loc));
@@ -171,18 +764,27 @@ impl<'src> Lower<'src> for ast::FnDecl<'src> {
 
         let output = lcx.fresh_location();
         out_heap.push((output, out.lower(lcx)));
-
-        // TODO: regions, outputs
-        let regions = vec![];
-        let outputs = vec![];
+        lcx.reject_binders();
+        lcx.pred_params.truncate(pred_params_len);
 
         FnDecl {
+            ty_params: lcx.ty_params.clone(),
+            pred_params,
             in_heap: Heap::from_iter(in_heap),
             inputs,
             out_heap: Heap::from_iter(out_heap),
             output,
             outputs,
             regions,
+            outlives: vec![],
         }
     }
 }
+
+/// The name of the struct `ty` stands for, if it is a bare name.
+fn struct_name<'a>(ty: &ast::Ty<'a>) -> Option<&'a str> {
+    match &ty.kind {
+        ast::TyKind::Param(ident) | ast::TyKind::Indexed(ident, _) => Some(ident.symbol),
+        _ => None,
+    }
+}
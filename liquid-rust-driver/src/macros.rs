@@ -0,0 +1,225 @@
+//! Support for the macros of the `liquid` namespace.
+//!
+//! Loop invariants, loop metrics, assertions and assumptions are written as
+//! `liquid::invariant!(pred)`, `liquid::decreases!(expr)`, `liquid::assert!(pred)` and
+//! `liquid::assume!(pred)` statements. The `liquid` tool namespace
+//! can't define macros, so before expansion we replace each of them with a call to a marker
+//! function receiving the predicate as a string. The call survives until MIR, where the
+//! translation attaches the predicate to the corresponding program point.
+
+use liquid_rust_parser::{parse_pred, ParseErrorKind};
+use rustc_ast::{
+    ast::{Crate, MacCall, StmtKind},
+    mut_visit::{self, MutVisitor},
+    Stmt,
+};
+use rustc_ast_pretty::pprust::tts_to_string;
+use rustc_hir::def_id::DefId;
+use rustc_middle::{
+    mir::{self, interpret::ConstValue},
+    ty::{self, TyCtxt},
+};
+use rustc_parse::{new_parser_from_source_str, parser::ForceCollect};
+use rustc_session::Session;
+use rustc_span::{FileName, Span};
+use std::collections::HashSet;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum LiquidMacro {
+    /// Fixes the invariant of the innermost loop containing it.
+    Invariant,
+    /// Fixes the termination metric of the innermost loop containing it.
+    Decreases,
+    /// Checks that the predicate holds at this point.
+    Assert,
+    /// Trusts that the predicate holds at this point.
+    Assume,
+}
+
+const MACROS: [LiquidMacro; 4] = [
+    LiquidMacro::Invariant,
+    LiquidMacro::Decreases,
+    LiquidMacro::Assert,
+    LiquidMacro::Assume,
+];
+
+impl LiquidMacro {
+    fn name(self) -> &'static str {
+        match self {
+            LiquidMacro::Invariant => "invariant",
+            LiquidMacro::Decreases => "decreases",
+            LiquidMacro::Assert => "assert",
+            LiquidMacro::Assume => "assume",
+        }
+    }
+
+    /// The name of the function marking the position of the macro.
+    fn marker(self) -> String {
+        format!("__liquid_rust_{}", self.name())
+    }
+}
+
+/// Replaces the statements calling a `liquid` macro in the crate by calls to the marker functions
+/// and defines the ones used.
+pub fn expand_macros(sess: &Session, krate: &mut Crate) {
+    let mut expander = MacroExpander {
+        sess,
+        found: vec![],
+    };
+    expander.visit_crate(krate);
+    for mac in expander.found {
+        let source = format!(
+            "#[allow(dead_code)] fn {}(_: &'static str) {{}}",
+            mac.marker()
+        );
+        let item = parse_source(sess, source, |parser| parser.parse_item(ForceCollect::No))
+            .flatten()
+            .expect("the marker function should parse");
+        krate.module.items.push(item);
+    }
+}
+
+/// Returns the macro and its predicate if `terminator` is a call to a marker function.
+pub fn macro_call<'tcx>(
+    tcx: TyCtxt<'tcx>,
+    terminator: &mir::Terminator<'tcx>,
+) -> Option<(LiquidMacro, String)> {
+    let (func, args) = match &terminator.kind {
+        mir::TerminatorKind::Call { func, args, .. } => (func, args),
+        _ => return None,
+    };
+    let mac = match func.constant().map(|c| c.literal.ty.kind()) {
+        Some(ty::TyKind::FnDef(def_id, _)) => marker_of(tcx, *def_id)?,
+        _ => return None,
+    };
+    match args[0].constant().map(|c| c.literal.val) {
+        Some(ty::ConstKind::Value(ConstValue::Slice { data, start, end })) => {
+            let bytes = data.inspect_with_uninit_and_ptr_outside_interpreter(start..end);
+            Some((mac, String::from_utf8(bytes.to_vec()).unwrap()))
+        }
+        _ => bug!("the argument of a marker function should be a string literal"),
+    }
+}
+
+/// Whether `def_id` is a marker function, which is not checked.
+pub fn is_marker(tcx: TyCtxt, def_id: DefId) -> bool {
+    marker_of(tcx, def_id).is_some()
+}
+
+fn marker_of(tcx: TyCtxt, def_id: DefId) -> Option<LiquidMacro> {
+    if !def_id.is_local() {
+        return None;
+    }
+    let name = tcx.opt_item_name(def_id)?;
+    MACROS
+        .iter()
+        .copied()
+        .find(|mac| name.as_str() == mac.marker())
+}
+
+/// Returns the head of the innermost loop containing `bb`, i.e., the closest block dominating
+/// `bb` which is the target of a back edge.
+pub fn loop_head(body: &mir::Body, bb: mir::BasicBlock) -> Option<mir::BasicBlock> {
+    let dominators = body.dominators();
+    let predecessors = body.predecessors();
+    let mut head = bb;
+    loop {
+        if predecessors[head]
+            .iter()
+            .any(|pred| dominators.is_dominated_by(*pred, head))
+        {
+            return Some(head);
+        }
+        let idom = dominators.immediate_dominator(head);
+        if idom == head {
+            return None;
+        }
+        head = idom;
+    }
+}
+
+/// Returns the blocks of the natural loop with head `head`, i.e., the head and the blocks that can
+/// reach a back edge to it without going through it.
+pub fn loop_body(body: &mir::Body, head: mir::BasicBlock) -> HashSet<mir::BasicBlock> {
+    let dominators = body.dominators();
+    let predecessors = body.predecessors();
+    let mut blocks = HashSet::new();
+    blocks.insert(head);
+    let mut stack: Vec<_> = predecessors[head]
+        .iter()
+        .copied()
+        .filter(|pred| dominators.is_dominated_by(*pred, head))
+        .collect();
+    while let Some(bb) = stack.pop() {
+        if blocks.insert(bb) {
+            stack.extend(predecessors[bb].iter().copied());
+        }
+    }
+    blocks
+}
+
+struct MacroExpander<'a> {
+    sess: &'a Session,
+    found: Vec<LiquidMacro>,
+}
+
+impl MutVisitor for MacroExpander<'_> {
+    fn flat_map_stmt(&mut self, mut stmt: Stmt) -> smallvec::SmallVec<[Stmt; 1]> {
+        if let StmtKind::MacCall(mac) = &stmt.kind {
+            if let Some(liquid_mac) = liquid_macro(&mac.mac) {
+                let span = mac.mac.span();
+                let pred = tts_to_string(&mac.mac.args.inner_tokens());
+                if let Err(err) = parse_pred(&pred) {
+                    self.sess.span_err(span, &parse_error_msg(err.kind));
+                }
+                let source = format!("crate::{}({:?})", liquid_mac.marker(), pred);
+                let mut expr = parse_source(self.sess, source, |parser| parser.parse_expr())
+                    .expect("the call to the marker function should parse");
+                SetSpan(span).visit_expr(&mut expr);
+                stmt.kind = StmtKind::Semi(expr);
+                if !self.found.contains(&liquid_mac) {
+                    self.found.push(liquid_mac);
+                }
+            }
+        }
+        mut_visit::noop_flat_map_stmt(stmt, self)
+    }
+}
+
+/// The message reported when the predicate of a `liquid` macro or attribute doesn't parse.
+pub fn parse_error_msg(kind: ParseErrorKind) -> String {
+    match kind {
+        ParseErrorKind::UnexpectedEOF => "Predicate ended unexpectedly.".to_owned(),
+        ParseErrorKind::UnexpectedToken(token) => format!("Unexpected token `{}`.", token),
+        ParseErrorKind::InvalidToken => "Invalid token".to_owned(),
+    }
+}
+
+fn liquid_macro(mac: &MacCall) -> Option<LiquidMacro> {
+    match &mac.path.segments[..] {
+        [namespace, name] if namespace.ident.as_str() == "liquid" => MACROS
+            .iter()
+            .copied()
+            .find(|mac| name.ident.as_str() == mac.name()),
+        _ => None,
+    }
+}
+
+fn parse_source<'a, T>(
+    sess: &'a Session,
+    source: String,
+    parse: impl FnOnce(&mut rustc_parse::parser::Parser<'a>) -> rustc_errors::PResult<'a, T>,
+) -> Option<T> {
+    let name = FileName::macro_expansion_source_code(&source);
+    let mut parser = new_parser_from_source_str(&sess.parse_sess, name, source);
+    parse(&mut parser).map_err(|mut err| err.cancel()).ok()
+}
+
+/// Attributes the generated code to the macro call it replaces.
+struct SetSpan(Span);
+
+impl MutVisitor for SetSpan {
+    fn visit_span(&mut self, span: &mut Span) {
+        *span = self.0;
+    }
+}
@@ -0,0 +1,263 @@
+//! Reflection of pure functions into the refinement logic.
+//!
+//! A function marked with `liquid::pure` is translated into the definition of a measure with the
+//! same name, so it can be used in the predicates of other annotations. The body has to be a
+//! single expression built from the parameters, literals, arithmetic and boolean operators,
+//! conditionals and calls to other pure functions, which guarantees that it has no side effects
+//! and terminates as long as pure functions are not recursive.
+
+use std::{collections::HashMap, iter::FromIterator};
+
+use crate::{translate::Unsupported, visitor::is_pure};
+use liquid_rust_core::{
+    ast::{
+        pred::{self, BinOp, Constant, UnOp},
+        FnDecl, Heap, MeasureDecl, MeasureDef, Pred, Refine, Ty,
+    },
+    names::{Local, Location, MeasureId},
+};
+use rustc_ast::ast::LitKind;
+use rustc_hir::{
+    self as hir,
+    def::{DefKind, Res},
+    def_id::LocalDefId,
+    BinOpKind, ExprKind, HirId, PatKind, QPath,
+};
+use rustc_middle::ty::TyCtxt;
+
+/// Translates the body of the pure function `def_id` into the definition of its measure.
+pub fn reflect(tcx: TyCtxt, def_id: LocalDefId) -> Result<MeasureDef, Unsupported> {
+    let hir_id = tcx.hir().local_def_id_to_hir_id(def_id);
+    let body = tcx.hir().body(tcx.hir().body_owned_by(hir_id));
+
+    let mut params = HashMap::new();
+    for (i, param) in body.params.iter().enumerate() {
+        match param.pat.kind {
+            PatKind::Binding(_, hir_id, _, None) => {
+                params.insert(hir_id, Location::new(i));
+            }
+            _ => {
+                return Err(Unsupported::new(
+                    "Patterns in the parameters of pure functions are not supported.".to_owned(),
+                )
+                .or_span(param.pat.span))
+            }
+        }
+    }
+
+    let body = Reflector {
+        tcx,
+        params: &params,
+    }
+    .reflect_expr(&body.value)?;
+    Ok(MeasureDef {
+        params: (0..params.len()).map(Location::new).collect(),
+        body,
+    })
+}
+
+/// Returns the measures reflecting pure functions that call themselves, directly or through
+/// other pure functions. Their definitions could be unfolded indefinitely.
+pub fn recursive_measures(measures: &HashMap<MeasureId, MeasureDecl>) -> Vec<MeasureId> {
+    let callees = |measure: &MeasureId| {
+        let mut callees = vec![];
+        if let Some(def) = &measures[measure].def {
+            collect_apps(&def.body, &mut callees);
+        }
+        callees
+    };
+    measures
+        .keys()
+        .filter(|measure| {
+            let mut stack = callees(measure);
+            let mut visited = vec![];
+            while let Some(callee) = stack.pop() {
+                if callee == **measure {
+                    return true;
+                }
+                if !visited.contains(&callee) && measures.contains_key(&callee) {
+                    visited.push(callee);
+                    stack.extend(callees(&callee));
+                }
+            }
+            false
+        })
+        .copied()
+        .collect()
+}
+
+/// The signature of a pure function, i.e., its annotation or a trivially refined one if it has
+/// none, strengthened such that the output is the reflected function applied to the inputs.
+pub fn reflected_decl(measure: MeasureId, decl: &MeasureDecl, annot: Option<FnDecl>) -> FnDecl {
+    let mut annot = annot.unwrap_or_else(|| {
+        let n = decl.inputs.len();
+        let in_heap = decl
+            .inputs
+            .iter()
+            .enumerate()
+            .map(|(i, bty)| (Location::new(i), Ty::Refine(*bty, Refine::Pred(Pred::tt()))));
+        FnDecl {
+            ty_params: vec![],
+            pred_params: vec![],
+            regions: vec![],
+            outlives: vec![],
+            in_heap: in_heap.collect(),
+            inputs: (0..n)
+                .map(|i| (Local::new(i + 1), Location::new(i)))
+                .collect(),
+            out_heap: Heap::from_iter(vec![(
+                Location::new(n),
+                Ty::Refine(decl.output, Refine::Pred(Pred::tt())),
+            )]),
+            outputs: vec![],
+            output: Location::new(n),
+        }
+    });
+
+    let app = Pred::App(
+        measure,
+        annot
+            .inputs
+            .iter()
+            .map(|(_, l)| Pred::Place(pred::Place::from(*l)))
+            .collect(),
+    );
+    let eq = Pred::BinaryOp(
+        BinOp::Eq,
+        Box::new(Pred::Place(pred::Place::from(pred::Var::Nu))),
+        Box::new(app),
+    );
+    let out_heap = annot.out_heap.iter().cloned().map(|(l, ty)| match ty {
+        Ty::Refine(bty, Refine::Pred(p)) if l == annot.output => (
+            l,
+            Ty::Refine(
+                bty,
+                Refine::Pred(Pred::BinaryOp(
+                    BinOp::And,
+                    Box::new(p),
+                    Box::new(eq.clone()),
+                )),
+            ),
+        ),
+        _ => (l, ty),
+    });
+    annot.out_heap = out_heap.collect();
+    annot
+}
+
+fn collect_apps(pred: &Pred, apps: &mut Vec<MeasureId>) {
+    match pred {
+        Pred::Constant(_) | Pred::Place(_) => {}
+        Pred::BinaryOp(_, op1, op2) => {
+            collect_apps(op1, apps);
+            collect_apps(op2, apps);
+        }
+        Pred::UnaryOp(_, op) => collect_apps(op, apps),
+        Pred::App(measure, args) => {
+            apps.push(*measure);
+            for arg in args {
+                collect_apps(arg, apps);
+            }
+        }
+        Pred::Ite(p1, p2, p3) => {
+            collect_apps(p1, apps);
+            collect_apps(p2, apps);
+            collect_apps(p3, apps);
+        }
+    }
+}
+
+struct Reflector<'a, 'tcx> {
+    tcx: TyCtxt<'tcx>,
+    params: &'a HashMap<HirId, Location>,
+}
+
+impl Reflector<'_, '_> {
+    fn reflect_expr(&self, expr: &hir::Expr) -> Result<Pred, Unsupported> {
+        let pred = match &expr.kind {
+            ExprKind::Lit(lit) => match lit.node {
+                LitKind::Int(n, _) => Pred::Constant(Constant::Int(n)),
+                LitKind::Bool(b) => Pred::Constant(Constant::Bool(b)),
+                _ => return self.unsupported(expr),
+            },
+            ExprKind::Path(QPath::Resolved(None, path)) => match path.res {
+                Res::Local(hir_id) => match self.params.get(&hir_id) {
+                    Some(l) => Pred::Place(pred::Place::from(*l)),
+                    None => return self.unsupported(expr),
+                },
+                _ => return self.unsupported(expr),
+            },
+            ExprKind::Binary(op, e1, e2) => {
+                let op = match op.node {
+                    BinOpKind::Add => BinOp::Add,
+                    BinOpKind::Sub => BinOp::Sub,
+                    BinOpKind::And => BinOp::And,
+                    BinOpKind::Or => BinOp::Or,
+                    BinOpKind::Eq => BinOp::Eq,
+                    BinOpKind::Ne => BinOp::Neq,
+                    BinOpKind::Lt => BinOp::Lt,
+                    BinOpKind::Le => BinOp::Le,
+                    BinOpKind::Gt => BinOp::Gt,
+                    BinOpKind::Ge => BinOp::Ge,
+                    _ => return self.unsupported(expr),
+                };
+                Pred::BinaryOp(
+                    op,
+                    Box::new(self.reflect_expr(e1)?),
+                    Box::new(self.reflect_expr(e2)?),
+                )
+            }
+            ExprKind::Unary(op, e) => {
+                let op = match op {
+                    hir::UnOp::Not => UnOp::Not,
+                    hir::UnOp::Neg => UnOp::Neg,
+                    hir::UnOp::Deref => return self.unsupported(expr),
+                };
+                Pred::UnaryOp(op, Box::new(self.reflect_expr(e)?))
+            }
+            ExprKind::If(cond, then, Some(else_)) => Pred::Ite(
+                Box::new(self.reflect_expr(cond)?),
+                Box::new(self.reflect_expr(then)?),
+                Box::new(self.reflect_expr(else_)?),
+            ),
+            ExprKind::Block(
+                hir::Block {
+                    stmts: [],
+                    expr: Some(e),
+                    ..
+                },
+                None,
+            )
+            | ExprKind::DropTemps(e) => self.reflect_expr(e)?,
+            ExprKind::Call(func, args) => match &func.kind {
+                ExprKind::Path(QPath::Resolved(None, path)) => match path.res {
+                    Res::Def(DefKind::Fn, def_id)
+                        if def_id.is_local() && is_pure(self.tcx, def_id) =>
+                    {
+                        let args = args
+                            .iter()
+                            .map(|arg| self.reflect_expr(arg))
+                            .collect::<Result<_, _>>()?;
+                        Pred::App(MeasureId::new(def_id.index.as_usize()), args)
+                    }
+                    _ => {
+                        return Err(Unsupported::new(
+                            "Pure functions can only call other pure functions.".to_owned(),
+                        )
+                        .or_span(expr.span))
+                    }
+                },
+                _ => return self.unsupported(expr),
+            },
+            _ => return self.unsupported(expr),
+        };
+        Ok(pred)
+    }
+
+    fn unsupported<T>(&self, expr: &hir::Expr) -> Result<T, Unsupported> {
+        Err(
+            Unsupported::new("Unsupported expression in a pure function.".to_owned())
+                .or_span(expr.span),
+        )
+    }
+}
@@ -1,7 +1,16 @@
 //! Handles the translation from Rust MIR to the CPS IR.
 
-use std::{collections::HashMap, iter::FromIterator};
+use std::{
+    collections::{HashMap, HashSet},
+    iter::FromIterator,
+};
 
+use crate::{
+    builtins::{Builtin, Builtins},
+    lower::{Lower, LowerCtx, Measures, Structs},
+    macros::{loop_body, loop_head, macro_call, LiquidMacro},
+    visitor::{is_panic_free, local_struct_name, metric_annotation},
+};
 use dataflow::ResultsCursor;
 use liquid_rust_core::{ast::*, names::*};
 use rustc_ast::Mutability;
@@ -14,20 +23,21 @@ use rustc_middle::{
         terminator::TerminatorKind,
         PlaceRef,
     },
-    ty::{self, ParamEnv},
+    ty::{self, adjustment::PointerCast, GenericParamDefKind, ParamEnv},
 };
 use rustc_mir::dataflow::{
     self,
-    impls::MaybeUninitializedPlaces,
+    impls::{MaybeLiveLocals, MaybeUninitializedPlaces},
     move_paths::{LookupResult, MoveData},
     Analysis, MoveDataParamEnv,
 };
+use rustc_span::{sym, Span, Symbol};
 use rustc_target::abi;
 
 // TODO: This is ugly as hell, but the MoveDataParamEnv struct fields
 // are private, and we want to reuse the MIR dataflow analysis
 // that the compiler provides us
-struct MPDE<'tcx> {
+struct Mpde<'tcx> {
     pub move_data: MoveData<'tcx>,
     pub param_env: ParamEnv<'tcx>,
 }
@@ -36,37 +46,61 @@ fn create_mpde<'tcx>(
     move_data: MoveData<'tcx>,
     param_env: ParamEnv<'tcx>,
 ) -> MoveDataParamEnv<'tcx> {
-    let res = MPDE {
+    let res = Mpde {
         move_data,
         param_env,
     };
 
-    unsafe { std::mem::transmute::<MPDE<'tcx>, MoveDataParamEnv<'tcx>>(res) }
+    unsafe { std::mem::transmute::<Mpde<'tcx>, MoveDataParamEnv<'tcx>>(res) }
 }
 
-fn translate_statement(stmt: &mir::Statement) -> Statement<()> {
-    match &stmt.kind {
-        mir::StatementKind::Assign(pr) => {
-            let place = translate_place(&pr.0);
-            let rval = translate_rvalue(&pr.1);
+/// A construct in the MIR of a function that the translation doesn't support. The function is
+/// reported and left unchecked instead.
+pub struct Unsupported {
+    pub span: Option<Span>,
+    pub msg: String,
+}
 
-            Statement {
-                kind: StatementKind::Assign(place, rval),
-                source_info: (),
-            }
+impl Unsupported {
+    pub fn new(msg: String) -> Self {
+        Unsupported { span: None, msg }
+    }
+
+    /// Attributes the construct to `span` unless it was attributed to a more precise one.
+    pub fn or_span(self, span: Span) -> Self {
+        Unsupported {
+            span: self.span.or(Some(span)),
+            ..self
+        }
+    }
+}
+
+type TransResult<T> = Result<T, Unsupported>;
+
+fn unsupported<T>(msg: String) -> TransResult<T> {
+    Err(Unsupported::new(msg))
+}
+
+fn translate_statement(stmt: &mir::Statement) -> TransResult<Statement<Span>> {
+    let kind = match &stmt.kind {
+        mir::StatementKind::Assign(pr) => {
+            let place = translate_place(&pr.0)?;
+            let rval = translate_rvalue(&pr.1)?;
+            StatementKind::Assign(place, rval)
         }
         mir::StatementKind::StorageDead(..)
         | mir::StatementKind::StorageLive(..)
-        | mir::StatementKind::Nop => Statement {
-            kind: StatementKind::Nop,
-            source_info: (),
-        },
-        _ => todo!(),
-    }
+        | mir::StatementKind::Nop => StatementKind::Nop,
+        _ => return unsupported(format!("Unsupported statement `{:?}`.", stmt)),
+    };
+    Ok(Statement {
+        kind,
+        source_info: stmt.source_info.span,
+    })
 }
 
 /// Translates an `mir::Place` to a CPS IR Place.
-fn translate_place(from: &mir::Place) -> Place {
+fn translate_place(from: &mir::Place) -> TransResult<Place> {
     let base = Local::new(from.local.as_usize());
     let mut projs = vec![];
 
@@ -74,82 +108,97 @@ fn translate_place(from: &mir::Place) -> Place {
         match proj {
             mir::ProjectionElem::Field(f, _ty) => projs.push(Proj::Field(f.as_usize())),
             mir::ProjectionElem::Deref => projs.push(Proj::Deref),
-            _ => todo!(),
+            _ => return unsupported(format!("Unsupported place `{:?}`.", from)),
         };
     }
 
-    Place { base, projs }
+    Ok(Place { base, projs })
 }
 
-fn translate_op(from: &mir::Operand) -> Operand {
+fn translate_op(from: &mir::Operand) -> TransResult<Operand> {
     match from {
-        mir::Operand::Copy(p) => Operand::Copy(translate_place(p)),
-        mir::Operand::Move(p) => Operand::Move(translate_place(p)),
+        mir::Operand::Copy(p) => Ok(Operand::Copy(translate_place(p)?)),
+        mir::Operand::Move(p) => Ok(Operand::Move(translate_place(p)?)),
         mir::Operand::Constant(bc) => translate_const(bc),
     }
 }
 
 // Adapted from
 // https://github.com/rust-lang/rust/blob/master/compiler/rustc_middle/src/ty/print/pretty.rs
-fn translate_const(from: &mir::Constant) -> Operand {
-    match from.literal.val {
+fn translate_const(from: &mir::Constant) -> TransResult<Operand> {
+    let c = match from.literal.val {
         ty::ConstKind::Value(ConstValue::Scalar(s)) => {
             match (s, from.literal.ty.kind()) {
                 // Unit
-                (Scalar::Int(s), _) if s.size() == abi::Size::ZERO => {
-                    Operand::Constant(Constant::Unit)
-                }
+                (Scalar::Int(s), _) if s.size() == abi::Size::ZERO => Constant::Unit,
                 // Bool
-                (Scalar::Int(s), ty::Bool) if s == ty::ScalarInt::FALSE => {
-                    Operand::Constant(Constant::Bool(false))
-                }
-                (Scalar::Int(s), ty::Bool) if s == ty::ScalarInt::TRUE => {
-                    Operand::Constant(Constant::Bool(true))
-                }
+                (Scalar::Int(s), ty::Bool) if s == ty::ScalarInt::FALSE => Constant::Bool(false),
+                (Scalar::Int(s), ty::Bool) if s == ty::ScalarInt::TRUE => Constant::Bool(true),
                 // TODO: Floats, when support is added
                 // Int
                 (Scalar::Int(s), ty::Uint(_) | ty::Int(_)) => {
-                    Operand::Constant(Constant::Int(s.to_bits(s.size()).unwrap()))
+                    Constant::Int(s.to_bits(s.size()).unwrap())
                 }
                 // TODO: Signed ints, when support is added
                 // TODO: Chars, when support is added
-                _ => todo!("{:?}", from),
+                _ => return unsupported(format!("Unsupported constant `{:?}`.", from)),
             }
         }
-        _ => todo!(),
-    }
+        _ => return unsupported(format!("Unsupported constant `{:?}`.", from)),
+    };
+    Ok(Operand::Constant(c))
 }
 
-fn translate_rvalue(from: &mir::Rvalue) -> Rvalue {
-    match from {
-        mir::Rvalue::Use(op) => Rvalue::Use(translate_op(op)),
+fn translate_rvalue(from: &mir::Rvalue) -> TransResult<Rvalue> {
+    let rvalue = match from {
+        mir::Rvalue::Use(op) => Rvalue::Use(translate_op(op)?),
         mir::Rvalue::BinaryOp(bin_op, op1, op2) => Rvalue::BinaryOp(
-            translate_bin_op(*bin_op),
-            translate_op(op1),
-            translate_op(op2),
+            translate_bin_op(*bin_op)?,
+            translate_op(op1)?,
+            translate_op(op2)?,
         ),
         mir::Rvalue::CheckedBinaryOp(bin_op, op1, op2) => Rvalue::CheckedBinaryOp(
-            translate_bin_op(*bin_op),
-            translate_op(op1),
-            translate_op(op2),
+            translate_bin_op(*bin_op)?,
+            translate_op(op1)?,
+            translate_op(op2)?,
         ),
+        mir::Rvalue::Ref(
+            _,
+            mir::BorrowKind::Mut {
+                allow_two_phase_borrow: true,
+            },
+            place,
+        ) => Rvalue::TwoPhaseRef(translate_place(place)?),
         mir::Rvalue::Ref(_, bk, place) => {
             let bk = match bk {
                 mir::BorrowKind::Mut { .. } => BorrowKind::Mut,
                 mir::BorrowKind::Shared => BorrowKind::Shared,
-                _ => todo!(),
+                _ => return unsupported(format!("Unsupported borrow `{:?}`.", from)),
             };
-            Rvalue::Ref(bk, translate_place(place))
+            Rvalue::Ref(bk, translate_place(place)?)
         }
         mir::Rvalue::UnaryOp(un_op, op) => {
-            Rvalue::UnaryOp(translate_un_op(*un_op), translate_op(op))
+            Rvalue::UnaryOp(translate_un_op(*un_op), translate_op(op)?)
         }
-        _ => todo!(),
-    }
+        mir::Rvalue::Cast(mir::CastKind::Pointer(PointerCast::ReifyFnPointer), op, _) => {
+            match op.constant().map(|c| c.literal.ty.kind()) {
+                Some(ty::TyKind::FnDef(def_id, _)) if def_id.is_local() => {
+                    Rvalue::FnPtr(FnId::new(def_id.expect_local().index()))
+                }
+                _ => {
+                    return unsupported(
+                        "Pointers to non-local functions are not supported yet.".to_owned(),
+                    )
+                }
+            }
+        }
+        _ => return unsupported(format!("Unsupported rvalue `{:?}`.", from)),
+    };
+    Ok(rvalue)
 }
 
-fn translate_bin_op(bin_op: mir::BinOp) -> BinOp {
-    match bin_op {
+fn translate_bin_op(bin_op: mir::BinOp) -> TransResult<BinOp> {
+    let bin_op = match bin_op {
         mir::BinOp::Add => BinOp::Add,
         mir::BinOp::Sub => BinOp::Sub,
         mir::BinOp::Lt => BinOp::Lt,
@@ -157,8 +206,9 @@ fn translate_bin_op(bin_op: mir::BinOp) -> BinOp {
         mir::BinOp::Eq => BinOp::Eq,
         mir::BinOp::Ge => BinOp::Ge,
         mir::BinOp::Gt => BinOp::Gt,
-        _ => todo!(),
-    }
+        _ => return unsupported(format!("Unsupported binary operator `{:?}`.", bin_op)),
+    };
+    Ok(bin_op)
 }
 
 fn translate_un_op(un_op: mir::UnOp) -> UnOp {
@@ -168,16 +218,16 @@ fn translate_un_op(un_op: mir::UnOp) -> UnOp {
     }
 }
 
-fn get_base_ty(t: ty::Ty) -> BaseTy {
+fn get_base_ty(t: ty::Ty) -> TransResult<BaseTy> {
     match t.kind() {
-        ty::TyKind::Bool => BaseTy::Bool,
-        ty::TyKind::Int(_) | ty::TyKind::Uint(_) => BaseTy::Int,
-        _ => todo!(),
+        ty::TyKind::Bool => Ok(BaseTy::Bool),
+        ty::TyKind::Int(_) | ty::TyKind::Uint(_) => Ok(BaseTy::Int),
+        _ => unsupported(format!("Unsupported type `{}`.", t)),
     }
 }
 
 /// Creates a `TypeLayout` based on a Rust `TyKind`.
-fn get_layout(t: ty::Ty) -> TypeLayout {
+fn get_layout<'tcx>(tcx: ty::TyCtxt<'tcx>, structs: &Structs, t: ty::Ty<'tcx>) -> TypeLayout {
     // Get the Rust type for ints, bools, tuples (of ints, bools, tuples)
     // Do case analysis, generate TypeLayout based on that.
     // Give up if not supported type
@@ -199,8 +249,25 @@ fn get_layout(t: ty::Ty) -> TypeLayout {
     //     _ => todo!(),
     // }
     match t.kind() {
-        ty::TyKind::Tuple(_) => {
-            tuple_layout_or_block(t.tuple_fields().map(|c| get_layout(c)).collect())
+        ty::TyKind::Tuple(_) => tuple_layout_or_block(
+            t.tuple_fields()
+                .map(|c| get_layout(tcx, structs, c))
+                .collect(),
+        ),
+        ty::TyKind::Adt(adt, substs) if is_local_struct(adt) => {
+            let fields = struct_fields(tcx, adt, substs)
+                .map(|c| get_layout(tcx, structs, c))
+                .collect();
+            match struct_invariant(tcx, structs, adt) {
+                Some(invariant) if !adt.non_enum_variant().fields.is_empty() => {
+                    TypeLayout::Struct(fields, invariant)
+                }
+                _ => tuple_layout_or_block(fields),
+            }
+        }
+        // A closure is represented by its captured state.
+        ty::TyKind::Closure(_, substs) => {
+            get_layout(tcx, structs, substs.as_closure().tupled_upvars_ty())
         }
         _ => TypeLayout::Block(1),
     }
@@ -211,9 +278,30 @@ fn get_layout(t: ty::Ty) -> TypeLayout {
 pub struct Transformer<'low, 'tcx> {
     tcx: ty::TyCtxt<'tcx>,
     annots: &'low mut HashMap<DefId, FnDecl>,
+    builtins: &'low mut Builtins<'tcx>,
+    measures: &'low Measures,
+    structs: &'low Structs,
     body: &'low mir::Body<'tcx>,
+    /// The refined types of function values that can't be derived from their Rust type, i.e.,
+    /// type parameters and function pointers refined with a function type by the annotation.
+    fn_tys: HashMap<ty::Ty<'tcx>, Ty>,
+    /// The invariants of the loops in the body indexed by their head, together with the span of
+    /// the first one.
+    invariants: HashMap<mir::BasicBlock, (String, Span)>,
+    /// The termination metrics of the loops in the body indexed by their head.
+    metrics: HashMap<mir::BasicBlock, String>,
+    /// The head of the innermost loop with a metric containing each block, other than the head
+    /// itself. The continuations of these blocks are defined inside the one of the head, where
+    /// the locations at the start of the iteration are in scope.
+    loop_scopes: HashMap<mir::BasicBlock, mir::BasicBlock>,
+    /// Whether reaching a panic must be proven impossible.
+    panic_free: bool,
     move_data: MoveData<'tcx>,
     maybe_uninitialized_cursor: ResultsCursor<'low, 'tcx, MaybeUninitializedPlaces<'low, 'tcx>>,
+    maybe_live_cursor: ResultsCursor<'low, 'tcx, MaybeLiveLocals>,
+    /// The locals that are borrowed, directly or by reborrowing through them, somewhere in the
+    /// body. Their loans can't be ended when they die because they may be borrowed from.
+    borrowed_locals: HashSet<mir::Local>,
     names: NameProducer,
 }
 
@@ -221,8 +309,11 @@ impl<'low, 'tcx> Transformer<'low, 'tcx> {
     pub fn translate(
         tcx: ty::TyCtxt<'tcx>,
         annots: &mut HashMap<DefId, FnDecl>,
+        builtins: &mut Builtins<'tcx>,
+        measures: &Measures,
+        structs: &Structs,
         body: &mir::Body<'tcx>,
-    ) -> FnDef<()> {
+    ) -> TransResult<FnDef<Span>> {
         let param_env = tcx.param_env(body.source.def_id());
         let mdpe_move_data = MoveData::gather_moves(body, tcx, param_env).unwrap_or_else(|x| x.0);
         let move_data = MoveData::gather_moves(body, tcx, param_env).unwrap_or_else(|x| x.0);
@@ -232,17 +323,238 @@ impl<'low, 'tcx> Transformer<'low, 'tcx> {
             .into_engine(tcx, body)
             .iterate_to_fixpoint()
             .into_results_cursor(body);
+        let maybe_live_cursor = MaybeLiveLocals
+            .into_engine(tcx, body)
+            .iterate_to_fixpoint()
+            .into_results_cursor(body);
         let mut transformer = Transformer {
             tcx,
             annots,
+            builtins,
+            measures,
+            structs,
             body,
             maybe_uninitialized_cursor,
+            maybe_live_cursor,
+            borrowed_locals: borrowed_locals(body),
             move_data,
             names: NameProducer::new(body),
+            fn_tys: HashMap::new(),
+            invariants: HashMap::new(),
+            metrics: HashMap::new(),
+            loop_scopes: HashMap::new(),
+            panic_free: is_panic_free(tcx, body.source.def_id()),
         };
+        transformer.collect_fn_tys()?;
+        transformer.check_pops()?;
+        transformer.collect_loop_annotations();
+        if transformer.panic_free {
+            transformer.warn_unchecked_overflow();
+        }
+        transformer.collect_loop_scopes();
         transformer.translate_body()
     }
 
+    /// Collects the refined types of the function values in the body. Arguments take their type
+    /// from the annotation and the remaining function pointers get a signature to be inferred.
+    fn collect_fn_tys(&mut self) -> TransResult<()> {
+        let def_id = self.body.source.def_id();
+        if let Some(decl) = self.annots.get(&def_id) {
+            let skip = self.tcx.is_closure(def_id) as usize;
+            for ((_, l), lix) in decl.inputs.iter().zip(self.body.args_iter().skip(skip)) {
+                let rust_ty = self.body.local_decls[lix].ty;
+                if !matches!(rust_ty.kind(), ty::TyKind::Param(_) | ty::TyKind::FnPtr(_)) {
+                    continue;
+                }
+                if let Some((_, ty @ Ty::Fn(_))) = decl.in_heap.iter().find(|(l2, _)| l == l2) {
+                    self.fn_tys.insert(rust_ty, ty.clone());
+                }
+            }
+        }
+        for local_decl in self.body.local_decls.iter() {
+            if let ty::TyKind::FnPtr(sig) = local_decl.ty.kind() {
+                if self.fn_tys.get(&local_decl.ty).is_none() {
+                    let sig = sig.skip_binder();
+                    let decl = Ty::Fn(
+                        box self
+                            .get_holy_fn_decl(sig.inputs(), sig.output())
+                            .map_err(|err| err.or_span(local_decl.source_info.span))?,
+                    );
+                    self.fn_tys.insert(local_decl.ty, decl);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that the result of every call to `Vec::pop` is immediately unwrapped and that only
+    /// those options are unwrapped. Options are represented by their payload, so `pop` requires
+    /// the vector to be non-empty, which is only sound if a `None` would make the program panic.
+    fn check_pops(&self) -> TransResult<()> {
+        let mut unwraps = HashSet::new();
+        for bbd in self.body.basic_blocks() {
+            let terminator = bbd.terminator();
+            if self.call_to(terminator) != Some(Builtin::VecPop) {
+                continue;
+            }
+            let unwrap = match &terminator.kind {
+                TerminatorKind::Call {
+                    destination: Some((place, target)),
+                    ..
+                } if place.projection.is_empty() => {
+                    let target_data = &self.body[*target];
+                    let only_storage = target_data.statements.iter().all(|stmt| {
+                        matches!(
+                            stmt.kind,
+                            mir::StatementKind::StorageLive(_) | mir::StatementKind::StorageDead(_)
+                        )
+                    });
+                    let unwrapped = match &target_data.terminator().kind {
+                        TerminatorKind::Call { args, .. } => {
+                            matches!(args.as_slice(), [mir::Operand::Move(arg)] if arg == place)
+                        }
+                        _ => false,
+                    };
+                    let is_unwrap =
+                        self.call_to(target_data.terminator()) == Some(Builtin::OptionUnwrap);
+                    if only_storage && unwrapped && is_unwrap {
+                        Some(*target)
+                    } else {
+                        None
+                    }
+                }
+                _ => None,
+            };
+            match unwrap {
+                Some(bb) => {
+                    unwraps.insert(bb);
+                }
+                None => {
+                    return Err(Unsupported::new(
+                        "`Vec::pop` is only supported when its result is immediately unwrapped."
+                            .to_owned(),
+                    )
+                    .or_span(terminator.source_info.span))
+                }
+            }
+        }
+        for (bb, bbd) in self.body.basic_blocks().iter_enumerated() {
+            let terminator = bbd.terminator();
+            if self.call_to(terminator) == Some(Builtin::OptionUnwrap) && !unwraps.contains(&bb) {
+                return Err(Unsupported::new(
+                    "`Option::unwrap` is only supported on the result of `Vec::pop`.".to_owned(),
+                )
+                .or_span(terminator.source_info.span));
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the builtin called by a terminator, if any.
+    fn call_to(&self, terminator: &mir::Terminator<'tcx>) -> Option<Builtin> {
+        match &terminator.kind {
+            TerminatorKind::Call { func, .. } => match func.constant()?.literal.ty.kind() {
+                ty::TyKind::FnDef(def_id, substs) => {
+                    Builtin::resolve(self.tcx, *def_id, substs).map(|(builtin, _)| builtin)
+                }
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// Warns that the overflow checks of a panic-free function are assumed to pass.
+    fn warn_unchecked_overflow(&self) {
+        let span = self.body.basic_blocks().iter().find_map(|bbd| {
+            let terminator = bbd.terminator();
+            match &terminator.kind {
+                TerminatorKind::Assert {
+                    msg: mir::AssertKind::Overflow(..) | mir::AssertKind::OverflowNeg(_),
+                    ..
+                } => Some(terminator.source_info.span),
+                _ => None,
+            }
+        });
+        if let Some(span) = span {
+            self.tcx
+                .sess
+                .struct_span_warn(span, "Overflows are not checked in panic-free functions.")
+                .note("integers are unbounded in the logic")
+                .emit();
+        }
+    }
+
+    /// Attaches the invariants and metrics annotated inside loops to the head of the innermost
+    /// loop containing them. Several invariants of the same loop are conjoined.
+    fn collect_loop_annotations(&mut self) {
+        for (bb, bbd) in self.body.basic_blocks().iter_enumerated() {
+            let terminator = bbd.terminator();
+            let span = terminator.source_info.span;
+            match macro_call(self.tcx, terminator) {
+                Some((LiquidMacro::Invariant, pred)) => {
+                    let head = loop_head(self.body, bb).unwrap_or_else(|| {
+                        self.tcx
+                            .sess
+                            .span_fatal(span, "Invariant outside of a loop.")
+                    });
+                    self.invariants
+                        .entry(head)
+                        .and_modify(|(invariant, _)| {
+                            *invariant = format!("({}) && ({})", invariant, pred)
+                        })
+                        .or_insert((pred, span));
+                }
+                Some((LiquidMacro::Decreases, metric)) => {
+                    let head = loop_head(self.body, bb).unwrap_or_else(|| {
+                        self.tcx.sess.span_fatal(span, "Metric outside of a loop.")
+                    });
+                    if self.metrics.insert(head, metric).is_some() {
+                        self.tcx
+                            .sess
+                            .span_fatal(span, "Loop with more than one metric.");
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Assigns every block in the body of a loop with a metric to the innermost such loop.
+    fn collect_loop_scopes(&mut self) {
+        let bodies: Vec<(mir::BasicBlock, HashSet<mir::BasicBlock>)> = self
+            .metrics
+            .keys()
+            .map(|head| (*head, loop_body(self.body, *head)))
+            .collect();
+        for bb in self.body.basic_blocks().indices() {
+            let scope = bodies
+                .iter()
+                .filter(|(head, blocks)| *head != bb && blocks.contains(&bb))
+                .min_by_key(|(_, blocks)| blocks.len());
+            if let Some((head, _)) = scope {
+                self.loop_scopes.insert(bb, *head);
+            }
+        }
+    }
+
+    /// Translates the blocks whose innermost loop with a metric has head `scope`, or that are not
+    /// in any such loop if `scope` is `None`.
+    fn translate_scope(
+        &mut self,
+        scope: Option<mir::BasicBlock>,
+    ) -> TransResult<Vec<ContDef<Span>>> {
+        let blocks: Vec<_> = self
+            .body
+            .basic_blocks()
+            .indices()
+            .filter(|bb| self.loop_scopes.get(bb).copied() == scope)
+            .collect();
+        blocks
+            .into_iter()
+            .map(|bb| self.translate_basic_block(bb))
+            .collect()
+    }
+
     /// Generates a fresh variable with a certain prefix.
     fn fresh_local(&mut self) -> Local {
         self.names.fresh_local()
@@ -252,22 +564,145 @@ impl<'low, 'tcx> Transformer<'low, 'tcx> {
         self.names.fresh_location()
     }
 
-    /// Returns a `Ty` where all the refinements should be inferred
-    fn get_holy_type(&mut self, t: ty::Ty<'tcx>) -> Ty {
-        match t.kind() {
+    /// Returns a `Ty` where all the refinements should be inferred. The contents of boxes are
+    /// allocated in `heap`.
+    fn get_holy_type(
+        &mut self,
+        t: ty::Ty<'tcx>,
+        heap: &mut Vec<(Location, Ty)>,
+    ) -> TransResult<Ty> {
+        if let Some(ty) = self.fn_tys.get(&t) {
+            return Ok(ty.clone());
+        }
+        let ty = match t.kind() {
             ty::TyKind::Tuple(substs) if !substs.is_empty() => Ty::Tuple(
                 t.tuple_fields()
                     .enumerate()
-                    .map(|(i, f)| (Field::new(i), self.get_holy_type(f)))
-                    .collect(),
+                    .map(|(i, f)| Ok((Field::new(i), self.get_holy_type(f, heap)?)))
+                    .collect::<TransResult<_>>()?,
             ),
             ty::TyKind::Tuple(_) => Ty::unit(),
-            _ => Ty::Refine(get_base_ty(t), Refine::Infer),
+            ty::TyKind::Adt(adt, substs) if self.tcx.is_diagnostic_item(sym::vec_type, adt.did) => {
+                Ty::Vec(
+                    box self.get_holy_type(substs.type_at(0), heap)?,
+                    Refine::Infer,
+                )
+            }
+            ty::TyKind::Adt(adt, substs) if adt.is_box() => {
+                let ty = self.get_holy_type(substs.type_at(0), heap)?;
+                let l = self.fresh_location();
+                heap.push((l, ty));
+                Ty::OwnRef(l)
+            }
+            ty::TyKind::Adt(adt, substs) if is_local_struct(adt) => {
+                let fields = struct_fields(self.tcx, adt, substs)
+                    .enumerate()
+                    .map(|(i, f)| Ok((Field::new(i), self.get_holy_type(f, heap)?)))
+                    .collect::<TransResult<_>>()?;
+                mk_struct(fields, struct_invariant(self.tcx, self.structs, adt))
+            }
+            ty::TyKind::Closure(_, substs) => {
+                self.get_holy_type(substs.as_closure().tupled_upvars_ty(), heap)?
+            }
+            ty::TyKind::Param(param) => Ty::Param(lower_ty_param(*param)),
+            _ => Ty::Refine(get_base_ty(t)?, Refine::Infer),
+        };
+        Ok(ty)
+    }
+
+    /// Returns a function signature where all the refinements should be inferred.
+    fn get_holy_fn_decl(
+        &mut self,
+        inputs: &[ty::Ty<'tcx>],
+        output: ty::Ty<'tcx>,
+    ) -> TransResult<FnDecl> {
+        let mut in_heap = vec![];
+        let mut args = vec![];
+        for (i, input) in inputs.iter().enumerate() {
+            let ty = self.get_holy_type(input, &mut in_heap)?;
+            let l = self.fresh_location();
+            in_heap.push((l, ty));
+            args.push((Local::new(i), l));
         }
+        let mut out_heap = vec![];
+        let output_loc = self.fresh_location();
+        let ty = self.get_holy_type(output, &mut out_heap)?;
+        out_heap.push((output_loc, ty));
+        Ok(FnDecl {
+            ty_params: vec![],
+            pred_params: vec![],
+            regions: vec![],
+            outlives: vec![],
+            in_heap: Heap::from_iter(in_heap),
+            inputs: args,
+            out_heap: Heap::from_iter(out_heap),
+            outputs: vec![],
+            output: output_loc,
+        })
     }
 
+    /// Adds the captured state of a closure as the first input of its signature, which only
+    /// mentions the arguments of the closure. Depending on the kind of the closure, the state is
+    /// received by value or through a reference. The state is added at the end of the input heap,
+    /// so the refinements of the arguments can't depend on it.
+    fn add_closure_env(&mut self, decl: &mut FnDecl, env: Local) -> TransResult<()> {
+        let env_ty = self.body.local_decls[mir::Local::new(env.as_usize())].ty;
+        let mut next = decl
+            .in_heap
+            .iter()
+            .chain(decl.out_heap.iter())
+            .map(|(l, _)| l.as_usize() + 1)
+            .max()
+            .unwrap_or(0);
+        let mut fresh_location = || {
+            next += 1;
+            Location::new(next - 1)
+        };
+
+        let (state_ty, bk) = match env_ty.kind() {
+            ty::TyKind::Ref(_, ty, Mutability::Not) => (ty, Some(BorrowKind::Shared)),
+            ty::TyKind::Ref(_, ty, Mutability::Mut) => (ty, Some(BorrowKind::Mut)),
+            _ => (&env_ty, None),
+        };
+        let upvars = match state_ty.kind() {
+            ty::TyKind::Closure(_, substs) => substs.as_closure().upvar_tys(),
+            _ => bug!("unexpected closure environment `{}`", env_ty),
+        };
+        let fields: Vec<_> = upvars
+            .enumerate()
+            .map(|(i, ty)| {
+                if ty.is_ref() || ty.is_box() {
+                    return unsupported(
+                        "Closures capturing references or boxes are not supported yet.".to_owned(),
+                    );
+                }
+                Ok((Field::new(i), self.get_holy_type(ty, &mut vec![])?))
+            })
+            .collect::<TransResult<_>>()?;
+        let state = if fields.is_empty() {
+            Ty::unit()
+        } else {
+            Ty::Tuple(fields)
+        };
+
+        let state_loc = fresh_location();
+        let mut heap = vec![(state_loc, state)];
+        let env_loc = match bk {
+            Some(bk) => {
+                let region = UniversalRegion::new(decl.regions.len());
+                decl.regions.push(region);
+                let l = fresh_location();
+                heap.push((l, Ty::Ref(bk, Region::Universal(region), state_loc)));
+                l
+            }
+            None => state_loc,
+        };
+        decl.in_heap = decl.in_heap.iter().cloned().chain(heap).collect();
+        decl.inputs.insert(0, (env, env_loc));
+        Ok(())
+    }
     /// Translates an MIR function body to a CPS IR `FnDef`.
-    pub fn translate_body(&mut self) -> FnDef<()> {
+    pub fn translate_body(&mut self) -> TransResult<FnDef<Span>> {
         // We then generate a jump instruction to jump to the continuation
         // corresponding to the first/root basic block, bb0.
         let mut nb = FnBody::Jump {
@@ -276,15 +711,8 @@ impl<'low, 'tcx> Transformer<'low, 'tcx> {
         };
 
         // Translate every basic block into a continuation definition and define them all
-        // to be mutually recursive.
-        nb = FnBody::LetCont(
-            self.body
-                .basic_blocks()
-                .indices()
-                .map(|bb| self.translate_basic_block(bb))
-                .collect(),
-            box nb,
-        );
+        // to be mutually recursive, except for the bodies of loops with a metric.
+        nb = FnBody::LetCont(self.translate_scope(None)?, box nb);
 
         // We finish by taking care of the let bindings - let binding all of the
         // locals in our MIR function body.
@@ -298,16 +726,31 @@ impl<'low, 'tcx> Transformer<'low, 'tcx> {
             }
 
             let sym = Local::new(ix.as_usize());
+            // Closures without captured state are never initialized in MIR, so we initialize
+            // them with a unit value.
+            if is_stateless_closure(decl.ty) {
+                let s = Statement {
+                    kind: StatementKind::Assign(
+                        Place::from(sym),
+                        Rvalue::Use(Operand::Constant(Constant::Unit)),
+                    ),
+                    source_info: decl.source_info.span,
+                };
+                nb = FnBody::Seq(s, Box::new(nb));
+            }
             let s = Statement {
-                kind: StatementKind::Let(sym, get_layout(decl.ty)),
-                source_info: (),
+                kind: StatementKind::Let(sym, get_layout(self.tcx, self.structs, decl.ty)),
+                source_info: decl.source_info.span,
             };
             nb = FnBody::Seq(s, Box::new(nb));
         }
 
+        // The captured state of a closure is received through its first argument.
+        let is_closure = self.tcx.is_closure(self.body.source.def_id());
+
         // For our function type, if we have a provided function type annotation,
         // we use that. Otherwise, we fall back to generating holy types etc.
-        if let Some(ty) = self.annots.remove(&self.body.source.def_id()) {
+        if let Some(mut ty) = self.annots.remove(&self.body.source.def_id()) {
             let mut params = vec![];
 
             for lix in self.body.args_iter() {
@@ -316,14 +759,30 @@ impl<'low, 'tcx> Transformer<'low, 'tcx> {
                 params.push(arg);
             }
 
+            // The annotation doesn't know the name of the arguments in the body, so we rename
+            // the inputs and outputs to match the parameters.
+            let mut renaming = HashMap::new();
+            for ((local, _), param) in ty.inputs.iter_mut().zip(&params[is_closure as usize..]) {
+                renaming.insert(*local, *param);
+                *local = *param;
+            }
+            for (local, _) in &mut ty.outputs {
+                *local = renaming[local];
+            }
+            if is_closure {
+                self.add_closure_env(&mut ty, params[0])?;
+            }
+            let decreases = self.fn_metric(&ty);
+
             // TODO: Different out_heap than input heap?
-            FnDef {
+            Ok(FnDef {
                 // name: Symbol::intern(self.tcx.def_path_str(source.def_id()).as_str()),
                 ty,
                 params,
                 ret: self.retk(),
                 body: nb,
-            }
+                decreases,
+            })
         } else {
             let mut inputs = vec![];
             let mut params = vec![];
@@ -333,8 +792,14 @@ impl<'low, 'tcx> Transformer<'low, 'tcx> {
                 let decl = &self.body.local_decls[lix];
 
                 let arg = Local::new(lix.index());
+                if is_closure && params.is_empty() {
+                    params.push(arg);
+                    continue;
+                }
                 let loc = self.fresh_location();
-                let ty = self.get_holy_type(decl.ty);
+                let ty = self
+                    .get_holy_type(decl.ty, &mut in_heap)
+                    .map_err(|err| err.or_span(decl.source_info.span))?;
 
                 params.push(arg);
                 inputs.push((arg, loc));
@@ -345,46 +810,94 @@ impl<'low, 'tcx> Transformer<'low, 'tcx> {
             // our return type
             let mut out_heap = vec![];
             let output = self.fresh_location();
-            let out_ty = self.get_holy_type(self.body.return_ty());
+            let out_ty = self
+                .get_holy_type(self.body.return_ty(), &mut out_heap)
+                .map_err(|err| err.or_span(self.body.span))?;
             out_heap.push((output, out_ty));
 
             // TODO: regions, outputs
             let regions = vec![];
             let outputs = vec![];
-
-            let fn_ty = FnDecl {
+            let ty_params = generic_ty_params(self.tcx, self.body.source.def_id())
+                .into_iter()
+                .map(|(_, param)| param)
+                .collect();
+
+            let mut fn_ty = FnDecl {
+                ty_params,
+                pred_params: vec![],
                 regions,
+                outlives: vec![],
                 in_heap: Heap::from_iter(in_heap),
                 inputs,
                 out_heap: Heap::from_iter(out_heap),
                 outputs,
                 output,
             };
+            if is_closure {
+                self.add_closure_env(&mut fn_ty, params[0])?;
+            }
+            let decreases = self.fn_metric(&fn_ty);
 
             // TODO: Different out_heap than input heap?
-            FnDef {
+            Ok(FnDef {
                 // name: Symbol::intern(self.tcx.def_path_str(source.def_id()).as_str()),
                 ty: fn_ty,
                 params,
                 ret: self.retk(),
                 body: nb,
-            }
+                decreases,
+            })
         }
     }
 
-    fn translate_basic_block(&mut self, bb: mir::BasicBlock) -> ContDef<()> {
+    /// Returns the termination metric of the function over the locations of its inputs.
+    fn fn_metric(&self, decl: &FnDecl) -> Option<Pred> {
+        metric_annotation(self.tcx, self.body.source.def_id())
+            .map(|metric| self.lower_pred_at(&metric, &decl.inputs))
+    }
+
+    fn translate_basic_block(&mut self, bb: mir::BasicBlock) -> TransResult<ContDef<Span>> {
         let bbd = &self.body.basic_blocks()[bb];
 
         // We generate a statement for the terminator first, then we go through the statements
         // in reverse, building onto the FnBody this way.
-        let mut bbod = self.translate_terminator(&bbd.terminator());
+        let terminator = bbd.terminator();
+        let mut bbod = self
+            .translate_terminator(terminator)
+            .map_err(|err| err.or_span(terminator.source_info.span))?;
 
         for stmt in bbd.statements.iter().rev() {
-            bbod = FnBody::Seq(translate_statement(stmt), box bbod);
+            let stmt =
+                translate_statement(stmt).map_err(|err| err.or_span(stmt.source_info.span))?;
+            bbod = FnBody::Seq(stmt, box bbod);
+        }
+
+        let mut cont_ty = self.cont_ty(bb)?;
+        if let Some((invariant, span)) = self.invariants.get(&bb) {
+            cont_ty.invariant = Some(self.lower_pred_at(invariant, &cont_ty.locals));
+            if let Err(err) = cont_ty.invariant_location() {
+                self.tcx.sess.span_fatal(*span, &format!("Loop {}.", err));
+            }
+        }
+        if let Some(metric) = self.metrics.get(&bb) {
+            cont_ty.decreases = Some(self.lower_pred_at(metric, &cont_ty.locals));
+            let defs = self.translate_scope(Some(bb))?;
+            if !defs.is_empty() {
+                bbod = FnBody::LetCont(defs, box bbod);
+            }
         }
 
-        // We update our body here
+        Ok(ContDef {
+            name: ContId::new(bb.as_usize()),
+            ty: cont_ty,
+            params: vec![],
+            body: box bbod,
+        })
+    }
 
+    /// Returns the type of the continuation for the start of `bb`.
+    fn cont_ty(&mut self, bb: mir::BasicBlock) -> TransResult<ContTy> {
         // For our continuations, we use all of the locals
         // as our env arguments, keeping the parameters empty.
         // These env arguments point to locations on the heap, one for each
@@ -395,15 +908,27 @@ impl<'low, 'tcx> Transformer<'low, 'tcx> {
         // Otherwise, the heap argument is given an Uninit type, since all types
         // are subtypes of the Uninit type of the same size.
 
+        //
+        // References that are dead are also given an Uninit type, so the checker can end their
+        // loans before jumping, unless they may be borrowed from.
+
         self.maybe_uninitialized_cursor.seek_to_block_start(bb);
+        self.maybe_live_cursor.seek_to_block_start(bb);
 
         let mut locals = vec![];
         let mut heap = vec![];
 
         for (mir_local, decl) in self.body.local_decls.iter_enumerated() {
-            let ty = self
-                .type_lower_ctxt(mir_local, &mut heap)
-                .lower(decl.ty, &mut vec![]);
+            let is_dead_ref = decl.ty.is_ref()
+                && !self.maybe_live_cursor.contains(mir_local)
+                && !self.borrowed_locals.contains(&mir_local);
+            let ty = if is_dead_ref {
+                Ty::Uninit(1)
+            } else {
+                self.type_lower_ctxt(mir_local, &mut heap)
+                    .lower(decl.ty, &mut vec![])
+                    .map_err(|err| err.or_span(decl.source_info.span))?
+            };
             let local = Local::new(mir_local.index());
             let l = self.fresh_location();
 
@@ -411,32 +936,169 @@ impl<'low, 'tcx> Transformer<'low, 'tcx> {
             heap.push((l, ty));
         }
 
-        let cont_ty = ContTy {
+        Ok(ContTy {
             heap: Heap::from_iter(heap),
             locals,
             inputs: vec![],
-        };
+            invariant: None,
+            decreases: None,
+        })
+    }
 
-        ContDef {
-            name: ContId::new(bb.as_usize()),
-            ty: cont_ty,
-            params: vec![],
-            body: box bbod,
+    /// Lowers a predicate of a `liquid` macro or attribute, where user variables stand for the
+    /// location of the local they are stored in, e.g., at the start of a continuation.
+    fn lower_pred_at(&self, pred: &str, locals: &[(Local, Location)]) -> Pred {
+        let names: Vec<_> = self
+            .body
+            .var_debug_info
+            .iter()
+            .filter_map(|info| match info.value {
+                mir::VarDebugInfoContents::Place(place) if place.projection.is_empty() => locals
+                    .iter()
+                    .find(|(x, _)| *x == Local::new(place.local.index()))
+                    .map(|(_, l)| {
+                        let strukt =
+                            local_struct_name(self.tcx, self.body.local_decls[place.local].ty);
+                        (info.name.to_string(), *l, strukt)
+                    }),
+                _ => None,
+            })
+            .collect();
+        let ast = liquid_rust_parser::parse_pred(pred)
+            .unwrap_or_else(|_| bug!("predicates are parsed before expansion"));
+        let mut lcx = LowerCtx::new(self.measures).with_structs(self.structs);
+        for (name, l, strukt) in &names {
+            lcx.define_var(name, pred::Var::Location(*l));
+            lcx.define_var_struct(name, strukt.as_deref());
+        }
+        let lowered = ast.lower(&mut lcx);
+        for (_, msg) in lcx.take_errors() {
+            self.tcx.sess.err(&format!("{} (in `{}`)", msg, pred));
+        }
+        lowered
+    }
+
+    /// Translates a call to the marker function of a `liquid` macro. Invariants and metrics are
+    /// attached to the loop head, while assertions and assumptions are placed in a new
+    /// continuation between the call and its target, where the locations of the locals are known.
+    fn translate_macro_call(
+        &mut self,
+        terminator: &mir::Terminator<'tcx>,
+        mac: LiquidMacro,
+        pred: &str,
+    ) -> TransResult<FnBody<Span>> {
+        let span = terminator.source_info.span;
+        let (place, target) = match &terminator.kind {
+            TerminatorKind::Call {
+                destination: Some((place, target)),
+                ..
+            } => (place, *target),
+            _ => bug!("calls to marker functions return"),
+        };
+        let mut body = FnBody::Jump {
+            target: ContId::new(target.index()),
+            args: Vec::new(),
+        };
+        if let LiquidMacro::Assert | LiquidMacro::Assume = mac {
+            let ty = self.cont_ty(target)?;
+            let pred = self.lower_pred_at(pred, &ty.locals);
+            let kind = if let LiquidMacro::Assert = mac {
+                StatementKind::Assert(pred)
+            } else {
+                StatementKind::Assume(pred)
+            };
+            let name = self.names.fresh_cont();
+            let def = ContDef {
+                name,
+                ty,
+                params: vec![],
+                body: box FnBody::Seq(
+                    Statement {
+                        kind,
+                        source_info: span,
+                    },
+                    box body,
+                ),
+            };
+            body = FnBody::LetCont(
+                vec![def],
+                box FnBody::Jump {
+                    target: name,
+                    args: Vec::new(),
+                },
+            );
         }
+        let assign = Statement {
+            kind: StatementKind::Assign(
+                translate_place(place)?,
+                Rvalue::Use(Operand::Constant(Constant::Unit)),
+            ),
+            source_info: span,
+        };
+        Ok(FnBody::Seq(assign, box body))
     }
 
     #[allow(clippy::clippy::too_many_lines)]
-    fn translate_terminator(&mut self, terminator: &mir::Terminator<'tcx>) -> FnBody<()> {
-        match &terminator.kind {
+    fn translate_terminator(
+        &mut self,
+        terminator: &mir::Terminator<'tcx>,
+    ) -> TransResult<FnBody<Span>> {
+        if let Some((mac, pred)) = macro_call(self.tcx, terminator) {
+            return self.translate_macro_call(terminator, mac, &pred);
+        }
+        let span = terminator.source_info.span;
+        let body = match &terminator.kind {
             TerminatorKind::Goto { target } => FnBody::Jump {
                 target: ContId::new(target.index()),
                 args: Vec::new(),
             },
-            // TODO: Actually do the asserting
-            TerminatorKind::Assert { target, .. } => FnBody::Jump {
+            // Failing assertions only matter in panic-free functions.
+            TerminatorKind::Assert { target, .. } if !self.panic_free => FnBody::Jump {
                 target: ContId::new(target.index()),
                 args: Vec::new(),
             },
+            // Integers are unbounded in the logic, so overflows can't be expressed and their
+            // checks are assumed to pass, which is reported by `warn_unchecked_overflow`.
+            TerminatorKind::Assert { msg, target, .. }
+                if matches!(
+                    msg,
+                    mir::AssertKind::Overflow(..) | mir::AssertKind::OverflowNeg(_)
+                ) =>
+            {
+                FnBody::Jump {
+                    target: ContId::new(target.index()),
+                    args: Vec::new(),
+                }
+            }
+            TerminatorKind::Assert {
+                cond,
+                expected,
+                target,
+                ..
+            } => {
+                let ok = FnBody::Jump {
+                    target: ContId::new(target.index()),
+                    args: Vec::new(),
+                };
+                match cond {
+                    mir::Operand::Copy(place) | mir::Operand::Move(place) => {
+                        let (then, else_) = if *expected {
+                            (ok, self.panic(span))
+                        } else {
+                            (self.panic(span), ok)
+                        };
+                        FnBody::Ite {
+                            discr: translate_place(place)?,
+                            then: box then,
+                            else_: box else_,
+                        }
+                    }
+                    mir::Operand::Constant(c) => match translate_const(c)? {
+                        Operand::Constant(Constant::Bool(b)) if b == *expected => ok,
+                        _ => self.panic(span),
+                    },
+                }
+            }
             TerminatorKind::SwitchInt {
                 discr,
                 targets,
@@ -459,7 +1121,7 @@ impl<'low, 'tcx> Transformer<'low, 'tcx> {
                 let targets: Vec<_> = targets.iter().collect();
                 for (val, target) in targets.into_iter().rev() {
                     // We first have to translate our discriminator into an AST Operand.
-                    let op = translate_op(discr);
+                    let op = translate_op(discr)?;
 
                     let then = FnBody::Jump {
                         target: ContId::new(target.index()),
@@ -473,7 +1135,7 @@ impl<'low, 'tcx> Transformer<'low, 'tcx> {
                     // TypeLayout should be ok!
                     let bind = Statement {
                         kind: StatementKind::Let(temp, TypeLayout::Block(1)),
-                        source_info: (),
+                        source_info: span,
                     };
 
                     let temp = Place::from(temp);
@@ -497,7 +1159,7 @@ impl<'low, 'tcx> Transformer<'low, 'tcx> {
                         );
                         Statement {
                             kind,
-                            source_info: (),
+                            source_info: span,
                         }
                     };
 
@@ -523,6 +1185,28 @@ impl<'low, 'tcx> Transformer<'low, 'tcx> {
                 target: self.retk(),
                 args: vec![Transformer::retv()],
             },
+            TerminatorKind::Call { func, .. } if self.is_panic(func) => self.panic(span),
+            // Drop elaboration frees a box with `box_free` after its contents have been moved
+            // out. Freeing the allocation doesn't change the types, and the arguments are fields
+            // of the box that we don't model, so we just assign the unit result.
+            TerminatorKind::Call {
+                func,
+                destination: Some((place, target)),
+                ..
+            } if self.is_box_free(func) => {
+                let assign = Statement {
+                    kind: StatementKind::Assign(
+                        translate_place(place)?,
+                        Rvalue::Use(Operand::Constant(Constant::Unit)),
+                    ),
+                    source_info: span,
+                };
+                let jump = FnBody::Jump {
+                    target: ContId::new(target.index()),
+                    args: Vec::new(),
+                };
+                FnBody::Seq(assign, box jump)
+            }
             TerminatorKind::Call {
                 func,
                 args,
@@ -530,55 +1214,281 @@ impl<'low, 'tcx> Transformer<'low, 'tcx> {
                 ..
             } => {
                 let args_temp: Vec<Local> = (0..args.len()).map(|_| self.fresh_local()).collect();
+                let destination = match destination {
+                    Some((place, bb)) => {
+                        Some((translate_place(place)?, ContId::new(bb.as_usize())))
+                    }
+                    None => None,
+                };
 
-                let mut fb = match func {
-                    mir::Operand::Constant(bc) => {
-                        let c = &*bc;
-                        let kind = c.literal.ty.kind();
-
-                        match kind {
-                            ty::TyKind::FnDef(def_id, _) => {
-                                let def_id = def_id
-                                    .as_local()
-                                    .expect("Calls to non-local function are not supported yet.");
-
-                                FnBody::Call {
-                                    func: FnId::new(def_id.index()),
-                                    args: args_temp.clone(),
-                                    destination: destination.map(|(place, bb)| {
-                                        (translate_place(&place), ContId::new(bb.as_usize()))
-                                    }),
+                // Calls through the `Fn*` traits receive the callee as the first argument and the
+                // actual arguments packed in a tuple, which we spread into temporaries.
+                let fn_trait_call = self.fn_trait_call(func);
+                let mut spread = vec![];
+                let (func, call_args) = if let Some((kind, self_ty)) = fn_trait_call {
+                    for (i, ty) in args[1].ty(self.body, self.tcx).tuple_fields().enumerate() {
+                        let field = Place {
+                            base: args_temp[1],
+                            projs: vec![Proj::Field(i)],
+                        };
+                        spread.push((self.fresh_local(), ty, field));
+                    }
+                    let spread_args = spread.iter().map(|(temp, ..)| *temp);
+                    match self_ty.kind() {
+                        // A known closure is called directly with its captured state.
+                        ty::TyKind::Closure(def_id, _) if def_id.is_local() => (
+                            Callee::Fn(FnId::new(def_id.expect_local().index())),
+                            std::iter::once(args_temp[0]).chain(spread_args).collect(),
+                        ),
+                        _ => {
+                            let callee = if let ty::ClosureKind::FnOnce = kind {
+                                Place::from(args_temp[0])
+                            } else {
+                                Place {
+                                    base: args_temp[0],
+                                    projs: vec![Proj::Deref],
                                 }
+                            };
+                            (Callee::Place(callee), spread_args.collect())
+                        }
+                    }
+                } else {
+                    match func {
+                        mir::Operand::Constant(bc) => match bc.literal.ty.kind() {
+                            ty::TyKind::FnDef(def_id, substs) => {
+                                let func = if let Some(def_id) = def_id.as_local() {
+                                    FnId::new(def_id.index())
+                                } else if let Some(func) = self.builtins.lookup(*def_id, substs)? {
+                                    func
+                                } else {
+                                    return unsupported(format!(
+                                        "Calls to `{}` are not supported yet.",
+                                        self.tcx.def_path_str(*def_id)
+                                    ));
+                                };
+                                (Callee::Fn(func), args_temp.clone())
                             }
                             _ => unreachable!(),
+                        },
+                        mir::Operand::Copy(place) | mir::Operand::Move(place) => {
+                            (Callee::Place(translate_place(place)?), args_temp.clone())
                         }
                     }
-                    _ => todo!(),
                 };
 
+                let mut fb = FnBody::Call {
+                    func,
+                    args: call_args,
+                    destination,
+                };
+
+                for (temp, ty, field) in spread.into_iter().rev() {
+                    let bind = Statement {
+                        kind: StatementKind::Let(temp, get_layout(self.tcx, self.structs, ty)),
+                        source_info: span,
+                    };
+                    let assign = Statement {
+                        kind: StatementKind::Assign(
+                            Place::from(temp),
+                            Rvalue::Use(Operand::Move(field)),
+                        ),
+                        source_info: span,
+                    };
+                    fb = FnBody::Seq(bind, Box::new(FnBody::Seq(assign, Box::new(fb))));
+                }
+
                 // We now have to actually create and assign locals for our operands.
                 for (&temp, arg) in args_temp.iter().zip(args) {
                     // We let-define a new variable for our function arg, then
                     // assign it to the value of the arg.
 
                     let tys = arg.ty(self.body, self.tcx);
+                    let (layout, rvalue) = match tys.kind() {
+                        // A closure passed to a function becomes a function value that packs its
+                        // captured state.
+                        ty::TyKind::Closure(def_id, substs)
+                            if def_id.is_local() && fn_trait_call.is_none() =>
+                        {
+                            let mut captures = vec![];
+                            if let Some(place) = arg.place() {
+                                let place = translate_place(&place)?;
+                                for i in 0..substs.as_closure().upvar_tys().count() {
+                                    let mut place = place.clone();
+                                    place.projs.push(Proj::Field(i));
+                                    captures.push(match arg {
+                                        mir::Operand::Copy(_) => Operand::Copy(place),
+                                        _ => Operand::Move(place),
+                                    });
+                                }
+                            }
+                            let func = FnId::new(def_id.expect_local().index());
+                            (TypeLayout::Block(1), Rvalue::Closure(func, captures))
+                        }
+                        _ => (
+                            get_layout(self.tcx, self.structs, &tys),
+                            Rvalue::Use(translate_op(arg)?),
+                        ),
+                    };
                     let bind = Statement {
-                        kind: StatementKind::Let(temp, get_layout(&tys)),
-                        source_info: (),
+                        kind: StatementKind::Let(temp, layout),
+                        source_info: span,
                     };
 
                     let temp = Place::from(temp);
                     let assign = Statement {
-                        kind: StatementKind::Assign(temp, Rvalue::Use(translate_op(arg))),
-                        source_info: (),
+                        kind: StatementKind::Assign(temp, rvalue),
+                        source_info: span,
                     };
                     fb = FnBody::Seq(bind, Box::new(FnBody::Seq(assign, Box::new(fb))));
                 }
 
                 fb
             }
-            TerminatorKind::Abort => FnBody::Abort,
-            _ => todo!(),
+            // Dropping a value with a `Drop` implementation calls its `drop` method, and the
+            // place is uninitialized in the target since MIR drops move out of the place.
+            TerminatorKind::Drop { place, target, .. } => {
+                let target = ContId::new(target.index());
+                match self.destructor(place) {
+                    Some(func) => self.call_destructor(func, translate_place(place)?, target, span),
+                    None => FnBody::Seq(
+                        Statement {
+                            kind: StatementKind::Drop(translate_place(place)?),
+                            source_info: span,
+                        },
+                        box FnBody::Jump {
+                            target,
+                            args: Vec::new(),
+                        },
+                    ),
+                }
+            }
+            TerminatorKind::DropAndReplace {
+                place,
+                value,
+                target,
+                ..
+            } => {
+                if self.destructor(place).is_some() {
+                    return unsupported(
+                        "Replacing a value with a `Drop` implementation is not supported yet."
+                            .to_string(),
+                    );
+                }
+                let place = translate_place(place)?;
+                let drop = Statement {
+                    kind: StatementKind::Drop(place.clone()),
+                    source_info: span,
+                };
+                let assign = Statement {
+                    kind: StatementKind::Assign(place, Rvalue::Use(translate_op(value)?)),
+                    source_info: span,
+                };
+                let jump = FnBody::Jump {
+                    target: ContId::new(target.index()),
+                    args: Vec::new(),
+                };
+                FnBody::Seq(drop, box FnBody::Seq(assign, box jump))
+            }
+            // Unwinding continues a panic, which was already checked where it started.
+            TerminatorKind::Resume => FnBody::Abort,
+            TerminatorKind::Abort => self.panic(span),
+            _ => return unsupported(format!("Unsupported terminator `{:?}`.", terminator.kind)),
+        };
+        Ok(body)
+    }
+
+    /// Ends the execution with a panic, which must be unreachable in panic-free functions.
+    fn panic(&self, span: Span) -> FnBody<Span> {
+        if self.panic_free {
+            let assert = Statement {
+                kind: StatementKind::Assert(Pred::Constant(pred::Constant::Bool(false))),
+                source_info: span,
+            };
+            FnBody::Seq(assert, box FnBody::Abort)
+        } else {
+            FnBody::Abort
+        }
+    }
+
+    /// Returns the kind of the `Fn*` trait and the type of the callee if `func` is one of the
+    /// methods used to call a function value.
+    fn fn_trait_call(&self, func: &mir::Operand<'tcx>) -> Option<(ty::ClosureKind, ty::Ty<'tcx>)> {
+        match func.constant().map(|c| c.literal.ty.kind()) {
+            Some(ty::TyKind::FnDef(def_id, substs)) => {
+                let trait_id = self.tcx.trait_of_item(*def_id)?;
+                let kind = self.tcx.fn_trait_kind_from_lang_item(trait_id)?;
+                Some((kind, substs.type_at(0)))
+            }
+            _ => None,
+        }
+    }
+
+    fn is_panic(&self, func: &mir::Operand<'tcx>) -> bool {
+        match func.constant().map(|c| c.literal.ty.kind()) {
+            Some(ty::TyKind::FnDef(def_id, _)) => {
+                let lang_items = self.tcx.lang_items();
+                [
+                    lang_items.panic_fn(),
+                    lang_items.panic_str(),
+                    lang_items.panic_bounds_check_fn(),
+                    lang_items.begin_panic_fn(),
+                ]
+                .contains(&Some(*def_id))
+            }
+            _ => false,
+        }
+    }
+
+    /// Returns the `drop` method of the user `Drop` implementation for the type of `place`.
+    fn destructor(&self, place: &mir::Place<'tcx>) -> Option<FnId> {
+        match place.ty(self.body, self.tcx).ty.kind() {
+            ty::TyKind::Adt(adt_def, _) => {
+                let destructor = self.tcx.adt_destructor(adt_def.did)?;
+                let def_id = destructor.did.as_local()?;
+                Some(FnId::new(def_id.index()))
+            }
+            _ => None,
+        }
+    }
+
+    /// Calls the `drop` method `func` with a mutable reference to `place` and returns to
+    /// `target`, where the value is no longer initialized.
+    fn call_destructor(
+        &mut self,
+        func: FnId,
+        place: Place,
+        target: ContId,
+        span: Span,
+    ) -> FnBody<Span> {
+        let arg = self.fresh_local();
+        let ret = self.fresh_local();
+        let call = FnBody::Call {
+            func: Callee::Fn(func),
+            args: vec![arg],
+            destination: Some((Place::from(ret), target)),
+        };
+        let stmnts = vec![
+            StatementKind::Let(arg, TypeLayout::Block(1)),
+            StatementKind::Assign(Place::from(arg), Rvalue::Ref(BorrowKind::Mut, place)),
+            StatementKind::Let(ret, TypeLayout::Block(1)),
+        ];
+        stmnts.into_iter().rev().fold(call, |body, kind| {
+            FnBody::Seq(
+                Statement {
+                    kind,
+                    source_info: span,
+                },
+                box body,
+            )
+        })
+    }
+
+    fn is_box_free(&self, func: &mir::Operand<'tcx>) -> bool {
+        match func.constant().map(|c| c.literal.ty.kind()) {
+            Some(ty::TyKind::FnDef(def_id, _)) => {
+                Some(*def_id) == self.tcx.lang_items().box_free_fn()
+            }
+            _ => false,
         }
     }
 
@@ -588,11 +1498,14 @@ impl<'low, 'tcx> Transformer<'low, 'tcx> {
         heap: &'a mut Vec<(Location, Ty)>,
     ) -> TyLowerCtxt<'a, 'low, 'tcx> {
         TyLowerCtxt {
+            tcx: self.tcx,
             local,
             names: &mut self.names,
             heap,
             move_data: &self.move_data,
             maybe_uninitialized_cursor: &self.maybe_uninitialized_cursor,
+            fn_tys: &self.fn_tys,
+            structs: self.structs,
         }
     }
 
@@ -605,6 +1518,82 @@ impl<'low, 'tcx> Transformer<'low, 'tcx> {
     }
 }
 
+/// Returns the locals that are borrowed somewhere in the body.
+fn borrowed_locals(body: &mir::Body) -> HashSet<mir::Local> {
+    body.basic_blocks()
+        .iter()
+        .flat_map(|data| &data.statements)
+        .filter_map(|stmt| match &stmt.kind {
+            mir::StatementKind::Assign(assign) => match &assign.1 {
+                mir::Rvalue::Ref(_, _, place) => Some(place.local),
+                _ => None,
+            },
+            _ => None,
+        })
+        .collect()
+}
+
+/// Returns the type parameters of a function, including the ones of its parents, together with
+/// their names.
+pub fn generic_ty_params(tcx: ty::TyCtxt, def_id: DefId) -> Vec<(Symbol, TyParam)> {
+    let mut params = vec![];
+    let mut generics = Some(tcx.generics_of(def_id));
+    while let Some(g) = generics {
+        for param in &g.params {
+            if let GenericParamDefKind::Type { .. } = param.kind {
+                params.push((param.name, TyParam::new(param.index as usize)));
+            }
+        }
+        generics = g.parent.map(|parent| tcx.generics_of(parent));
+    }
+    params.sort_by_key(|(_, param)| *param);
+    params
+}
+
+fn lower_ty_param(param: ty::ParamTy) -> TyParam {
+    TyParam::new(param.index as usize)
+}
+
+fn is_stateless_closure(ty: ty::Ty) -> bool {
+    match ty.kind() {
+        ty::TyKind::Closure(_, substs) => substs.as_closure().upvar_tys().next().is_none(),
+        _ => false,
+    }
+}
+
+/// Whether `adt` is a struct defined in the crate. Structs are represented by tuples of their
+/// fields.
+fn is_local_struct(adt: &ty::AdtDef) -> bool {
+    adt.is_struct() && adt.did.is_local()
+}
+
+/// The types of the fields of a struct instantiated with `substs`, in order.
+fn struct_fields<'tcx>(
+    tcx: ty::TyCtxt<'tcx>,
+    adt: &'tcx ty::AdtDef,
+    substs: ty::subst::SubstsRef<'tcx>,
+) -> impl Iterator<Item = ty::Ty<'tcx>> {
+    adt.non_enum_variant()
+        .fields
+        .iter()
+        .map(move |field| field.ty(tcx, substs))
+}
+
+/// The invariant of a struct defined in the crate, if it has one.
+fn struct_invariant(tcx: ty::TyCtxt, structs: &Structs, adt: &ty::AdtDef) -> Option<Pred> {
+    let name = local_struct_name(tcx, tcx.type_of(adt.did))?;
+    structs.get(&name)?.invariant.clone()
+}
+
+/// Makes the tuple representing a struct with `fields`, which carries the invariant of the struct.
+fn mk_struct(fields: Vec<(Field, Ty)>, invariant: Option<Pred>) -> Ty {
+    match invariant {
+        _ if fields.is_empty() => Ty::unit(),
+        Some(invariant) => Ty::Struct(fields, invariant),
+        None => Ty::Tuple(fields),
+    }
+}
+
 fn tuple_layout_or_block(tup: Vec<TypeLayout>) -> TypeLayout {
     if tup.is_empty() {
         TypeLayout::Block(1)
@@ -614,31 +1603,52 @@ fn tuple_layout_or_block(tup: Vec<TypeLayout>) -> TypeLayout {
 }
 
 struct TyLowerCtxt<'a, 'low, 'tcx> {
+    tcx: ty::TyCtxt<'tcx>,
     local: mir::Local,
     names: &'a mut NameProducer,
     heap: &'a mut Vec<(Location, Ty)>,
     move_data: &'a MoveData<'tcx>,
     maybe_uninitialized_cursor: &'a ResultsCursor<'low, 'tcx, MaybeUninitializedPlaces<'low, 'tcx>>,
+    fn_tys: &'a HashMap<ty::Ty<'tcx>, Ty>,
+    structs: &'a Structs,
 }
 
 impl<'a, 'low, 'tcx> TyLowerCtxt<'a, 'low, 'tcx> {
-    fn lower(&mut self, ty: ty::Ty<'tcx>, projection: &mut Vec<mir::PlaceElem<'tcx>>) -> Ty {
-        if self.is_maybe_unitialized(projection) {
+    fn lower(
+        &mut self,
+        ty: ty::Ty<'tcx>,
+        projection: &mut Vec<mir::PlaceElem<'tcx>>,
+    ) -> TransResult<Ty> {
+        if is_stateless_closure(ty) {
+            return Ok(Ty::unit());
+        }
+        // The fields of an aggregate are moved out and initialized separately, so they are
+        // checked one by one. A field without a move path of its own falls back to the aggregate.
+        let is_aggregate = match ty.kind() {
+            ty::TyKind::Tuple(subst) => !subst.is_empty(),
+            ty::TyKind::Adt(adt, _) => is_local_struct(adt),
+            ty::TyKind::Closure(..) => true,
+            _ => false,
+        };
+        if !is_aggregate && self.is_maybe_unitialized(projection) {
             return self.lower_uninitialized(ty);
         }
+        if let Some(ty) = self.fn_tys.get(&ty) {
+            return Ok(ty.clone());
+        }
 
-        match ty.kind() {
+        let lowered = match ty.kind() {
             ty::TyKind::Tuple(subst) if !subst.is_empty() => {
                 let tup = ty
                     .tuple_fields()
                     .enumerate()
                     .map(|(i, ty)| {
                         projection.push(mir::PlaceElem::Field(mir::Field::from_usize(i), ty));
-                        let r = (Field::new(i), self.lower(ty, projection));
+                        let r = self.lower(ty, projection);
                         projection.pop();
-                        r
+                        Ok((Field::new(i), r?))
                     })
-                    .collect();
+                    .collect::<TransResult<_>>()?;
                 Ty::Tuple(tup)
             }
             ty::TyKind::Tuple(_) => Ty::unit(),
@@ -647,7 +1657,7 @@ impl<'a, 'low, 'tcx> TyLowerCtxt<'a, 'low, 'tcx> {
             ty::TyKind::Ref(_, ty, mutability) => {
                 // Rust won't allow having an initialized reference to uninitialized memory, so we
                 // assume everything is initialized from now on.
-                let ty = self.lower_initialized(ty);
+                let ty = self.lower_initialized(ty)?;
                 let l = self.names.fresh_location();
                 self.heap.push((l, ty));
                 match mutability {
@@ -655,23 +1665,62 @@ impl<'a, 'low, 'tcx> TyLowerCtxt<'a, 'low, 'tcx> {
                     Mutability::Not => Ty::Ref(BorrowKind::Shared, Region::Infer, l),
                 }
             }
-            _ => todo!(),
-        }
+            ty::TyKind::Adt(adt, substs) if self.is_vec(adt) => Ty::Vec(
+                box self.lower_initialized(substs.type_at(0))?,
+                Refine::Infer,
+            ),
+            // Options are represented by their payload (see `Builtin::OptionUnwrap`)
+            ty::TyKind::Adt(adt, substs) if self.is_option(adt) => {
+                self.lower_initialized(substs.type_at(0))?
+            }
+            // The contents of a box may have been moved out.
+            ty::TyKind::Adt(adt, substs) if adt.is_box() => {
+                projection.push(mir::PlaceElem::Deref);
+                let ty = self.lower(substs.type_at(0), projection);
+                projection.pop();
+                let ty = ty?;
+                let l = self.names.fresh_location();
+                self.heap.push((l, ty));
+                Ty::OwnRef(l)
+            }
+            ty::TyKind::Adt(adt, substs) if is_local_struct(adt) => {
+                let fields = struct_fields(self.tcx, adt, substs)
+                    .enumerate()
+                    .map(|(i, ty)| {
+                        projection.push(mir::PlaceElem::Field(mir::Field::from_usize(i), ty));
+                        let r = self.lower(ty, projection);
+                        projection.pop();
+                        Ok((Field::new(i), r?))
+                    })
+                    .collect::<TransResult<_>>()?;
+                mk_struct(fields, struct_invariant(self.tcx, self.structs, adt))
+            }
+            // A closure is represented by its captured state.
+            ty::TyKind::Closure(_, substs) => {
+                self.lower(substs.as_closure().tupled_upvars_ty(), projection)?
+            }
+            ty::TyKind::Param(param) => Ty::Param(lower_ty_param(*param)),
+            _ => return unsupported(format!("Unsupported type `{}`.", ty)),
+        };
+        Ok(lowered)
     }
 
-    fn lower_initialized(&mut self, ty: ty::Ty<'tcx>) -> Ty {
-        match ty.kind() {
+    fn lower_initialized(&mut self, ty: ty::Ty<'tcx>) -> TransResult<Ty> {
+        if let Some(ty) = self.fn_tys.get(&ty) {
+            return Ok(ty.clone());
+        }
+        let lowered = match ty.kind() {
             ty::TyKind::Tuple(subst) if !subst.is_empty() => Ty::Tuple(
                 ty.tuple_fields()
                     .enumerate()
-                    .map(|(i, ty)| (Field::new(i), self.lower_initialized(ty)))
-                    .collect(),
+                    .map(|(i, ty)| Ok((Field::new(i), self.lower_initialized(ty)?)))
+                    .collect::<TransResult<_>>()?,
             ),
             ty::TyKind::Tuple(_) => Ty::unit(),
             ty::TyKind::Bool => Ty::Refine(BaseTy::Bool, Refine::Infer),
             ty::TyKind::Int(_) | ty::TyKind::Uint(_) => Ty::Refine(BaseTy::Int, Refine::Infer),
             ty::TyKind::Ref(_, ty, mutability) => {
-                let ty = self.lower_initialized(ty);
+                let ty = self.lower_initialized(ty)?;
                 let l = self.names.fresh_location();
                 self.heap.push((l, ty));
                 match mutability {
@@ -679,27 +1728,83 @@ impl<'a, 'low, 'tcx> TyLowerCtxt<'a, 'low, 'tcx> {
                     Mutability::Not => Ty::Ref(BorrowKind::Shared, Region::Infer, l),
                 }
             }
-            _ => todo!(),
-        }
+            ty::TyKind::Adt(adt, substs) if self.is_vec(adt) => Ty::Vec(
+                box self.lower_initialized(substs.type_at(0))?,
+                Refine::Infer,
+            ),
+            // Options are represented by their payload (see `Builtin::OptionUnwrap`)
+            ty::TyKind::Adt(adt, substs) if self.is_option(adt) => {
+                self.lower_initialized(substs.type_at(0))?
+            }
+            ty::TyKind::Adt(adt, substs) if adt.is_box() => {
+                let ty = self.lower_initialized(substs.type_at(0))?;
+                let l = self.names.fresh_location();
+                self.heap.push((l, ty));
+                Ty::OwnRef(l)
+            }
+            ty::TyKind::Adt(adt, substs) if is_local_struct(adt) => {
+                let fields = struct_fields(self.tcx, adt, substs)
+                    .enumerate()
+                    .map(|(i, ty)| Ok((Field::new(i), self.lower_initialized(ty)?)))
+                    .collect::<TransResult<_>>()?;
+                mk_struct(fields, struct_invariant(self.tcx, self.structs, adt))
+            }
+            ty::TyKind::Closure(_, substs) => {
+                self.lower_initialized(substs.as_closure().tupled_upvars_ty())?
+            }
+            ty::TyKind::Param(param) => Ty::Param(lower_ty_param(*param)),
+            _ => return unsupported(format!("Unsupported type `{}`.", ty)),
+        };
+        Ok(lowered)
     }
 
-    fn lower_uninitialized(&mut self, ty: ty::Ty<'tcx>) -> Ty {
-        match ty.kind() {
+    fn lower_uninitialized(&mut self, ty: ty::Ty<'tcx>) -> TransResult<Ty> {
+        let lowered = match ty.kind() {
             ty::TyKind::Tuple(subst) if !subst.is_empty() => {
                 let tup = ty
                     .tuple_fields()
                     .enumerate()
-                    .map(|(i, ty)| (Field::new(i), self.lower_uninitialized(ty)))
-                    .collect();
+                    .map(|(i, ty)| Ok((Field::new(i), self.lower_uninitialized(ty)?)))
+                    .collect::<TransResult<_>>()?;
                 Ty::Tuple(tup)
             }
             ty::TyKind::Tuple(_)
             | ty::TyKind::Bool
             | ty::TyKind::Int(_)
             | ty::TyKind::Uint(_)
-            | ty::TyKind::Ref(..) => Ty::Uninit(1),
-            _ => todo!(),
-        }
+            | ty::TyKind::Ref(..)
+            | ty::TyKind::Param(_)
+            | ty::TyKind::FnPtr(_) => Ty::Uninit(1),
+            ty::TyKind::Closure(_, substs) => {
+                self.lower_uninitialized(substs.as_closure().tupled_upvars_ty())?
+            }
+            ty::TyKind::Adt(adt, _) if self.is_vec(adt) || adt.is_box() => Ty::Uninit(1),
+            ty::TyKind::Adt(adt, substs) if self.is_option(adt) => {
+                self.lower_uninitialized(substs.type_at(0))?
+            }
+            ty::TyKind::Adt(adt, _)
+                if is_local_struct(adt) && adt.all_fields().next().is_none() =>
+            {
+                Ty::Uninit(1)
+            }
+            ty::TyKind::Adt(adt, substs) if is_local_struct(adt) => {
+                let fields = struct_fields(self.tcx, adt, substs)
+                    .enumerate()
+                    .map(|(i, ty)| Ok((Field::new(i), self.lower_uninitialized(ty)?)))
+                    .collect::<TransResult<_>>()?;
+                mk_struct(fields, struct_invariant(self.tcx, self.structs, adt))
+            }
+            _ => return unsupported(format!("Unsupported type `{}`.", ty)),
+        };
+        Ok(lowered)
+    }
+
+    fn is_vec(&self, adt: &ty::AdtDef) -> bool {
+        self.tcx.is_diagnostic_item(sym::vec_type, adt.did)
+    }
+
+    fn is_option(&self, adt: &ty::AdtDef) -> bool {
+        self.tcx.is_diagnostic_item(sym::option_type, adt.did)
     }
 
     fn is_maybe_unitialized(&self, projection: &[mir::PlaceElem<'tcx>]) -> bool {
@@ -722,6 +1827,7 @@ impl<'a, 'low, 'tcx> TyLowerCtxt<'a, 'low, 'tcx> {
 struct NameProducer {
     next_location: usize,
     next_local: usize,
+    next_cont: usize,
 }
 
 impl NameProducer {
@@ -729,6 +1835,8 @@ impl NameProducer {
         Self {
             next_location: 0,
             next_local: body.local_decls.len(),
+            // The continuations after the ones of the basic blocks and the return continuation.
+            next_cont: body.basic_blocks().len() + 1,
         }
     }
 
@@ -741,4 +1849,9 @@ impl NameProducer {
         self.next_location += 1;
         Location::new(self.next_location - 1)
     }
+
+    fn fresh_cont(&mut self) -> ContId {
+        self.next_cont += 1;
+        ContId::new(self.next_cont - 1)
+    }
 }
@@ -1,21 +1,39 @@
-use crate::lower::{Lower, LowerCtx};
+use crate::{
+    builtins::trivially_refined,
+    lower::{Aliases, Lower, LowerCtx, Measures, StructDef, StructIndices, Structs},
+    macros::parse_error_msg,
+    translate::{generic_ty_params, Unsupported},
+};
 
-use liquid_rust_core::ast::*;
-use liquid_rust_parser::{parse_ty, ParseErrorKind};
+use liquid_rust_core::{
+    ast::{pred::Var, *},
+    names::{Field, MeasureId},
+};
+use liquid_rust_parser::{
+    parse_alias, parse_index_params, parse_pred, parse_refined_ty, parse_ty, ParseErrorKind,
+};
 
-use rustc_ast::ast::{AttrItem, AttrKind, Attribute};
+use rustc_ast::ast::{AttrItem, AttrKind, Attribute, Path};
 use rustc_ast_pretty::pprust::tts_to_string;
 use rustc_errors::{Diagnostic, Handler};
 use rustc_hir::{
-    def_id::DefId, itemlikevisit::ItemLikeVisitor, ForeignItem, ImplItem, Item, ItemKind, TraitItem,
+    def_id::DefId, itemlikevisit::ItemLikeVisitor, ForeignItem, ImplItem, ImplItemKind, Item,
+    ItemKind, TraitItem, TraitItemKind,
 };
-use rustc_middle::ty::TyCtxt;
+use rustc_middle::ty::{self, GenericParamDefKind, TyCtxt};
 use rustc_span::{BytePos, Pos, Span};
 use std::{collections::HashMap, ops::Range};
 
 pub struct DefCollector<'tcx, 'vis> {
     tcx: TyCtxt<'tcx>,
     annotations: HashMap<DefId, FnDecl>,
+    impl_specs: Vec<(DefId, FnDecl)>,
+    /// The functions whose annotation or trait specification relies on something we don't
+    /// support, which are left unchecked.
+    unsupported: HashMap<DefId, Unsupported>,
+    measures: &'vis Measures,
+    aliases: &'vis Aliases<'vis>,
+    structs: &'vis Structs,
     handler: &'vis Handler,
     buffer: &'vis mut Vec<Diagnostic>,
 }
@@ -23,19 +41,47 @@ pub struct DefCollector<'tcx, 'vis> {
 impl<'tcx, 'vis> DefCollector<'tcx, 'vis> {
     pub fn new(
         tcx: TyCtxt<'tcx>,
+        measures: &'vis Measures,
+        aliases: &'vis Aliases<'vis>,
+        structs: &'vis Structs,
         handler: &'vis Handler,
         buffer: &'vis mut Vec<Diagnostic>,
     ) -> Self {
         Self {
             tcx,
             annotations: HashMap::default(),
+            impl_specs: Vec::new(),
+            unsupported: HashMap::new(),
+            measures,
+            aliases,
+            structs,
             buffer,
             handler,
         }
     }
 
-    pub fn annotations(self) -> HashMap<DefId, FnDecl> {
-        self.annotations
+    /// Returns the annotations of every function together with the specifications that annotated
+    /// trait methods implementations must conform to, i.e., the annotation of the trait method
+    /// instantiated for the impl. The functions whose annotation isn't supported are returned
+    /// last.
+    #[allow(clippy::type_complexity)]
+    pub fn annotations(
+        self,
+    ) -> (
+        HashMap<DefId, FnDecl>,
+        Vec<(DefId, FnDecl)>,
+        HashMap<DefId, Unsupported>,
+    ) {
+        (self.annotations, self.impl_specs, self.unsupported)
+    }
+
+    /// Collects the annotation of a closure. Closures are expressions, so they aren't visited as
+    /// items. The annotation only mentions the arguments of the closure and not its captured
+    /// state.
+    pub fn visit_closure(&mut self, def_id: DefId) {
+        if let Some(ty) = self.extract_annotations(def_id, self.tcx.get_attrs(def_id)) {
+            self.annotations.insert(def_id, ty);
+        }
     }
 
     fn buffer_error(&mut self, span: Span, msg: &str) {
@@ -44,7 +90,86 @@ impl<'tcx, 'vis> DefCollector<'tcx, 'vis> {
             .buffer(self.buffer);
     }
 
-    fn extract_annotations(&mut self, attrs: &[Attribute]) -> Option<FnDecl> {
+    fn extract_annotations(&mut self, def_id: DefId, attrs: &[Attribute]) -> Option<FnDecl> {
+        let ty_params = generic_ty_params(self.tcx, def_id);
+        let mut decl = self.extract_annotations_with(attrs, |lcx| {
+            for (name, param) in ty_params {
+                lcx.define_ty_param(name.to_string(), param);
+            }
+        })?;
+        match region_bounds(self.tcx, def_id, &decl) {
+            Ok(outlives) => decl.outlives = outlives,
+            Err(err) => {
+                self.unsupported.insert(def_id, err);
+            }
+        }
+        Some(decl)
+    }
+
+    /// Returns the annotation of the trait method implemented by `impl_item_id` instantiated with
+    /// the types of the impl, or `None` if the method doesn't implement a local trait or the trait
+    /// method is not annotated.
+    fn trait_spec(&mut self, impl_item_id: DefId) -> Result<Option<FnDecl>, Unsupported> {
+        let tcx = self.tcx;
+        let (trait_ref, trait_item_id) = match local_trait_item(tcx, impl_item_id) {
+            Some(trait_item) => trait_item,
+            None => return Ok(None),
+        };
+
+        // The parameters of the trait, including `Self`, are replaced by the types in the impl
+        // and the parameters of the trait method by the ones of the impl method.
+        let mut instances = vec![];
+        for param in &tcx.generics_of(trait_ref.def_id).params {
+            if let GenericParamDefKind::Type { .. } = param.kind {
+                let ty = trait_ref.substs.type_at(param.index as usize);
+                let mut heap = vec![];
+                // Parameters instantiated with other types can't be refined by the annotation.
+                let refined = trivially_refined(tcx, ty, &mut heap).ok();
+                if !heap.is_empty() {
+                    return Err(Unsupported::new(format!(
+                        "Implementations for `{}` are not supported yet: boxes can't instantiate \
+                         the parameters of a trait.",
+                        ty
+                    )));
+                }
+                instances.push((param.name, refined));
+            }
+        }
+        let own_params = |def_id| {
+            tcx.generics_of(def_id)
+                .params
+                .iter()
+                .filter(|param| matches!(param.kind, GenericParamDefKind::Type { .. }))
+                .map(|param| (param.name, TyParam::new(param.index as usize)))
+                .collect::<Vec<_>>()
+        };
+        for ((name, _), (_, param)) in own_params(trait_item_id)
+            .into_iter()
+            .zip(own_params(impl_item_id))
+        {
+            instances.push((name, Some(Ty::Param(param))));
+        }
+
+        let ty_params = generic_ty_params(tcx, impl_item_id);
+        Ok(
+            self.extract_annotations_with(tcx.get_attrs(trait_item_id), |lcx| {
+                for (name, param) in ty_params {
+                    lcx.define_ty_param(name.to_string(), param);
+                }
+                for (name, ty) in instances {
+                    lcx.instantiate_ty_param(name.to_string(), ty);
+                }
+            }),
+        )
+    }
+
+    /// Parses and lowers the first annotation in `attrs`. `init` is used to set up the type
+    /// parameters available to the annotation.
+    fn extract_annotations_with(
+        &mut self,
+        attrs: &[Attribute],
+        init: impl FnOnce(&mut LowerCtx<'_>),
+    ) -> Option<FnDecl> {
         for attr in attrs {
             if let AttrKind::Normal(AttrItem { path, args, .. }, ..) = &attr.kind {
                 let path = path
@@ -86,9 +211,20 @@ impl<'tcx, 'vis> DefCollector<'tcx, 'vis> {
                         }
                     };
 
-                    let mut lcx = LowerCtx::new();
+                    let mut lcx = LowerCtx::new(self.measures)
+                        .with_aliases(self.aliases)
+                        .with_structs(self.structs);
+                    init(&mut lcx);
                     let res = ast.lower(&mut lcx);
 
+                    let errors = lcx.take_errors();
+                    if !errors.is_empty() {
+                        for (span, msg) in errors {
+                            self.buffer_error(map_span(span), &msg);
+                        }
+                        return None;
+                    }
+
                     return Some(res);
                 }
             }
@@ -102,13 +238,469 @@ impl<'hir, 'tcx, 'vis> ItemLikeVisitor<'hir> for DefCollector<'tcx, 'vis> {
         if let ItemKind::Fn(..) = item.kind {
             let def_id = self.tcx.hir().local_def_id(item.hir_id).to_def_id();
 
-            if let Some(ty) = self.extract_annotations(item.attrs) {
+            if let Some(ty) = self.extract_annotations(def_id, item.attrs) {
                 self.annotations.insert(def_id, ty);
             };
         }
     }
 
-    fn visit_trait_item(&mut self, _trait_item: &'hir TraitItem<'hir>) {}
-    fn visit_impl_item(&mut self, _impl_item: &'hir ImplItem<'hir>) {}
+    fn visit_trait_item(&mut self, trait_item: &'hir TraitItem<'hir>) {
+        if let TraitItemKind::Fn(..) = trait_item.kind {
+            let def_id = self.tcx.hir().local_def_id(trait_item.hir_id).to_def_id();
+
+            if let Some(ty) = self.extract_annotations(def_id, trait_item.attrs) {
+                self.annotations.insert(def_id, ty);
+            };
+        }
+    }
+
+    fn visit_impl_item(&mut self, impl_item: &'hir ImplItem<'hir>) {
+        if let ImplItemKind::Fn(..) = impl_item.kind {
+            let def_id = self.tcx.hir().local_def_id(impl_item.hir_id).to_def_id();
+
+            // An implementation without annotation gets the specification of the trait method,
+            // otherwise its annotation has to be checked against it.
+            let spec = self.trait_spec(def_id).unwrap_or_else(|err| {
+                self.unsupported.insert(def_id, err);
+                None
+            });
+            match (self.extract_annotations(def_id, impl_item.attrs), spec) {
+                (Some(ty), Some(spec)) => {
+                    self.annotations.insert(def_id, ty);
+                    self.impl_specs.push((def_id, spec));
+                }
+                (Some(ty), None) | (None, Some(ty)) => {
+                    self.annotations.insert(def_id, ty);
+                }
+                (None, None) => {}
+            }
+        }
+    }
     fn visit_foreign_item(&mut self, _foreign_item: &'hir ForeignItem<'hir>) {}
 }
+
+/// Collects the functions marked with `liquid::measure` or `liquid::pure`. A measure is only
+/// known in the logic by its name and the sorts of its Rust signature, its body is never checked.
+/// The definitions of pure functions are added later on.
+pub fn collect_measures(tcx: TyCtxt) -> Measures {
+    let mut measures = HashMap::new();
+    for item in tcx.hir().krate().items.values() {
+        if !matches!(item.kind, ItemKind::Fn(..))
+            || !(has_liquid_attr(item.attrs, "measure") || has_liquid_attr(item.attrs, "pure"))
+        {
+            continue;
+        }
+        let def_id = tcx.hir().local_def_id(item.hir_id);
+        let sig = tcx.fn_sig(def_id).skip_binder();
+        let decl = MeasureDecl {
+            inputs: sig.inputs().iter().map(|ty| sort_of(ty)).collect(),
+            output: sort_of(sig.output()),
+            def: None,
+        };
+        let measure = MeasureId::new(def_id.local_def_index.as_usize());
+        measures.insert(item.ident.to_string(), (measure, decl));
+    }
+    measures
+}
+
+/// Collects the definitions of the refinement type aliases given by `liquid::alias` attributes on
+/// the crate or any of its items, together with the span of the definition.
+pub fn alias_definitions(tcx: TyCtxt) -> Vec<(String, Span)> {
+    let krate = tcx.hir().krate();
+    krate
+        .items
+        .values()
+        .flat_map(|item| item.attrs)
+        .chain(tcx.hir().krate_attrs())
+        .filter_map(|attr| match &attr.kind {
+            AttrKind::Normal(AttrItem { path, args, .. }, ..) if is_liquid_path(path, "alias") => {
+                let tokens = args.inner_tokens();
+                let span = tokens.span().unwrap_or(attr.span);
+                // The aliased type is a string literal, its quotes are replaced by whitespace so
+                // offsets in the definition are preserved.
+                Some((tts_to_string(&tokens).replace('"', " "), span))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// Parses the definitions of aliases collected by [`alias_definitions`]. Definitions that don't
+/// parse or redefine an alias are reported and ignored.
+pub fn parse_aliases<'a>(tcx: TyCtxt, definitions: &'a [(String, Span)]) -> Aliases<'a> {
+    let mut aliases = HashMap::new();
+    for (input, span) in definitions {
+        let map_span = |range: Range<usize>| {
+            Span::new(
+                span.lo() + BytePos::from_usize(range.start),
+                span.lo() + BytePos::from_usize(range.end),
+                span.ctxt(),
+            )
+        };
+        match parse_alias(input) {
+            Ok(alias) if aliases.contains_key(alias.name.symbol) => {
+                tcx.sess.span_err(
+                    map_span(alias.name.span),
+                    &format!("Alias `{}` is defined more than once.", alias.name.symbol),
+                );
+            }
+            Ok(alias) => {
+                aliases.insert(alias.name.symbol, alias);
+            }
+            Err(err) => {
+                let msg = match err.kind {
+                    ParseErrorKind::UnexpectedEOF => "Alias ended unexpectedly.".to_owned(),
+                    ParseErrorKind::UnexpectedToken(token) => {
+                        format!("Unexpected token `{}`.", token)
+                    }
+                    ParseErrorKind::InvalidToken => "Invalid token".to_owned(),
+                };
+                tcx.sess.span_err(map_span(err.span), &msg);
+            }
+        }
+    }
+    aliases
+}
+
+/// Collects the structs defined in the crate together with their invariant, given by a
+/// `liquid::invariant` attribute on the struct where `self` stands for the struct. Invariants that
+/// don't parse or access unknown fields are reported and ignored.
+pub fn collect_structs(tcx: TyCtxt, measures: &Measures) -> Structs {
+    let items: Vec<_> = tcx
+        .hir()
+        .krate()
+        .items
+        .values()
+        .filter(|item| matches!(item.kind, ItemKind::Struct(..)))
+        .collect();
+
+    let mut structs: Structs = items
+        .iter()
+        .map(|item| {
+            let adt = tcx.adt_def(tcx.hir().local_def_id(item.hir_id));
+            let fields = adt
+                .non_enum_variant()
+                .fields
+                .iter()
+                .map(|field| {
+                    let strukt = local_struct_name(tcx, tcx.type_of(field.did));
+                    (field.ident.to_string(), strukt)
+                })
+                .collect();
+            let def = StructDef {
+                fields,
+                ty: None,
+                invariant: None,
+                indices: None,
+            };
+            (item.ident.to_string(), def)
+        })
+        .collect();
+
+    // Invariants may access the fields of nested structs, so they are lowered once every struct
+    // is known.
+    let mut invariants = vec![];
+    for item in &items {
+        let (input, span) = match liquid_attr_arg(item.attrs, "invariant") {
+            Some(invariant) => invariant,
+            None => continue,
+        };
+        let pred = match parse_pred(&input) {
+            Ok(pred) => pred,
+            Err(err) => {
+                tcx.sess
+                    .span_err(span_in(span, err.span), &parse_error_msg(err.kind));
+                continue;
+            }
+        };
+        let name = item.ident.to_string();
+        let mut lcx = LowerCtx::new(measures).with_structs(&structs);
+        lcx.define_var("self", Var::Nu);
+        lcx.define_var_struct("self", Some(&name));
+        let invariant = pred.lower(&mut lcx);
+        let errors = lcx.take_errors();
+        for (range, msg) in &errors {
+            tcx.sess.span_err(span_in(span, range.clone()), msg);
+        }
+        if errors.is_empty() {
+            invariants.push((name, invariant));
+        }
+    }
+    for (name, invariant) in invariants {
+        structs.get_mut(&name).unwrap().invariant = Some(invariant);
+    }
+
+    for item in &items {
+        let indices = struct_indices(tcx, item);
+        structs.get_mut(&*item.ident.as_str()).unwrap().indices = indices;
+    }
+
+    for item in &items {
+        let def_id = tcx.hir().local_def_id(item.hir_id).to_def_id();
+        let ty = struct_ty(tcx, &structs, def_id);
+        structs.get_mut(&*item.ident.as_str()).unwrap().ty = ty;
+    }
+    structs
+}
+
+/// Collects the indices of a struct given by a `liquid::refined_by` attribute and the types of its
+/// fields given by `liquid::field` attributes. Annotations that don't parse are reported, in which
+/// case the struct is not refined by indices.
+fn struct_indices(tcx: TyCtxt, item: &Item) -> Option<StructIndices> {
+    let fields = match &item.kind {
+        ItemKind::Struct(data, _) => data.fields(),
+        _ => return None,
+    };
+    let mut valid = true;
+    let fields = fields
+        .iter()
+        .map(|field| {
+            let (input, span) = liquid_attr_arg(field.attrs, "field")?;
+            if let Err(err) = parse_refined_ty(&input) {
+                tcx.sess
+                    .span_err(span_in(span, err.span), &parse_error_msg(err.kind));
+                valid = false;
+            }
+            Some(input)
+        })
+        .collect::<Vec<_>>();
+
+    let (input, span) = match liquid_attr_arg(item.attrs, "refined_by") {
+        Some(refined_by) => refined_by,
+        None => {
+            if fields.iter().any(Option::is_some) {
+                tcx.sess.span_err(
+                    item.span,
+                    "Field annotations require the struct to be refined by indices.",
+                );
+            }
+            return None;
+        }
+    };
+    let params = match parse_index_params(&input) {
+        Ok(params) => params
+            .into_iter()
+            .map(|(name, sort)| (name.symbol.to_owned(), sort))
+            .collect(),
+        Err(err) => {
+            tcx.sess
+                .span_err(span_in(span, err.span), &parse_error_msg(err.kind));
+            return None;
+        }
+    };
+    if !valid {
+        return None;
+    }
+    Some(StructIndices { params, fields })
+}
+
+/// The name of the struct `ty` is an instance of if it is defined in the crate.
+pub fn local_struct_name(tcx: TyCtxt, ty: ty::Ty) -> Option<String> {
+    match ty.kind() {
+        ty::TyKind::Adt(adt, _) if adt.is_struct() && adt.did.is_local() => {
+            Some(tcx.item_name(adt.did).to_string())
+        }
+        _ => None,
+    }
+}
+
+/// The type standing for the struct `def_id` in annotations, see [`StructDef::ty`].
+fn struct_ty(tcx: TyCtxt, structs: &Structs, def_id: DefId) -> Option<Ty> {
+    if tcx.generics_of(def_id).count() > 0 {
+        return None;
+    }
+    let def = &structs[&*tcx.item_name(def_id).as_str()];
+    let fields = tcx
+        .adt_def(def_id)
+        .non_enum_variant()
+        .fields
+        .iter()
+        .enumerate()
+        .map(|(i, field)| {
+            let ty = tcx.type_of(field.did);
+            let ty = match ty.kind() {
+                ty::TyKind::Bool | ty::TyKind::Int(_) | ty::TyKind::Uint(_) => {
+                    Ty::Refine(sort_of(ty), Refine::Pred(Pred::tt()))
+                }
+                ty::TyKind::Adt(adt, _) if local_struct_name(tcx, ty).is_some() => {
+                    struct_ty(tcx, structs, adt.did)?
+                }
+                _ => return None,
+            };
+            Some((Field::new(i), ty))
+        })
+        .collect::<Option<Vec<_>>>()?;
+    let ty = match &def.invariant {
+        _ if fields.is_empty() => Ty::unit(),
+        Some(invariant) => Ty::Struct(fields, invariant.clone()),
+        None => Ty::Tuple(fields),
+    };
+    Some(ty)
+}
+
+/// Returns the trait implemented by the impl of `impl_item_id` and the method of the trait it
+/// implements, if the trait is local.
+fn local_trait_item(tcx: TyCtxt, impl_item_id: DefId) -> Option<(ty::TraitRef, DefId)> {
+    let impl_id = tcx.impl_of_method(impl_item_id)?;
+    let trait_ref = tcx.impl_trait_ref(impl_id)?;
+    if !trait_ref.def_id.is_local() {
+        return None;
+    }
+    let name = tcx.item_name(impl_item_id);
+    let trait_item_id = *tcx
+        .associated_item_def_ids(trait_ref.def_id)
+        .iter()
+        .find(|def_id| tcx.item_name(**def_id) == name)?;
+    Some((trait_ref, trait_item_id))
+}
+
+/// The bounds `'a: 'b` in the where clauses of `def_id` between the universal regions of `decl`.
+/// For now, only the lifetime of a reference receiver is given a universal region, so a bound on
+/// any other lifetime is unsupported.
+fn region_bounds(
+    tcx: TyCtxt,
+    def_id: DefId,
+    decl: &FnDecl,
+) -> Result<Vec<(UniversalRegion, UniversalRegion)>, Unsupported> {
+    if tcx.is_closure(def_id) {
+        return Ok(vec![]);
+    }
+    // Lifetimes with bounds are early bound, so they are identified by their index.
+    let mut regions = HashMap::new();
+    let sig = tcx.fn_sig(def_id).skip_binder();
+    if let (Some((_, l)), Some(input)) = (decl.inputs.first(), sig.inputs().first()) {
+        let receiver = decl.in_heap.iter().find(|(l2, _)| l == l2);
+        if let (
+            Some((_, Ty::Ref(_, Region::Universal(region), _))),
+            ty::TyKind::Ref(ty::ReEarlyBound(ebr), ..),
+        ) = (receiver, input.kind())
+        {
+            regions.insert(ebr.index, *region);
+        }
+    }
+    let predicates = tcx.predicates_of(def_id).instantiate_identity(tcx);
+    let mut outlives = vec![];
+    for (pred, span) in predicates.predicates.iter().zip(predicates.spans) {
+        if let ty::PredicateKind::RegionOutlives(ty::OutlivesPredicate(
+            ty::ReEarlyBound(ebr1),
+            ty::ReEarlyBound(ebr2),
+        )) = pred.kind().skip_binder()
+        {
+            match (regions.get(&ebr1.index), regions.get(&ebr2.index)) {
+                (Some(r1), Some(r2)) => outlives.push((*r1, *r2)),
+                _ => {
+                    return Err(Unsupported::new(format!(
+                        "Bounds between lifetimes other than the one of `self` are not supported \
+                         yet: `{}: {}`.",
+                        ebr1.name, ebr2.name
+                    ))
+                    .or_span(span))
+                }
+            }
+        }
+    }
+    Ok(outlives)
+}
+
+/// Whether `def_id` is a measure, i.e., a function that is only used in the logic.
+pub fn is_measure(tcx: TyCtxt, def_id: DefId) -> bool {
+    has_liquid_attr(tcx.get_attrs(def_id), "measure")
+}
+
+/// Whether `def_id` is a pure function, which is checked like any other function but also
+/// reflected into the logic.
+pub fn is_pure(tcx: TyCtxt, def_id: DefId) -> bool {
+    has_liquid_attr(tcx.get_attrs(def_id), "pure")
+}
+
+/// The sort of the values of `ty` in the logic. Values of other types than booleans and unit are
+/// represented by integers, e.g., vectors by their length and type parameters opaquely.
+fn sort_of(ty: ty::Ty) -> BaseTy {
+    match ty.kind() {
+        ty::TyKind::Bool => BaseTy::Bool,
+        ty::TyKind::Tuple(tys) if tys.is_empty() => BaseTy::Unit,
+        _ => BaseTy::Int,
+    }
+}
+
+/// Whether panics must be proven unreachable in the body of `def_id`, i.e., if the function, the
+/// function enclosing a closure, or the crate is marked with `liquid::panic_free`.
+pub fn is_panic_free(tcx: TyCtxt, def_id: DefId) -> bool {
+    let def_id = tcx.closure_base_def_id(def_id);
+    has_liquid_attr(tcx.get_attrs(def_id), "panic_free")
+        || has_liquid_attr(tcx.hir().krate_attrs(), "panic_free")
+}
+
+/// Whether the body of `def_id` is trusted, i.e., calls use its annotation but the body is not
+/// checked. Closures are trusted if the enclosing function is.
+pub fn is_trusted(tcx: TyCtxt, def_id: DefId) -> bool {
+    has_liquid_attr(tcx.get_attrs(tcx.closure_base_def_id(def_id)), "trusted")
+}
+
+/// Whether `def_id` is ignored, i.e., neither checked nor callable from checked functions.
+/// Closures are ignored if the enclosing function is.
+pub fn is_ignored(tcx: TyCtxt, def_id: DefId) -> bool {
+    has_liquid_attr(tcx.get_attrs(tcx.closure_base_def_id(def_id)), "ignore")
+}
+
+/// Returns the termination metric of `def_id` given by a `liquid::decreases` attribute. Metrics
+/// that don't parse are reported and ignored.
+pub fn metric_annotation(tcx: TyCtxt, def_id: DefId) -> Option<String> {
+    let (args, span) = tcx
+        .get_attrs(def_id)
+        .iter()
+        .find_map(|attr| match &attr.kind {
+            AttrKind::Normal(AttrItem { path, args, .. }, ..)
+                if is_liquid_path(path, "decreases") =>
+            {
+                Some((args, attr.span))
+            }
+            _ => None,
+        })?;
+    let input = tts_to_string(&args.inner_tokens());
+    let metric = input.trim_matches('"');
+    match parse_pred(metric) {
+        Ok(_) => Some(metric.to_owned()),
+        Err(err) => {
+            tcx.sess.span_err(span, &parse_error_msg(err.kind));
+            None
+        }
+    }
+}
+
+/// The argument of the `liquid::<name>` attribute in `attrs`, if any, together with its span. The
+/// argument is a string literal whose quotes are replaced by whitespace so offsets in the string are
+/// preserved.
+fn liquid_attr_arg(attrs: &[Attribute], name: &str) -> Option<(String, Span)> {
+    attrs.iter().find_map(|attr| match &attr.kind {
+        AttrKind::Normal(AttrItem { path, args, .. }, ..) if is_liquid_path(path, name) => {
+            let tokens = args.inner_tokens();
+            let span = tokens.span().unwrap_or(attr.span);
+            Some((tts_to_string(&tokens).replace('"', " "), span))
+        }
+        _ => None,
+    })
+}
+
+/// The span of `range` relative to the beginning of `span`.
+fn span_in(span: Span, range: Range<usize>) -> Span {
+    Span::new(
+        span.lo() + BytePos::from_usize(range.start),
+        span.lo() + BytePos::from_usize(range.end),
+        span.ctxt(),
+    )
+}
+
+fn has_liquid_attr(attrs: &[Attribute], name: &str) -> bool {
+    attrs.iter().any(|attr| match &attr.kind {
+        AttrKind::Normal(AttrItem { path, .. }, ..) => is_liquid_path(path, name),
+        _ => false,
+    })
+}
+
+fn is_liquid_path(path: &Path, name: &str) -> bool {
+    match &path.segments[..] {
+        [namespace, attr] => namespace.ident.as_str() == "liquid" && attr.ident.as_str() == name,
+        _ => false,
+    }
+}
@@ -2,10 +2,10 @@ pub mod solver;
 
 use std::{collections::HashMap, fmt, io};
 
-use liquid_rust_core::ty::{pred::Constant, BinOp, UnOp};
+use liquid_rust_core::ty::{pred::Constant, BaseTy, BinOp, UnOp};
 use quickscope::ScopeMap;
-pub use solver::solve;
 use solver::LiquidResult;
+pub use solver::{solve, write_query};
 
 #[derive(Debug)]
 pub enum Constraint {
@@ -17,11 +17,22 @@ pub enum Constraint {
 }
 
 impl Constraint {
-    pub fn solve(&self) -> io::Result<LiquidResult> {
-        solve(self)
+    pub fn solve(&self, consts: &[Const]) -> io::Result<LiquidResult> {
+        solve(self, consts)
     }
 }
 
+/// A function symbol declared in the preamble of the query.
+#[derive(Debug)]
+pub struct Const {
+    pub name: String,
+    pub inputs: Vec<Sort>,
+    pub output: Sort,
+    /// The names of the parameters and the body of the function if it is defined, otherwise it
+    /// is uninterpreted.
+    pub def: Option<(Vec<String>, Expr)>,
+}
+
 #[derive(Debug, Clone, Copy)]
 pub enum Sort {
     Int,
@@ -29,6 +40,16 @@ pub enum Sort {
     Unit,
 }
 
+impl From<BaseTy> for Sort {
+    fn from(bty: BaseTy) -> Self {
+        match bty {
+            BaseTy::Int => Sort::Int,
+            BaseTy::Bool => Sort::Bool,
+            BaseTy::Unit => Sort::Unit,
+        }
+    }
+}
+
 impl fmt::Display for Sort {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
@@ -53,6 +74,8 @@ pub enum Expr {
     Constant(Constant),
     BinaryOp(BinOp, Box<Expr>, Box<Expr>),
     UnaryOp(UnOp, Box<Expr>),
+    App(String, Vec<Expr>),
+    Ite(Box<Expr>, Box<Expr>, Box<Expr>),
 }
 
 #[derive(Debug)]
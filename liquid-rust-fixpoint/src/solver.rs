@@ -27,8 +27,13 @@ impl fmt::Display for Safeness {
     }
 }
 
-pub fn solve(constraint: &Constraint) -> io::Result<LiquidResult> {
-    let mut kid = Command::new("fixpoint")
+pub fn solve(constraint: &Constraint, consts: &[Const]) -> io::Result<LiquidResult> {
+    let mut cmd = Command::new("fixpoint");
+    // Definitions are only unfolded with rewriting enabled
+    if consts.iter().any(|c| c.def.is_some()) {
+        cmd.arg("--rewrite");
+    }
+    let mut kid = cmd
         .arg("-q")
         .arg("--stdin")
         .arg("--json")
@@ -42,11 +47,7 @@ pub fn solve(constraint: &Constraint) -> io::Result<LiquidResult> {
     {
         let mut w = BufWriter::new(stdin.unwrap());
         // let mut w = BufWriter::new(io::stdout());
-        emit_preamble(&mut w)?;
-        emit_kvars(&mut w, constraint)?;
-        write!(w, "(constraint")?;
-        constraint.emit(&mut w, 2)?;
-        write!(w, ")")?;
+        write_query(&mut w, constraint, consts)?;
     }
 
     let out = kid.wait_with_output()?;
@@ -55,6 +56,20 @@ pub fn solve(constraint: &Constraint) -> io::Result<LiquidResult> {
     Ok(result)
 }
 
+/// Writes the query sent to fixpoint to check `constraint`.
+pub fn write_query<W: Write>(
+    w: &mut W,
+    constraint: &Constraint,
+    consts: &[Const],
+) -> io::Result<()> {
+    emit_preamble(w)?;
+    emit_consts(w, consts)?;
+    emit_kvars(w, constraint)?;
+    write!(w, "(constraint")?;
+    constraint.emit(w, 2)?;
+    write!(w, ")")
+}
+
 fn emit_kvars<W: Write>(w: &mut W, c: &Constraint) -> io::Result<()> {
     for (kvid, sorts) in KvarInferer::new().infer(c) {
         write!(w, "(var $k{} (", kvid)?;
@@ -69,6 +84,34 @@ fn emit_kvars<W: Write>(w: &mut W, c: &Constraint) -> io::Result<()> {
     Ok(())
 }
 
+fn emit_consts<W: Write>(w: &mut W, consts: &[Const]) -> io::Result<()> {
+    for c in consts {
+        write!(w, "(constant {} (func 0 (", c.name)?;
+        for (i, sort) in c.inputs.iter().enumerate() {
+            if i > 0 {
+                write!(w, " ")?;
+            }
+            write!(w, "{}", sort)?;
+        }
+        writeln!(w, ") {}))", c.output)?;
+    }
+    for c in consts {
+        if let Some((params, body)) = &c.def {
+            write!(w, "(define {} (", c.name)?;
+            for (i, (param, sort)) in params.iter().zip(&c.inputs).enumerate() {
+                if i > 0 {
+                    write!(w, " ")?;
+                }
+                write!(w, "({} {})", param, sort)?;
+            }
+            write!(w, ") {} (", c.output)?;
+            body.emit(w)?;
+            writeln!(w, "))")?;
+        }
+    }
+    Ok(())
+}
+
 fn emit_preamble<W: Write>(w: &mut W) -> io::Result<()> {
     write!(
         w,
@@ -198,6 +241,23 @@ impl Expr {
                 op2.emit(w)?;
                 write!(w, ")")?;
             }
+            Expr::App(func, args) => {
+                write!(w, "({}", func)?;
+                for arg in args {
+                    write!(w, " ")?;
+                    arg.emit(w)?;
+                }
+                write!(w, ")")?;
+            }
+            Expr::Ite(p1, p2, p3) => {
+                write!(w, "(if ")?;
+                p1.emit(w)?;
+                write!(w, " then ")?;
+                p2.emit(w)?;
+                write!(w, " else ")?;
+                p3.emit(w)?;
+                write!(w, ")")?;
+            }
             Expr::UnaryOp(un_op, op) => match un_op {
                 UnOp::Not => {
                     write!(w, "(not ")?;
@@ -101,21 +101,54 @@ pub enum PredicateKind<'source> {
     /// A literal.
     Lit(Constant),
     /// A place.
-    Place(Ident<'source>, Vec<usize>),
+    Place(Ident<'source>, Vec<Proj<'source>>),
     /// An unary operation between predicates.
     UnaryOp(UnOp, Box<Predicate<'source>>),
     /// A binary operation between predicates.
     BinaryOp(BinOp, Box<Predicate<'source>>, Box<Predicate<'source>>),
+    /// An application of a measure.
+    App(Ident<'source>, Vec<Predicate<'source>>),
+}
+
+/// A projection of a place.
+#[derive(Debug, Clone)]
+pub enum Proj<'source> {
+    /// A field accessed by its position, e.g., `x.0`.
+    Index(usize),
+    /// A field of a struct accessed by its name, e.g., `self.lo`.
+    Field(Ident<'source>),
 }
 
 /// The AST representation of a function type
 #[derive(Debug, Clone)]
 pub struct FnDecl<'source> {
+    /// The predicate parameters of the function together with the sorts of their arguments, e.g.,
+    /// `p: int -> bool`.
+    pub pred_params: Vec<(Ident<'source>, Vec<BaseTy>)>,
+    pub receiver: Option<Receiver<'source>>,
     pub args: Vec<(Ident<'source>, Ty<'source>)>,
     pub output: Box<Ty<'source>>,
     pub span: Span,
 }
 
+/// The AST representation of the `self` argument of a method.
+#[derive(Debug, Clone)]
+pub struct Receiver<'source> {
+    pub kind: ReceiverKind<'source>,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone)]
+pub enum ReceiverKind<'source> {
+    /// `self: T`, the receiver is taken by value.
+    Value(Ty<'source>),
+    /// `&self: T`, a shared reference to a receiver of type `T`.
+    Ref(Ty<'source>),
+    /// `&mut self: T -> U`, a mutable reference to a receiver of type `T` which has type `U`
+    /// when the method returns. `U` can refer to the initial value as `self` and defaults to `T`.
+    RefMut(Ty<'source>, Option<Ty<'source>>),
+}
+
 /// The AST representation of a refinement type.
 #[derive(Debug, Clone)]
 pub struct Ty<'source> {
@@ -131,4 +164,39 @@ pub enum TyKind<'source> {
     Refined(Option<Ident<'source>>, BaseTy, Predicate<'source>),
     /// A dependent product type.
     Tuple(Vec<(Ident<'source>, Ty<'source>)>),
+    /// An unrefined vector.
+    Vec(Box<Ty<'source>>),
+    /// A vector refined by its length.
+    RefinedVec(Option<Ident<'source>>, Box<Ty<'source>>, Predicate<'source>),
+    /// A type parameter of the function.
+    Param(Ident<'source>),
+    /// A function value, e.g., a closure passed to a higher-order function.
+    Fn(Box<FnDecl<'source>>),
+    /// An alias applied to arguments. Aliases without parameters are parsed as type parameters.
+    Alias(Ident<'source>, Vec<Predicate<'source>>),
+    /// A base type whose value is given by an index, e.g., `int[n]`.
+    IndexedBase(BaseTy, Index<'source>),
+    /// A struct refined by indices, e.g., `Pair[a, @b]`.
+    Indexed(Ident<'source>, Vec<Index<'source>>),
+}
+
+/// An index of a type.
+#[derive(Debug, Clone)]
+pub enum Index<'source> {
+    /// An expression, e.g., the `n + 1` of `int[n + 1]`.
+    Expr(Predicate<'source>),
+    /// A binder introducing a name for the index of an argument, e.g., the `@n` of `int[@n]`.
+    /// Binders can only be used in the arguments of a function and are in scope in the rest of
+    /// the signature.
+    Binder(Ident<'source>),
+}
+
+/// The AST representation of a refinement type alias, e.g., `Range(lo, hi) = {int | lo <= v}`.
+/// In the aliased type `v` stands for the refined value, unless it is one of the parameters.
+#[derive(Debug, Clone)]
+pub struct Alias<'source> {
+    pub name: Ident<'source>,
+    pub params: Vec<Ident<'source>>,
+    pub ty: Ty<'source>,
+    pub span: Span,
 }
@@ -14,6 +14,7 @@ pub struct ParseError<'source, S = Span> {
     pub span: S,
 }
 
+#[allow(clippy::upper_case_acronyms)]
 pub enum ParseErrorKind<'source> {
     /// Parsing failed because the type annotation ended too early.
     UnexpectedEOF,
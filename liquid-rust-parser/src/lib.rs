@@ -14,3 +14,33 @@ pub fn parse_ty<'source>(source: &'source str) -> err::ParseResult<ast::FnDecl<'
         .parse(source)
         .map_err(err::ParseError::from)
 }
+
+/// Parse the definition of a refinement type alias.
+pub fn parse_alias<'source>(source: &'source str) -> err::ParseResult<ast::Alias<'source>> {
+    grammar::AliasParser::new()
+        .parse(source)
+        .map_err(err::ParseError::from)
+}
+
+/// Parse a refinement type, e.g., the type of a field of a struct.
+pub fn parse_refined_ty<'source>(source: &'source str) -> err::ParseResult<ast::Ty<'source>> {
+    grammar::TyParser::new()
+        .parse(source)
+        .map_err(err::ParseError::from)
+}
+
+/// Parse the indices a struct is refined by, e.g., `lo: int, hi: int`.
+pub fn parse_index_params<'source>(
+    source: &'source str,
+) -> err::ParseResult<Vec<(ast::Ident<'source>, ast::BaseTy)>> {
+    grammar::IndexParamsParser::new()
+        .parse(source)
+        .map_err(err::ParseError::from)
+}
+
+/// Parse a predicate, e.g., the invariant of a loop.
+pub fn parse_pred<'source>(source: &'source str) -> err::ParseResult<ast::Predicate<'source>> {
+    grammar::PredicateParser::new()
+        .parse(source)
+        .map_err(err::ParseError::from)
+}
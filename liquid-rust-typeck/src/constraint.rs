@@ -1,11 +1,13 @@
 use std::{collections::HashMap, fmt};
 
 use liquid_rust_core::{
-    names::Field,
+    ast::MeasureDecl,
+    lower::TypeLowerer,
+    names::{Field, Location, MeasureId},
     ty::{
         self,
         pred::{Constant, Place},
-        BaseTy, BinOp, KVid, Ty, TyS, UnOp,
+        BaseTy, BinOp, KVid, PredParam, Ty, TyCtxt, TyS, UnOp,
     },
 };
 
@@ -34,10 +36,10 @@ impl Constraint {
         })
     }
 
-    pub fn from_subtype(bty: ty::BaseTy, refine1: &ty::Refine, refine2: &ty::Refine) -> Constraint {
+    pub fn from_subtype(sort: Sort, refine1: &ty::Refine, refine2: &ty::Refine) -> Constraint {
         Constraint::Forall(
             Var::Nu,
-            Sort::from(bty),
+            sort,
             embed_refine(refine1, &Place::from(Var::Nu), &HashMap::new()),
             box Constraint::Pred(embed_refine(
                 refine2,
@@ -47,6 +49,35 @@ impl Constraint {
         )
     }
 
+    pub fn from_pred(pred: &ty::Pred) -> Constraint {
+        Constraint::Pred(Pred::Expr(embed_pred(
+            pred,
+            &Place::from(Var::Nu),
+            &HashMap::new(),
+        )))
+    }
+
+    /// Checks that the fields of a struct satisfy its invariant. Nested structs are assumed to
+    /// satisfy their own invariants.
+    pub fn from_invariant(tcx: &TyCtxt, ty: &Ty) -> Constraint {
+        match ty.kind() {
+            TyKind::Tuple(tup) => match tup.invariant() {
+                Some(invariant) => {
+                    let var = Var::Location(tcx.fresh::<Location>());
+                    let check = Constraint::Pred(Pred::Expr(embed_pred(
+                        invariant,
+                        &Place::from(var),
+                        &HashMap::new(),
+                    )));
+                    Constraint::from_binding(var, tcx.mk_tuple(tup.without_invariant()), check)
+                }
+                None => Constraint::True,
+            },
+            TyKind::Indexed(ty, _) => Constraint::from_invariant(tcx, ty),
+            _ => Constraint::True,
+        }
+    }
+
     pub fn guard(pred: &ty::Pred, body: Constraint) -> Constraint {
         Constraint::Guard(
             Pred::Expr(embed_pred(pred, &Place::from(Var::Nu), &HashMap::new())),
@@ -55,6 +86,54 @@ impl Constraint {
     }
 }
 
+macro_rules! indent {
+    ($formatter:expr, $indent:expr) => {
+        ::std::write!($formatter, "\n{:>1$}", "", $indent)
+    };
+}
+
+impl Constraint {
+    fn fmt_indented(&self, f: &mut fmt::Formatter<'_>, indent: usize) -> fmt::Result {
+        match self {
+            Constraint::True => write!(f, "true"),
+            Constraint::Pred(pred) => write!(f, "{}", pred),
+            Constraint::Conj(constraints) => {
+                // Trivial conjuncts are left out to keep the output readable.
+                let constraints: Vec<_> = constraints
+                    .iter()
+                    .filter(|c| !matches!(c, Constraint::True))
+                    .collect();
+                if constraints.is_empty() {
+                    return write!(f, "true");
+                }
+                for (i, c) in constraints.iter().enumerate() {
+                    if i > 0 {
+                        indent!(f, indent)?;
+                    }
+                    c.fmt_indented(f, indent)?;
+                }
+                Ok(())
+            }
+            Constraint::Forall(var, sort, pred, body) => {
+                write!(f, "forall {}: {}. {} =>", var, sort, pred)?;
+                indent!(f, indent + 2)?;
+                body.fmt_indented(f, indent + 2)
+            }
+            Constraint::Guard(guard, body) => {
+                write!(f, "{} =>", guard)?;
+                indent!(f, indent + 2)?;
+                body.fmt_indented(f, indent + 2)
+            }
+        }
+    }
+}
+
+impl fmt::Display for Constraint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.fmt_indented(f, 0)
+    }
+}
+
 #[derive(Debug)]
 pub enum Sort {
     Int,
@@ -67,8 +146,16 @@ impl<'a> From<&'a Ty> for Sort {
     fn from(ty: &'a Ty) -> Self {
         match ty.kind() {
             TyKind::Tuple(tup) => Sort::Tuple(tup.types().map(Sort::from).collect()),
+            // Indices are not stored, an indexed value is represented by its fields.
+            TyKind::Indexed(ty, _) => Sort::from(ty),
             TyKind::Refine(bty, _) => Sort::from(*bty),
-            TyKind::OwnRef(_) | TyKind::Ref(_, _, _) | TyKind::Uninit(_) => Sort::Int,
+            // The logical value of a vector is its length
+            TyKind::Vec(..) => Sort::Int,
+            TyKind::OwnRef(_)
+            | TyKind::Ref(_, _, _)
+            | TyKind::Uninit(_)
+            | TyKind::Param(_)
+            | TyKind::Fn(_) => Sort::Int,
         }
     }
 }
@@ -83,9 +170,30 @@ impl From<BaseTy> for Sort {
     }
 }
 
+impl fmt::Display for Sort {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Sort::Int => write!(f, "int"),
+            Sort::Bool => write!(f, "bool"),
+            Sort::Unit => write!(f, "()"),
+            Sort::Tuple(sorts) => {
+                let sorts = sorts
+                    .iter()
+                    .map(|sort| format!("{}", sort))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                write!(f, "({})", sorts)
+            }
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum Pred {
     Kvar(Kvar),
+    /// A predicate parameter applied to some places. Inside the function binding the parameter it
+    /// is an uninterpreted boolean function.
+    Abstract(PredParam, Vec<Place>),
     Conj(Vec<Pred>),
     Expr(Expr),
     True,
@@ -97,6 +205,55 @@ pub enum Expr {
     Constant(Constant),
     BinaryOp(BinOp, Box<Expr>, Box<Expr>),
     UnaryOp(UnOp, Box<Expr>),
+    App(MeasureId, Vec<Expr>),
+    Ite(Box<Expr>, Box<Expr>, Box<Expr>),
+}
+
+impl fmt::Display for Pred {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Pred::Kvar(kvar) => write!(f, "{}", kvar),
+            Pred::Abstract(param, args) => {
+                let args = args
+                    .iter()
+                    .map(|arg| format!("{}", arg))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                write!(f, "p{}({})", param.as_usize(), args)
+            }
+            Pred::Conj(preds) if preds.is_empty() => write!(f, "true"),
+            Pred::Conj(preds) => {
+                let preds = preds
+                    .iter()
+                    .map(|pred| format!("{}", pred))
+                    .collect::<Vec<_>>()
+                    .join(" && ");
+                write!(f, "({})", preds)
+            }
+            Pred::Expr(expr) => write!(f, "{}", expr),
+            Pred::True => write!(f, "true"),
+        }
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Expr::Place(place) => write!(f, "{}", place),
+            Expr::Constant(c) => write!(f, "{}", c),
+            Expr::BinaryOp(op, lhs, rhs) => write!(f, "({} {} {})", lhs, op, rhs),
+            Expr::UnaryOp(op, operand) => write!(f, "{}({})", op, operand),
+            Expr::App(measure, args) => {
+                let args = args
+                    .iter()
+                    .map(|arg| format!("{}", arg))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                write!(f, "m{}({})", measure.as_usize(), args)
+            }
+            Expr::Ite(p1, p2, p3) => write!(f, "(if {} then {} else {})", p1, p2, p3),
+        }
+    }
 }
 
 pub struct Kvar(KVid, Vec<Place>);
@@ -119,13 +276,17 @@ impl fmt::Debug for Kvar {
 }
 
 fn collect_field_map(place: &mut Place, ty: &TyS, map: &mut HashMap<Field, Place>) {
-    if let TyKind::Tuple(tup) = ty.kind() {
-        for (i, (fld, ty)) in tup.iter().enumerate() {
-            place.projs.push(i);
-            map.insert(*fld, place.clone());
-            collect_field_map(place, ty, map);
-            place.projs.pop();
+    match ty.kind() {
+        TyKind::Tuple(tup) => {
+            for (i, (fld, ty)) in tup.iter().enumerate() {
+                place.projs.push(i);
+                map.insert(*fld, place.clone());
+                collect_field_map(place, ty, map);
+                place.projs.pop();
+            }
         }
+        TyKind::Indexed(ty, _) => collect_field_map(place, ty, map),
+        _ => {}
     }
 }
 
@@ -139,15 +300,38 @@ fn embed_ty(var: Var, ty: &TyS) -> Pred {
 fn embed_ty_rec(ty: &TyS, nu: &Place, fld_map: &HashMap<Field, Place>) -> Pred {
     match ty.kind() {
         TyKind::Tuple(tup) => {
-            let preds = tup
+            let mut preds = tup
                 .types()
                 .enumerate()
                 .map(|(i, ty)| embed_ty_rec(ty, &nu.extend_path(i), fld_map))
-                .collect();
+                .collect::<Vec<_>>();
+            // Moved out or uninitialized fields cannot be assumed to satisfy the invariant.
+            match tup.invariant() {
+                Some(invariant) if ty.is_initialized() => {
+                    preds.push(Pred::Expr(embed_pred(invariant, nu, fld_map)));
+                }
+                _ => {}
+            }
             Pred::Conj(preds)
         }
+        TyKind::Indexed(ty, _) => embed_ty_rec(ty, nu, fld_map),
         TyKind::Refine(_, refine) => embed_refine(refine, &nu, fld_map),
-        TyKind::OwnRef(_) | TyKind::Ref(..) | TyKind::Uninit(..) => Pred::True,
+        TyKind::Vec(_, refine) => {
+            let len_nonneg = Expr::BinaryOp(
+                BinOp::Ge,
+                box Expr::Place(nu.clone()),
+                box Expr::Constant(Constant::Int(0)),
+            );
+            Pred::Conj(vec![
+                Pred::Expr(len_nonneg),
+                embed_refine(refine, &nu, fld_map),
+            ])
+        }
+        TyKind::OwnRef(_)
+        | TyKind::Ref(..)
+        | TyKind::Uninit(..)
+        | TyKind::Param(_)
+        | TyKind::Fn(_) => Pred::True,
     }
 }
 
@@ -155,6 +339,7 @@ fn embed_refine(refine: &ty::Refine, nu: &Place, fld_map: &HashMap<Field, Place>
     match refine {
         ty::Refine::Pred(pred) => Pred::Expr(embed_pred(pred, nu, fld_map)),
         ty::Refine::Infer(kvar) => Pred::Kvar(embed_kvar(kvar, nu, fld_map)),
+        ty::Refine::Abstract(param, args) => Pred::Abstract(*param, embed_vars(args, nu, fld_map)),
     }
 }
 
@@ -168,6 +353,17 @@ fn embed_pred(pred: &ty::Pred, nu: &Place, fld_map: &HashMap<Field, Place>) -> E
         ),
         PredKind::UnaryOp(un_op, op) => Expr::UnaryOp(*un_op, box embed_pred(op, nu, fld_map)),
         PredKind::Place(place) => Expr::Place(embed_place(place, nu.clone(), fld_map)),
+        PredKind::App(measure, args) => Expr::App(
+            *measure,
+            args.iter()
+                .map(|arg| embed_pred(arg, nu, fld_map))
+                .collect(),
+        ),
+        PredKind::Ite(p1, p2, p3) => Expr::Ite(
+            box embed_pred(p1, nu, fld_map),
+            box embed_pred(p2, nu, fld_map),
+            box embed_pred(p3, nu, fld_map),
+        ),
     }
 }
 
@@ -185,8 +381,12 @@ fn embed_place(place: &Place, nu: Place, fld_map: &HashMap<Field, Place>) -> Pla
 }
 
 fn embed_kvar(kvar: &ty::Kvar, nu: &Place, fld_map: &HashMap<Field, Place>) -> Kvar {
+    Kvar(kvar.0, embed_vars(&kvar.1, nu, fld_map))
+}
+
+fn embed_vars(vars: &[Var], nu: &Place, fld_map: &HashMap<Field, Place>) -> Vec<Place> {
     let mut places = vec![];
-    for var in &kvar.1 {
+    for var in vars {
         match var {
             Var::Field(fld) => places.push(
                 fld_map
@@ -198,7 +398,7 @@ fn embed_kvar(kvar: &ty::Kvar, nu: &Place, fld_map: &HashMap<Field, Place>) -> K
             Var::Nu => places.push(nu.clone()),
         }
     }
-    Kvar(kvar.0, places)
+    places
 }
 
 // Lowering
@@ -275,6 +475,13 @@ impl Pred {
     pub fn lower(self, vars: &HashMap<Var, Sort>) -> fixpoint::Pred {
         match self {
             Pred::Kvar(kvar) => fixpoint::Pred::Kvar(kvar.lower(vars)),
+            Pred::Abstract(param, args) => fixpoint::Pred::Expr(fixpoint::Expr::App(
+                pred_param_name(param),
+                lower_places(args, vars)
+                    .into_iter()
+                    .map(fixpoint::Expr::Var)
+                    .collect(),
+            )),
             Pred::Conj(preds) => {
                 fixpoint::Pred::Conj(preds.into_iter().map(|p| p.lower(vars)).collect())
             }
@@ -293,28 +500,84 @@ impl Expr {
                 fixpoint::Expr::BinaryOp(bin_op, box op1.lower(vars), box op2.lower(vars))
             }
             Expr::UnaryOp(un_op, op) => fixpoint::Expr::UnaryOp(un_op, box op.lower(vars)),
+            Expr::App(measure, args) => fixpoint::Expr::App(
+                measure_name(measure),
+                args.into_iter().map(|arg| arg.lower(vars)).collect(),
+            ),
+            Expr::Ite(p1, p2, p3) => {
+                fixpoint::Expr::Ite(box p1.lower(vars), box p2.lower(vars), box p3.lower(vars))
+            }
         }
     }
 }
 
 impl Kvar {
     pub fn lower(self, sorts: &HashMap<Var, Sort>) -> fixpoint::Kvar {
-        let mut vars = vec![];
-        for place in self.1 {
-            match sorts.get(&place.base) {
-                Some(sort) => {
-                    for (_, mut projs) in sort.flatten() {
-                        projs.extend(place.projs.iter());
-                        vars.push(place_to_string(place.base, projs));
-                    }
-                }
-                None => {
-                    vars.push(place_to_string(place.base, place.projs));
+        fixpoint::Kvar(self.0.as_usize(), lower_places(self.1, sorts))
+    }
+}
+
+/// Lowers places to fixpoint variables, expanding the ones of a tuple sort into a variable per
+/// component.
+fn lower_places(places: Vec<Place>, sorts: &HashMap<Var, Sort>) -> Vec<String> {
+    let mut vars = vec![];
+    for place in places {
+        match sorts.get(&place.base) {
+            Some(sort) => {
+                for (_, mut projs) in sort.flatten() {
+                    projs.extend(place.projs.iter());
+                    vars.push(place_to_string(place.base, projs));
                 }
             }
+            None => {
+                vars.push(place_to_string(place.base, place.projs));
+            }
         }
-        fixpoint::Kvar(self.0.as_usize(), vars)
     }
+    vars
+}
+
+/// Declares a measure as a function in fixpoint, which is uninterpreted unless the measure
+/// reflects a pure function.
+pub fn lower_measure(tcx: &TyCtxt, measure: MeasureId, decl: &MeasureDecl) -> fixpoint::Const {
+    let def = decl.def.as_ref().map(|def| {
+        let params = def
+            .params
+            .iter()
+            .map(|l| place_to_string(Var::Location(*l), vec![]))
+            .collect();
+        let body = TypeLowerer::lower_stmnt_pred(tcx, &def.body);
+        let body = embed_pred(&body, &Place::from(Var::Nu), &HashMap::new());
+        (params, body.lower(&HashMap::new()))
+    });
+    fixpoint::Const {
+        name: measure_name(measure),
+        inputs: decl
+            .inputs
+            .iter()
+            .map(|bty| fixpoint::Sort::from(*bty))
+            .collect(),
+        output: fixpoint::Sort::from(decl.output),
+        def,
+    }
+}
+
+/// Declares a predicate parameter as an uninterpreted boolean function in fixpoint.
+pub fn lower_pred_param(param: PredParam, sorts: &[BaseTy]) -> fixpoint::Const {
+    fixpoint::Const {
+        name: pred_param_name(param),
+        inputs: sorts.iter().map(|bty| fixpoint::Sort::from(*bty)).collect(),
+        output: fixpoint::Sort::Bool,
+        def: None,
+    }
+}
+
+fn pred_param_name(param: PredParam) -> String {
+    format!("p{}", param.as_usize())
+}
+
+fn measure_name(measure: MeasureId) -> String {
+    format!("m{}", measure.as_usize())
 }
 
 fn place_to_string(base: Var, projs: Vec<usize>) -> String {
@@ -0,0 +1,135 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt, fs,
+    io::{self, Write},
+    path::PathBuf,
+    str::FromStr,
+};
+
+/// The directory the dumps are written to.
+const DUMP_DIR: &str = "liquid-rust-dump";
+
+/// A stage of the checker whose output can be dumped.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Stage {
+    /// The program as given to the checker.
+    Core,
+    /// The program after freshening its names.
+    Fresh,
+    /// The signature and the continuation types of a function after region inference.
+    Regions,
+    /// The constraint generated by the refinement checker.
+    Constraint,
+    /// The query sent to fixpoint.
+    Fq,
+    /// The whole program in the syntax of the core language, which can be checked again with
+    /// `liquid-rust-core-cli`.
+    Lr,
+}
+
+impl Stage {
+    fn extension(self) -> &'static str {
+        match self {
+            Stage::Core => "core",
+            Stage::Fresh => "fresh",
+            Stage::Regions => "regions",
+            Stage::Constraint => "constraint",
+            Stage::Fq => "fq",
+            Stage::Lr => "lr",
+        }
+    }
+}
+
+impl FromStr for Stage {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "core" => Ok(Stage::Core),
+            "fresh" => Ok(Stage::Fresh),
+            "regions" => Ok(Stage::Regions),
+            "constraint" => Ok(Stage::Constraint),
+            "fq" => Ok(Stage::Fq),
+            "lr" => Ok(Stage::Lr),
+            _ => Err(format!(
+                "unknown stage `{}`, expected one of core, fresh, regions, constraint, fq or lr",
+                s
+            )),
+        }
+    }
+}
+
+/// The stages to dump, selected with `--dump=core,fresh,...`. The output of a stage for a
+/// function is written to `liquid-rust-dump/<function>.<stage>`.
+#[derive(Default)]
+pub struct DumpConfig {
+    stages: HashSet<Stage>,
+    /// The names used for the files of functions, for front-ends that identify them by number.
+    names: HashMap<String, String>,
+}
+
+impl DumpConfig {
+    /// Removes the `--dump` flags from `args`, returning the stages they select.
+    pub fn from_args(args: &mut Vec<String>) -> Result<Self, String> {
+        let mut config = DumpConfig::default();
+        let mut i = 0;
+        while i < args.len() {
+            let stages = if let Some(stages) = args[i].strip_prefix("--dump=") {
+                let stages = stages.to_string();
+                args.remove(i);
+                stages
+            } else if args[i] == "--dump" && i + 1 < args.len() {
+                args.remove(i);
+                args.remove(i)
+            } else {
+                i += 1;
+                continue;
+            };
+            for stage in stages.split(',') {
+                config.stages.insert(stage.parse()?);
+            }
+        }
+        Ok(config)
+    }
+
+    pub fn is_enabled(&self, stage: Stage) -> bool {
+        self.stages.contains(&stage)
+    }
+
+    /// Uses `name` for the files of the function identified by `fn_id`.
+    pub fn name_fn(&mut self, fn_id: impl fmt::Display, name: String) {
+        self.names.insert(fn_id.to_string(), name);
+    }
+
+    /// The name used for the files of the function identified by `fn_id`.
+    pub fn fn_name(&self, fn_id: impl fmt::Display) -> String {
+        let fn_id = fn_id.to_string();
+        self.names.get(&fn_id).cloned().unwrap_or(fn_id)
+    }
+
+    /// Writes the output of `stage` for the function `fn_id`, if the stage is enabled.
+    pub fn dump(&self, stage: Stage, fn_id: impl fmt::Display, contents: impl fmt::Display) {
+        self.dump_with(stage, fn_id, |w| writeln!(w, "{}", contents));
+    }
+
+    /// Like `dump`, but the output is written by `write`.
+    pub fn dump_with(
+        &self,
+        stage: Stage,
+        fn_id: impl fmt::Display,
+        write: impl FnOnce(&mut fs::File) -> io::Result<()>,
+    ) {
+        if !self.is_enabled(stage) {
+            return;
+        }
+        let name = self.fn_name(fn_id);
+        let mut path = PathBuf::from(DUMP_DIR);
+        path.push(format!("{}.{}", name, stage.extension()));
+        let result = fs::create_dir_all(DUMP_DIR)
+            .and_then(|_| fs::File::create(&path))
+            .and_then(|mut file| write(&mut file));
+        if let Err(err) = result {
+            eprintln!("failed to write `{}`: {}", path.display(), err);
+        }
+    }
+}
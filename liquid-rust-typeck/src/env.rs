@@ -2,18 +2,28 @@ use crate::region_inference as region;
 use ast::Proj;
 use liquid_rust_core::{
     ast,
+    freshen::SourceNames,
     names::{Local, Location},
-    ty::{self, pred::Place, subst::Subst, FnDecl, Heap, LocalsMap, Region, Ty, TyCtxt, TyS, Walk},
+    ty::{
+        self, pred::Place, subst::Subst, ContTy, FnDecl, Heap, KVid, Kvar, LocalsMap, Region, Ty,
+        TyCtxt, TyS, UniversalRegion, Walk,
+    },
 };
 use std::{collections::HashSet, fmt};
 use ty::{BorrowKind, TyKind};
 
-use crate::constraint::Constraint;
+use crate::constraint::{Constraint, Sort};
 
 pub struct Env<'a> {
     tcx: &'a TyCtxt,
     locals: Vec<LocalsMap>,
     heap: Heap,
+    /// The bounds `'a: 'b` between the universal regions of the function being checked.
+    outlives: Vec<(UniversalRegion, UniversalRegion)>,
+    /// The locations holding a two-phase borrow that hasn't been activated yet.
+    reserved: HashSet<Location>,
+    /// The errors found while ending loans, which are taken by the caller.
+    errors: Vec<OwnershipError>,
 }
 
 impl<'a> Env<'a> {
@@ -22,25 +32,37 @@ impl<'a> Env<'a> {
             tcx,
             locals: vec![LocalsMap::empty()],
             heap: Heap::new(),
+            outlives: Vec::new(),
+            reserved: HashSet::new(),
+            errors: Vec::new(),
         }
     }
 }
 
 impl Env<'_> {
+    /// Assumes the bounds between universal regions declared by the signature being checked.
+    pub fn assume_outlives(&mut self, outlives: &[(UniversalRegion, UniversalRegion)]) {
+        self.outlives.extend(outlives);
+    }
+
     pub fn alloc(&mut self, x: Local, ty: Ty) {
         let l = self.fresh_location();
         self.insert_local(x, l);
         self.heap.insert(l, ty);
     }
 
-    pub fn update(&mut self, place: &ast::Place, ty: Ty) {
+    /// Updates the type of `place`. The returned constraint checks the invariants of the structs
+    /// whose fields were updated.
+    pub fn update(&mut self, place: &ast::Place, ty: Ty) -> Constraint {
         let l = self.lookup_local(&place.base);
         let root = self.tcx.selfify(self.lookup_location(l), Place::from(*l));
 
         let fresh_l = self.fresh_location();
-        let ty = self.update_ty(&root, &place.projs, ty);
+        let mut constraints = vec![];
+        let ty = self.update_ty(&root, &place.projs, ty, &mut constraints);
         self.insert_local(place.base, fresh_l);
         self.heap.insert(fresh_l, ty);
+        Constraint::Conj(constraints)
     }
 
     pub fn borrow(&mut self, place: &ast::Place) -> Location {
@@ -52,27 +74,98 @@ impl Env<'_> {
         l
     }
 
+    /// Marks the borrow stored in `x` as reserved. It is activated by the first use of `x`.
+    pub fn reserve(&mut self, x: Local) {
+        let l = *self.lookup_local(&x);
+        self.reserved.insert(l);
+    }
+
+    /// Activates the two-phase borrow stored in `x`, if any. From then on it is a mutable borrow,
+    /// so it must not conflict with the borrows taken while it was reserved.
+    pub fn activate(&mut self, x: Local) -> Result<(), OwnershipError> {
+        let l = *self.lookup_local(&x);
+        if !self.reserved.remove(&l) {
+            return Ok(());
+        }
+        let region = match self.lookup(&ast::Place::from(x)).kind() {
+            TyKind::Ref(_, region, _) => region.clone(),
+            _ => bug!(),
+        };
+        for place in region.places() {
+            self.check_ownership_safety(RefKind::Mut, place, &mut vec![ast::Place::from(x)])?;
+        }
+        Ok(())
+    }
+
+    /// Checks that no part of the value in `place`, including the contents of its boxes, has been
+    /// moved out or is yet to be initialized.
+    pub fn check_initialized(&self, place: &ast::Place) -> Result<(), OwnershipError> {
+        let ty = self.lookup(place);
+        if let TyKind::Uninit(_) = ty.kind() {
+            return Err(OwnershipError::UseOfMoved(place.clone()));
+        }
+        self.walk_owned(ty, |ty, _| match ty.kind() {
+            TyKind::Uninit(_) => Walk::Stop(OwnershipError::UseOfPartiallyMoved(place.clone())),
+            _ => Walk::Continue,
+        })?;
+        Ok(())
+    }
+
     pub fn drop(&mut self, place: &ast::Place) -> Constraint {
         let root = self.lookup(place).clone();
         let constraint = self.drop_ty(&root);
-        self.update(place, self.tcx.uninitialize(&root));
-        constraint
+        let uninit = self.update(place, self.tcx.uninitialize(&root));
+        Constraint::Conj(vec![constraint, uninit])
+    }
+
+    /// Drops the locals that are not `live` and hold a borrow, ending their loans. A local is kept
+    /// while another local borrows through it, so loans are ended innermost first.
+    pub fn end_dead_loans(&mut self, live: &HashSet<Local>) -> Constraint {
+        let mut constraints = vec![];
+        while let Some(x) = self.find_dead_loan(live) {
+            constraints.push(self.drop(&ast::Place::from(x)));
+        }
+        Constraint::Conj(constraints)
+    }
+
+    /// Takes the errors found while dropping references since the last call.
+    pub fn take_errors(&mut self) -> Vec<OwnershipError> {
+        std::mem::take(&mut self.errors)
     }
 
     pub fn lookup(&self, place: &ast::Place) -> &Ty {
+        self.try_lookup(place)
+            .unwrap_or_else(|| bug!("{:?} can't be looked up", place))
+    }
+
+    /// Like `lookup`, but returns `None` if the place goes through a value that has been moved
+    /// out, e.g., a dereference of an uninitialized local.
+    pub fn try_lookup(&self, place: &ast::Place) -> Option<&Ty> {
         let mut ty = self.lookup_location(self.lookup_local(&place.base));
         for proj in &place.projs {
-            match (ty.kind(), proj) {
+            match (ty::unfold(ty).kind(), proj) {
                 (TyKind::Tuple(tuple), &Proj::Field(n)) => {
                     ty = tuple.ty_at(n);
                 }
-                (TyKind::Ref(.., l), Proj::Deref) => {
+                (TyKind::Ref(.., l) | TyKind::OwnRef(l), Proj::Deref) => {
                     ty = self.lookup_location(l);
                 }
-                _ => bug!("{:?} {:?} {:?}", ty, place, proj),
+                _ => return None,
+            }
+        }
+        Some(ty)
+    }
+
+    /// Returns the signature of the function value stored in `place`, looking through references.
+    pub fn lookup_fn(&self, place: &ast::Place) -> &FnDecl {
+        let mut ty = self.lookup(place);
+        loop {
+            match ty.kind() {
+                TyKind::Fn(decl) => return decl,
+                TyKind::Ref(.., l) | TyKind::OwnRef(l) => ty = self.lookup_location(l),
+                _ => bug!("not a function: {}", ty),
             }
         }
-        ty
     }
 
     pub fn check_ownership_safety(
@@ -83,8 +176,14 @@ impl Env<'_> {
     ) -> Result<(), OwnershipError> {
         for (&x, l) in self.locals() {
             let ty = self.lookup_location(l);
-            ty.walk(|ty, projs| {
+            self.walk_owned(ty, |ty, projs| {
                 if let TyKind::Ref(bk, region, ..) = ty.kind() {
+                    // A reserved borrow only conflicts with mutable accesses.
+                    let bk = if projs.is_empty() && self.reserved.contains(l) {
+                        BorrowKind::Shared
+                    } else {
+                        *bk
+                    };
                     let in_reborrow_list = reborrow_list
                         .iter()
                         .any(|p| p.base == x && p.projs == projs);
@@ -108,24 +207,22 @@ impl Env<'_> {
                     let prefix = ast::Place::new(place.base, Vec::from(&place.projs[0..i]));
                     let ty = self.lookup(&prefix);
                     reborrow_list.push(prefix);
-                    match ty.kind() {
-                        TyKind::Ref(bk, region, _) => {
-                            if kind > *bk {
-                                return Err(OwnershipError::BehindRef(*bk));
-                            }
-                            for p in region.places() {
-                                let projs = p
-                                    .projs
-                                    .iter()
-                                    .chain(&place.projs[i + 1..])
-                                    .copied()
-                                    .collect();
-                                let place = &ast::Place::new(p.base, projs);
-                                self.check_ownership_safety(kind, place, reborrow_list)?;
-                            }
+                    // The contents of a box are owned by the box, so they can only be borrowed
+                    // through it, which we already checked above.
+                    if let TyKind::Ref(bk, region, _) = ty.kind() {
+                        if kind > *bk {
+                            return Err(OwnershipError::BehindRef(*bk));
+                        }
+                        for p in region.places() {
+                            let projs = p
+                                .projs
+                                .iter()
+                                .chain(&place.projs[i + 1..])
+                                .copied()
+                                .collect();
+                            let place = &ast::Place::new(p.base, projs);
+                            self.check_ownership_safety(kind, place, reborrow_list)?;
                         }
-                        TyKind::OwnRef(_) => todo!(),
-                        _ => {}
                     }
                     reborrow_list.pop();
                 }
@@ -140,12 +237,12 @@ impl Env<'_> {
 
         let mut projs = Vec::new();
         for proj in &place.projs {
-            match (ty.kind(), proj) {
+            match (ty::unfold(ty).kind(), proj) {
                 (TyKind::Tuple(tup), &Proj::Field(n)) => {
                     ty = tup.ty_at(n);
                     projs.push(n);
                 }
-                (TyKind::Ref(.., l), Proj::Deref) => {
+                (TyKind::Ref(.., l) | TyKind::OwnRef(l), Proj::Deref) => {
                     projs.clear();
                     base = *l;
                     ty = self.lookup_location(l);
@@ -229,6 +326,10 @@ impl Env<'_> {
         let tcx = self.tcx;
         let heap1 = &self.heap;
         match (ty1.kind(), ty2.kind()) {
+            // The indices are already substituted in the fields, so checking the fields is
+            // enough to fold a value into an indexed type.
+            (TyKind::Indexed(ty1, _), _) => self.subtyping(ty1, heap2, ty2),
+            (_, TyKind::Indexed(ty2, _)) => self.subtyping(ty1, heap2, ty2),
             (TyKind::Tuple(tup1), TyKind::Tuple(tup2)) if tup1.len() == tup2.len() => tup1
                 .iter()
                 .zip(tup2.types())
@@ -239,8 +340,10 @@ impl Env<'_> {
                         Constraint::from_binding(*f, ty1.clone(), c),
                     ])
                 }),
+            // Region inference reports the constraints between regions that don't hold, so the
+            // regions are already known to be related here.
             (TyKind::Ref(bk1, r1, l1), TyKind::Ref(bk2, r2, l2)) if bk1 >= bk2 => {
-                assert!(Env::outlives(r1, r2), "{} :> {}", r1, r2);
+                assert!(self.outlives(r1, r2), "{} :> {}", r1, r2);
                 let ty1 = &tcx.selfify(&heap1[l1], Place::from(*l1));
                 let ty2 = &heap2[l2];
                 self.subtyping(ty1, heap2, ty2)
@@ -251,14 +354,117 @@ impl Env<'_> {
                 self.subtyping(ty1, heap2, ty2)
             }
             (TyKind::Refine(bty1, refine1), TyKind::Refine(bty2, refine2)) if bty1 == bty2 => {
-                Constraint::from_subtype(*bty1, refine1, refine2)
+                Constraint::from_subtype(Sort::from(*bty1), refine1, refine2)
             }
-            (TyKind::Uninit(n1), TyKind::Uninit(n2)) if n1 == n2 => Constraint::True,
-            (TyKind::Refine(..), TyKind::Uninit(n)) if ty1.size() == *n => Constraint::True,
+            (TyKind::Vec(ty1, refine1), TyKind::Vec(ty2, refine2)) => Constraint::Conj(vec![
+                Constraint::from_subtype(Sort::Int, refine1, refine2),
+                self.subtyping(ty1, heap2, ty2),
+            ]),
+            (TyKind::Param(param1), TyKind::Param(param2)) if param1 == param2 => Constraint::True,
+            (TyKind::Fn(decl1), TyKind::Fn(decl2)) if decl1 == decl2 => Constraint::True,
+            (TyKind::Fn(decl1), TyKind::Fn(decl2)) => self.fn_subtyping(decl1, decl2),
+            (_, TyKind::Uninit(n)) if ty1.size() == *n => Constraint::True,
             _ => bug!("{} <: {}", ty1, ty2),
         }
     }
 
+    /// Checks that a function with signature `decl1` can be used where one with signature `decl2`
+    /// is expected, i.e., that it accepts the inputs of `decl2` and its outputs satisfy the ones
+    /// of `decl2`.
+    pub fn fn_subtyping(&self, decl1: &FnDecl, decl2: &FnDecl) -> Constraint {
+        let tcx = self.tcx;
+        let args: Vec<Local> = decl2.inputs.locals().copied().collect();
+        let mut env = Env::new(tcx);
+        env.assume_outlives(&decl2.outlives);
+        env.insert_locals(decl2.inputs.clone());
+        env.extend_heap(&decl2.in_heap);
+
+        let (in_heap, inputs, out_heap, outputs, output) = env.instantiate_fn_call(decl1, &args);
+        let c1 = env.check_args(&in_heap, inputs);
+
+        let ret = tcx.fresh::<Local>();
+        let ret_cont_ty = ContTy::new(
+            decl2.out_heap.clone(),
+            decl2.outputs(&args),
+            vec![decl2.output],
+        );
+        let (c2, bindings) = env.capture_bindings(|env| {
+            env.extend_heap(&out_heap);
+            env.insert_locals(outputs);
+            env.insert_locals(vec![(ret, output)].into_iter().collect());
+            env.check_jump(&ret_cont_ty, &[ret])
+        });
+        Constraint::from_bindings(
+            decl2.in_heap.bindings(),
+            Constraint::Conj(vec![c1, Constraint::from_bindings(bindings, c2)]),
+        )
+    }
+
+    /// Checks that the arguments of a call are subtypes of the instantiated inputs.
+    pub fn check_args(&self, in_heap: &Heap, inputs: LocalsMap) -> Constraint {
+        Constraint::Conj(
+            inputs
+                .into_iter()
+                .map(|(x, l)| {
+                    let ty1 = &self
+                        .tcx
+                        .selfify(&self.lookup(&ast::Place::from(x)), Place::from(l));
+                    let ty2 = &in_heap[&l];
+                    self.subtyping(ty1, in_heap, ty2)
+                })
+                .collect(),
+        )
+    }
+
+    /// Checks that `metric`, which is over the locations of `heap`, is non-negative and strictly
+    /// smaller when evaluated in the environment, where `locals` are bound to `args`.
+    pub fn check_decreases(
+        &self,
+        heap: &Heap,
+        locals: &LocalsMap,
+        metric: &ty::Pred,
+    ) -> Constraint {
+        use ty::BinOp::*;
+        let tcx = self.tcx;
+        let subst = Subst::infer(tcx, self.heap(), self.locals(), heap, locals);
+        let next = subst.apply(tcx, metric);
+        let zero = tcx.mk_constant(ty::pred::Constant::Int(0));
+        Constraint::from_pred(&tcx.mk_bin_op(
+            And,
+            tcx.mk_bin_op(Ge, next.clone(), zero),
+            tcx.mk_bin_op(Lt, next, metric.clone()),
+        ))
+    }
+
+    /// Checks that the environment satisfies the type of a continuation when jumping to it with
+    /// `args`.
+    pub fn check_jump(&self, cont_ty: &ContTy, args: &[Local]) -> Constraint {
+        let mut subst = Subst::infer(
+            self.tcx,
+            self.heap(),
+            self.locals(),
+            &cont_ty.heap,
+            &cont_ty.locals(args),
+        );
+        subst.remove_universal_region_substs();
+
+        let heap = &subst.apply(self.tcx, &cont_ty.heap);
+        let locals = subst.apply(self.tcx, &cont_ty.locals(args));
+
+        Constraint::Conj(
+            locals
+                .into_iter()
+                .map(|(x, l)| {
+                    let ty1 = &self
+                        .tcx
+                        .selfify(&self.lookup(&ast::Place::from(x)), Place::from(l));
+                    let ty2 = &heap[&l];
+                    self.subtyping(ty1, heap, ty2)
+                })
+                .collect(),
+        )
+    }
+
     pub fn instantiate_fn_call(
         &self,
         fn_ty: &FnDecl,
@@ -266,7 +472,16 @@ impl Env<'_> {
     ) -> (Heap, LocalsMap, Heap, LocalsMap, Location) {
         let tcx = self.tcx;
         let inputs = &fn_ty.inputs(args);
-        let mut subst = Subst::infer(self.heap(), self.locals(), &fn_ty.in_heap, &inputs);
+        let mut subst = Subst::infer(tcx, self.heap(), self.locals(), &fn_ty.in_heap, &inputs);
+        // Type parameters are instantiated with the shape of the actual arguments, but with
+        // fresh refinements, so they can be inferred to be a supertype of every argument.
+        let vars_in_scope = self.vars_in_scope();
+        subst.map_ty_substs(|ty| tcx.replace_refines_with_fresh_vars(ty, &vars_in_scope));
+        // Predicate parameters are instantiated with a fresh kvar, which also gets to mention the
+        // variables in scope besides the arguments of the parameter.
+        for (param, _) in &fn_ty.pred_params {
+            subst.add_pred_subst(*param, Kvar(tcx.fresh::<KVid>(), vars_in_scope.clone()));
+        }
 
         let in_heap = subst.apply(tcx, &fn_ty.in_heap);
         let inputs = subst.apply(tcx, inputs);
@@ -289,6 +504,25 @@ impl Env<'_> {
 
     // Private
 
+    fn find_dead_loan(&self, live: &HashSet<Local>) -> Option<Local> {
+        let mut borrowed = HashSet::new();
+        let mut holders = vec![];
+        for (&x, l) in self.locals() {
+            let mut holds_loan = false;
+            self.walk_owned(self.lookup_location(l), |ty, _| {
+                if let TyKind::Ref(_, region, _) = ty.kind() {
+                    borrowed.extend(region.places().iter().map(|p| p.base));
+                    holds_loan |= !region.places().is_empty();
+                }
+                Walk::Continue::<()>
+            });
+            if holds_loan && !live.contains(&x) {
+                holders.push(x);
+            }
+        }
+        holders.into_iter().find(|x| !borrowed.contains(x))
+    }
+
     fn insert_local(&mut self, x: Local, l: Location) {
         self.locals.last_mut().unwrap().insert(x, l);
     }
@@ -311,18 +545,33 @@ impl Env<'_> {
         self.tcx.fresh::<Location>()
     }
 
-    fn update_ty(&mut self, root: &TyS, projs: &[Proj], ty: Ty) -> Ty {
+    fn update_ty(
+        &mut self,
+        root: &TyS,
+        projs: &[Proj],
+        ty: Ty,
+        constraints: &mut Vec<Constraint>,
+    ) -> Ty {
         match (root.kind(), projs) {
             (_, []) => ty,
+            // Updating a field of an indexed value unfolds it.
+            (ty::TyKind::Indexed(root, _), [Proj::Field(_), ..]) => {
+                self.update_ty(root, projs, ty, constraints)
+            }
             (ty::TyKind::Tuple(tup), [Proj::Field(n), ..]) => {
-                let ty = self.update_ty(tup.ty_at(*n), &projs[1..], ty);
-                self.tcx.mk_tuple(tup.map_ty_at(*n, |_| ty))
+                let ty = self.update_ty(tup.ty_at(*n), &projs[1..], ty, constraints);
+                let ty = self.tcx.mk_tuple(tup.map_ty_at(*n, |_| ty));
+                // Structs are checked once all their fields have been initialized
+                if ty.is_initialized() {
+                    constraints.push(Constraint::from_invariant(self.tcx, &ty));
+                }
+                ty
             }
             (ty::TyKind::Ref(bk, r, l), [Proj::Deref, ..]) => {
                 let root = self.tcx.selfify(self.lookup_location(l), Place::from(*l));
 
                 let fresh_l = self.fresh_location();
-                let ty = self.update_ty(&root, &projs[1..], ty);
+                let ty = self.update_ty(&root, &projs[1..], ty, constraints);
                 self.heap.insert(fresh_l, ty);
                 self.tcx.mk_ref(*bk, r.clone(), fresh_l)
             }
@@ -330,7 +579,7 @@ impl Env<'_> {
                 let root = self.tcx.selfify(self.lookup_location(l), Place::from(*l));
 
                 let fresh_l = self.fresh_location();
-                let ty = self.update_ty(&root, &projs[1..], ty);
+                let ty = self.update_ty(&root, &projs[1..], ty, constraints);
                 self.heap.insert(fresh_l, ty);
                 self.tcx.mk_own_ref(fresh_l)
             }
@@ -338,88 +587,176 @@ impl Env<'_> {
         }
     }
 
+    /// Walks a type like [`TyS::walk`] but also walking into the locations owned by it.
+    fn walk_owned<T>(&self, ty: &TyS, mut f: impl FnMut(&TyS, &[Proj]) -> Walk<T>) -> Walk<T> {
+        self.walk_owned_internal(ty, &mut f, &mut vec![])
+    }
+
+    fn walk_owned_internal<T>(
+        &self,
+        ty: &TyS,
+        f: &mut impl FnMut(&TyS, &[Proj]) -> Walk<T>,
+        projs: &mut Vec<Proj>,
+    ) -> Walk<T> {
+        ty.walk(|ty, inner| {
+            let len = projs.len();
+            projs.extend(inner);
+            f(ty, projs)?;
+            if let TyKind::OwnRef(l) = ty.kind() {
+                projs.push(Proj::Deref);
+                self.walk_owned_internal(self.lookup_location(l), f, projs)?;
+            }
+            projs.truncate(len);
+            Walk::Continue
+        })
+    }
+
     fn drop_ty(&mut self, ty: &Ty) -> Constraint {
         let vars_in_scope = self.vars_in_scope();
         let mut constraints = vec![];
 
-        ty.walk(|ty, _| {
+        // Dropping a box drops its contents
+        let mut tys = vec![];
+        self.walk_owned(ty, |ty, _| {
+            tys.push(ty.clone());
+            Walk::Continue::<()>
+        });
+
+        for ty in tys {
             if let TyKind::Ref(BorrowKind::Mut, r, l) = ty.kind() {
                 let ty = self.lookup_location(l).clone();
-                match r.places() {
+                // A reborrow through a reference that has been moved out since, e.g., when the
+                // reference is uninitialized at a join, can't be given back through it anymore.
+                let places: Vec<_> = r
+                    .places()
+                    .iter()
+                    .filter(|place| self.try_lookup(place).is_some())
+                    .cloned()
+                    .collect();
+                match &places[..] {
                     [] => {}
-                    [place] => {
-                        self.update(place, ty);
+                    // A mutable reference to an element of a vector (as returned by
+                    // `index_mut`) borrows the whole vector. Elements cannot be strongly
+                    // updated, so we check that the element type is preserved instead.
+                    [place] if self.lookup(place).is_vec() && !ty.is_vec() => {
+                        if let TyKind::Vec(elem, _) = self.lookup(place).kind() {
+                            constraints.push(self.subtyping(&ty, &self.heap, elem));
+                        }
+                    }
+                    // If the reference may also point to a universal region, the place may not have
+                    // been written through it, so it is joined like for several places.
+                    [place] if !matches!(r, Region::Mixed(..)) => {
+                        constraints.push(self.update(place, ty));
                     }
                     places => {
                         // Get join
                         let ty_join = self.tcx.replace_with_fresh_vars(&ty, &vars_in_scope);
                         let mut region_constraints = region::Constraints::new();
 
-                        constraints.push(shallow_subtyping(&ty, &ty_join, &mut region_constraints));
-                        for place in r.places() {
+                        constraints.push(self.shallow_subtyping(
+                            &ty,
+                            &ty_join,
+                            &mut region_constraints,
+                        ));
+                        for place in places {
                             let ty = self.lookup(place);
-                            constraints.push(shallow_subtyping(
+                            constraints.push(self.shallow_subtyping(
                                 &ty,
                                 &ty_join,
                                 &mut region_constraints,
                             ));
                         }
-                        let sol = region_constraints.solve();
+                        // The join may require a region outliving two unrelated universal regions.
+                        let (sol, errors) = region_constraints.solve(self);
+                        self.errors
+                            .extend(errors.into_iter().map(|error| error.error));
                         let ty_join = sol.fix_regions_ty(self.tcx, ty_join);
 
                         // Update places
                         for place in places {
-                            self.update(place, ty_join.clone());
+                            constraints.push(self.update(place, ty_join.clone()));
                         }
                     }
                 }
             }
-            Walk::Continue::<()>
-        });
+        }
         Constraint::Conj(constraints)
     }
 
-    fn outlives(region1: &Region, region2: &Region) -> bool {
-        match (region1, region2) {
-            (Region::Concrete(places1), Region::Concrete(places2)) => {
-                let places1: HashSet<_> = places1.iter().collect();
-                let places2: HashSet<_> = places2.iter().collect();
-                places1.is_subset(&places2)
+    pub fn outlives(&self, region1: &Region, region2: &Region) -> bool {
+        // A region outlives another one if the loans of its places and of its universal region
+        // are included in the other one.
+        let universal = |region: &Region| match region {
+            Region::Concrete(_) => None,
+            Region::Universal(r) | Region::Mixed(r, _) => Some(*r),
+            Region::Infer(_) => bug!(),
+        };
+        let places2: HashSet<_> = region2.places().iter().collect();
+        let places_outlive = region1.places().iter().all(|p| places2.contains(p));
+        places_outlive
+            && match (universal(region1), universal(region2)) {
+                (None, _) => true,
+                (Some(r1), Some(r2)) => self.universal_outlives(r1, r2),
+                (Some(_), None) => false,
             }
-            // TODO: we should consider outlive annotations here.
-            (Region::Universal(r1), Region::Universal(r2)) => r1 == r2,
-            (Region::Universal(_), _) | (_, Region::Universal(_)) => false,
-            (Region::Infer(_), _) | (_, Region::Infer(_)) => bug!(),
-        }
     }
-}
 
-fn shallow_subtyping(
-    ty1: &Ty,
-    ty2: &Ty,
-    region_constraints: &mut region::Constraints,
-) -> Constraint {
-    match (ty1.kind(), ty2.kind()) {
-        (TyKind::Tuple(tup1), TyKind::Tuple(tup2)) if tup1.len() == tup2.len() => tup1
-            .iter()
-            .zip(tup2.types())
-            .rev()
-            .fold(Constraint::True, |c, ((f, ty1), ty2)| {
-                Constraint::Conj(vec![
-                    shallow_subtyping(ty1, ty2, region_constraints),
-                    Constraint::from_binding(*f, ty1.clone(), c),
-                ])
-            }),
-        (TyKind::Refine(bty1, refine1), TyKind::Refine(bty2, refine2)) if bty1 == bty2 => {
-            Constraint::from_subtype(*bty1, refine1, refine2)
+    /// Whether `r1: r2` follows from the bounds of the signature by reflexivity and transitivity.
+    fn universal_outlives(&self, r1: UniversalRegion, r2: UniversalRegion) -> bool {
+        let mut visited = HashSet::new();
+        let mut stack = vec![r1];
+        while let Some(r) = stack.pop() {
+            if r == r2 {
+                return true;
+            }
+            if visited.insert(r) {
+                stack.extend(
+                    self.outlives
+                        .iter()
+                        .filter(|(longer, _)| *longer == r)
+                        .map(|(_, shorter)| *shorter),
+                );
+            }
         }
-        (TyKind::Ref(bk1, r1, _), TyKind::Ref(bk2, r2, _)) if bk1 == bk2 => {
-            region_constraints.add(r1.clone(), r2.clone());
-            Constraint::True
+        false
+    }
+
+    fn shallow_subtyping(
+        &self,
+        ty1: &Ty,
+        ty2: &Ty,
+        region_constraints: &mut region::Constraints,
+    ) -> Constraint {
+        match (ty1.kind(), ty2.kind()) {
+            (TyKind::Indexed(ty1, _), _) => self.shallow_subtyping(ty1, ty2, region_constraints),
+            (_, TyKind::Indexed(ty2, _)) => self.shallow_subtyping(ty1, ty2, region_constraints),
+            (TyKind::Tuple(tup1), TyKind::Tuple(tup2)) if tup1.len() == tup2.len() => tup1
+                .iter()
+                .zip(tup2.types())
+                .rev()
+                .fold(Constraint::True, |c, ((f, ty1), ty2)| {
+                    Constraint::Conj(vec![
+                        self.shallow_subtyping(ty1, ty2, region_constraints),
+                        Constraint::from_binding(*f, ty1.clone(), c),
+                    ])
+                }),
+            (TyKind::Refine(bty1, refine1), TyKind::Refine(bty2, refine2)) if bty1 == bty2 => {
+                Constraint::from_subtype(Sort::from(*bty1), refine1, refine2)
+            }
+            (TyKind::Vec(ty1, refine1), TyKind::Vec(ty2, refine2)) => Constraint::Conj(vec![
+                Constraint::from_subtype(Sort::Int, refine1, refine2),
+                self.shallow_subtyping(ty1, ty2, region_constraints),
+            ]),
+            (TyKind::Ref(bk1, r1, _), TyKind::Ref(bk2, r2, _)) if bk1 == bk2 => {
+                region_constraints.add(r1.clone(), r2.clone());
+                Constraint::True
+            }
+            (TyKind::Uninit(n1), TyKind::Uninit(n2)) if n1 == n2 => Constraint::True,
+            (TyKind::OwnRef(_), TyKind::OwnRef(_)) => Constraint::True,
+            (TyKind::Param(param1), TyKind::Param(param2)) if param1 == param2 => Constraint::True,
+            (TyKind::Fn(decl1), TyKind::Fn(decl2)) if decl1 == decl2 => Constraint::True,
+            _ => bug!("{} <: {}", ty1, ty2),
         }
-        (TyKind::Uninit(n1), TyKind::Uninit(n2)) if n1 == n2 => Constraint::True,
-        (TyKind::OwnRef(_), TyKind::OwnRef(_)) => Constraint::True,
-        _ => bug!("{} <: {}", ty1, ty2),
     }
 }
 
@@ -460,10 +797,104 @@ impl std::cmp::PartialOrd<BorrowKind> for RefKind {
     }
 }
 
+/// An ownership error together with the source of the statement where it was found.
+#[derive(Debug)]
+pub struct SpannedError<I> {
+    pub error: OwnershipError,
+    pub source_info: Option<I>,
+}
+
 #[derive(Debug)]
 pub enum OwnershipError {
     ConflictingBorrow(ast::Place),
     BehindRef(BorrowKind),
+    /// A value was used after being moved out.
+    UseOfMoved(ast::Place),
+    /// A value was used after some of its parts were moved out.
+    UseOfPartiallyMoved(ast::Place),
+    /// A region was required to outlive another one but region inference couldn't satisfy it.
+    UnsatisfiedOutlives(Region, Region),
+}
+
+impl OwnershipError {
+    /// Returns a value displaying the error in terms of the names of the source program, where
+    /// `local_name` gives the name of a local as written by the front end.
+    pub fn source<'a, S>(
+        &'a self,
+        names: &'a SourceNames<S>,
+        local_name: &'a dyn Fn(&Local<S>) -> String,
+    ) -> SourceError<'a, S> {
+        SourceError {
+            error: self,
+            names,
+            local_name,
+        }
+    }
+}
+
+/// An ownership error displayed in terms of the names of the source program.
+pub struct SourceError<'a, S> {
+    error: &'a OwnershipError,
+    names: &'a SourceNames<S>,
+    local_name: &'a dyn Fn(&Local<S>) -> String,
+}
+
+impl<S: fmt::Display + Copy> SourceError<'_, S> {
+    fn place(&self, place: &ast::Place) -> String {
+        match self.names.locals.get(&place.base) {
+            Some(base) => place.format_with_base((self.local_name)(base)),
+            None => format!("{}", place),
+        }
+    }
+
+    fn universal(&self, region: &UniversalRegion) -> String {
+        match self.names.regions.get(region) {
+            Some(region) => format!("`'{}`", region.inner()),
+            None => format!("`'{}`", region.as_usize()),
+        }
+    }
+
+    /// Regions other than universal ones are made of the loans of the function, whose locations
+    /// don't appear in the source, so they are only described.
+    fn region(&self, region: &Region) -> String {
+        match region {
+            Region::Universal(region) => self.universal(region),
+            Region::Mixed(region, _) => {
+                format!("{} together with a local borrow", self.universal(region))
+            }
+            Region::Concrete(_) | Region::Infer(_) => "a local borrow".to_owned(),
+        }
+    }
+}
+
+impl<S: fmt::Display + Copy> fmt::Display for SourceError<'_, S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.error {
+            OwnershipError::ConflictingBorrow(place) => write!(
+                f,
+                "Cannot use the value because it is borrowed by `{}`.",
+                self.place(place)
+            ),
+            OwnershipError::BehindRef(BorrowKind::Shared) => {
+                write!(f, "Cannot mutate or move out of a shared reference.")
+            }
+            OwnershipError::BehindRef(BorrowKind::Mut) => {
+                write!(f, "Cannot move out of a mutable reference.")
+            }
+            OwnershipError::UseOfMoved(place) => {
+                write!(f, "Use of moved value `{}`.", self.place(place))
+            }
+            OwnershipError::UseOfPartiallyMoved(place) => {
+                write!(f, "Use of partially moved value `{}`.", self.place(place))
+            }
+            OwnershipError::UnsatisfiedOutlives(r1, r2) => write!(
+                f,
+                "Cannot prove that {} outlives {}.",
+                self.region(r1),
+                self.region(r2)
+            ),
+        }
+    }
 }
 
 pub struct Snapshot {
@@ -28,6 +28,11 @@ impl GlobEnv {
         );
     }
 
+    /// Inserts the type of a function without its continuations, e.g., for an extern function.
+    pub fn insert_fn_ty(&mut self, fn_id: FnId, ty: FnDecl) {
+        self.fn_tys.insert(fn_id, ty);
+    }
+
     pub fn get_ty(&self, fn_id: FnId) -> Option<&FnDecl> {
         self.fn_tys.get(&fn_id)
     }
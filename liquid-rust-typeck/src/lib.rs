@@ -3,17 +3,30 @@
 #![feature(or_patterns)]
 
 pub mod constraint;
+pub mod dump;
 pub mod env;
 pub mod glob_env;
+pub mod liveness;
 pub mod refineck;
 pub mod region_inference;
 
 use crate::{refineck::RefineChecker, region_inference::infer_regions};
 
+use std::collections::HashMap;
+
+use constraint::Constraint;
+use dump::{DumpConfig, Stage};
+use env::SpannedError;
 use glob_env::GlobEnv;
 use liquid_rust_core::{
-    ast::Program, freshen::NameFreshener, lower::TypeLowerer, name_check::NameChecker, ty::TyCtxt,
+    ast::{visitor::fn_refs, Program},
+    freshen::NameFreshener,
+    lower::TypeLowerer,
+    name_check::NameChecker,
+    names::{FnId, Local},
+    ty::TyCtxt,
 };
+use liquid_rust_fixpoint as fixpoint;
 pub use liquid_rust_fixpoint::solver::Safeness;
 
 #[macro_use]
@@ -22,35 +35,170 @@ extern crate liquid_rust_common;
 #[macro_use]
 extern crate liquid_rust_core;
 
-pub fn check_program<I, S>(program: Program<I, S>)
-where
+/// Checks every function of `program`, printing whether it is safe. Ownership errors are passed
+/// to `report` together with the function and the source of the statement where they were found.
+/// Locals are named in the errors as given by `local_name`.
+pub fn check_program<I, S>(
+    program: Program<I, S>,
+    dump: &DumpConfig,
+    local_name: impl Fn(&Local<S>) -> String,
+    mut report: impl FnMut(FnId<S>, Option<I>, String),
+) where
+    I: Clone,
     S: Eq + Copy + std::hash::Hash + std::fmt::Debug + std::fmt::Display,
 {
     let tcx = TyCtxt::new();
-    // println!("{}\n", program);
+    for (fn_id, fn_def) in program.iter() {
+        dump.dump(Stage::Core, fn_id.inner(), fn_def);
+    }
     NameChecker::new().check(&program);
-    let program = NameFreshener::new(&tcx).freshen(program);
+    let (program, names) = NameFreshener::new(&tcx).freshen(program);
+    // Dumps are named after the original name of the function.
+    let name = |fn_id: &FnId| names.fns[fn_id].inner().to_string();
+    for (fn_id, fn_def) in program.iter() {
+        dump.dump(Stage::Fresh, name(fn_id), fn_def);
+    }
+
+    let mut consts: Vec<_> = program
+        .measures()
+        .map(|(measure, decl)| constraint::lower_measure(&tcx, *measure, decl))
+        .collect();
+    // Predicate parameters are fresh across the program, so they can all be declared upfront.
+    let decls = program
+        .iter()
+        .map(|(_, def)| &def.ty)
+        .chain(program.externs().map(|(_, decl)| decl));
+    for decl in decls {
+        for (param, sorts) in &decl.pred_params {
+            consts.push(constraint::lower_pred_param(*param, sorts));
+        }
+    }
 
     let mut glob_env = GlobEnv::new();
+    for (fn_id, decl) in program.externs() {
+        glob_env.insert_fn_ty(*fn_id, TypeLowerer::lower_extern_fn(&tcx, decl));
+    }
+    // Region inference needs the type of every function that may be called, so we first
+    // lower all the signatures.
+    let mut lowered = Vec::new();
     for (fn_id, fn_def) in program.iter() {
         let (conts, fn_ty) = TypeLowerer::lower_fn_def(&tcx, &fn_def);
-        // println!("{}\n", fn_def);
-        let (conts, fn_ty) = infer_regions(&tcx, &fn_def, conts, fn_ty);
-        glob_env.insert_fn(*fn_id, fn_ty, conts);
-        // println!("{}\n", fn_def);
+        glob_env.insert_fn_ty(*fn_id, fn_ty.clone());
+        lowered.push((*fn_id, fn_def, conts, fn_ty));
+    }
+    let mut region_errors = HashMap::new();
+    for (fn_id, fn_def, conts, fn_ty) in lowered {
+        match infer_regions(&tcx, &glob_env, &fn_def, conts, fn_ty) {
+            Ok((conts, fn_ty)) => {
+                if dump.is_enabled(Stage::Regions) {
+                    let mut conts: Vec<_> = conts.iter().collect();
+                    conts.sort_by_key(|(cont_id, _)| **cont_id);
+                    let mut regions = format!("fn: {}", fn_ty);
+                    for (cont_id, cont_ty) in conts {
+                        regions.push_str(&format!("\nbb{}: {}", cont_id.as_usize(), cont_ty));
+                    }
+                    dump.dump(Stage::Regions, name(&fn_id), regions);
+                }
+                glob_env.insert_fn(fn_id, fn_ty, conts)
+            }
+            Err(errors) => {
+                region_errors.insert(fn_id, errors);
+            }
+        }
     }
 
-    for (fn_id, fn_def) in program.iter() {
-        let constraint = RefineChecker::new(&tcx, &glob_env, *fn_id).check(fn_def);
-        match constraint {
-            Ok(constraint) => {
-                let safeness = constraint.lower().solve().unwrap().tag;
-                println!("{:?}", safeness);
+    let solve = |name: String, constraint: Constraint| {
+        dump.dump(Stage::Constraint, &name, &constraint);
+        let constraint = constraint.lower();
+        dump.dump_with(Stage::Fq, &name, |w| {
+            fixpoint::write_query(w, &constraint, &consts)
+        });
+        constraint.solve(&consts).unwrap().tag
+    };
+
+    for group in query_groups(&program) {
+        let mut constraints = vec![];
+        let mut checked = 0;
+        for fn_id in &group {
+            let errors = match region_errors.remove(fn_id) {
+                Some(errors) => errors,
+                None => {
+                    let fn_def = program.get(fn_id).unwrap();
+                    match RefineChecker::new(&tcx, &glob_env, *fn_id).check(fn_def) {
+                        Ok(constraint) => {
+                            constraints.push(constraint);
+                            checked += 1;
+                            continue;
+                        }
+                        Err(errors) => errors,
+                    }
+                }
+            };
+            for SpannedError { error, source_info } in errors {
+                report(
+                    names.fns[fn_id],
+                    source_info,
+                    error.source(&names, &local_name).to_string(),
+                );
             }
-            Err(err) => {
-                println!("{:?}", err)
+        }
+        if checked > 0 {
+            // A query for several functions is named after all of them.
+            let name = group
+                .iter()
+                .map(|fn_id| dump.fn_name(name(fn_id)))
+                .collect::<Vec<_>>()
+                .join("+");
+            let safeness = solve(name, Constraint::Conj(constraints));
+            for _ in 0..checked {
+                println!("{:?}", safeness);
             }
         }
     }
+
+    for (impl_fn, trait_fn) in program.impls() {
+        let constraint =
+            RefineChecker::<I>::new(&tcx, &glob_env, *trait_fn).check_impl(*impl_fn, *trait_fn);
+        let safeness = solve(format!("{}.impl", name(impl_fn)), constraint);
+        println!("{:?}", safeness);
+    }
     // Ok(constraint.solve().unwrap().tag)
 }
+
+/// Groups the functions that must be solved in the same query. The kvars of a signature with
+/// holes, e.g., the captured state of a closure, are assumed by the body of the function and
+/// must be proven where the function is called or created. Solving them separately would let
+/// the body assume anything about them, so a function is grouped with the ones referring to it.
+fn query_groups<I>(program: &Program<I>) -> Vec<Vec<FnId>> {
+    fn find(parents: &HashMap<FnId, FnId>, mut fn_id: FnId) -> FnId {
+        while parents[&fn_id] != fn_id {
+            fn_id = parents[&fn_id];
+        }
+        fn_id
+    }
+
+    let mut parents: HashMap<FnId, FnId> =
+        program.iter().map(|(fn_id, _)| (*fn_id, *fn_id)).collect();
+    for (fn_id, fn_def) in program.iter() {
+        for callee in fn_refs(&fn_def.body) {
+            if program.get(&callee).map_or(false, |def| def.ty.has_holes()) {
+                let root = find(&parents, callee);
+                parents.insert(root, find(&parents, *fn_id));
+            }
+        }
+    }
+    let mut groups: HashMap<FnId, Vec<FnId>> = HashMap::new();
+    for (fn_id, _) in program.iter() {
+        groups
+            .entry(find(&parents, *fn_id))
+            .or_default()
+            .push(*fn_id);
+    }
+    groups
+        .into_iter()
+        .map(|(_, mut group)| {
+            group.sort();
+            group
+        })
+        .collect()
+}
@@ -0,0 +1,182 @@
+use std::collections::{HashMap, HashSet};
+
+use liquid_rust_core::{
+    ast::{Callee, ContDef, FnBody, FnDef, Operand, Place, Rvalue, Statement, StatementKind, Ty},
+    names::{ContId, Local},
+};
+
+/// The locals that are live at the start of each continuation of a function. A local is live if
+/// it may be used later, or if the continuation expects it to be initialized. Loans held by a
+/// dead local can be ended, as in rustc's non-lexical lifetimes.
+#[derive(Default)]
+pub struct Liveness {
+    conts: HashMap<ContId, ContLiveness>,
+}
+
+struct ContLiveness {
+    params: Vec<Local>,
+    live: HashSet<Local>,
+}
+
+impl Liveness {
+    pub fn new<I>(func: &FnDef<I>) -> Self {
+        let mut defs = vec![];
+        collect_cont_defs(&func.body, &mut defs);
+
+        let conts = defs
+            .iter()
+            .map(|def| {
+                let cont_liveness = ContLiveness {
+                    params: def.params.clone(),
+                    live: required_locals(def),
+                };
+                (def.name, cont_liveness)
+            })
+            .collect();
+        let mut liveness = Liveness { conts };
+
+        // The live sets only grow, so we iterate until none of them changes.
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for def in &defs {
+                let live = liveness.live_in(&def.body);
+                let cont_live = &mut liveness.conts.get_mut(&def.name).unwrap().live;
+                for x in live {
+                    changed |= cont_live.insert(x);
+                }
+            }
+        }
+        liveness
+    }
+
+    /// Returns the locals that are live before executing `body`.
+    pub fn live_in<I>(&self, body: &FnBody<I>) -> HashSet<Local> {
+        match body {
+            FnBody::LetCont(_, rest) => self.live_in(rest),
+            FnBody::Ite { discr, then, else_ } => {
+                let mut live = self.live_in(then);
+                live.extend(self.live_in(else_));
+                live.insert(discr.base);
+                live
+            }
+            FnBody::Call {
+                func,
+                args,
+                destination,
+            } => {
+                let mut live = HashSet::new();
+                if let Some((place, ret)) = destination {
+                    live = self.live_at_jump(*ret, &[]);
+                    kill_or_use(place, &mut live);
+                }
+                if let Callee::Place(place) = func {
+                    live.insert(place.base);
+                }
+                live.extend(args);
+                live
+            }
+            FnBody::Jump { target, args } => self.live_at_jump(*target, args),
+            FnBody::Seq(stmnt, rest) => {
+                let mut live = self.live_in(rest);
+                transfer_stmnt(stmnt, &mut live);
+                live
+            }
+            FnBody::Abort => HashSet::new(),
+        }
+    }
+
+    fn live_at_jump(&self, target: ContId, args: &[Local]) -> HashSet<Local> {
+        let mut live: HashSet<_> = args.iter().copied().collect();
+        // The return continuation of the function only takes its arguments.
+        if let Some(cont) = self.conts.get(&target) {
+            live.extend(cont.live.iter().filter(|x| !cont.params.contains(x)));
+        }
+        live
+    }
+}
+
+fn collect_cont_defs<'a, I>(body: &'a FnBody<I>, defs: &mut Vec<&'a ContDef<I>>) {
+    match body {
+        FnBody::LetCont(cont_defs, rest) => {
+            for def in cont_defs {
+                defs.push(def);
+                collect_cont_defs(&def.body, defs);
+            }
+            collect_cont_defs(rest, defs);
+        }
+        FnBody::Ite { then, else_, .. } => {
+            collect_cont_defs(then, defs);
+            collect_cont_defs(else_, defs);
+        }
+        FnBody::Seq(_, rest) => collect_cont_defs(rest, defs),
+        FnBody::Call { .. } | FnBody::Jump { .. } | FnBody::Abort => {}
+    }
+}
+
+/// The locals the type of a continuation expects to be initialized. Dropping them before jumping
+/// to the continuation would make the jump ill-typed.
+fn required_locals<I>(def: &ContDef<I>) -> HashSet<Local> {
+    def.ty
+        .locals
+        .iter()
+        .filter(|(_, l)| {
+            def.ty
+                .heap
+                .iter()
+                .any(|(l2, ty)| l == l2 && !matches!(ty, Ty::Uninit(_)))
+        })
+        .map(|(x, _)| *x)
+        .chain(def.params.iter().copied())
+        .collect()
+}
+
+fn transfer_stmnt<I>(stmnt: &Statement<I>, live: &mut HashSet<Local>) {
+    match &stmnt.kind {
+        StatementKind::Let(x, _) => {
+            live.remove(x);
+        }
+        StatementKind::Assign(place, rvalue) => {
+            kill_or_use(place, live);
+            match rvalue {
+                Rvalue::Use(op) | Rvalue::UnaryOp(_, op) => use_operand(op, live),
+                Rvalue::BinaryOp(_, op1, op2) | Rvalue::CheckedBinaryOp(_, op1, op2) => {
+                    use_operand(op1, live);
+                    use_operand(op2, live);
+                }
+                Rvalue::Ref(_, place) | Rvalue::TwoPhaseRef(place) => {
+                    live.insert(place.base);
+                }
+                Rvalue::Closure(_, captures) => {
+                    for op in captures {
+                        use_operand(op, live);
+                    }
+                }
+                Rvalue::FnPtr(_) => {}
+            }
+        }
+        StatementKind::Drop(place) => {
+            live.insert(place.base);
+        }
+        // Predicates in statements refer to locations rather than locals.
+        StatementKind::Assert(_) | StatementKind::Assume(_) | StatementKind::Nop => {}
+    }
+}
+
+/// Assigning to a local overwrites it, but assigning to a part of it uses the rest.
+fn kill_or_use(place: &Place, live: &mut HashSet<Local>) {
+    if place.projs.is_empty() {
+        live.remove(&place.base);
+    } else {
+        live.insert(place.base);
+    }
+}
+
+fn use_operand(op: &Operand, live: &mut HashSet<Local>) {
+    match op {
+        Operand::Copy(place) | Operand::Move(place) => {
+            live.insert(place.base);
+        }
+        Operand::Constant(_) => {}
+    }
+}
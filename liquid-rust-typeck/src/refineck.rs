@@ -1,31 +1,45 @@
 use crate::{
     constraint::Constraint,
-    env::{OwnershipError, RefKind},
+    env::{OwnershipError, RefKind, SpannedError},
     glob_env::GlobEnv,
+    liveness::Liveness,
 };
-use ast::{FnBody, StatementKind};
+use ast::{Callee, FnBody, StatementKind};
 use liquid_rust_core::{
     ast::{self, ContDef, FnDef, Rvalue, Statement},
+    lower::TypeLowerer,
     names::*,
-    ty::{self, pred, subst::Subst, BaseTy, ContTy, Pred, Ty, TyCtxt},
+    ty::{self, pred, BaseTy, ContTy, Pred, Ty, TyCtxt},
 };
 
 use crate::env::Env;
 
-pub struct RefineChecker<'a> {
+pub struct RefineChecker<'a, I> {
     tcx: &'a TyCtxt,
     fn_id: FnId,
     glob_env: &'a GlobEnv,
-    errors: Vec<OwnershipError>,
+    errors: Vec<SpannedError<I>>,
+    /// The source of the last statement checked, to which errors are attributed.
+    source_info: Option<I>,
+    /// The termination metric of the function, which must decrease at recursive calls.
+    metric: Option<Pred>,
+    /// The termination metrics of the loops whose body is being checked, which must decrease
+    /// at each jump to their head.
+    loop_metrics: Vec<(ContId, Pred)>,
+    liveness: Liveness,
 }
 
-impl<'a> RefineChecker<'a> {
+impl<'a, I: Clone> RefineChecker<'a, I> {
     pub fn new(tcx: &'a TyCtxt, glob_env: &'a GlobEnv, fn_id: FnId) -> Self {
         Self {
             tcx,
             fn_id,
             glob_env,
             errors: vec![],
+            source_info: None,
+            metric: None,
+            loop_metrics: vec![],
+            liveness: Liveness::default(),
         }
     }
 
@@ -33,13 +47,19 @@ impl<'a> RefineChecker<'a> {
         self.glob_env.get_cont_ty(self.fn_id, cont_id).unwrap()
     }
 
-    pub fn check<I>(mut self, func: &FnDef<I>) -> Result<Constraint, Vec<OwnershipError>> {
+    pub fn check(mut self, func: &FnDef<I>) -> Result<Constraint, Vec<SpannedError<I>>> {
         let fn_ty = self.glob_env.get_ty(self.fn_id).unwrap();
         let mut env = Env::new(self.tcx);
+        env.assume_outlives(&fn_ty.outlives);
         env.insert_locals(fn_ty.inputs(&func.params));
         env.extend_heap(&fn_ty.in_heap);
+        self.metric = func
+            .decreases
+            .as_ref()
+            .map(|metric| TypeLowerer::lower_stmnt_pred(self.tcx, metric));
+        self.liveness = Liveness::new(func);
 
-        let constraint = self.check_body(&mut env, &func.body);
+        let constraint = self.check_live_body(&mut env, &func.body);
 
         if self.errors.is_empty() {
             Ok(Constraint::from_bindings(
@@ -52,7 +72,7 @@ impl<'a> RefineChecker<'a> {
     }
 
     #[allow(clippy::too_many_lines)]
-    pub fn check_body<I>(&mut self, env: &mut Env, body: &FnBody<I>) -> Constraint {
+    pub fn check_body(&mut self, env: &mut Env, body: &FnBody<I>) -> Constraint {
         match body {
             FnBody::LetCont(defs, rest) => {
                 let mut vec = Vec::new();
@@ -79,52 +99,85 @@ impl<'a> RefineChecker<'a> {
                 args,
                 destination,
             } => {
-                let fn_ty = self.glob_env.get_ty(*func).unwrap();
+                for arg in args {
+                    self.activate(*arg, env);
+                }
+                if !self.check_initialized_args(args, env) {
+                    return Constraint::True;
+                }
+                let fn_ty = match func {
+                    Callee::Fn(func) => self.glob_env.get_ty(*func).unwrap().clone(),
+                    Callee::Place(place) => {
+                        if self.activate(place.base, env) {
+                            self.check_ownership_safety(RefKind::Shared, place, env);
+                        }
+                        env.lookup_fn(place).clone()
+                    }
+                };
 
                 let (in_heap, inputs, out_heap, outputs, output) =
-                    env.instantiate_fn_call(fn_ty, args);
+                    env.instantiate_fn_call(&fn_ty, args);
 
-                let c1 = Constraint::Conj(
-                    inputs
-                        .into_iter()
-                        .map(|(x, l)| {
-                            let ty1 = &self
-                                .tcx
-                                .selfify(&env.lookup(&ast::Place::from(x)), pred::Place::from(l));
-                            let ty2 = &in_heap[&l];
-                            env.subtyping(ty1, &in_heap, ty2)
-                        })
-                        .collect(),
-                );
+                let mut c1 = env.check_args(&in_heap, inputs);
+                if let (Callee::Fn(func), Some(metric)) = (func, &self.metric) {
+                    if *func == self.fn_id {
+                        let c = env.check_decreases(&fn_ty.in_heap, &fn_ty.inputs(args), metric);
+                        c1 = Constraint::Conj(vec![c1, c]);
+                    }
+                }
                 if let Some((place, ret)) = destination {
                     let (c2, bindings) = env.capture_bindings(|env| {
                         env.extend_heap(&out_heap);
                         env.insert_locals(outputs);
-                        env.update(place, out_heap[&output].clone());
-
+                        let mut constraints = vec![env.update(place, out_heap[&output].clone())];
                         for arg in args {
-                            env.drop(&ast::Place::from(*arg));
+                            constraints.push(env.drop(&ast::Place::from(*arg)));
                         }
-                        self.check_jump(env, self.cont_ty(*ret), &[])
+                        constraints.push(env.check_jump(self.cont_ty(*ret), &[]));
+                        Constraint::Conj(constraints)
                     });
+                    self.report_env_errors(env);
                     Constraint::Conj(vec![c1, Constraint::from_bindings(bindings, c2)])
                 } else {
                     c1
                 }
             }
             FnBody::Jump { target, args } => {
+                if !self.check_initialized_args(args, env) {
+                    return Constraint::True;
+                }
                 let cont_ty = self.cont_ty(*target);
-                self.check_jump(env, cont_ty, args)
+                let c = env.check_jump(cont_ty, args);
+                match self.loop_metrics.iter().find(|(cont, _)| cont == target) {
+                    Some((_, metric)) => Constraint::Conj(vec![
+                        c,
+                        env.check_decreases(&cont_ty.heap, &cont_ty.locals(args), metric),
+                    ]),
+                    None => c,
+                }
+            }
+            // The rest of the body can assume the predicate without checking it.
+            FnBody::Seq(
+                Statement {
+                    kind: StatementKind::Assume(pred),
+                    ..
+                },
+                rest,
+            ) => {
+                let pred = TypeLowerer::lower_stmnt_pred(self.tcx, pred);
+                Constraint::guard(&pred, self.check_body(env, rest))
             }
             FnBody::Seq(stmnt, rest) => {
+                self.source_info = Some(stmnt.source_info.clone());
                 let (c, bindings) = env.capture_bindings(|env| self.check_stmnt(env, stmnt));
+                self.report_env_errors(env);
                 // if !matches!(&stmnt.kind, StatementKind::Nop) {
                 //     println!("{}", stmnt);
                 //     println!("{}", env.heap());
                 //     println!("{}\n", env.locals());
                 // }
                 Constraint::Conj(vec![
-                    Constraint::from_bindings(bindings, self.check_body(env, rest)),
+                    Constraint::from_bindings(bindings, self.check_live_body(env, rest)),
                     c,
                 ])
             }
@@ -132,76 +185,103 @@ impl<'a> RefineChecker<'a> {
         }
     }
 
-    fn check_jump(&self, env: &Env, cont_ty: &ContTy, args: &[Local]) -> Constraint {
-        let subst = Subst::infer(
-            env.heap(),
-            env.locals(),
-            &cont_ty.heap,
-            &cont_ty.locals(args),
-        );
-
-        let heap = &subst.apply(self.tcx, &cont_ty.heap);
-        let locals = subst.apply(self.tcx, &cont_ty.locals(args));
+    /// Ends the loans of the locals that are dead before `body` and checks it.
+    fn check_live_body(&mut self, env: &mut Env, body: &FnBody<I>) -> Constraint {
+        let live = self.liveness.live_in(body);
+        let (c, bindings) = env.capture_bindings(|env| env.end_dead_loans(&live));
+        self.report_env_errors(env);
+        Constraint::Conj(vec![
+            Constraint::from_bindings(bindings, self.check_body(env, body)),
+            c,
+        ])
+    }
 
-        Constraint::Conj(
-            locals
-                .into_iter()
-                .map(|(x, l)| {
-                    let ty1 = &self
-                        .tcx
-                        .selfify(&env.lookup(&ast::Place::from(x)), pred::Place::from(l));
-                    let ty2 = &heap[&l];
-                    env.subtyping(ty1, heap, ty2)
-                })
-                .collect(),
-        )
+    /// Checks that the signature of `impl_id` is a subtype of the one of `trait_id`, i.e., that
+    /// `impl_id` accepts the inputs of `trait_id` and its outputs satisfy the ones of `trait_id`.
+    pub fn check_impl(self, impl_id: FnId, trait_id: FnId) -> Constraint {
+        let trait_ty = self.glob_env.get_ty(trait_id).unwrap();
+        let impl_ty = self.glob_env.get_ty(impl_id).unwrap();
+        Env::new(self.tcx).fn_subtyping(impl_ty, trait_ty)
     }
 
-    fn check_cont_def<I>(&mut self, env: &mut Env, def: &ContDef<I>) -> Constraint {
+    fn check_cont_def(&mut self, env: &mut Env, def: &ContDef<I>) -> Constraint {
         let snapshot = env.snapshot_without_locals();
 
         let cont_ty = self.cont_ty(def.name);
         let bindings = cont_ty.heap.bindings();
         env.insert_locals(cont_ty.locals(&def.params));
         env.extend_heap(&cont_ty.heap);
-        let c = self.check_body(env, &def.body);
+        if let Some(metric) = &def.ty.decreases {
+            let metric = TypeLowerer::lower_stmnt_pred(self.tcx, metric);
+            self.loop_metrics.push((def.name, metric));
+        }
+        let c = self.check_live_body(env, &def.body);
+        if def.ty.decreases.is_some() {
+            self.loop_metrics.pop();
+        }
 
         env.rollback_to(snapshot);
         Constraint::from_bindings(bindings, c)
     }
 
-    fn check_stmnt<I>(&mut self, env: &mut Env, stmnt: &Statement<I>) -> Constraint {
+    fn check_stmnt(&mut self, env: &mut Env, stmnt: &Statement<I>) -> Constraint {
         match &stmnt.kind {
             StatementKind::Let(x, layout) => {
                 env.alloc(*x, self.tcx.mk_ty_for_layout(layout));
                 Constraint::True
             }
             StatementKind::Assign(place, rvalue) => {
-                let ty = self.check_rvalue(rvalue, env);
-                self.check_ownership_safety(RefKind::Mut, place, env);
-                env.update(place, ty);
-                Constraint::True
+                let (ty, c) = self.check_rvalue(rvalue, env);
+                if place.projs.is_empty() || self.activate(place.base, env) {
+                    self.check_ownership_safety(RefKind::Mut, place, env);
+                }
+                let c2 = env.update(place, ty);
+                // Two-phase borrows stored anywhere else are rejected when building the program.
+                if let Rvalue::TwoPhaseRef(_) = rvalue {
+                    assert!(
+                        place.projs.is_empty(),
+                        "two-phase borrows must be stored in a local"
+                    );
+                    env.reserve(place.base);
+                }
+                Constraint::Conj(vec![c, c2])
             }
             StatementKind::Drop(place) => {
                 self.check_ownership_safety(RefKind::Owned, place, env);
                 env.drop(place)
             }
+            StatementKind::Assert(pred) => {
+                Constraint::from_pred(&TypeLowerer::lower_stmnt_pred(self.tcx, pred))
+            }
+            StatementKind::Assume(_) => unreachable!("assumptions are checked with the body"),
             StatementKind::Nop => Constraint::True,
         }
     }
 
-    fn check_rvalue(&mut self, rvalue: &Rvalue, env: &mut Env) -> Ty {
+    fn check_rvalue(&mut self, rvalue: &Rvalue, env: &mut Env) -> (Ty, Constraint) {
         let tcx = self.tcx;
-        match rvalue {
+        let ty = match rvalue {
             ast::Rvalue::Use(op) => {
                 let (_, ty) = self.check_operand(op, env);
                 ty
             }
             ast::Rvalue::Ref(bk, place) => {
-                self.check_ownership_safety(RefKind::from(*bk), place, env);
+                if place.projs.is_empty() || self.activate(place.base, env) {
+                    self.check_ownership_safety(RefKind::from(*bk), place, env);
+                }
                 let l = env.borrow(place);
                 self.tcx.mk_ref(*bk, ty::Region::from(place.clone()), l)
             }
+            // The borrow is only reserved, so it is checked like a shared one until the
+            // reference is used.
+            ast::Rvalue::TwoPhaseRef(place) => {
+                if place.projs.is_empty() || self.activate(place.base, env) {
+                    self.check_ownership_safety(RefKind::Shared, place, env);
+                }
+                let l = env.borrow(place);
+                self.tcx
+                    .mk_ref(ast::BorrowKind::Mut, ty::Region::from(place.clone()), l)
+            }
             ast::Rvalue::BinaryOp(bin_op, op1, op2) => self.check_bin_op(*bin_op, op1, op2, env),
             ast::Rvalue::CheckedBinaryOp(bin_op, op1, op2) => {
                 let ty = self.check_bin_op(*bin_op, op1, op2, env);
@@ -210,7 +290,35 @@ impl<'a> RefineChecker<'a> {
                 tcx.mk_tuple(tup!(f1 => ty, f2 => tcx.types.bool()))
             }
             ast::Rvalue::UnaryOp(un_op, op) => self.check_un_op(*un_op, op, env),
-        }
+            ast::Rvalue::Closure(func, captures) => {
+                // The captured state is packed in a tuple and checked against the type the closure
+                // expects in its first argument, either by value or behind a reference. The type
+                // of the state may mention the other arguments, so it must hold for any of them.
+                let decl = self.glob_env.get_ty(*func).unwrap();
+                let (l, closure_decl) = decl.split_first_input();
+                let tup = captures
+                    .iter()
+                    .map(|op| (tcx.fresh::<Field>(), self.check_operand(op, env).1))
+                    .collect();
+                let state_loc = match decl.in_heap[&l].kind() {
+                    ty::TyKind::Ref(.., state_loc) => *state_loc,
+                    _ => l,
+                };
+                let c = env.subtyping(&tcx.mk_tuple(tup), &decl.in_heap, &decl.in_heap[&state_loc]);
+                let bindings = decl
+                    .in_heap
+                    .bindings()
+                    .into_iter()
+                    .filter(|(l2, _)| *l2 != l && *l2 != state_loc)
+                    .collect();
+                return (
+                    tcx.mk_fn(closure_decl),
+                    Constraint::from_bindings(bindings, c),
+                );
+            }
+            ast::Rvalue::FnPtr(func) => tcx.mk_fn(self.glob_env.get_ty(*func).unwrap().clone()),
+        };
+        (ty, Constraint::True)
     }
 
     fn check_un_op(&mut self, un_op: ast::UnOp, op: &ast::Operand, env: &mut Env) -> Ty {
@@ -279,13 +387,19 @@ impl<'a> RefineChecker<'a> {
         let tcx = self.tcx;
         match operand {
             ast::Operand::Copy(place) => {
+                self.activate(place.base, env);
+                let initialized = self.check_initialized(place, env);
                 let ty = tcx.selfify(env.lookup(place), env.resolve_place(place));
-                assert!(ty.is_copy());
+                assert!(ty.may_be_copied() || !initialized);
                 (tcx.mk_pred_place(env.resolve_place(place)), ty)
             }
             ast::Operand::Move(place) => {
+                let activated = self.activate(place.base, env);
+                self.check_initialized(place, env);
                 let ty = tcx.selfify(env.lookup(place), env.resolve_place(place));
-                self.check_ownership_safety(RefKind::Owned, place, env);
+                if activated {
+                    self.check_ownership_safety(RefKind::Owned, place, env);
+                }
                 let pred = tcx.mk_pred_place(env.resolve_place(place));
                 env.drop(place);
                 (pred, ty)
@@ -311,7 +425,50 @@ impl<'a> RefineChecker<'a> {
         }
     }
 
-    fn report_ownership_error(&mut self, err: OwnershipError) {
-        self.errors.push(err);
+    /// Checks that no part of the value in `place` has been moved out before using it. Returns
+    /// whether it is initialized.
+    fn check_initialized(&mut self, place: &ast::Place, env: &Env) -> bool {
+        match env.check_initialized(place) {
+            Ok(()) => true,
+            Err(err) => {
+                self.report_ownership_error(err);
+                false
+            }
+        }
+    }
+
+    /// Checks the arguments of a call or a jump. The rest of the body isn't checked if some of
+    /// them was moved out, since its type wouldn't match the expected one.
+    fn check_initialized_args(&mut self, args: &[Local], env: &Env) -> bool {
+        let mut initialized = true;
+        for arg in args {
+            initialized &= self.check_initialized(&ast::Place::from(*arg), env);
+        }
+        initialized
+    }
+
+    /// Activates the two-phase borrow stored in `x` when it is first used. Returns whether the
+    /// activation succeeded, since accesses through `x` would report the same conflict again.
+    fn activate(&mut self, x: Local, env: &mut Env) -> bool {
+        match env.activate(x) {
+            Ok(()) => true,
+            Err(err) => {
+                self.report_ownership_error(err);
+                false
+            }
+        }
+    }
+
+    fn report_env_errors(&mut self, env: &mut Env) {
+        for error in env.take_errors() {
+            self.report_ownership_error(error);
+        }
+    }
+
+    fn report_ownership_error(&mut self, error: OwnershipError) {
+        self.errors.push(SpannedError {
+            error,
+            source_info: self.source_info.clone(),
+        });
     }
 }
@@ -1,72 +1,151 @@
 use std::collections::{HashMap, HashSet};
 
-use crate::env::Env;
+use crate::{
+    env::{Env, OwnershipError, SpannedError},
+    glob_env::GlobEnv,
+    liveness::Liveness,
+};
 use ast::{FnDef, Place, StatementKind};
 use liquid_rust_common::data_structures::WorkQueue;
 use liquid_rust_core::{
     ast::{
         self,
         visitor::{self as vis, Visitor},
-        FnBody, Statement,
+        Callee, FnBody, Statement,
     },
-    names::{ContId, Field},
-    ty::{self, BaseTy, ContTy, Heap, Ty, TyCtxt, TyS},
+    names::{ContId, Field, Local},
+    ty::{self, BaseTy, ContTy, Heap, Ty, TyCtxt, TyS, UniversalRegion},
 };
 use ty::FnDecl;
 
-pub fn infer_regions<I>(
+/// The continuations and signature of a function with their regions inferred.
+type Inferred = (HashMap<ContId, ContTy>, FnDecl);
+
+pub fn infer_regions<I: Clone>(
     tcx: &TyCtxt,
+    glob_env: &GlobEnv,
     func: &FnDef<I>,
     conts: HashMap<ContId, ContTy>,
     fn_ty: FnDecl,
-) -> (HashMap<ContId, ContTy>, FnDecl) {
-    let solution = RegionInferer::new(tcx, &conts).infer(func, &fn_ty);
-    solution.fix_regions(tcx, fn_ty, conts)
+) -> Result<Inferred, Vec<SpannedError<I>>> {
+    let solution = RegionInferer::new(tcx, glob_env, &conts).infer(func, &fn_ty)?;
+    Ok(solution.fix_regions(tcx, fn_ty, conts))
 }
 
 // Infer Regions
 
-struct RegionInferer<'a> {
+struct RegionInferer<'a, I> {
+    glob_env: &'a GlobEnv,
     conts: &'a HashMap<ContId, ContTy>,
     tcx: &'a TyCtxt,
     env: Env<'a>,
-    constraints: Constraints,
+    constraints: Constraints<I>,
+    liveness: Liveness,
+    /// Errors found while joining the types of the places a reference may point to.
+    errors: Vec<SpannedError<I>>,
 }
 
-impl<'a> RegionInferer<'a> {
-    pub fn new(tcx: &'a TyCtxt, conts: &'a HashMap<ContId, ContTy>) -> Self {
+impl<'a, I: Clone> RegionInferer<'a, I> {
+    pub fn new(tcx: &'a TyCtxt, glob_env: &'a GlobEnv, conts: &'a HashMap<ContId, ContTy>) -> Self {
         RegionInferer {
+            glob_env,
             conts,
             tcx,
             env: Env::new(tcx),
             constraints: Constraints::new(),
+            liveness: Liveness::default(),
+            errors: vec![],
         }
     }
 
-    pub fn infer<I>(mut self, func: &ast::FnDef<I>, fn_ty: &FnDecl) -> Solution {
+    pub fn infer(
+        mut self,
+        func: &ast::FnDef<I>,
+        fn_ty: &FnDecl,
+    ) -> Result<Solution, Vec<SpannedError<I>>> {
+        self.env.assume_outlives(&fn_ty.outlives);
         self.env.insert_locals(fn_ty.inputs(&func.params));
         self.env.extend_heap(&fn_ty.in_heap);
-        self.visit_fn_body(&func.body);
-        self.constraints.solve()
+        self.liveness = Liveness::new(func);
+        self.visit_live_body(&func.body);
+        let (solution, errors) = self.constraints.solve(&self.env);
+        self.errors.extend(errors);
+        if self.errors.is_empty() {
+            Ok(solution)
+        } else {
+            Err(self.errors)
+        }
+    }
+
+    /// Ends the loans of the locals that are dead before `body`, as the checker does, and visits
+    /// it.
+    fn visit_live_body(&mut self, body: &FnBody<I>) {
+        self.env.end_dead_loans(&self.liveness.live_in(body));
+        self.take_env_errors();
+        self.visit_fn_body(body);
+    }
+
+    /// Attributes the errors found by the environment to the current statement.
+    fn take_env_errors(&mut self) {
+        for error in self.env.take_errors() {
+            self.errors.push(SpannedError {
+                error,
+                source_info: self.constraints.source_info.clone(),
+            });
+        }
+    }
+
+    fn jump(&mut self, target: ContId, args: &[Local]) {
+        let cont_ty = &self.conts[&target];
+        for (x, l) in cont_ty.locals(args) {
+            let ty1 = self.env.lookup(&Place::from(x));
+            let ty2 = &cont_ty.heap[&l];
+            subtyping(
+                &mut self.constraints,
+                self.env.heap(),
+                ty1,
+                &cont_ty.heap,
+                ty2,
+            );
+        }
     }
 }
 
-impl<I> Visitor<I> for RegionInferer<'_> {
+impl<I: Clone> Visitor<I> for RegionInferer<'_, I> {
     fn visit_fn_body(&mut self, body: &FnBody<I>) {
         match body {
-            FnBody::Jump { target, args } => {
-                let cont_ty = &self.conts[target];
-                for (x, l) in cont_ty.locals(args) {
+            FnBody::Jump { target, args } => self.jump(*target, args),
+            FnBody::Call {
+                func,
+                args,
+                destination,
+            } => {
+                let fn_ty = match func {
+                    Callee::Fn(func) => self.glob_env.get_ty(*func).unwrap().clone(),
+                    Callee::Place(place) => self.env.lookup_fn(place).clone(),
+                };
+                let (in_heap, inputs, out_heap, outputs, output) =
+                    self.env.instantiate_fn_call(&fn_ty, args);
+                for (x, l) in inputs {
                     let ty1 = self.env.lookup(&Place::from(x));
-                    let ty2 = &cont_ty.heap[&l];
                     subtyping(
                         &mut self.constraints,
                         self.env.heap(),
                         ty1,
-                        &cont_ty.heap,
-                        ty2,
+                        &in_heap,
+                        &in_heap[&l],
                     );
                 }
+                if let Some((place, ret)) = destination {
+                    self.env.extend_heap(&out_heap);
+                    self.env.insert_locals(outputs);
+                    self.env.update(place, out_heap[&output].clone());
+                    for arg in args {
+                        self.env.drop(&Place::from(*arg));
+                    }
+                    self.take_env_errors();
+                    self.jump(*ret, &[]);
+                }
             }
             FnBody::Ite { then, else_, .. } => {
                 let snapshot = self.env.snapshot();
@@ -81,11 +160,18 @@ impl<I> Visitor<I> for RegionInferer<'_> {
                     let locals = cont_ty.locals(&def.params);
                     self.env.insert_locals(locals);
                     self.env.extend_heap(&cont_ty.heap);
-                    self.visit_cont_def(def);
+                    self.visit_live_body(&def.body);
                     self.env.rollback_to(snapshot);
                 }
                 self.visit_fn_body(rest);
             }
+            FnBody::Seq(stmnt, rest) => {
+                self.constraints
+                    .set_source_info(Some(stmnt.source_info.clone()));
+                self.visit_stmnt(stmnt);
+                self.take_env_errors();
+                self.visit_live_body(rest);
+            }
             _ => vis::walk_fn_body(self, body),
         }
     }
@@ -97,20 +183,20 @@ impl<I> Visitor<I> for RegionInferer<'_> {
                 self.env.alloc(*local, ty);
             }
             StatementKind::Assign(place, rvalue) => {
-                let ty = synth(rvalue, self.tcx, &mut self.env);
+                let ty = synth(rvalue, self.tcx, self.glob_env, &mut self.env);
                 self.env.update(place, ty);
             }
             StatementKind::Drop(place) => {
                 self.env.drop(place);
             }
-            StatementKind::Nop => {}
+            StatementKind::Assert(_) | StatementKind::Assume(_) | StatementKind::Nop => {}
         }
     }
 }
 
 // Synth
 
-fn synth(rvalue: &ast::Rvalue, tcx: &TyCtxt, env: &mut Env) -> Ty {
+fn synth(rvalue: &ast::Rvalue, tcx: &TyCtxt, glob_env: &GlobEnv, env: &mut Env) -> Ty {
     match rvalue {
         ast::Rvalue::Use(ast::Operand::Constant(c)) => tcx.mk_refine(c.base_ty(), tcx.preds.tt()),
         ast::Rvalue::Use(ast::Operand::Move(place) | ast::Operand::Copy(place)) => {
@@ -121,6 +207,10 @@ fn synth(rvalue: &ast::Rvalue, tcx: &TyCtxt, env: &mut Env) -> Ty {
             let l = env.borrow(place);
             tcx.mk_ref(*bk, ty::Region::from(place.clone()), l)
         }
+        ast::Rvalue::TwoPhaseRef(place) => {
+            let l = env.borrow(place);
+            tcx.mk_ref(ast::BorrowKind::Mut, ty::Region::from(place.clone()), l)
+        }
         ast::Rvalue::BinaryOp(bin_op, ..) => ty_for_bin_op(*bin_op, tcx),
         ast::Rvalue::CheckedBinaryOp(bin_op, ..) => {
             let ty = ty_for_bin_op(*bin_op, tcx);
@@ -130,6 +220,11 @@ fn synth(rvalue: &ast::Rvalue, tcx: &TyCtxt, env: &mut Env) -> Ty {
             ast::UnOp::Not => tcx.mk_refine(BaseTy::Bool, tcx.preds.tt()),
             ast::UnOp::Neg => tcx.mk_refine(BaseTy::Int, tcx.preds.tt()),
         },
+        ast::Rvalue::Closure(func, _) => {
+            let (_, decl) = glob_env.get_ty(*func).unwrap().split_first_input();
+            tcx.mk_fn(decl)
+        }
+        ast::Rvalue::FnPtr(func) => tcx.mk_fn(glob_env.get_ty(*func).unwrap().clone()),
     }
 }
 
@@ -142,14 +237,16 @@ fn ty_for_bin_op(bin_op: ast::BinOp, tcx: &TyCtxt) -> Ty {
     tcx.mk_refine(bty, tcx.preds.tt())
 }
 
-fn subtyping(
-    constraints: &mut Constraints,
+fn subtyping<I: Clone>(
+    constraints: &mut Constraints<I>,
     heap1: &ty::Heap,
     ty1: &TyS,
     heap2: &ty::Heap,
     ty2: &TyS,
 ) {
     match (ty1.kind(), ty2.kind()) {
+        (ty::TyKind::Indexed(ty1, _), _) => subtyping(constraints, heap1, ty1, heap2, ty2),
+        (_, ty::TyKind::Indexed(ty2, _)) => subtyping(constraints, heap1, ty1, heap2, ty2),
         (ty::TyKind::Tuple(tup1), ty::TyKind::Tuple(tup2)) if tup1.len() == tup2.len() => {
             for (ty1, ty2) in tup1.types().zip(tup2.types()) {
                 subtyping(constraints, heap1, ty1, heap2, ty2);
@@ -163,42 +260,80 @@ fn subtyping(
             subtyping(constraints, heap1, &heap1[l1], heap2, &heap2[l2]);
         }
         (ty::TyKind::Refine(bty1, ..), ty::TyKind::Refine(bty2, ..)) if bty1 == bty2 => {}
+        (ty::TyKind::Vec(ty1, ..), ty::TyKind::Vec(ty2, ..)) => {
+            subtyping(constraints, heap1, ty1, heap2, ty2);
+        }
+        (ty::TyKind::Param(param1), ty::TyKind::Param(param2)) if param1 == param2 => {}
+        (ty::TyKind::Fn(_), ty::TyKind::Fn(_)) => {}
         (_, ty::TyKind::Uninit(n)) if ty1.size() == *n => {}
+        // A value that was moved out, which is reported by the checker.
+        (ty::TyKind::Uninit(_), _) => {}
         _ => bug!("{} <: {}", ty1, ty2),
     }
 }
 
 // Constraints
 
-#[derive(Default)]
-pub struct Constraints(Vec<(ty::Region, ty::Region)>);
+pub struct Constraints<I = ()> {
+    constraints: Vec<(ty::Region, ty::Region, Option<I>)>,
+    /// The source of the statement the constraints being added come from.
+    source_info: Option<I>,
+}
 
-impl Constraints {
+impl<I> Default for Constraints<I> {
+    fn default() -> Self {
+        Self {
+            constraints: Vec::new(),
+            source_info: None,
+        }
+    }
+}
+
+impl<I: Clone> Constraints<I> {
     pub fn new() -> Self {
-        Self(Vec::new())
+        Self::default()
+    }
+
+    pub fn set_source_info(&mut self, source_info: Option<I>) {
+        self.source_info = source_info;
     }
 
     pub fn add(&mut self, r1: ty::Region, r2: ty::Region) {
-        self.0.push((r1, r2))
+        self.constraints.push((r1, r2, self.source_info.clone()))
     }
 
-    pub fn solve(self) -> Solution {
+    /// Computes the least solution of the constraints with an inference variable on the right
+    /// and then checks that every constraint holds under that solution. The solution is returned
+    /// together with the constraints that don't hold, e.g., when it requires a region outliving
+    /// two unrelated universal regions.
+    pub fn solve(self, env: &Env) -> (Solution, Vec<SpannedError<I>>) {
         let mut edges: HashMap<_, Vec<_>> = HashMap::new();
         let mut map: HashMap<_, HashSet<_>> = HashMap::new();
+        let mut universals: HashMap<_, HashSet<_>> = HashMap::new();
         let mut dirty_queue = WorkQueue::with_capacity(edges.len());
-        for (r1, r2) in self.0 {
+        for (r1, r2, _) in &self.constraints {
             match (r1, r2) {
                 (ty::Region::Infer(rvid1), ty::Region::Infer(rvid2)) => {
-                    dirty_queue.insert(rvid1);
-                    dirty_queue.insert(rvid2);
-                    edges.entry(rvid1).or_default().push(rvid2);
+                    dirty_queue.insert(*rvid1);
+                    dirty_queue.insert(*rvid2);
+                    map.entry(*rvid1).or_default();
+                    map.entry(*rvid2).or_default();
+                    edges.entry(*rvid1).or_default().push(*rvid2);
                 }
                 (ty::Region::Concrete(places), ty::Region::Infer(rvid)) => {
-                    dirty_queue.insert(rvid);
-                    map.entry(rvid).or_default().extend(places)
+                    dirty_queue.insert(*rvid);
+                    map.entry(*rvid).or_default().extend(places.iter().cloned())
+                }
+                (ty::Region::Universal(region), ty::Region::Infer(rvid)) => {
+                    dirty_queue.insert(*rvid);
+                    map.entry(*rvid).or_default();
+                    universals.entry(*rvid).or_default().insert(*region);
+                }
+                (ty::Region::Mixed(region, places), ty::Region::Infer(rvid)) => {
+                    dirty_queue.insert(*rvid);
+                    map.entry(*rvid).or_default().extend(places.iter().cloned());
+                    universals.entry(*rvid).or_default().insert(*region);
                 }
-                // TODO: we should check that the rest of the constraints are satisfied at
-                // the end of the fixpoint iteration.
                 _ => {}
             }
         }
@@ -207,21 +342,73 @@ impl Constraints {
             for rvid2 in edges.entry(rvid1).or_default() {
                 let new_places = map.entry(rvid1).or_default().clone();
                 let places = map.entry(*rvid2).or_default();
-                if new_places.into_iter().any(|place| places.insert(place)) {
+                let mut changed = new_places.into_iter().any(|place| places.insert(place));
+                let new_universals = universals.entry(rvid1).or_default().clone();
+                let regions = universals.entry(*rvid2).or_default();
+                changed |= new_universals
+                    .into_iter()
+                    .any(|region| regions.insert(region));
+                if changed {
                     dirty_queue.insert(*rvid2);
                 }
             }
         }
-        map.into_iter()
-            .map(|(rvid, places)| (rvid, places.into_iter().collect()))
-            .collect()
+        // The universal regions flowing into a region are represented by the one they all outlive,
+        // and the places borrowed locally are kept alongside it, as their loans still have to be
+        // ended.
+        let solution: Solution = map
+            .into_iter()
+            .map(|(rvid, places)| {
+                let places: Vec<_> = places.into_iter().collect();
+                let region = match least_universal(env, universals.remove(&rvid)) {
+                    None => ty::Region::Concrete(places),
+                    Some(region) if places.is_empty() => ty::Region::Universal(region),
+                    Some(region) => ty::Region::Mixed(region, places),
+                };
+                (rvid, region)
+            })
+            .collect();
+
+        let errors = self
+            .constraints
+            .into_iter()
+            .map(|(r1, r2, source_info)| {
+                let r1 = solution.fix_region(&r1);
+                let r2 = solution.fix_region(&r2);
+                (r1, r2, source_info)
+            })
+            .filter(|(r1, r2, _)| !env.outlives(r1, r2))
+            .map(|(r1, r2, source_info)| SpannedError {
+                error: OwnershipError::UnsatisfiedOutlives(r1, r2),
+                source_info,
+            })
+            .collect();
+        (solution, errors)
     }
 }
 
-pub struct Solution(HashMap<ty::RegionVid, Vec<Place>>);
+/// Returns the universal region outlived by all of `universals`, if any. If there is no such
+/// region, the smallest of them is returned, so the result doesn't depend on the order of the
+/// set. Re-checking the constraints then reports every region that doesn't outlive it, i.e.,
+/// the pairs of unrelated regions.
+fn least_universal(
+    env: &Env,
+    universals: Option<HashSet<UniversalRegion>>,
+) -> Option<UniversalRegion> {
+    let mut universals: Vec<_> = universals?.into_iter().collect();
+    universals.sort();
+    let outlives = |r1, r2| env.outlives(&ty::Region::Universal(r1), &ty::Region::Universal(r2));
+    universals
+        .iter()
+        .copied()
+        .find(|&r2| universals.iter().all(|&r1| outlives(r1, r2)))
+        .or_else(|| universals.first().copied())
+}
+
+pub struct Solution(HashMap<ty::RegionVid, ty::Region>);
 
 wrap_iterable! {
-    Solution: HashMap<ty::RegionVid, Vec<Place>>
+    Solution: HashMap<ty::RegionVid, ty::Region>
 }
 
 impl Solution {
@@ -249,7 +436,10 @@ impl Solution {
 
     fn fix_regions_fn_ty(&self, tcx: &TyCtxt, fn_ty: FnDecl) -> FnDecl {
         FnDecl {
+            ty_params: fn_ty.ty_params,
+            pred_params: fn_ty.pred_params,
             regions: fn_ty.regions.clone(),
+            outlives: fn_ty.outlives,
             in_heap: self.fix_regions_heap(tcx, fn_ty.in_heap),
             inputs: fn_ty.inputs,
             out_heap: self.fix_regions_heap(tcx, fn_ty.out_heap),
@@ -270,13 +460,23 @@ impl Solution {
                 let tup = tup.map(|_, fld, ty| (*fld, self.fix_regions_ty(tcx, ty.clone())));
                 tcx.mk_tuple(tup)
             }
+            ty::TyKind::Indexed(ty, indices) => {
+                tcx.mk_indexed(self.fix_regions_ty(tcx, ty.clone()), indices.clone())
+            }
             ty::TyKind::Ref(bk, r, l) => match r {
-                ty::Region::Infer(kvid) => {
-                    tcx.mk_ref(*bk, ty::Region::Concrete(self.0[kvid].clone()), *l)
-                }
-                ty::Region::Concrete(_) | ty::Region::Universal(_) => ty,
+                ty::Region::Infer(_) => tcx.mk_ref(*bk, self.fix_region(r), *l),
+                ty::Region::Concrete(_) | ty::Region::Universal(_) | ty::Region::Mixed(..) => ty,
             },
             _ => ty,
         }
     }
+
+    fn fix_region(&self, region: &ty::Region) -> ty::Region {
+        match region {
+            ty::Region::Infer(rvid) => self.0[rvid].clone(),
+            ty::Region::Concrete(_) | ty::Region::Universal(_) | ty::Region::Mixed(..) => {
+                region.clone()
+            }
+        }
+    }
 }